@@ -0,0 +1,912 @@
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::classes::ExpiringValue::unix_millis_now;
+use crate::classes::Glob::glob_match;
+
+// Access control lists: the users connections authenticate as, and what
+// each of them may run. A user is described by the rules of ACL SETUSER,
+// applied in order, which is also how ACL LIST and the aclfile show it.
+
+// The categories of ACL CAT, in Redis's order
+pub const CATEGORIES: &[&str] = &[
+    "keyspace", "read", "write", "set", "sortedset", "list", "hash", "string", "bitmap", "hyperloglog", "geo", "stream",
+    "pubsub", "admin", "fast", "slow", "blocking", "dangerous", "connection", "transaction", "scripting",
+];
+
+// Where the keys of a command are among its arguments
+#[derive(Clone, Copy)]
+pub enum KeyPosition {
+    // from `first` to `last` (negative counts from the end) every `step`
+    Range(usize, isize, usize),
+    // as many keys as the count at this index says, right after it
+    Counted(usize),
+    // the first half of the arguments after the keyword, like XREAD STREAMS
+    AfterKeyword(&'static str),
+}
+
+// Keys a command reads (returns something of) or writes
+#[derive(Clone, Copy)]
+pub struct KeySpec {
+    pub read: bool,
+    pub write: bool,
+    pub position: KeyPosition,
+}
+
+const fn ro(position: KeyPosition) -> KeySpec {
+    KeySpec { read: true, write: false, position }
+}
+
+const fn wo(position: KeyPosition) -> KeySpec {
+    KeySpec { read: false, write: true, position }
+}
+
+const fn rw(position: KeyPosition) -> KeySpec {
+    KeySpec { read: true, write: true, position }
+}
+
+// A command, or a subcommand as `parent|sub`, as ACL rules know it.
+// Container commands have no categories of their own: their subcommands do.
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub categories: &'static [&'static str],
+    pub keys: &'static [KeySpec],
+}
+
+const fn command(name: &'static str, categories: &'static [&'static str], keys: &'static [KeySpec]) -> CommandSpec {
+    CommandSpec { name, categories, keys }
+}
+
+use KeyPosition::{AfterKeyword, Counted, Range};
+
+const FIRST: KeyPosition = Range(1, 1, 1);
+const SECOND: KeyPosition = Range(2, 2, 1);
+const ALL: KeyPosition = Range(1, -1, 1);
+
+pub const COMMANDS: &[CommandSpec] = &[
+    // strings
+    command("get", &["read", "string", "fast"], &[ro(FIRST)]),
+    command("set", &["write", "string", "slow"], &[wo(FIRST)]),
+    command("incr", &["write", "string", "fast"], &[rw(FIRST)]),
+    command("incrby", &["write", "string", "fast"], &[rw(FIRST)]),
+    command("decr", &["write", "string", "fast"], &[rw(FIRST)]),
+    command("decrby", &["write", "string", "fast"], &[rw(FIRST)]),
+    command("incrbyfloat", &["write", "string", "fast"], &[rw(FIRST)]),
+    command("append", &["write", "string", "fast"], &[wo(FIRST)]),
+    command("strlen", &["read", "string", "fast"], &[ro(FIRST)]),
+    command("getrange", &["read", "string", "slow"], &[ro(FIRST)]),
+    command("substr", &["read", "string", "slow"], &[ro(FIRST)]),
+    command("setrange", &["write", "string", "slow"], &[wo(FIRST)]),
+    command("getset", &["write", "string", "fast"], &[rw(FIRST)]),
+    command("getdel", &["write", "string", "fast"], &[rw(FIRST)]),
+    command("getex", &["write", "string", "fast"], &[rw(FIRST)]),
+    command("setnx", &["write", "string", "fast"], &[wo(FIRST)]),
+    command("setex", &["write", "string", "slow"], &[wo(FIRST)]),
+    command("psetex", &["write", "string", "slow"], &[wo(FIRST)]),
+    command("mset", &["write", "string", "slow"], &[wo(Range(1, -1, 2))]),
+    command("msetnx", &["write", "string", "slow"], &[wo(Range(1, -1, 2))]),
+    command("mget", &["read", "string", "fast"], &[ro(ALL)]),
+    command("lcs", &["read", "string", "slow"], &[ro(Range(1, 2, 1))]),
+    // bitmaps
+    command("setbit", &["write", "bitmap", "slow"], &[rw(FIRST)]),
+    command("getbit", &["read", "bitmap", "fast"], &[ro(FIRST)]),
+    command("bitcount", &["read", "bitmap", "slow"], &[ro(FIRST)]),
+    command("bitpos", &["read", "bitmap", "slow"], &[ro(FIRST)]),
+    command("bitop", &["write", "bitmap", "slow"], &[wo(SECOND), ro(Range(3, -1, 1))]),
+    command("bitfield", &["write", "bitmap", "slow"], &[rw(FIRST)]),
+    command("bitfield_ro", &["read", "bitmap", "fast"], &[ro(FIRST)]),
+    // hyperloglogs
+    command("pfadd", &["write", "hyperloglog", "fast"], &[wo(FIRST)]),
+    command("pfcount", &["read", "hyperloglog", "slow"], &[ro(ALL)]),
+    command("pfmerge", &["write", "hyperloglog", "slow"], &[rw(FIRST), ro(Range(2, -1, 1))]),
+    // sorted sets and geo
+    command("zadd", &["write", "sortedset", "fast"], &[wo(FIRST)]),
+    command("zrem", &["write", "sortedset", "fast"], &[wo(FIRST)]),
+    command("zscore", &["read", "sortedset", "fast"], &[ro(FIRST)]),
+    command("zcard", &["read", "sortedset", "fast"], &[ro(FIRST)]),
+    command("zrank", &["read", "sortedset", "fast"], &[ro(FIRST)]),
+    command("zrange", &["read", "sortedset", "slow"], &[ro(FIRST)]),
+    command("zscan", &["read", "sortedset", "slow"], &[ro(FIRST)]),
+    command("geoadd", &["write", "geo", "slow"], &[wo(FIRST)]),
+    command("geopos", &["read", "geo", "slow"], &[ro(FIRST)]),
+    command("geodist", &["read", "geo", "slow"], &[ro(FIRST)]),
+    command("geohash", &["read", "geo", "slow"], &[ro(FIRST)]),
+    command("geosearch", &["read", "geo", "slow"], &[ro(FIRST)]),
+    command("geosearchstore", &["write", "geo", "slow"], &[wo(FIRST), ro(SECOND)]),
+    // streams
+    command("xadd", &["write", "stream", "fast"], &[wo(FIRST)]),
+    command("xtrim", &["write", "stream", "slow"], &[wo(FIRST)]),
+    command("xdel", &["write", "stream", "fast"], &[wo(FIRST)]),
+    command("xsetid", &["write", "stream", "fast"], &[wo(FIRST)]),
+    command("xlen", &["read", "stream", "fast"], &[ro(FIRST)]),
+    command("xrange", &["read", "stream", "slow"], &[ro(FIRST)]),
+    command("xrevrange", &["read", "stream", "slow"], &[ro(FIRST)]),
+    command("xread", &["read", "stream", "slow", "blocking"], &[ro(AfterKeyword("STREAMS"))]),
+    command("xreadgroup", &["write", "stream", "slow", "blocking"], &[rw(AfterKeyword("STREAMS"))]),
+    command("xack", &["write", "stream", "fast"], &[wo(FIRST)]),
+    command("xclaim", &["write", "stream", "fast"], &[rw(FIRST)]),
+    command("xautoclaim", &["write", "stream", "fast"], &[rw(FIRST)]),
+    command("xpending", &["read", "stream", "slow"], &[ro(FIRST)]),
+    command("xgroup", &[], &[]),
+    command("xgroup|create", &["write", "stream", "slow"], &[wo(SECOND)]),
+    command("xgroup|setid", &["write", "stream", "slow"], &[wo(SECOND)]),
+    command("xgroup|destroy", &["write", "stream", "slow"], &[wo(SECOND)]),
+    command("xgroup|createconsumer", &["write", "stream", "slow"], &[wo(SECOND)]),
+    command("xgroup|delconsumer", &["write", "stream", "slow"], &[wo(SECOND)]),
+    command("xgroup|help", &["stream", "slow"], &[]),
+    command("xinfo", &[], &[]),
+    command("xinfo|stream", &["read", "stream", "slow"], &[ro(SECOND)]),
+    command("xinfo|groups", &["read", "stream", "slow"], &[ro(SECOND)]),
+    command("xinfo|consumers", &["read", "stream", "slow"], &[ro(SECOND)]),
+    command("xinfo|help", &["stream", "slow"], &[]),
+    // lists
+    command("lpush", &["write", "list", "fast"], &[wo(FIRST)]),
+    command("rpush", &["write", "list", "fast"], &[wo(FIRST)]),
+    command("lpushx", &["write", "list", "fast"], &[wo(FIRST)]),
+    command("rpushx", &["write", "list", "fast"], &[wo(FIRST)]),
+    command("lpop", &["write", "list", "fast"], &[rw(FIRST)]),
+    command("rpop", &["write", "list", "fast"], &[rw(FIRST)]),
+    command("llen", &["read", "list", "fast"], &[ro(FIRST)]),
+    command("lrange", &["read", "list", "slow"], &[ro(FIRST)]),
+    command("lindex", &["read", "list", "slow"], &[ro(FIRST)]),
+    command("lpos", &["read", "list", "slow"], &[ro(FIRST)]),
+    command("lset", &["write", "list", "slow"], &[wo(FIRST)]),
+    command("linsert", &["write", "list", "slow"], &[wo(FIRST)]),
+    command("lrem", &["write", "list", "slow"], &[wo(FIRST)]),
+    command("ltrim", &["write", "list", "slow"], &[wo(FIRST)]),
+    command("lmove", &["write", "list", "slow"], &[rw(FIRST), wo(SECOND)]),
+    command("rpoplpush", &["write", "list", "slow"], &[rw(FIRST), wo(SECOND)]),
+    command("lmpop", &["write", "list", "slow"], &[rw(Counted(1))]),
+    command("blpop", &["write", "list", "slow", "blocking"], &[rw(Range(1, -2, 1))]),
+    command("brpop", &["write", "list", "slow", "blocking"], &[rw(Range(1, -2, 1))]),
+    command("blmove", &["write", "list", "slow", "blocking"], &[rw(FIRST), wo(SECOND)]),
+    command("brpoplpush", &["write", "list", "slow", "blocking"], &[rw(FIRST), wo(SECOND)]),
+    command("blmpop", &["write", "list", "slow", "blocking"], &[rw(Counted(2))]),
+    // pub/sub, whose channels are checked apart, see `command_channels`
+    command("subscribe", &["pubsub", "slow"], &[]),
+    command("unsubscribe", &["pubsub", "slow"], &[]),
+    command("psubscribe", &["pubsub", "slow"], &[]),
+    command("punsubscribe", &["pubsub", "slow"], &[]),
+    command("ssubscribe", &["pubsub", "slow"], &[]),
+    command("sunsubscribe", &["pubsub", "slow"], &[]),
+    command("publish", &["pubsub", "fast"], &[]),
+    command("spublish", &["pubsub", "fast"], &[]),
+    command("pubsub", &[], &[]),
+    command("pubsub|channels", &["pubsub", "slow"], &[]),
+    command("pubsub|numsub", &["pubsub", "slow"], &[]),
+    command("pubsub|numpat", &["pubsub", "slow"], &[]),
+    command("pubsub|shardchannels", &["pubsub", "slow"], &[]),
+    command("pubsub|shardnumsub", &["pubsub", "slow"], &[]),
+    command("pubsub|help", &["slow"], &[]),
+    // the keyspace
+    command("del", &["keyspace", "write", "slow"], &[wo(ALL)]),
+    command("unlink", &["keyspace", "write", "fast"], &[wo(ALL)]),
+    command("exists", &["keyspace", "read", "fast"], &[ro(ALL)]),
+    command("type", &["keyspace", "read", "fast"], &[ro(FIRST)]),
+    command("keys", &["keyspace", "read", "slow", "dangerous"], &[]),
+    command("scan", &["keyspace", "read", "slow"], &[]),
+    command("dbsize", &["keyspace", "read", "fast"], &[]),
+    command("expire", &["keyspace", "write", "fast"], &[wo(FIRST)]),
+    command("pexpire", &["keyspace", "write", "fast"], &[wo(FIRST)]),
+    command("expireat", &["keyspace", "write", "fast"], &[wo(FIRST)]),
+    command("pexpireat", &["keyspace", "write", "fast"], &[wo(FIRST)]),
+    command("persist", &["keyspace", "write", "fast"], &[wo(FIRST)]),
+    command("ttl", &["keyspace", "read", "fast"], &[ro(FIRST)]),
+    command("pttl", &["keyspace", "read", "fast"], &[ro(FIRST)]),
+    command("expiretime", &["keyspace", "read", "fast"], &[ro(FIRST)]),
+    command("pexpiretime", &["keyspace", "read", "fast"], &[ro(FIRST)]),
+    command("rename", &["keyspace", "write", "slow"], &[rw(FIRST), wo(SECOND)]),
+    command("renamenx", &["keyspace", "write", "fast"], &[rw(FIRST), wo(SECOND)]),
+    command("move", &["keyspace", "write", "fast"], &[rw(FIRST)]),
+    command("swapdb", &["keyspace", "write", "fast", "dangerous"], &[]),
+    command("flushdb", &["keyspace", "write", "slow", "dangerous"], &[]),
+    command("flushall", &["keyspace", "write", "slow", "dangerous"], &[]),
+    command("object", &[], &[]),
+    command("object|encoding", &["keyspace", "read", "slow"], &[ro(SECOND)]),
+    command("object|idletime", &["keyspace", "read", "slow"], &[ro(SECOND)]),
+    command("object|freq", &["keyspace", "read", "slow"], &[ro(SECOND)]),
+    command("object|refcount", &["keyspace", "read", "slow"], &[ro(SECOND)]),
+    command("object|help", &["keyspace", "slow"], &[]),
+    // the server
+    command("memory", &[], &[]),
+    command("memory|usage", &["read", "slow"], &[ro(SECOND)]),
+    command("memory|stats", &["slow"], &[]),
+    command("memory|doctor", &["slow"], &[]),
+    command("memory|bigkeys", &["keyspace", "read", "slow"], &[]),
+    command("memory|malloc-stats", &["slow"], &[]),
+    command("memory|purge", &["slow"], &[]),
+    command("memory|help", &["slow"], &[]),
+    command("info", &["slow", "dangerous"], &[]),
+    command("config", &[], &[]),
+    command("config|get", &["admin", "slow", "dangerous"], &[]),
+    command("config|set", &["admin", "slow", "dangerous"], &[]),
+    command("config|rewrite", &["admin", "slow", "dangerous"], &[]),
+    command("config|resetstat", &["admin", "slow", "dangerous"], &[]),
+    command("config|help", &["slow"], &[]),
+    command("acl", &[], &[]),
+    command("acl|cat", &["slow"], &[]),
+    command("acl|whoami", &["slow"], &[]),
+    command("acl|setuser", &["admin", "slow", "dangerous"], &[]),
+    command("acl|getuser", &["admin", "slow", "dangerous"], &[]),
+    command("acl|deluser", &["admin", "slow", "dangerous"], &[]),
+    command("acl|list", &["admin", "slow", "dangerous"], &[]),
+    command("acl|users", &["admin", "slow", "dangerous"], &[]),
+    command("acl|dryrun", &["admin", "slow", "dangerous"], &[]),
+    command("acl|log", &["admin", "slow", "dangerous"], &[]),
+    command("acl|load", &["admin", "slow", "dangerous"], &[]),
+    command("acl|save", &["admin", "slow", "dangerous"], &[]),
+    command("acl|help", &["slow"], &[]),
+    command("save", &["admin", "slow", "dangerous"], &[]),
+    command("bgsave", &["admin", "slow", "dangerous"], &[]),
+    command("lastsave", &["admin", "fast", "dangerous"], &[]),
+    command("replconf", &["admin", "slow", "dangerous"], &[]),
+    command("psync", &["admin", "slow", "dangerous"], &[]),
+    command("wait", &["slow", "connection"], &[]),
+    command("cluster", &[], &[]),
+    command("cluster|keyslot", &["slow"], &[]),
+    // connections and transactions
+    command("ping", &["fast", "connection"], &[]),
+    command("echo", &["fast", "connection"], &[]),
+    command("auth", &["fast", "connection"], &[]),
+    command("hello", &["fast", "connection"], &[]),
+    command("quit", &["fast", "connection"], &[]),
+    command("reset", &["fast", "connection"], &[]),
+    command("select", &["fast", "connection"], &[]),
+    command("multi", &["fast", "transaction"], &[]),
+    command("exec", &["slow", "transaction"], &[]),
+    command("discard", &["fast", "transaction"], &[]),
+];
+
+// The command the arguments run: the subcommand of a container command
+// when there is one by that name, the command itself otherwise
+pub fn find_command(args: &[String]) -> Option<&'static CommandSpec> {
+    let name = args.first()?.to_lowercase();
+    let by_name = |name: &str| COMMANDS.iter().find(|spec| spec.name == name);
+    args.get(1)
+        .and_then(|sub| by_name(&format!("{}|{}", name, sub.to_lowercase())))
+        .or_else(|| by_name(&name))
+}
+
+// The keys among the arguments with whether they are read and written
+pub fn command_keys<'a>(spec: &CommandSpec, args: &'a [String]) -> Vec<(&'a String, bool, bool)> {
+    let mut keys = Vec::new();
+    for key_spec in spec.keys {
+        let positions: Vec<usize> = match key_spec.position {
+            Range(first, last, step) => {
+                let last = if last < 0 { args.len() as isize + last } else { last };
+                (first..=last.max(0) as usize).step_by(step).collect()
+            }
+            Counted(index) => {
+                let count = args.get(index).and_then(|count| count.parse::<usize>().ok()).unwrap_or(0);
+                (index + 1..=index + count).collect()
+            }
+            AfterKeyword(keyword) => match args.iter().position(|arg| arg.eq_ignore_ascii_case(keyword)) {
+                Some(index) => {
+                    let after = args.len() - index - 1;
+                    (index + 1..=index + after / 2).collect()
+                }
+                None => Vec::new(),
+            },
+        };
+        for position in positions {
+            if let Some(key) = args.get(position) {
+                keys.push((key, key_spec.read, key_spec.write));
+            }
+        }
+    }
+    keys
+}
+
+// The channels among the arguments, and whether they are patterns
+fn command_channels<'a>(spec: &CommandSpec, args: &'a [String]) -> Vec<(&'a String, bool)> {
+    match spec.name {
+        "subscribe" | "ssubscribe" => args.iter().skip(1).map(|channel| (channel, false)).collect(),
+        "psubscribe" => args.iter().skip(1).map(|pattern| (pattern, true)).collect(),
+        "publish" | "spublish" => args.get(1).map(|channel| (channel, false)).into_iter().collect(),
+        _ => Vec::new(),
+    }
+}
+
+// What a command was denied for
+pub enum Denial {
+    Command,
+    Key(String),
+    Channel(String),
+}
+
+impl Denial {
+    // The reason ACL LOG gives
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Denial::Command => "command",
+            Denial::Key(_) => "key",
+            Denial::Channel(_) => "channel",
+        }
+    }
+
+    // What ACL LOG says was denied: the command, key or channel
+    pub fn object(&self, command: &str) -> String {
+        match self {
+            Denial::Command => command.to_string(),
+            Denial::Key(name) | Denial::Channel(name) => name.clone(),
+        }
+    }
+
+    pub fn message(&self, username: &str, command: &str) -> String {
+        match self {
+            Denial::Command => format!("User {} has no permissions to run the '{}' command", username, command),
+            Denial::Key(key) => format!("User {} has no permissions to access the '{}' key", username, key),
+            Denial::Channel(channel) => format!("User {} has no permissions to access the '{}' channel", username, channel),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+struct KeyPattern {
+    pattern: String,
+    read: bool,
+    write: bool,
+}
+
+#[derive(Clone)]
+pub struct User {
+    pub name: String,
+    enabled: bool,
+    nopass: bool,
+    // SHA-256 hashes of the passwords, in hex
+    passwords: Vec<String>,
+    // `+`/`-` command, `command|subcommand` and `@category` rules, the
+    // last one matching a command deciding about it
+    commands: Vec<String>,
+    keys: Vec<KeyPattern>,
+    channels: Vec<String>,
+}
+
+impl User {
+    // A new user can do nothing until rules say otherwise
+    fn new(name: &str) -> User {
+        User {
+            name: name.to_string(),
+            enabled: false,
+            nopass: false,
+            passwords: Vec::new(),
+            commands: vec!["-@all".to_string()],
+            keys: Vec::new(),
+            channels: Vec::new(),
+        }
+    }
+
+    // The default user can do anything without a password
+    fn default_user() -> User {
+        let mut user = User::new("default");
+        for rule in ["on", "nopass", "allkeys", "allchannels", "allcommands"] {
+            user.apply_rule(rule).expect("valid rule");
+        }
+        user
+    }
+
+    fn apply_rule(&mut self, rule: &str) -> Result<(), &'static str> {
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => return self.apply_rule("~*"),
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => return self.apply_rule("&*"),
+            "resetchannels" => self.channels.clear(),
+            "allcommands" => return self.apply_rule("+@all"),
+            "nocommands" => return self.apply_rule("-@all"),
+            "reset" => {
+                for rule in ["resetpass", "resetkeys", "resetchannels", "off", "-@all"] {
+                    self.apply_rule(rule)?;
+                }
+            }
+            _ => return self.apply_operator_rule(rule),
+        }
+        Ok(())
+    }
+
+    // The rules made of an operator and an argument, like `>password`
+    fn apply_operator_rule(&mut self, rule: &str) -> Result<(), &'static str> {
+        let Some(operator) = rule.chars().next() else {
+            return Err(SYNTAX_ERROR);
+        };
+        let argument = &rule[operator.len_utf8()..];
+        match operator {
+            '>' => self.add_password(hash_password(argument)),
+            '#' => self.add_password(parse_hash(argument)?),
+            '<' => self.remove_password(&hash_password(argument))?,
+            '!' => self.remove_password(&parse_hash(argument)?)?,
+            '~' => self.add_key_pattern(argument, true, true)?,
+            '%' => {
+                let (flags, pattern) = argument.split_once('~').ok_or(SYNTAX_ERROR)?;
+                let read = flags.to_uppercase().contains('R');
+                let write = flags.to_uppercase().contains('W');
+                if flags.is_empty() || flags.to_uppercase().chars().any(|flag| flag != 'R' && flag != 'W') {
+                    return Err(SYNTAX_ERROR);
+                }
+                self.add_key_pattern(pattern, read, write)?;
+            }
+            '&' => {
+                if self.channels.iter().any(|channel| channel == "*") {
+                    return Err("Adding a pattern after the * pattern (or the 'allchannels' flag) is not valid and does not have any effect. Try 'resetchannels' to start with an empty list of channels");
+                }
+                if argument == "*" {
+                    self.channels.clear();
+                }
+                if !self.channels.iter().any(|channel| channel == argument) {
+                    self.channels.push(argument.to_string());
+                }
+            }
+            '+' | '-' => {
+                let target = argument.to_lowercase();
+                let known = match target.strip_prefix('@') {
+                    Some(category) => category == "all" || CATEGORIES.contains(&category),
+                    None => COMMANDS.iter().any(|spec| spec.name == target),
+                };
+                if !known {
+                    return Err("Unknown command or category name in ACL");
+                }
+                // everything before `+@all` or `-@all` is overridden by it
+                if target == "@all" {
+                    self.commands.clear();
+                }
+                self.commands.push(format!("{}{}", operator, target));
+            }
+            _ => return Err(SYNTAX_ERROR),
+        }
+        Ok(())
+    }
+
+    fn add_password(&mut self, hash: String) {
+        self.nopass = false;
+        if !self.passwords.contains(&hash) {
+            self.passwords.push(hash);
+        }
+    }
+
+    fn remove_password(&mut self, hash: &str) -> Result<(), &'static str> {
+        let Some(index) = self.passwords.iter().position(|password| password == hash) else {
+            return Err("The password you are trying to remove from the user does not exist");
+        };
+        self.passwords.remove(index);
+        Ok(())
+    }
+
+    fn add_key_pattern(&mut self, pattern: &str, read: bool, write: bool) -> Result<(), &'static str> {
+        if self.keys.iter().any(|key| key.pattern == "*" && key.read && key.write) {
+            return Err("Adding a pattern after the * pattern (or the 'allkeys' flag) is not valid and does not have any effect. Try 'resetkeys' to start with an empty list of patterns");
+        }
+        if pattern == "*" && read && write {
+            self.keys.clear();
+        }
+        let key = KeyPattern { pattern: pattern.to_string(), read, write };
+        if !self.keys.contains(&key) {
+            self.keys.push(key);
+        }
+        Ok(())
+    }
+
+    pub fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    pub fn password_hashes(&self) -> &[String] {
+        &self.passwords
+    }
+
+    pub fn describe_commands(&self) -> String {
+        self.commands.join(" ")
+    }
+
+    pub fn describe_keys(&self) -> String {
+        let keys: Vec<String> = self
+            .keys
+            .iter()
+            .map(|key| match (key.read, key.write) {
+                (true, true) => format!("~{}", key.pattern),
+                (true, false) => format!("%R~{}", key.pattern),
+                _ => format!("%W~{}", key.pattern),
+            })
+            .collect();
+        keys.join(" ")
+    }
+
+    pub fn describe_channels(&self) -> String {
+        let channels: Vec<String> = self.channels.iter().map(|channel| format!("&{}", channel)).collect();
+        channels.join(" ")
+    }
+
+    // The user as ACL LIST and the aclfile show it, rules that recreate it
+    pub fn describe(&self) -> String {
+        let mut rules = vec![format!("user {}", self.name)];
+        rules.extend(self.flags().iter().map(|flag| flag.to_string()));
+        rules.extend(self.passwords.iter().map(|hash| format!("#{}", hash)));
+        if !self.keys.is_empty() {
+            rules.push(self.describe_keys());
+        }
+        if self.channels.iter().any(|channel| channel == "*") {
+            rules.push("&*".to_string());
+        } else {
+            rules.push("resetchannels".to_string());
+            if !self.channels.is_empty() {
+                rules.push(self.describe_channels());
+            }
+        }
+        rules.push(self.describe_commands());
+        rules.join(" ")
+    }
+
+    fn check_password(&self, password: &str) -> bool {
+        self.enabled && (self.nopass || self.passwords.contains(&hash_password(password)))
+    }
+
+    // Whether no rule takes any command away, which is all that lets a
+    // user run commands the table doesn't know
+    pub fn allows_all_commands(&self) -> bool {
+        self.commands == ["+@all"]
+    }
+
+    // Whether the user may run the command with these arguments
+    pub fn check(&self, spec: &CommandSpec, args: &[String]) -> Result<(), Denial> {
+        let parent = spec.name.split('|').next().unwrap_or(spec.name);
+        let mut allowed = false;
+        for rule in &self.commands {
+            let target = &rule[1..];
+            let matches = match target.strip_prefix('@') {
+                Some(category) => category == "all" || spec.categories.contains(&category),
+                None => target == spec.name || target == parent,
+            };
+            if matches {
+                allowed = rule.starts_with('+');
+            }
+        }
+        if !allowed {
+            return Err(Denial::Command);
+        }
+        for (key, read, write) in command_keys(spec, args) {
+            let permitted = self
+                .keys
+                .iter()
+                .any(|pattern| (pattern.read || !read) && (pattern.write || !write) && glob_match(&pattern.pattern, key));
+            if !permitted {
+                return Err(Denial::Key(key.clone()));
+            }
+        }
+        for (channel, is_pattern) in command_channels(spec, args) {
+            // patterns must be allowed as such, not only match
+            let permitted = self.channels.iter().any(|allowed| {
+                allowed == "*" || if is_pattern { allowed == channel } else { glob_match(allowed, channel) }
+            });
+            if !permitted {
+                return Err(Denial::Channel(channel.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+const SYNTAX_ERROR: &str = "Syntax error";
+
+fn with_rules(mut user: User, rules: &[String]) -> Result<User, String> {
+    if user.name.contains(|c: char| c.is_whitespace() || c == '\0') {
+        return Err("Usernames can't contain spaces or null characters".to_string());
+    }
+    for rule in rules {
+        user.apply_rule(rule)
+            .map_err(|error| format!("Error in ACL SETUSER modifier '{}': {}", rule, error))?;
+    }
+    Ok(user)
+}
+
+pub fn hash_password(password: &str) -> String {
+    hex::encode(Sha256::digest(password.as_bytes()))
+}
+
+fn parse_hash(hash: &str) -> Result<String, &'static str> {
+    if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)) {
+        return Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters");
+    }
+    Ok(hash.to_string())
+}
+
+// An entry of ACL LOG. Denials alike within a minute of each other are
+// counted in one entry.
+pub struct LogEntry {
+    pub count: u64,
+    pub reason: &'static str,
+    pub context: &'static str,
+    pub object: String,
+    pub username: String,
+    pub client_info: String,
+    pub entry_id: u64,
+    pub created: u64,
+    pub updated: u64,
+}
+
+const LOG_GROUPING_MILLIS: u64 = 60 * 1000;
+
+pub struct Acl {
+    users: BTreeMap<String, User>,
+    // the most recent first
+    log: VecDeque<LogEntry>,
+    next_entry_id: u64,
+}
+
+impl Acl {
+    pub fn new() -> Acl {
+        let mut users = BTreeMap::new();
+        users.insert("default".to_string(), User::default_user());
+        Acl { users, log: VecDeque::new(), next_entry_id: 0 }
+    }
+
+    pub fn user(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    pub fn users(&self) -> impl Iterator<Item = &User> {
+        self.users.values()
+    }
+
+    // ACL SETUSER: creates the user when needed, then applies the rules,
+    // all of them or none
+    pub fn set_user(&mut self, name: &str, rules: &[String]) -> Result<(), String> {
+        let user = self.users.get(name).cloned().unwrap_or_else(|| User::new(name));
+        self.users.insert(name.to_string(), with_rules(user, rules)?);
+        Ok(())
+    }
+
+    pub fn delete_user(&mut self, name: &str) -> bool {
+        self.users.remove(name).is_some()
+    }
+
+    // `requirepass` is the password of the default user, None for nopass
+    pub fn set_default_password(&mut self, password: Option<&str>) {
+        let user = self.users.entry("default".to_string()).or_insert_with(User::default_user);
+        match password {
+            Some(password) => {
+                user.passwords.clear();
+                user.add_password(hash_password(password));
+            }
+            None => {
+                let _ = user.apply_rule("nopass");
+            }
+        }
+    }
+
+    // The first of the passwords in clear that the default user has, None
+    // when it needs none
+    pub fn default_password<'a>(&self, candidates: impl IntoIterator<Item = &'a str>) -> Option<String> {
+        let user = self.users.get("default").filter(|user| !user.nopass)?;
+        candidates
+            .into_iter()
+            .find(|password| user.passwords.contains(&hash_password(password)))
+            .map(str::to_string)
+    }
+
+    // Whether new connections start unauthenticated: the default user they
+    // run as needs a password, or is disabled
+    pub fn requires_auth(&self) -> bool {
+        self.users.get("default").is_none_or(|user| !user.enabled || !user.nopass)
+    }
+
+    pub fn authenticate(&self, username: &str, password: &str) -> bool {
+        self.users.get(username).is_some_and(|user| user.check_password(password))
+    }
+
+    // Replaces every user with the ones of an aclfile, made of `user name
+    // rules...` lines. The default user is recreated when it's missing.
+    pub fn load(&mut self, text: &str, path: &str) -> Result<(), String> {
+        let mut users = BTreeMap::new();
+        let mut errors = String::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words: Vec<String> = line.split_whitespace().map(str::to_string).collect();
+            let error = match words.get(1) {
+                _ if words[0] != "user" => Some("line should start with user keyword".to_string()),
+                None => Some("'' is not a valid username".to_string()),
+                Some(name) if users.contains_key(name) => Some(format!("Duplicate user '{}' found", name)),
+                Some(name) => match with_rules(User::new(name), &words[2..]) {
+                    Ok(user) => {
+                        users.insert(name.clone(), user);
+                        None
+                    }
+                    Err(error) => Some(error),
+                },
+            };
+            if let Some(error) = error {
+                errors.push_str(&format!("{}:{}: {}. ", path, index + 1, error));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors.trim_end().to_string());
+        }
+        users.entry("default".to_string()).or_insert_with(User::default_user);
+        self.users = users;
+        Ok(())
+    }
+
+    pub fn to_file(&self) -> String {
+        self.users.values().map(|user| user.describe() + "\n").collect()
+    }
+
+    pub fn log_denial(
+        &mut self,
+        reason: &'static str,
+        context: &'static str,
+        object: String,
+        username: String,
+        client_info: String,
+        max_len: usize,
+    ) {
+        let now = unix_millis_now();
+        let similar = self.log.iter().position(|entry| {
+            entry.reason == reason
+                && entry.context == context
+                && entry.object == object
+                && entry.username == username
+                && now.saturating_sub(entry.updated) < LOG_GROUPING_MILLIS
+        });
+        let entry = match similar.and_then(|index| self.log.remove(index)) {
+            Some(mut entry) => {
+                entry.count += 1;
+                entry.updated = now;
+                entry.client_info = client_info;
+                entry
+            }
+            None => {
+                let entry_id = self.next_entry_id;
+                self.next_entry_id += 1;
+                LogEntry {
+                    count: 1,
+                    reason,
+                    context,
+                    object,
+                    username,
+                    client_info,
+                    entry_id,
+                    created: now,
+                    updated: now,
+                }
+            }
+        };
+        self.log.push_front(entry);
+        self.log.truncate(max_len);
+    }
+
+    pub fn log_entries(&self) -> impl Iterator<Item = &LogEntry> {
+        self.log.iter()
+    }
+
+    pub fn reset_log(&mut self) {
+        self.log.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(words: &str) -> Vec<String> {
+        words.split_whitespace().map(str::to_string).collect()
+    }
+
+    fn check(acl: &Acl, username: &str, command: &str) -> Result<(), Denial> {
+        let args = strings(command);
+        acl.user(username).unwrap().check(find_command(&args).unwrap(), &args)
+    }
+
+    // The user of the ACL documentation's examples
+    fn alice() -> Acl {
+        let mut acl = Acl::new();
+        acl.set_user("alice", &strings("on >p1pp0 ~cached:* +get")).unwrap();
+        acl
+    }
+
+    #[test]
+    fn describes_users_like_acl_list() {
+        let acl = alice();
+        assert_eq!(
+            acl.user("alice").unwrap().describe(),
+            "user alice on #2d9c75273d72b32df726fb545c8a4edc719f0a95a6fd993950b10c474ad9c927 ~cached:* resetchannels -@all +get"
+        );
+        assert_eq!(acl.user("default").unwrap().describe(), "user default on nopass ~* &* +@all");
+        assert_eq!(hash_password("foobar"), "c3ab8ff13720e8ad9047dd39466b3c8974e592c2fa383d4a3960714caef0c4f2");
+    }
+
+    #[test]
+    fn checks_commands_and_keys() {
+        let acl = alice();
+        assert!(check(&acl, "alice", "GET cached:1").is_ok());
+        assert!(matches!(check(&acl, "alice", "GET other"), Err(Denial::Key(key)) if key == "other"));
+        assert!(matches!(check(&acl, "alice", "SET cached:1 v"), Err(Denial::Command)));
+        assert!(acl.authenticate("alice", "p1pp0"));
+        assert!(!acl.authenticate("alice", "wrong"));
+    }
+
+    #[test]
+    fn last_matching_rule_wins() {
+        let mut acl = Acl::new();
+        acl.set_user("bob", &strings("on nopass allkeys +@all -@write +set")).unwrap();
+        assert!(check(&acl, "bob", "GET k").is_ok());
+        assert!(check(&acl, "bob", "SET k v").is_ok());
+        assert!(matches!(check(&acl, "bob", "DEL k"), Err(Denial::Command)));
+        // +@all clears everything before it
+        acl.set_user("bob", &strings("+@all")).unwrap();
+        assert_eq!(acl.user("bob").unwrap().describe_commands(), "+@all");
+        assert!(acl.user("bob").unwrap().allows_all_commands());
+    }
+
+    #[test]
+    fn read_and_write_key_permissions() {
+        let mut acl = Acl::new();
+        acl.set_user("reader", &strings("on nopass +@all %R~app:*")).unwrap();
+        assert!(check(&acl, "reader", "GET app:1").is_ok());
+        assert!(matches!(check(&acl, "reader", "SET app:1 v"), Err(Denial::Key(_))));
+        assert_eq!(acl.user("reader").unwrap().describe_keys(), "%R~app:*");
+    }
+
+    #[test]
+    fn channel_patterns_must_be_allowed_literally() {
+        let mut acl = Acl::new();
+        acl.set_user("news", &strings("on nopass +@all &news.*")).unwrap();
+        assert!(check(&acl, "news", "PUBLISH news.sport hi").is_ok());
+        assert!(check(&acl, "news", "PSUBSCRIBE news.*").is_ok());
+        assert!(matches!(check(&acl, "news", "PSUBSCRIBE news.s*"), Err(Denial::Channel(_))));
+        assert!(matches!(check(&acl, "news", "PUBLISH weather hi"), Err(Denial::Channel(_))));
+    }
+
+    #[test]
+    fn invalid_rules_change_nothing() {
+        let mut acl = alice();
+        let error = acl.set_user("alice", &strings("off +nosuchcommand")).unwrap_err();
+        assert_eq!(error, "Error in ACL SETUSER modifier '+nosuchcommand': Unknown command or category name in ACL");
+        assert!(acl.authenticate("alice", "p1pp0"));
+        assert!(acl.set_user("carol", &strings("on #abc")).is_err());
+        assert!(acl.user("carol").is_none());
+        assert!(acl.set_user("alice", &strings("<nosuchpassword")).is_err());
+        assert!(acl.set_user("alice", &strings("%X~foo")).is_err());
+        assert!(acl.set_user("dave", &strings("allkeys ~foo")).is_err());
+    }
+
+    #[test]
+    fn aclfile_round_trips() {
+        let mut acl = alice();
+        acl.set_user("news", &strings("on nopass +@all &news.* %W~log:*")).unwrap();
+        let text = acl.to_file();
+        let mut loaded = Acl::new();
+        loaded.load(&text, "users.acl").unwrap();
+        assert_eq!(loaded.to_file(), text);
+    }
+
+    #[test]
+    fn aclfile_errors_name_their_lines() {
+        let mut acl = alice();
+        let error = acl.load("user bob on\n\nuser bob off\nbob on\nuser eve +nosuchcommand\n", "users.acl").unwrap_err();
+        assert_eq!(
+            error,
+            "users.acl:3: Duplicate user 'bob' found. users.acl:4: line should start with user keyword. \
+             users.acl:5: Error in ACL SETUSER modifier '+nosuchcommand': Unknown command or category name in ACL."
+        );
+        // nothing was replaced
+        assert!(acl.user("alice").is_some());
+        // a file without the default user gets the built-in one
+        acl.load("user bob on nopass +@all\n", "users.acl").unwrap();
+        assert!(acl.user("alice").is_none());
+        assert!(acl.user("default").unwrap().allows_all_commands());
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Notify;
+
+use crate::classes::Connection::SharedReader;
+
+// Clients blocked on keys (BLPOP and friends, XREAD BLOCK), in the order
+// they blocked on every key. Writes that may make a key ready wake the
+// clients waiting on it; commands that consume elements only let the first
+// client of a key's queue take them, so clients are served in FIFO order.
+#[derive(Default)]
+pub struct BlockedClients {
+    next_id: u64,
+    // keyed by database and key
+    queues: HashMap<(usize, String), VecDeque<u64>>,
+    wakers: HashMap<u64, Arc<Notify>>,
+}
+
+impl BlockedClients {
+    // Queues a client on every key it waits for. The returned Notify fires
+    // whenever one of those keys may have become ready.
+    pub fn register(&mut self, db: usize, keys: &[String]) -> (u64, Arc<Notify>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        for key in keys {
+            self.queues.entry((db, key.clone())).or_default().push_back(id);
+        }
+        let waker = Arc::new(Notify::new());
+        self.wakers.insert(id, waker.clone());
+        (id, waker)
+    }
+
+    // Removes a client from its queues and wakes the clients now at their
+    // front, which may be able to take elements the removed client left
+    pub fn unregister(&mut self, db: usize, keys: &[String], id: u64) {
+        self.wakers.remove(&id);
+        for key in keys {
+            let key = (db, key.clone());
+            if let Some(queue) = self.queues.get_mut(&key) {
+                queue.retain(|blocked_id| *blocked_id != id);
+                if queue.is_empty() {
+                    self.queues.remove(&key);
+                }
+            }
+            if let Some(waker) = self.front_waker(&key) {
+                waker.notify_one();
+            }
+        }
+    }
+
+    pub fn is_first(&self, db: usize, key: &str, id: u64) -> bool {
+        self.queues.get(&(db, key.to_string())).and_then(|queue| queue.front()) == Some(&id)
+    }
+
+    pub fn has_clients(&self, db: usize, key: &str) -> bool {
+        self.queues.contains_key(&(db, key.to_string()))
+    }
+
+    // Wakes every client blocked on `key`; the ones that may not consume
+    // yet go back to waiting
+    pub fn signal_key_as_ready(&self, db: usize, key: &str) {
+        if let Some(queue) = self.queues.get(&(db, key.to_string())) {
+            self.wake(queue);
+        }
+    }
+
+    // Wakes every client blocked on a key of database `db`
+    pub fn signal_database_as_ready(&self, db: usize) {
+        for ((queue_db, _), queue) in &self.queues {
+            if *queue_db == db {
+                self.wake(queue);
+            }
+        }
+    }
+
+    fn wake(&self, queue: &VecDeque<u64>) {
+        for id in queue {
+            if let Some(waker) = self.wakers.get(id) {
+                waker.notify_one();
+            }
+        }
+    }
+
+    fn front_waker(&self, key: &(usize, String)) -> Option<&Arc<Notify>> {
+        self.queues.get(key)?.front().and_then(|id| self.wakers.get(id))
+    }
+}
+
+pub enum WakeReason {
+    Ready,
+    TimedOut,
+    Disconnected,
+}
+
+// Waits until the client is woken, its deadline passes or its connection
+// closes. Only the socket's state is peeked at: commands the client
+// pipelines while blocked stay unread until it is unblocked.
+pub async fn wait_until_ready(
+    waker: &Notify,
+    deadline: Option<Instant>,
+    reader: &SharedReader,
+) -> WakeReason {
+    let timeout = async {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+            None => std::future::pending().await,
+        }
+    };
+    let disconnect = async {
+        let mut buffer = [0u8; 1];
+        match reader.lock().await.peek(&mut buffer).await {
+            Ok(0) | Err(_) => {}
+            Ok(_) => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        _ = waker.notified() => WakeReason::Ready,
+        _ = timeout => WakeReason::TimedOut,
+        _ = disconnect => WakeReason::Disconnected,
+    }
+}
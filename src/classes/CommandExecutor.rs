@@ -1,51 +1,166 @@
 use crate::classes::{
-    RespDataType::RespDataType, State::State,
+    Acl::{find_command, Denial},
+    Parser::Command,
+    RespDataType::RespDataType,
+    State::{connection_user, is_authenticated, select_db, selected_db, State},
 };
 
-use tokio::net::tcp::OwnedReadHalf;
 use tokio::sync::Mutex;
-use tokio::{io::AsyncWriteExt, net::tcp::OwnedWriteHalf};
+use tokio::io::AsyncWriteExt;
+use crate::classes::Connection::{SharedReader, SharedWriter};
 
 use std::sync::Arc;
 
 // Import all command modules
-use crate::classes::string_commands::{handle_set, handle_get, handle_incr, handle_type};
-use crate::classes::stream_commands::{handle_xadd, handle_xrange, handle_xread};
+use crate::classes::string_commands::{
+    handle_set, handle_get, handle_incr, handle_type, handle_incrby, handle_decr, handle_decrby, handle_incrbyfloat,
+    handle_append, handle_strlen, handle_getrange, handle_setrange, handle_getset, handle_getdel, handle_getex,
+    handle_setnx, handle_setex, handle_psetex, handle_mset, handle_msetnx, handle_mget, handle_lcs,
+};
+use crate::classes::bitmap_commands::{handle_setbit, handle_getbit, handle_bitcount, handle_bitpos, handle_bitop, handle_bitfield};
+use crate::classes::hyperloglog_commands::{handle_pfadd, handle_pfcount, handle_pfmerge};
+use crate::classes::sorted_set_commands::{
+    handle_zadd, handle_zscore, handle_zcard, handle_zrem, handle_zrank, handle_zrange, handle_zscan,
+};
+use crate::classes::geo_commands::{
+    handle_geoadd, handle_geopos, handle_geodist, handle_geohash, handle_geosearch, handle_geosearchstore,
+};
+use crate::classes::stream_commands::{
+    handle_xadd, handle_xrange, handle_xread, handle_xgroup, handle_xreadgroup, handle_xack, handle_xpending, handle_xclaim,
+    handle_xautoclaim, handle_xtrim, handle_xdel, handle_xlen, handle_xsetid, handle_xrevrange, handle_xinfo,
+};
 use crate::classes::replication_commands::{handle_psync, handle_replconf, handle_wait};
-use crate::classes::meta_commands::{handle_info, handle_config_get, handle_keys, handle_echo, handle_ping};
+use crate::classes::meta_commands::{
+    handle_info, handle_config, handle_keys, handle_echo, handle_ping, handle_cluster, handle_auth, handle_hello,
+    handle_quit, handle_reset,
+};
+use crate::classes::keyspace_commands::{
+    handle_del, handle_exists, handle_expire, handle_pexpire, handle_expireat, handle_pexpireat,
+    handle_persist, handle_ttl, handle_pttl, handle_expiretime, handle_pexpiretime, handle_rename,
+    handle_select, handle_move, handle_swapdb, handle_flushdb, handle_flushall, handle_scan, handle_dbsize,
+    handle_object,
+};
+use crate::classes::persistence_commands::{handle_save, handle_bgsave, handle_lastsave};
+use crate::classes::memory_commands::handle_memory;
+use crate::classes::acl_commands::handle_acl;
 use crate::classes::transaction_commands::{handle_multi, handle_exec, handle_discard};
-use crate::classes::list_commands::{handle_rpush, handle_lrange, handle_lpush, handle_llen, handle_lpop, handle_blpop};
+use crate::classes::list_commands::{
+    handle_rpush, handle_lrange, handle_lpush, handle_llen, handle_lpop, handle_blpop, handle_rpop, handle_lindex,
+    handle_lset, handle_linsert, handle_lrem, handle_ltrim, handle_lpos, handle_lpushx, handle_rpushx, handle_lmove,
+    handle_rpoplpush, handle_lmpop, handle_brpop, handle_blmove, handle_brpoplpush, handle_blmpop,
+};
+use crate::classes::pubsub_commands::{
+    handle_subscribe, handle_unsubscribe, handle_psubscribe, handle_punsubscribe, handle_publish,
+    handle_ssubscribe, handle_sunsubscribe, handle_spublish, handle_pubsub,
+};
 
-pub struct CommandExecutor {}
+pub struct CommandExecutor {
+    // set while EXEC runs queued commands, where blocking commands must not block
+    pub in_exec: bool,
+}
 
 impl CommandExecutor {
     pub async fn execute(
         &mut self,
-        mut commands: Vec<String>,
-        reader: Arc<Mutex<OwnedReadHalf>>,
-        writer: Arc<Mutex<OwnedWriteHalf>>,
+        commands: Command,
+        reader: SharedReader,
+        writer: SharedWriter,
         state: Arc<Mutex<State>>,
-        queued: &mut Vec<Vec<String>>,
+        queued: &mut Vec<Command>,
         in_multi: &mut bool,
     ) {
-        self.handle_commands(&mut commands, reader, writer, state, queued, in_multi)
+        self.handle_commands(&commands, reader, writer, state, queued, in_multi)
             .await
     }
 
     async fn handle_commands(
         &mut self,
-        commands: &mut Vec<String>,
-        reader: Arc<Mutex<OwnedReadHalf>>,
-        mut writer: Arc<Mutex<OwnedWriteHalf>>,
+        commands: &Command,
+        reader: SharedReader,
+        writer: SharedWriter,
         state: Arc<Mutex<State>>,
-        queued: &mut Vec<Vec<String>>,
+        queued: &mut Vec<Command>,
         in_multi: &mut bool,
     ) {
         let first_command = &commands[0];
+
+        // Until it authenticates a connection may only run AUTH, HELLO, QUIT or RESET
+        if !is_authenticated()
+            && !matches!(first_command.to_uppercase().as_str(), "AUTH" | "HELLO" | "QUIT" | "RESET")
+            && state.lock().await.requires_auth().await
+        {
+            let error = RespDataType::SimpleError("NOAUTH Authentication required.".to_string());
+            writer.lock().await.write_all(error.to_string().as_bytes()).await.unwrap();
+            return;
+        }
+
+        if !self.check_permissions(commands, &writer, &state).await {
+            return;
+        }
+
+        // A connection with active subscriptions may only manage them
+        if state.lock().await.is_subscriber(&writer).await {
+            match first_command.to_uppercase().as_str() {
+                "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" | "SSUBSCRIBE" | "SUNSUBSCRIBE" | "QUIT" | "RESET" => {}
+                "PING" => {
+                    let pong = RespDataType::Array(vec![
+                        RespDataType::BulkString("pong".to_string()),
+                        RespDataType::BulkString(commands.get(1).cloned().unwrap_or_default()),
+                    ]);
+                    writer.lock().await.write_all(pong.to_string().as_bytes()).await.unwrap();
+                    return;
+                }
+                _ => {
+                    let error = format!(
+                        "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                        first_command.to_lowercase()
+                    );
+                    writer.lock().await.write_all(RespDataType::SimpleError(error).to_string().as_bytes()).await.unwrap();
+                    return;
+                }
+            }
+        }
+
+        state.lock().await.record_peak_memory().await;
+
+        // Make room for the command, and refuse the ones that would grow the
+        // dataset while it still doesn't fit (EXEC runs its queue at once)
+        if !self.in_exec {
+            let fits = self.perform_evictions(writer.clone(), state.clone()).await;
+            let command_name = first_command.to_uppercase();
+            let deny_oom = match command_name.as_str() {
+                "EXEC" => *in_multi && queued.iter().any(|command| is_deny_oom(&command[0].to_uppercase())),
+                name => is_deny_oom(name),
+            };
+            if !fits && deny_oom {
+                let error = if command_name == "EXEC" {
+                    queued.clear();
+                    *in_multi = false;
+                    format!("EXECABORT Transaction discarded because of: {}", OOM_ERROR)
+                } else {
+                    OOM_ERROR.to_string()
+                };
+                writer.lock().await.write_all(RespDataType::SimpleError(error).to_string().as_bytes()).await.unwrap();
+                return;
+            }
+        }
+
         match first_command.to_uppercase().as_str() {
                 "PING" => {
                     handle_ping(commands, writer.clone(), state.clone()).await;
                 }
+                "AUTH" => {
+                    handle_auth(commands, writer.clone(), state.clone()).await;
+                }
+                "HELLO" => {
+                    handle_hello(commands, writer.clone(), state.clone()).await;
+                }
+                "QUIT" => {
+                    handle_quit(writer.clone()).await;
+                }
+                "RESET" => {
+                    handle_reset(writer.clone(), state.clone(), queued, in_multi).await;
+                }
                 "ECHO" => {
                     handle_echo(commands, writer.clone()).await;
                 }
@@ -60,24 +175,123 @@ impl CommandExecutor {
                 "GET" => {
                     handle_get(commands, writer.clone(), state.clone()).await;
                 }
-                "INCR" => {
-                    handle_incr(commands, writer.clone(), state.clone()).await;
+                "INCR" | "INCRBY" | "DECR" | "DECRBY" | "INCRBYFLOAT" | "APPEND" | "SETRANGE" | "GETSET" | "GETDEL"
+                | "GETEX" | "SETNX" | "SETEX" | "PSETEX" | "MSET" | "MSETNX" => {
+                    match first_command.to_uppercase().as_str() {
+                        "INCR" => handle_incr(commands, writer.clone(), state.clone()).await,
+                        "INCRBY" => handle_incrby(commands, writer.clone(), state.clone()).await,
+                        "DECR" => handle_decr(commands, writer.clone(), state.clone()).await,
+                        "DECRBY" => handle_decrby(commands, writer.clone(), state.clone()).await,
+                        "INCRBYFLOAT" => handle_incrbyfloat(commands, writer.clone(), state.clone()).await,
+                        "APPEND" => handle_append(commands, writer.clone(), state.clone()).await,
+                        "SETRANGE" => handle_setrange(commands, writer.clone(), state.clone()).await,
+                        "GETSET" => handle_getset(commands, writer.clone(), state.clone()).await,
+                        "GETDEL" => handle_getdel(commands, writer.clone(), state.clone()).await,
+                        "GETEX" => handle_getex(commands, writer.clone(), state.clone()).await,
+                        "SETNX" => handle_setnx(commands, writer.clone(), state.clone()).await,
+                        "SETEX" => handle_setex(commands, writer.clone(), state.clone()).await,
+                        "PSETEX" => handle_psetex(commands, writer.clone(), state.clone()).await,
+                        "MSET" => handle_mset(commands, writer.clone(), state.clone()).await,
+                        _ => handle_msetnx(commands, writer.clone(), state.clone()).await,
+                    }
+                    // Propagate to replicas if master
+                    let role = state.lock().await.get_role().await;
+                    if role == "master" {
+                        self.propogate_to_replicas(commands, writer.clone(), state.clone()).await;
+                    }
+                }
+                "STRLEN" => {
+                    handle_strlen(commands, writer.clone(), state.clone()).await;
+                }
+                "GETRANGE" | "SUBSTR" => {
+                    handle_getrange(commands, writer.clone(), state.clone()).await;
+                }
+                "MGET" => {
+                    handle_mget(commands, writer.clone(), state.clone()).await;
+                }
+                "LCS" => {
+                    handle_lcs(commands, writer.clone(), state.clone()).await;
+                }
+                "SETBIT" | "BITOP" | "BITFIELD" => {
+                    match first_command.to_uppercase().as_str() {
+                        "SETBIT" => handle_setbit(commands, writer.clone(), state.clone()).await,
+                        "BITOP" => handle_bitop(commands, writer.clone(), state.clone()).await,
+                        _ => handle_bitfield(commands, writer.clone(), state.clone()).await,
+                    }
                     // Propagate to replicas if master
                     let role = state.lock().await.get_role().await;
                     if role == "master" {
                         self.propogate_to_replicas(commands, writer.clone(), state.clone()).await;
                     }
                 }
+                "PFADD" | "PFMERGE" => {
+                    match first_command.to_uppercase().as_str() {
+                        "PFADD" => handle_pfadd(commands, writer.clone(), state.clone()).await,
+                        _ => handle_pfmerge(commands, writer.clone(), state.clone()).await,
+                    }
+                    // Propagate to replicas if master
+                    let role = state.lock().await.get_role().await;
+                    if role == "master" {
+                        self.propogate_to_replicas(commands, writer.clone(), state.clone()).await;
+                    }
+                }
+                "PFCOUNT" => {
+                    handle_pfcount(commands, writer.clone(), state.clone()).await;
+                }
+                "ZADD" | "ZREM" | "GEOADD" | "GEOSEARCHSTORE" => {
+                    match first_command.to_uppercase().as_str() {
+                        "ZADD" => handle_zadd(commands, writer.clone(), state.clone()).await,
+                        "ZREM" => handle_zrem(commands, writer.clone(), state.clone()).await,
+                        "GEOADD" => handle_geoadd(commands, writer.clone(), state.clone()).await,
+                        _ => handle_geosearchstore(commands, writer.clone(), state.clone()).await,
+                    }
+                    // Propagate to replicas if master
+                    let role = state.lock().await.get_role().await;
+                    if role == "master" {
+                        self.propogate_to_replicas(commands, writer.clone(), state.clone()).await;
+                    }
+                }
+                "ZSCORE" => {
+                    handle_zscore(commands, writer.clone(), state.clone()).await;
+                }
+                "ZCARD" => {
+                    handle_zcard(commands, writer.clone(), state.clone()).await;
+                }
+                "ZRANK" => {
+                    handle_zrank(commands, writer.clone(), state.clone()).await;
+                }
+                "ZRANGE" => {
+                    handle_zrange(commands, writer.clone(), state.clone()).await;
+                }
+                "GEOPOS" => {
+                    handle_geopos(commands, writer.clone(), state.clone()).await;
+                }
+                "GEODIST" => {
+                    handle_geodist(commands, writer.clone(), state.clone()).await;
+                }
+                "GEOHASH" => {
+                    handle_geohash(commands, writer.clone(), state.clone()).await;
+                }
+                "GEOSEARCH" => {
+                    handle_geosearch(commands, writer.clone(), state.clone()).await;
+                }
+                "GETBIT" => {
+                    handle_getbit(commands, writer.clone(), state.clone()).await;
+                }
+                "BITCOUNT" => {
+                    handle_bitcount(commands, writer.clone(), state.clone()).await;
+                }
+                "BITPOS" => {
+                    handle_bitpos(commands, writer.clone(), state.clone()).await;
+                }
+                "BITFIELD_RO" => {
+                    handle_bitfield(commands, writer.clone(), state.clone()).await;
+                }
                 "TYPE" => {
                     handle_type(commands, writer.clone(), state.clone()).await;
                 }
                 "CONFIG" => {
-                    if let Some(bulk_config_second_string) = commands.get(1) {
-                        match bulk_config_second_string.as_str() {
-                            "GET" => handle_config_get(commands, writer.clone(), state.clone()).await,
-                            _ => {}
-                        }
-                    }
+                    handle_config(commands, writer.clone(), state.clone()).await;
                 }
                 "KEYS" => {
                     handle_keys(commands, writer.clone(), state.clone()).await;
@@ -95,40 +309,76 @@ impl CommandExecutor {
                 "WAIT" => {
                     handle_wait(commands, writer.clone(), state.clone()).await;
                 }
-                "XADD" => {
-                    handle_xadd(commands, writer.clone(), state.clone()).await;
+                "XRANGE" => {
+                    handle_xrange(commands, writer.clone(), state.clone()).await;
+                }
+                "XREVRANGE" => {
+                    handle_xrevrange(commands, writer.clone(), state.clone()).await;
+                }
+                "XLEN" => {
+                    handle_xlen(commands, writer.clone(), state.clone()).await;
+                }
+                "XINFO" => {
+                    handle_xinfo(commands, writer.clone(), state.clone()).await;
+                }
+                "XREAD" => {
+                    handle_xread(commands, writer.clone(), state.clone(), reader.clone(), !self.in_exec).await;
+                }
+                "XGROUP" | "XACK" | "XDEL" | "XSETID" => {
+                    match first_command.to_uppercase().as_str() {
+                        "XGROUP" => handle_xgroup(commands, writer.clone(), state.clone()).await,
+                        "XACK" => handle_xack(commands, writer.clone(), state.clone()).await,
+                        "XDEL" => handle_xdel(commands, writer.clone(), state.clone()).await,
+                        _ => handle_xsetid(commands, writer.clone(), state.clone()).await,
+                    }
                     // Propagate to replicas if master
                     let role = state.lock().await.get_role().await;
                     if role == "master" {
                         self.propogate_to_replicas(commands, writer.clone(), state.clone()).await;
                     }
                 }
-                "XRANGE" => {
-                    handle_xrange(commands, writer.clone(), state.clone()).await;
+                // these propagate the IDs they generate and what they trimmed
+                "XADD" => {
+                    handle_xadd(commands, writer.clone(), state.clone()).await;
                 }
-                "XREAD" => {
-                    handle_xread(commands, writer.clone(), state.clone()).await;
+                "XTRIM" => {
+                    handle_xtrim(commands, writer.clone(), state.clone()).await;
+                }
+                // these propagate the deliveries and claims they make as XCLAIMs
+                "XREADGROUP" => {
+                    handle_xreadgroup(commands, writer.clone(), state.clone(), reader.clone(), !self.in_exec).await;
+                }
+                "XCLAIM" => {
+                    handle_xclaim(commands, writer.clone(), state.clone()).await;
+                }
+                "XAUTOCLAIM" => {
+                    handle_xautoclaim(commands, writer.clone(), state.clone()).await;
+                }
+                "XPENDING" => {
+                    handle_xpending(commands, writer.clone(), state.clone()).await;
                 }
                 "MULTI" => {
                     handle_multi(commands, writer.clone(), in_multi).await;
                 }
                 "EXEC" => {
-                    handle_exec(commands, writer.clone(), queued, in_multi, state.clone()).await;
+                    handle_exec(commands, reader.clone(), writer.clone(), queued, in_multi, state.clone()).await;
                 }
                 "DISCARD" => {
                     handle_discard(commands, writer.clone(), queued, in_multi).await;
                 }
-                "BLPOP" => {
-                    handle_blpop(commands, writer.clone(), state.clone()).await;
+                // blocking pops propagate the non-blocking command they end up running
+                "BLPOP" | "BRPOP" | "BLMOVE" | "BRPOPLPUSH" | "BLMPOP" => {
+                    let can_block = !self.in_exec;
+                    match first_command.to_uppercase().as_str() {
+                        "BLPOP" => handle_blpop(commands, writer.clone(), state.clone(), reader.clone(), can_block).await,
+                        "BRPOP" => handle_brpop(commands, writer.clone(), state.clone(), reader.clone(), can_block).await,
+                        "BLMOVE" => handle_blmove(commands, writer.clone(), state.clone(), reader.clone(), can_block).await,
+                        "BRPOPLPUSH" => handle_brpoplpush(commands, writer.clone(), state.clone(), reader.clone(), can_block).await,
+                        _ => handle_blmpop(commands, writer.clone(), state.clone(), reader.clone(), can_block).await,
+                    }
                 }
                 "RPUSH" => {
                     handle_rpush(commands, writer.clone(), state.clone()).await;
-                    // Wake up blocked BLPOP clients if any
-                    if commands.len() >= 3 {
-                        let key = &commands[1];
-                        let value = commands.last().unwrap().clone();
-                        state.lock().await.wake_blpop_blocked_client(key, value).await;
-                    }
                     // Propagate to replicas if master
                     let role = state.lock().await.get_role().await;
                     if role == "master" {
@@ -140,24 +390,164 @@ impl CommandExecutor {
                 }
                 "LPUSH" => {
                     handle_lpush(commands, writer.clone(), state.clone()).await;
-                    // Wake up blocked BLPOP clients if any
-                    if commands.len() >= 3 {
-                        let key = &commands[1];
-                        let value = commands.last().unwrap().clone();
-                        state.lock().await.wake_blpop_blocked_client(key, value).await;
-                    }
                     // Propagate to replicas if master
                     let role = state.lock().await.get_role().await;
                     if role == "master" {
                         self.propogate_to_replicas(commands, writer.clone(), state.clone()).await;
                     }
                 }
-                "LPOP" => {
-                    handle_lpop(commands, writer.clone(), state.clone()).await;
+                "LPOP" | "RPOP" | "LSET" | "LINSERT" | "LREM" | "LTRIM" | "LPUSHX" | "RPUSHX" | "LMOVE" | "RPOPLPUSH"
+                | "LMPOP" => {
+                    match first_command.to_uppercase().as_str() {
+                        "LPOP" => handle_lpop(commands, writer.clone(), state.clone()).await,
+                        "RPOP" => handle_rpop(commands, writer.clone(), state.clone()).await,
+                        "LSET" => handle_lset(commands, writer.clone(), state.clone()).await,
+                        "LINSERT" => handle_linsert(commands, writer.clone(), state.clone()).await,
+                        "LREM" => handle_lrem(commands, writer.clone(), state.clone()).await,
+                        "LTRIM" => handle_ltrim(commands, writer.clone(), state.clone()).await,
+                        "LPUSHX" => handle_lpushx(commands, writer.clone(), state.clone()).await,
+                        "RPUSHX" => handle_rpushx(commands, writer.clone(), state.clone()).await,
+                        "LMOVE" => handle_lmove(commands, writer.clone(), state.clone()).await,
+                        "RPOPLPUSH" => handle_rpoplpush(commands, writer.clone(), state.clone()).await,
+                        _ => handle_lmpop(commands, writer.clone(), state.clone()).await,
+                    }
+                    // Propagate to replicas if master
+                    let role = state.lock().await.get_role().await;
+                    if role == "master" {
+                        self.propogate_to_replicas(commands, writer.clone(), state.clone()).await;
+                    }
                 }
                 "LLEN" => {
                     handle_llen(commands, writer.clone(), state.clone()).await;
                 }
+                "LINDEX" => {
+                    handle_lindex(commands, writer.clone(), state.clone()).await;
+                }
+                "LPOS" => {
+                    handle_lpos(commands, writer.clone(), state.clone()).await;
+                }
+                "SUBSCRIBE" => {
+                    handle_subscribe(commands, writer.clone(), state.clone()).await;
+                }
+                "UNSUBSCRIBE" => {
+                    handle_unsubscribe(commands, writer.clone(), state.clone()).await;
+                }
+                "PSUBSCRIBE" => {
+                    handle_psubscribe(commands, writer.clone(), state.clone()).await;
+                }
+                "PUNSUBSCRIBE" => {
+                    handle_punsubscribe(commands, writer.clone(), state.clone()).await;
+                }
+                "SSUBSCRIBE" => {
+                    handle_ssubscribe(commands, writer.clone(), state.clone()).await;
+                }
+                "SUNSUBSCRIBE" => {
+                    handle_sunsubscribe(commands, writer.clone(), state.clone()).await;
+                }
+                "PUBLISH" => {
+                    handle_publish(commands, writer.clone(), state.clone()).await;
+                    // Propagate to replicas if master
+                    let role = state.lock().await.get_role().await;
+                    if role == "master" {
+                        self.propogate_to_replicas(commands, writer.clone(), state.clone()).await;
+                    }
+                }
+                "SPUBLISH" => {
+                    handle_spublish(commands, writer.clone(), state.clone()).await;
+                    // Propagate to replicas if master
+                    let role = state.lock().await.get_role().await;
+                    if role == "master" {
+                        self.propogate_to_replicas(commands, writer.clone(), state.clone()).await;
+                    }
+                }
+                "PUBSUB" => {
+                    handle_pubsub(commands, writer.clone(), state.clone()).await;
+                }
+                "DEL" | "UNLINK" => {
+                    handle_del(commands, writer.clone(), state.clone()).await;
+                    // Propagate to replicas if master
+                    let role = state.lock().await.get_role().await;
+                    if role == "master" {
+                        self.propogate_to_replicas(commands, writer.clone(), state.clone()).await;
+                    }
+                }
+                "EXISTS" => {
+                    handle_exists(commands, writer.clone(), state.clone()).await;
+                }
+                "OBJECT" => {
+                    handle_object(commands, writer.clone(), state.clone()).await;
+                }
+                "MEMORY" => {
+                    handle_memory(commands, writer.clone(), state.clone()).await;
+                }
+                "ACL" => {
+                    handle_acl(commands, writer.clone(), state.clone()).await;
+                }
+                "SCAN" => {
+                    handle_scan(commands, writer.clone(), state.clone()).await;
+                }
+                "ZSCAN" => {
+                    handle_zscan(commands, writer.clone(), state.clone()).await;
+                }
+                "DBSIZE" => {
+                    handle_dbsize(commands, writer.clone(), state.clone()).await;
+                }
+                // the replication stream selects the database itself
+                "SELECT" => {
+                    handle_select(commands, writer.clone(), state.clone()).await;
+                }
+                "MOVE" | "SWAPDB" | "FLUSHDB" | "FLUSHALL" => {
+                    match first_command.to_uppercase().as_str() {
+                        "MOVE" => handle_move(commands, writer.clone(), state.clone()).await,
+                        "SWAPDB" => handle_swapdb(commands, writer.clone(), state.clone()).await,
+                        "FLUSHDB" => handle_flushdb(commands, writer.clone(), state.clone()).await,
+                        _ => handle_flushall(commands, writer.clone(), state.clone()).await,
+                    }
+                    // Propagate to replicas if master
+                    let role = state.lock().await.get_role().await;
+                    if role == "master" {
+                        self.propogate_to_replicas(commands, writer.clone(), state.clone()).await;
+                    }
+                }
+                "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" | "PERSIST" | "RENAME" | "RENAMENX" => {
+                    match first_command.to_uppercase().as_str() {
+                        "EXPIRE" => handle_expire(commands, writer.clone(), state.clone()).await,
+                        "PEXPIRE" => handle_pexpire(commands, writer.clone(), state.clone()).await,
+                        "EXPIREAT" => handle_expireat(commands, writer.clone(), state.clone()).await,
+                        "PEXPIREAT" => handle_pexpireat(commands, writer.clone(), state.clone()).await,
+                        "PERSIST" => handle_persist(commands, writer.clone(), state.clone()).await,
+                        _ => handle_rename(commands, writer.clone(), state.clone()).await,
+                    }
+                    // Propagate to replicas if master
+                    let role = state.lock().await.get_role().await;
+                    if role == "master" {
+                        self.propogate_to_replicas(commands, writer.clone(), state.clone()).await;
+                    }
+                }
+                "TTL" => {
+                    handle_ttl(commands, writer.clone(), state.clone()).await;
+                }
+                "PTTL" => {
+                    handle_pttl(commands, writer.clone(), state.clone()).await;
+                }
+                "EXPIRETIME" => {
+                    handle_expiretime(commands, writer.clone(), state.clone()).await;
+                }
+                "PEXPIRETIME" => {
+                    handle_pexpiretime(commands, writer.clone(), state.clone()).await;
+                }
+                "SAVE" => {
+                    handle_save(commands, writer.clone(), state.clone()).await;
+                }
+                "BGSAVE" => {
+                    handle_bgsave(commands, writer.clone(), state.clone()).await;
+                }
+                "LASTSAVE" => {
+                    handle_lastsave(commands, writer.clone(), state.clone()).await;
+                }
+                "CLUSTER" => {
+                    handle_cluster(commands, writer.clone()).await;
+                }
                 _ => {
                     // Handle unimplemented commands with an error response
                     writer
@@ -170,14 +560,81 @@ impl CommandExecutor {
         }
     }
 
-    async fn propogate_to_replicas(
+    // ACL: the user of the connection must be allowed the command and the
+    // keys and channels it names, and commands the table doesn't know only
+    // by allowing everything. The connections of deleted users are closed.
+    pub async fn check_permissions(&self, commands: &[String], writer: &SharedWriter, state: &Arc<Mutex<State>>) -> bool {
+        // the link to the master runs anything
+        let Some(username) = connection_user() else {
+            return true;
+        };
+        let spec = find_command(commands);
+        // what a connection authenticates with or leaves by
+        if spec.is_some_and(|spec| matches!(spec.name, "auth" | "hello" | "quit" | "reset")) {
+            return true;
+        }
+        let checked = state
+            .lock()
+            .await
+            .with_acl(|acl| {
+                acl.user(&username).map(|user| match spec {
+                    Some(spec) => user.check(spec, commands),
+                    None if user.allows_all_commands() => Ok(()),
+                    None => Err(Denial::Command),
+                })
+            })
+            .await;
+        let denial = match checked {
+            Some(Ok(())) => return true,
+            Some(Err(denial)) => denial,
+            None => {
+                let _ = writer.lock().await.shutdown().await;
+                return false;
+            }
+        };
+        let command = spec.map_or_else(|| commands[0].to_lowercase(), |spec| spec.name.to_string());
+        let context = if self.in_exec { "multi" } else { "toplevel" };
+        state
+            .lock()
+            .await
+            .log_acl_denial(denial.reason(), context, denial.object(&command), username.clone(), &command)
+            .await;
+        let error = RespDataType::SimpleError(format!("NOPERM {}", denial.message(&username, &command)));
+        writer.lock().await.write_all(error.to_string().as_bytes()).await.unwrap();
+        false
+    }
+
+    // Evicts keys until the dataset fits in `maxmemory`, replicating each
+    // eviction as a DEL. Returns false when it still doesn't fit.
+    async fn perform_evictions(&mut self, writer: SharedWriter, state: Arc<Mutex<State>>) -> bool {
+        let (fits, evicted) = state.lock().await.perform_evictions().await;
+        let db = selected_db();
+        for (evicted_db, key) in evicted {
+            // the replication stream follows the selected database
+            select_db(evicted_db);
+            let del = Command::from(vec!["DEL".to_string(), key]);
+            self.propogate_to_replicas(&del, writer.clone(), state.clone()).await;
+        }
+        select_db(db);
+        fits
+    }
+
+    pub async fn propogate_to_replicas(
         &mut self,
-        commands: &mut Vec<String>,
-        stream: Arc<Mutex<OwnedWriteHalf>>,
+        commands: &Command,
+        _stream: SharedWriter,
         state: Arc<Mutex<State>>,
     ) {
-        let payload = RespDataType::Array(commands.iter().map(|s| RespDataType::BulkString(s.clone())).collect()).to_string();
-        let payload_bytes = payload.as_bytes();
+        // replicas apply the command on the database it ran on
+        let mut payload = Vec::new();
+        let db = selected_db();
+        if state.lock().await.select_replication_db(db).await {
+            let select = vec![RespDataType::BulkString("SELECT".to_string()), RespDataType::BulkString(db.to_string())];
+            payload.extend(RespDataType::Array(select).to_bytes());
+        }
+        let args = commands.args().iter().map(|arg| RespDataType::BulkBytes(arg.clone())).collect();
+        payload.extend(RespDataType::Array(args).to_bytes());
+        let payload_bytes = payload.as_slice();
         let number_of_bytes_broadcasted = payload_bytes.len();
         
         let replicas = state.lock().await.get_replicas().await;
@@ -205,3 +662,70 @@ impl CommandExecutor {
         }
     }
 }
+
+const OOM_ERROR: &str = "OOM command not allowed when used memory > 'maxmemory'.";
+
+// Commands MULTI refuses to queue: subscribing from EXEC would leave the
+// connection in pub/sub mode in the middle of a transaction reply
+pub fn is_no_multi(command_name: &str) -> bool {
+    matches!(
+        command_name,
+        "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" | "SSUBSCRIBE" | "SUNSUBSCRIBE"
+    )
+}
+
+// Commands that may grow the dataset, refused while it's over `maxmemory`
+// (what Redis flags `denyoom`)
+fn is_deny_oom(command_name: &str) -> bool {
+    matches!(
+        command_name,
+        "SET" | "SETNX" | "SETEX" | "PSETEX" | "MSET" | "MSETNX" | "APPEND" | "SETRANGE" | "GETSET" | "INCR"
+            | "INCRBY" | "DECR" | "DECRBY" | "INCRBYFLOAT" | "SETBIT" | "BITOP" | "BITFIELD" | "PFADD" | "PFMERGE"
+            | "ZADD" | "GEOADD" | "GEOSEARCHSTORE" | "RPUSH" | "LPUSH" | "RPUSHX" | "LPUSHX" | "LINSERT" | "LSET"
+            | "LMOVE" | "RPOPLPUSH" | "BLMOVE" | "BRPOPLPUSH" | "XADD" | "XGROUP" | "XSETID"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::classes::{Connection::ConnectionReader, State::with_connection};
+
+    // Runs the command as a client of the default user would, returning the reply
+    async fn run(state: &Arc<Mutex<State>>, args: &[&[u8]]) -> Vec<u8> {
+        let command = Command::new(args.iter().map(|arg| arg.to_vec()).collect());
+        let reader = Arc::new(Mutex::new(ConnectionReader::new(tokio::io::empty())));
+        let buffer = Arc::new(Mutex::new(Vec::<u8>::new()));
+        let (mut queued, mut in_multi) = (Vec::new(), false);
+        let mut executor = CommandExecutor { in_exec: false };
+        let execution = executor.execute(command, reader, buffer.clone(), state.clone(), &mut queued, &mut in_multi);
+        with_connection(Some("default".to_string()), true, true, execution).await;
+        let reply = buffer.lock().await.clone();
+        reply
+    }
+
+    #[tokio::test]
+    async fn binary_values_round_trip_through_set_and_get() {
+        let state = Arc::new(Mutex::new(State::new()));
+        assert_eq!(run(&state, &[b"SET", b"k", b"\xff\x00\xfe"]).await, b"+OK\r\n");
+        assert_eq!(run(&state, &[b"STRLEN", b"k"]).await, b":3\r\n");
+        assert_eq!(run(&state, &[b"GET", b"k"]).await, b"$3\r\n\xff\x00\xfe\r\n");
+        assert_eq!(run(&state, &[b"APPEND", b"k", b"\x80"]).await, b":4\r\n");
+        assert_eq!(run(&state, &[b"GET", b"k"]).await, b"$4\r\n\xff\x00\xfe\x80\r\n");
+    }
+
+    #[tokio::test]
+    async fn hyperloglog_values_load_through_set() {
+        let state = Arc::new(Mutex::new(State::new()));
+        // Sparse: XZERO:100, VAL:3,2, XZERO:16282
+        let sparse = b"HYLL\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x80\x40\x63\x89\x7f\x99";
+        assert_eq!(run(&state, &[b"SET", b"sparse", sparse]).await, b"+OK\r\n");
+        assert_eq!(run(&state, &[b"PFCOUNT", b"sparse"]).await, b":2\r\n");
+        // Dense: registers 0 and 1 set, the rest zero
+        let mut dense = b"HYLL\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x80\x81".to_vec();
+        dense.resize(16 + 12288, 0);
+        assert_eq!(run(&state, &[b"SET", b"dense", &dense]).await, b"+OK\r\n");
+        assert_eq!(run(&state, &[b"PFCOUNT", b"dense"]).await, b":2\r\n");
+        assert_eq!(run(&state, &[b"PFCOUNT", b"sparse", b"dense"]).await, b":4\r\n");
+    }
+}
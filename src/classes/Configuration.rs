@@ -0,0 +1,703 @@
+use crate::classes::{
+    Eviction::MaxmemoryPolicy,
+    Memory::parse_memory,
+    Notifications::{keyspace_events_to_string, parse_keyspace_events},
+    State::Config,
+    Tls::TlsAuthClients,
+};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::Path;
+
+// The configuration parameters, as the redis.conf file, the `--name value`
+// command line options and CONFIG GET, SET and REWRITE know them. Each one
+// reads and writes its field of `Config`; `set` validates the value and
+// explains what is wrong with it otherwise.
+pub struct Parameter {
+    pub name: &'static str,
+    // an older name it is also known by
+    pub alias: Option<&'static str>,
+    // whether CONFIG SET may change it on a running server
+    pub mutable: bool,
+    // whether that also takes `enable-protected-configs`, for the ones
+    // that decide where files are written
+    pub protected: bool,
+    // how many arguments the value is made of, like `host port`, 0 for
+    // any number of them
+    pub args: usize,
+    pub get: fn(&Config) -> String,
+    pub set: fn(&mut Config, &str) -> Result<(), String>,
+}
+
+pub const PARAMETERS: &[Parameter] = &[
+    Parameter {
+        name: "dir",
+        alias: None,
+        mutable: true,
+        protected: true,
+        args: 1,
+        get: |config| {
+            config.db_dir.clone().unwrap_or_else(|| {
+                std::env::current_dir()
+                    .map(|dir| dir.to_string_lossy().to_string())
+                    .unwrap_or_default()
+            })
+        },
+        set: |config, value| {
+            if !Path::new(value).is_dir() {
+                return Err("No such file or directory".to_string());
+            }
+            config.db_dir = Some(value.to_string());
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "dbfilename",
+        alias: None,
+        mutable: true,
+        protected: true,
+        args: 1,
+        get: |config| config.db_file_name.clone().unwrap_or_else(|| "dump.rdb".to_string()),
+        set: |config, value| {
+            if value.contains('/') {
+                return Err("dbfilename can't be a path, just a filename".to_string());
+            }
+            config.db_file_name = Some(value.to_string());
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "enable-protected-configs",
+        alias: None,
+        mutable: false,
+        protected: false,
+        args: 1,
+        get: |config| config.enable_protected_configs.as_str().to_string(),
+        set: |config, value| {
+            config.enable_protected_configs = ProtectedAction::parse(value)
+                .ok_or_else(|| "argument(s) must be one of the following: no, yes, local".to_string())?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "port",
+        alias: None,
+        mutable: false,
+        protected: false,
+        args: 1,
+        get: |config| config.port.to_string(),
+        set: |config, value| {
+            config.port = parse_integer(value, 0, u16::MAX as i64)? as u16;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "bind",
+        alias: None,
+        mutable: false,
+        protected: false,
+        args: 0,
+        get: |config| config.bind.join(" "),
+        set: |config, value| {
+            let addresses: Vec<String> = value.split_whitespace().map(str::to_string).collect();
+            for address in &addresses {
+                let address = address.strip_prefix('-').unwrap_or(address);
+                if address != "*" && address != "::*" && address.parse::<IpAddr>().is_err() {
+                    return Err(format!("Invalid bind address '{}'", address));
+                }
+            }
+            config.bind = addresses;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "protected-mode",
+        alias: None,
+        mutable: true,
+        protected: false,
+        args: 1,
+        get: |config| yes_no(config.protected_mode),
+        set: |config, value| {
+            config.protected_mode = parse_yes_no(value)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "requirepass",
+        alias: None,
+        mutable: true,
+        protected: false,
+        args: 1,
+        get: |config| config.requirepass.clone().unwrap_or_default(),
+        set: |config, value| {
+            config.requirepass = Some(value.to_string()).filter(|password| !password.is_empty());
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "aclfile",
+        alias: None,
+        mutable: false,
+        protected: false,
+        args: 1,
+        get: |config| config.aclfile.clone().unwrap_or_default(),
+        set: |config, value| {
+            config.aclfile = Some(value.to_string()).filter(|path| !path.is_empty());
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "acllog-max-len",
+        alias: None,
+        mutable: true,
+        protected: false,
+        args: 1,
+        get: |config| config.acllog_max_len.to_string(),
+        set: |config, value| {
+            config.acllog_max_len = parse_integer(value, 0, i64::MAX)? as usize;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "unixsocket",
+        alias: None,
+        mutable: false,
+        protected: false,
+        args: 1,
+        get: |config| config.unixsocket.clone().unwrap_or_default(),
+        set: |config, value| {
+            config.unixsocket = Some(value.to_string()).filter(|path| !path.is_empty());
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "unixsocketperm",
+        alias: None,
+        mutable: false,
+        protected: false,
+        args: 1,
+        get: |config| format!("{:o}", config.unixsocketperm),
+        set: |config, value| {
+            match u32::from_str_radix(value, 8) {
+                Ok(mode) if mode <= 0o777 => config.unixsocketperm = mode,
+                Ok(_) => return Err("argument must be between 0 and 777 inclusive".to_string()),
+                Err(_) => return Err("argument couldn't be parsed into an integer".to_string()),
+            }
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tls-port",
+        alias: None,
+        mutable: false,
+        protected: false,
+        args: 1,
+        get: |config| config.tls_port.to_string(),
+        set: |config, value| {
+            config.tls_port = parse_integer(value, 0, u16::MAX as i64)? as u16;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tls-cert-file",
+        alias: None,
+        mutable: false,
+        protected: false,
+        args: 1,
+        get: |config| config.tls_cert_file.clone().unwrap_or_default(),
+        set: |config, value| {
+            config.tls_cert_file = Some(value.to_string()).filter(|path| !path.is_empty());
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tls-key-file",
+        alias: None,
+        mutable: false,
+        protected: false,
+        args: 1,
+        get: |config| config.tls_key_file.clone().unwrap_or_default(),
+        set: |config, value| {
+            config.tls_key_file = Some(value.to_string()).filter(|path| !path.is_empty());
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tls-ca-cert-file",
+        alias: None,
+        mutable: false,
+        protected: false,
+        args: 1,
+        get: |config| config.tls_ca_cert_file.clone().unwrap_or_default(),
+        set: |config, value| {
+            config.tls_ca_cert_file = Some(value.to_string()).filter(|path| !path.is_empty());
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tls-auth-clients",
+        alias: None,
+        mutable: false,
+        protected: false,
+        args: 1,
+        get: |config| config.tls_auth_clients.as_str().to_string(),
+        set: |config, value| {
+            config.tls_auth_clients = TlsAuthClients::parse(value)
+                .ok_or_else(|| "argument(s) must be one of the following: no, yes, optional".to_string())?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tls-replication",
+        alias: None,
+        mutable: false,
+        protected: false,
+        args: 1,
+        get: |config| yes_no(config.tls_replication),
+        set: |config, value| {
+            config.tls_replication = parse_yes_no(value)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "databases",
+        alias: None,
+        mutable: false,
+        protected: false,
+        args: 1,
+        get: |config| config.databases.to_string(),
+        set: |config, value| {
+            config.databases = parse_integer(value, 1, i32::MAX as i64)? as usize;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "replicaof",
+        alias: Some("slaveof"),
+        mutable: false,
+        protected: false,
+        args: 2,
+        get: |config| match (&config.master_host, &config.master_port) {
+            (Some(host), Some(port)) => format!("{} {}", host, port),
+            _ => String::new(),
+        },
+        set: |config, value| {
+            let mut parts = value.split_whitespace();
+            let (Some(host), Some(port)) = (parts.next(), parts.next()) else {
+                return Err("replicaof requires a host and a port".to_string());
+            };
+            parse_integer(port, 0, u16::MAX as i64)?;
+            config.master_host = Some(host.to_string());
+            config.master_port = Some(port.to_string());
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "masteruser",
+        alias: None,
+        mutable: true,
+        protected: false,
+        args: 1,
+        get: |config| config.masteruser.clone().unwrap_or_default(),
+        set: |config, value| {
+            config.masteruser = Some(value.to_string()).filter(|user| !user.is_empty());
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "masterauth",
+        alias: None,
+        mutable: true,
+        protected: false,
+        args: 1,
+        get: |config| config.masterauth.clone().unwrap_or_default(),
+        set: |config, value| {
+            config.masterauth = Some(value.to_string()).filter(|password| !password.is_empty());
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "notify-keyspace-events",
+        alias: None,
+        mutable: true,
+        protected: false,
+        args: 1,
+        get: |config| keyspace_events_to_string(config.notify_keyspace_events),
+        set: |config, value| {
+            config.notify_keyspace_events = parse_keyspace_events(value)
+                .ok_or_else(|| "Invalid event class character. Use 'Ag$lshzxeKEtmdn'.".to_string())?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "maxmemory",
+        alias: None,
+        mutable: true,
+        protected: false,
+        args: 1,
+        get: |config| config.maxmemory.maxmemory.to_string(),
+        set: |config, value| {
+            config.maxmemory.maxmemory = parse_memory(value).ok_or_else(|| "argument must be a memory value".to_string())?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "maxmemory-policy",
+        alias: None,
+        mutable: true,
+        protected: false,
+        args: 1,
+        get: |config| config.maxmemory.policy.as_str().to_string(),
+        set: |config, value| {
+            config.maxmemory.policy = MaxmemoryPolicy::parse(value).ok_or_else(|| {
+                let policies: Vec<&str> = MaxmemoryPolicy::ALL.iter().map(|policy| policy.as_str()).collect();
+                format!("argument(s) must be one of the following: {}", policies.join(", "))
+            })?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "maxmemory-samples",
+        alias: None,
+        mutable: true,
+        protected: false,
+        args: 1,
+        get: |config| config.maxmemory.samples.to_string(),
+        set: |config, value| {
+            config.maxmemory.samples = parse_integer(value, 1, 64)? as usize;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "lfu-log-factor",
+        alias: None,
+        mutable: true,
+        protected: false,
+        args: 1,
+        get: |config| config.maxmemory.lfu_log_factor.to_string(),
+        set: |config, value| {
+            config.maxmemory.lfu_log_factor = parse_integer(value, 0, i32::MAX as i64)? as u32;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "lfu-decay-time",
+        alias: None,
+        mutable: true,
+        protected: false,
+        args: 1,
+        get: |config| config.maxmemory.lfu_decay_time.to_string(),
+        set: |config, value| {
+            config.maxmemory.lfu_decay_time = parse_integer(value, 0, i32::MAX as i64)? as u64;
+            Ok(())
+        },
+    },
+];
+
+// Whether an action that is off by default, like changing protected
+// parameters, is allowed: never, always or from local connections only
+#[derive(Clone, Copy, PartialEq)]
+pub enum ProtectedAction {
+    No,
+    Yes,
+    Local,
+}
+
+impl ProtectedAction {
+    pub fn parse(value: &str) -> Option<ProtectedAction> {
+        match value.to_lowercase().as_str() {
+            "no" => Some(ProtectedAction::No),
+            "yes" => Some(ProtectedAction::Yes),
+            "local" => Some(ProtectedAction::Local),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ProtectedAction::No => "no",
+            ProtectedAction::Yes => "yes",
+            ProtectedAction::Local => "local",
+        }
+    }
+
+    pub fn allows(self, local: bool) -> bool {
+        match self {
+            ProtectedAction::No => false,
+            ProtectedAction::Yes => true,
+            ProtectedAction::Local => local,
+        }
+    }
+}
+
+// Finds a parameter by its name or alias, ignoring case
+pub fn find_parameter(name: &str) -> Option<&'static Parameter> {
+    PARAMETERS.iter().find(|parameter| {
+        parameter.name.eq_ignore_ascii_case(name)
+            || parameter.alias.is_some_and(|alias| alias.eq_ignore_ascii_case(name))
+    })
+}
+
+fn parse_yes_no(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("argument must be 'yes' or 'no'".to_string()),
+    }
+}
+
+fn yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+
+fn parse_integer(value: &str, min: i64, max: i64) -> Result<i64, String> {
+    match value.parse::<i64>() {
+        Ok(number) if (min..=max).contains(&number) => Ok(number),
+        Ok(_) => Err(format!("argument must be between {} and {} inclusive", min, max)),
+        Err(_) => Err("argument couldn't be parsed into an integer".to_string()),
+    }
+}
+
+// Applies a redis.conf file to `config`: one `name value...` directive per
+// line, blank lines and `#` comments ignored. Stops at the first bad line.
+pub fn load_config(config: &mut Config, text: &str) -> Result<(), String> {
+    for (number, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let error = |reason: &str| format!("Reading the configuration file, at line {}\n>>> '{}'\n{}", number + 1, trimmed, reason);
+        let args = split_config_args(trimmed).ok_or_else(|| error("Unbalanced quotes in configuration line"))?;
+        let value = args[1..].join(" ");
+        // a value of several arguments may also come as one, like the
+        // `--replicaof "host port"` command line option
+        let parameter = find_parameter(&args[0]).filter(|parameter| match parameter.args {
+            0 => args.len() > 1,
+            1 => args.len() == 2,
+            args => value.split_whitespace().count() == args,
+        });
+        let Some(parameter) = parameter else {
+            return Err(error("Bad directive or wrong number of arguments"));
+        };
+        (parameter.set)(config, &value).map_err(|reason| error(&reason))?;
+    }
+    Ok(())
+}
+
+// The `--name value...` command line options as redis.conf lines, to be
+// loaded after the file so they override it
+pub fn command_line_config(options: &[String]) -> String {
+    let mut text = String::new();
+    for option in options {
+        match option.strip_prefix("--") {
+            Some(name) => {
+                if !text.is_empty() {
+                    text.push('\n');
+                }
+                text.push_str(name);
+            }
+            None => {
+                text.push(' ');
+                text.push_str(&quote_config_arg(option));
+            }
+        }
+    }
+    text
+}
+
+// Marks the directives CONFIG REWRITE had to add at the end of the file
+const REWRITE_SIGNATURE: &str = "# Generated by CONFIG REWRITE";
+
+// The configuration file with every directive set to the current value of
+// its parameter, keeping comments and lines it doesn't know. The parameters
+// that aren't in the file yet are added at the end, unless they have their
+// default value.
+pub fn rewrite_config(existing: &str, config: &Config) -> String {
+    let defaults = Config::default();
+    let mut lines = Vec::new();
+    let mut rewritten = HashSet::new();
+    for line in existing.lines() {
+        if line == REWRITE_SIGNATURE {
+            continue;
+        }
+        let parameter = split_config_args(line.trim()).and_then(|args| args.first().and_then(|name| find_parameter(name)));
+        match parameter {
+            // later occurrences of a directive would override the rewritten one
+            Some(parameter) if rewritten.insert(parameter.name) => lines.extend(config_line(parameter, config)),
+            Some(_) => {}
+            None => lines.push(line.to_string()),
+        }
+    }
+
+    let mut added = PARAMETERS
+        .iter()
+        .filter(|parameter| !rewritten.contains(parameter.name))
+        .filter(|parameter| (parameter.get)(config) != (parameter.get)(&defaults))
+        .filter_map(|parameter| config_line(parameter, config))
+        .peekable();
+    if added.peek().is_some() {
+        lines.push(REWRITE_SIGNATURE.to_string());
+        lines.extend(added);
+    }
+    let mut text = lines.join("\n");
+    text.push('\n');
+    text
+}
+
+// The directive setting the parameter to its current value, None for an
+// unset value of several arguments like `replicaof` on a master
+fn config_line(parameter: &Parameter, config: &Config) -> Option<String> {
+    let value = (parameter.get)(config);
+    if parameter.args == 1 {
+        return Some(format!("{} {}", parameter.name, quote_config_arg(&value)));
+    }
+    if value.is_empty() {
+        return None;
+    }
+    let args: Vec<String> = value.split_whitespace().map(quote_config_arg).collect();
+    Some(format!("{} {}", parameter.name, args.join(" ")))
+}
+
+// Splits a configuration line into its arguments like Redis's sdssplitargs:
+// separated by whitespace, in "double quotes" with C-like escapes or in
+// 'single quotes'. None for unbalanced quotes.
+pub fn split_config_args(line: &str) -> Option<Vec<String>> {
+    let bytes = line.as_bytes();
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == bytes.len() {
+            return Some(args);
+        }
+        let mut arg = Vec::new();
+        let mut quote = None;
+        loop {
+            let Some(&byte) = bytes.get(i) else {
+                // the line ended inside quotes
+                if quote.is_some() {
+                    return None;
+                }
+                break;
+            };
+            match quote {
+                None if byte.is_ascii_whitespace() => break,
+                None if byte == b'"' || byte == b'\'' => quote = Some(byte),
+                None => arg.push(byte),
+                Some(b'"')
+                    if byte == b'\\'
+                        && i + 3 < bytes.len()
+                        && bytes[i + 1] == b'x'
+                        && bytes[i + 2..i + 4].iter().all(u8::is_ascii_hexdigit) =>
+                {
+                    let hex = std::str::from_utf8(&bytes[i + 2..i + 4]).unwrap();
+                    arg.push(u8::from_str_radix(hex, 16).unwrap());
+                    i += 3;
+                }
+                Some(b'"') if byte == b'\\' && i + 1 < bytes.len() => {
+                    i += 1;
+                    arg.push(match bytes[i] {
+                        b'n' => b'\n',
+                        b'r' => b'\r',
+                        b't' => b'\t',
+                        b'b' => 0x08,
+                        b'a' => 0x07,
+                        other => other,
+                    });
+                }
+                Some(b'\'') if byte == b'\\' && bytes.get(i + 1) == Some(&b'\'') => {
+                    i += 1;
+                    arg.push(b'\'');
+                }
+                Some(closing) if byte == closing => {
+                    // the closing quote must end the argument
+                    if bytes.get(i + 1).is_some_and(|next| !next.is_ascii_whitespace()) {
+                        return None;
+                    }
+                    i += 1;
+                    break;
+                }
+                Some(_) => arg.push(byte),
+            }
+            i += 1;
+        }
+        args.push(String::from_utf8_lossy(&arg).to_string());
+    }
+}
+
+// An argument as it can be read back by `split_config_args`: as it is when
+// that's unambiguous, in double quotes otherwise
+pub fn quote_config_arg(arg: &str) -> String {
+    let plain = |byte: &u8| byte.is_ascii_graphic() && !matches!(byte, b'"' | b'\'' | b'\\' | b'#');
+    if !arg.is_empty() && arg.as_bytes().iter().all(plain) {
+        return arg.to_string();
+    }
+    let mut quoted = "\"".to_string();
+    for c in arg.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\x07' => quoted.push_str("\\a"),
+            '\x08' => quoted.push_str("\\b"),
+            c if c.is_ascii_control() => quoted.push_str(&format!("\\x{:02x}", c as u8)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(line: &str) -> Option<Vec<String>> {
+        split_config_args(line)
+    }
+
+    #[test]
+    fn splits_like_sdssplitargs() {
+        assert_eq!(split("  dir   /tmp  "), Some(vec!["dir".to_string(), "/tmp".to_string()]));
+        assert_eq!(split("requirepass \"foo bar\""), Some(vec!["requirepass".to_string(), "foo bar".to_string()]));
+        assert_eq!(split("save ''"), Some(vec!["save".to_string(), String::new()]));
+        assert_eq!(split("a 'it\\'s'"), Some(vec!["a".to_string(), "it's".to_string()]));
+        assert_eq!(split(""), Some(Vec::new()));
+    }
+
+    #[test]
+    fn decodes_double_quoted_escapes() {
+        assert_eq!(split("\"\\x41\\x42\\n\\t\\\"\""), Some(vec!["AB\n\t\"".to_string()]));
+        // not two hex digits: `\x` is an escaped `x`
+        assert_eq!(split("\"\\xzz\""), Some(vec!["xzz".to_string()]));
+        assert_eq!(split("\"\\x+f\""), Some(vec!["x+f".to_string()]));
+        // escapes mean nothing in single quotes
+        assert_eq!(split("'\\n'"), Some(vec!["\\n".to_string()]));
+    }
+
+    #[test]
+    fn rejects_unbalanced_quotes() {
+        assert_eq!(split("dir \"/tmp"), None);
+        assert_eq!(split("dir '/tmp"), None);
+        // a closing quote must be followed by a space
+        assert_eq!(split("dir \"/tmp\"x"), None);
+    }
+
+    #[test]
+    fn quoted_arguments_split_back() {
+        for arg in ["plain", "", "with space", "quote\"s", "back\\slash", "new\nline", "bell\x07", "#comment", "\x01"] {
+            let line = format!("name {}", quote_config_arg(arg));
+            assert_eq!(split(&line), Some(vec!["name".to_string(), arg.to_string()]), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn command_line_options_become_directives() {
+        let options: Vec<String> = ["--port", "7000", "--save", "", "--requirepass", "a b"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(command_line_config(&options), "port 7000\nsave \"\"\nrequirepass \"a b\"");
+    }
+}
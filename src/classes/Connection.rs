@@ -0,0 +1,67 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::Mutex;
+
+use crate::classes::RespDataType::RespDataType;
+
+// Handlers write replies through a type-erased writer, so the same code can
+// answer a client socket or fill an in-memory buffer (EXEC collects the
+// replies of queued commands this way).
+pub type SharedWriter = Arc<Mutex<dyn AsyncWrite + Send + Unpin>>;
+
+// Requests are read the same way from TCP and Unix sockets
+pub type SharedReader = Arc<Mutex<ConnectionReader>>;
+
+// The read half of a connection, whatever its transport. Blocked clients
+// peek at it to notice a disconnection without consuming the commands
+// pipelined meanwhile; not every transport can peek, so the peeked bytes
+// are kept here until they are read.
+pub struct ConnectionReader {
+    inner: Box<dyn AsyncRead + Send + Unpin>,
+    peeked: Vec<u8>,
+}
+
+impl ConnectionReader {
+    pub fn new(inner: impl AsyncRead + Send + Unpin + 'static) -> ConnectionReader {
+        ConnectionReader {
+            inner: Box::new(inner),
+            peeked: Vec::new(),
+        }
+    }
+
+    // Like `TcpStream::peek`: waits for data and copies it into `buffer`
+    // without consuming it. 0 means the connection is closed.
+    pub async fn peek(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.peeked.is_empty() {
+            let mut chunk = [0u8; 1024];
+            let n = self.inner.read(&mut chunk).await?;
+            self.peeked.extend_from_slice(&chunk[..n]);
+        }
+        let n = buffer.len().min(self.peeked.len());
+        buffer[..n].copy_from_slice(&self.peeked[..n]);
+        Ok(n)
+    }
+}
+
+impl AsyncRead for ConnectionReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.peeked.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        let n = buf.remaining().min(self.peeked.len());
+        buf.put_slice(&self.peeked[..n]);
+        self.peeked.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub async fn write_response(stream: &SharedWriter, response: RespDataType) {
+    stream.lock().await.write_all(&response.to_bytes()).await.unwrap();
+}
+
+pub fn wrong_arity(command_name: &str) -> RespDataType {
+    RespDataType::SimpleError(format!("ERR wrong number of arguments for '{}' command", command_name))
+}
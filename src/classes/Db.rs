@@ -1,11 +1,47 @@
 use std::{
-    fs::File,
-    io::{BufRead, BufReader, Error, Read},
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Error, ErrorKind, Read, Write},
     path::Path,
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crate::classes::State::State;
+use crate::classes::Eviction::{KeyAccess, MaxmemoryConfig, LFU_INIT_VAL};
+use crate::classes::ExpiringValue::{instant_to_unix_millis, unix_millis_now, ExpiringValue};
+use crate::classes::SortedSet::SortedSet;
+use crate::classes::State::{State, Value};
+use crate::classes::Stream::{Consumer, ConsumerGroup, PendingEntry, Stream, StreamFields, STREAM_NODE_MAX_ENTRIES};
+use crate::classes::StreamId::StreamId;
+
+// Value types of the RDB format this server reads. It writes the first three
+// and the latest stream encoding.
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_ZSET: u8 = 3;
+const RDB_TYPE_ZSET_2: u8 = 5;
+const RDB_TYPE_STREAM_LISTPACKS: u8 = 15;
+const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+const RDB_TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const RDB_TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+const RDB_OPCODE_SLOT_INFO: u8 = 0xF4;
+const RDB_OPCODE_IDLE: u8 = 0xF8;
+const RDB_OPCODE_FREQ: u8 = 0xF9;
+const RDB_OPCODE_AUX: u8 = 0xFA;
+const RDB_OPCODE_RESIZEDB: u8 = 0xFB;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const RDB_OPCODE_EXPIRETIME: u8 = 0xFD;
+const RDB_OPCODE_SELECTDB: u8 = 0xFE;
+const RDB_OPCODE_EOF: u8 = 0xFF;
+
+// Flags of the entries in a stream listpack
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
 
 pub struct Db {}
 impl Db {
@@ -21,115 +57,811 @@ impl Db {
         }
     }
 
-    fn size_encoded_bytes(&mut self, reader: &mut BufReader<File>) -> u32 {
-        let mut number_byte = [0u8; 1];
-        reader.read_exact(&mut number_byte).unwrap();
-        let byte = number_byte[0];
+    fn read_bytes<const N: usize>(&mut self, reader: &mut impl Read) -> Result<[u8; N], Error> {
+        let mut bytes = [0u8; N];
+        reader.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    // Length prefix: 00xxxxxx, 01xxxxxx xxxxxxxx, 0x80 + 4 bytes or 0x81 + 8
+    // bytes big endian. Ok(Err(n)) is the special string encoding n of a
+    // 11xxxxxx prefix.
+    fn length_encoded(&mut self, reader: &mut impl Read) -> Result<Result<u64, u8>, Error> {
+        let [byte] = self.read_bytes::<1>(reader)?;
         match byte & 0xC0 {
-            0 => {
-                return byte.try_into().unwrap();
-            }
-            1 => {
-                let mut next_byte = [0u8; 1];
-                reader.read_exact(&mut next_byte).unwrap();
-                return (2u8.pow(8) * byte & 0x3F + next_byte[0])
-                    .try_into()
-                    .unwrap();
-            }
-            2 => {
-                let mut next_four_bytes = [0u8; 4];
-                reader.read_exact(&mut next_four_bytes).unwrap();
-                return u32::from_le_bytes(next_four_bytes);
-            }
-            3 => match byte {
-                0xC0 => {
-                    let mut next_one_byte = [0u8; 1];
-                    reader.read_exact(&mut next_one_byte).unwrap();
-                    return next_one_byte[0].try_into().unwrap();
+            0x00 => Ok(Ok((byte & 0x3F) as u64)),
+            0x40 => {
+                let [next_byte] = self.read_bytes::<1>(reader)?;
+                Ok(Ok((((byte & 0x3F) as u64) << 8) | next_byte as u64))
+            }
+            0x80 if byte == 0x80 => Ok(Ok(u32::from_be_bytes(self.read_bytes(reader)?) as u64)),
+            0x80 if byte == 0x81 => Ok(Ok(u64::from_be_bytes(self.read_bytes(reader)?))),
+            0xC0 => Ok(Err(byte & 0x3F)),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("unknown length encoding {:#x}", byte))),
+        }
+    }
+
+    fn size_encoded_bytes(&mut self, reader: &mut impl Read) -> Result<u64, Error> {
+        match self.length_encoded(reader)? {
+            Ok(length) => Ok(length),
+            Err(_) => Err(invalid_data("expected a length")),
+        }
+    }
+
+    // Strings are binary safe and may be stored as integers or LZF compressed
+    fn string_encoded(&mut self, reader: &mut impl Read) -> Result<Vec<u8>, Error> {
+        match self.length_encoded(reader)? {
+            Ok(length) => {
+                let mut string_buffer: Vec<u8> = vec![0u8; length as usize];
+                reader.read_exact(&mut string_buffer)?;
+                Ok(string_buffer)
+            }
+            Err(0) => Ok(i8::from_le_bytes(self.read_bytes(reader)?).to_string().into_bytes()),
+            Err(1) => Ok(i16::from_le_bytes(self.read_bytes(reader)?).to_string().into_bytes()),
+            Err(2) => Ok(i32::from_le_bytes(self.read_bytes(reader)?).to_string().into_bytes()),
+            Err(3) => {
+                let compressed_length = self.size_encoded_bytes(reader)?;
+                let length = self.size_encoded_bytes(reader)?;
+                let mut compressed = vec![0u8; compressed_length as usize];
+                reader.read_exact(&mut compressed)?;
+                lzf_decompress(&compressed, length as usize).ok_or_else(|| invalid_data("corrupted LZF string"))
+            }
+            Err(encoding) => Err(Error::new(ErrorKind::InvalidData, format!("unknown string encoding {}", encoding))),
+        }
+    }
+
+    fn key_encoded(&mut self, reader: &mut impl Read) -> Result<String, Error> {
+        Ok(String::from_utf8_lossy(&self.string_encoded(reader)?).to_string())
+    }
+
+    fn stream_id_encoded(&mut self, reader: &mut impl Read) -> Result<StreamId, Error> {
+        let ms = self.size_encoded_bytes(reader)?;
+        let seq = self.size_encoded_bytes(reader)?;
+        Ok(StreamId::new(ms, seq))
+    }
+
+    // Stream IDs inside PELs and listpack keys are 16 raw big endian bytes
+    fn stream_id_raw(&mut self, reader: &mut impl Read) -> Result<StreamId, Error> {
+        Ok(stream_id_from_bytes(&self.read_bytes::<16>(reader)?))
+    }
+
+    fn millisecond_time(&mut self, reader: &mut impl Read) -> Result<i64, Error> {
+        Ok(i64::from_le_bytes(self.read_bytes(reader)?))
+    }
+
+    fn value_encoded(&mut self, reader: &mut impl Read, value_type: u8) -> Result<Value, Error> {
+        match value_type {
+            RDB_TYPE_STRING => Ok(Value::String(self.string_encoded(reader)?)),
+            RDB_TYPE_LIST => {
+                let length = self.size_encoded_bytes(reader)?;
+                let mut list = Vec::new();
+                for _ in 0..length {
+                    list.push(self.key_encoded(reader)?);
                 }
-                0xC1 => {
-                    let mut next_two_bytes = [0u8; 2];
-                    reader.read_exact(&mut next_two_bytes).unwrap();
-                    return u16::from_le_bytes(next_two_bytes).into();
+                Ok(Value::List(list))
+            }
+            RDB_TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.size_encoded_bytes(reader)?;
+                let mut list = Vec::new();
+                for _ in 0..nodes {
+                    let container = self.size_encoded_bytes(reader)?;
+                    let node = self.string_encoded(reader)?;
+                    // a plain node holds one large element, a packed one a listpack
+                    match container {
+                        1 => list.push(String::from_utf8_lossy(&node).to_string()),
+                        _ => list.extend(listpack_strings(&node)?),
+                    }
                 }
-                0xC2 => {
-                    let mut next_four_bytes = [0u8; 4];
-                    reader.read_exact(&mut next_four_bytes).unwrap();
-                    return u32::from_le_bytes(next_four_bytes);
+                Ok(Value::List(list))
+            }
+            RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 => {
+                let length = self.size_encoded_bytes(reader)?;
+                let mut sorted_set = SortedSet::new();
+                for _ in 0..length {
+                    let member = self.key_encoded(reader)?;
+                    let score = match value_type {
+                        RDB_TYPE_ZSET_2 => f64::from_le_bytes(self.read_bytes(reader)?),
+                        _ => self.ascii_double(reader)?,
+                    };
+                    sorted_set.insert(&member, score);
                 }
-                _ => {}
-            },
+                Ok(Value::SortedSet(sorted_set))
+            }
+            RDB_TYPE_ZSET_LISTPACK => {
+                let elements = listpack_strings(&self.string_encoded(reader)?)?;
+                let mut sorted_set = SortedSet::new();
+                for pair in elements.chunks_exact(2) {
+                    let score = pair[1].parse::<f64>().map_err(|_| invalid_data("invalid zset score"))?;
+                    sorted_set.insert(&pair[0], score);
+                }
+                Ok(Value::SortedSet(sorted_set))
+            }
+            RDB_TYPE_STREAM_LISTPACKS | RDB_TYPE_STREAM_LISTPACKS_2 | RDB_TYPE_STREAM_LISTPACKS_3 => {
+                Ok(Value::Stream(self.stream_encoded(reader, value_type)?))
+            }
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("unsupported value type {}", value_type))),
+        }
+    }
+
+    // Scores of the old zset encoding: a length byte and the score as text,
+    // with 253, 254 and 255 standing for nan, inf and -inf
+    fn ascii_double(&mut self, reader: &mut impl Read) -> Result<f64, Error> {
+        let [length] = self.read_bytes::<1>(reader)?;
+        match length {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
             _ => {
-                unreachable!("should not be more than 3")
+                let mut text = vec![0u8; length as usize];
+                reader.read_exact(&mut text)?;
+                String::from_utf8_lossy(&text).parse::<f64>().map_err(|_| invalid_data("invalid zset score"))
             }
         }
-        return 1;
     }
 
-    fn string_encoded(&mut self, reader: &mut BufReader<File>) -> String {
-        let string_length = self.size_encoded_bytes(reader);
-        let mut string_buffer: Vec<u8> = vec![0u8; string_length as usize];
-        reader.read_exact(&mut string_buffer).unwrap();
-        return String::from_utf8(string_buffer).unwrap();
+    // Entries come in listpacks holding a "master" entry whose fields later
+    // entries may reuse, followed by the stream metadata and consumer groups.
+    // The older encodings lack the newer metadata.
+    fn stream_encoded(&mut self, reader: &mut impl Read, value_type: u8) -> Result<Stream, Error> {
+        let mut stream = Stream::new();
+        let nodes = self.size_encoded_bytes(reader)?;
+        for _ in 0..nodes {
+            let node_key = self.string_encoded(reader)?;
+            let master_id = stream_id_from_bytes(node_key.get(..16).ok_or_else(|| invalid_data("invalid stream node key"))?);
+            let node = listpack_strings(&self.string_encoded(reader)?)?;
+            for (id, fields) in stream_node_entries(master_id, &node)? {
+                stream.insert(id, fields);
+            }
+        }
+
+        let _length = self.size_encoded_bytes(reader)?;
+        stream.last_id = self.stream_id_encoded(reader)?;
+        if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+            let _first_id = self.stream_id_encoded(reader)?;
+            stream.max_deleted_id = self.stream_id_encoded(reader)?;
+            stream.entries_added = self.size_encoded_bytes(reader)?;
+        } else {
+            stream.entries_added = stream.len() as u64;
+        }
+
+        let groups = self.size_encoded_bytes(reader)?;
+        for _ in 0..groups {
+            let name = self.key_encoded(reader)?;
+            let last_id = self.stream_id_encoded(reader)?;
+            let entries_read = match value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+                true => Some(self.size_encoded_bytes(reader)?).filter(|entries_read| *entries_read != u64::MAX),
+                false => stream.estimate_entries_read(last_id),
+            };
+            let mut group = ConsumerGroup::new(last_id, entries_read);
+
+            let pending = self.size_encoded_bytes(reader)?;
+            for _ in 0..pending {
+                let id = self.stream_id_raw(reader)?;
+                let delivery_time = self.millisecond_time(reader)?.max(0) as u64;
+                let delivery_count = self.size_encoded_bytes(reader)?;
+                // the owner is filled in from the consumers' own PELs
+                group.pending.insert(id, PendingEntry { consumer: String::new(), delivery_time, delivery_count });
+            }
+
+            let consumers = self.size_encoded_bytes(reader)?;
+            for _ in 0..consumers {
+                let consumer_name = self.key_encoded(reader)?;
+                let seen_time = self.millisecond_time(reader)?.max(0) as u64;
+                let active_time = match value_type >= RDB_TYPE_STREAM_LISTPACKS_3 {
+                    true => Some(self.millisecond_time(reader)?).filter(|time| *time >= 0).map(|time| time as u64),
+                    false => Some(seen_time),
+                };
+                let mut consumer = Consumer { seen_time, active_time, ..Default::default() };
+                let owned = self.size_encoded_bytes(reader)?;
+                for _ in 0..owned {
+                    let id = self.stream_id_raw(reader)?;
+                    let pending = group.pending.get_mut(&id).ok_or_else(|| invalid_data("consumer entry missing from the group PEL"))?;
+                    pending.consumer = consumer_name.clone();
+                    consumer.pending.insert(id);
+                }
+                group.consumers.insert(consumer_name, consumer);
+            }
+            stream.groups.insert(name, group);
+        }
+        Ok(stream)
     }
 
     pub async fn load(&mut self, state: &mut State) -> Result<(), Error> {
         let (db_dir, db_file) = state.get_db_config().await;
         if let (Some(db_dir), Some(db_file)) = (db_dir, db_file) {
-            let file = File::open(&Path::new(format!("{}/{}", db_dir, db_file).as_str()))?;
+            let file = File::open(Path::new(format!("{}/{}", db_dir, db_file).as_str()))?;
             let mut reader = BufReader::new(file);
-            let mut _discard = Vec::new();
-            reader.read_until(0xFE, &mut _discard).unwrap();
-            let _db_index = self.size_encoded_bytes(&mut reader);
-            reader.consume(1);
-            let keys_size = self.size_encoded_bytes(&mut reader);
-            let _keys_size_with_expiration_size = self.size_encoded_bytes(&mut reader);
-            for _ in 0..keys_size {
-                let mut first_byte = [0u8; 1];
-                reader.read_exact(&mut first_byte).unwrap();
-                let raw_byte = first_byte[0];
-                match raw_byte {
-                    0x00 => {
-                        let hash_key = self.string_encoded(&mut reader);
-                        let hash_value = self.string_encoded(&mut reader);
-                        state.set_string(hash_key, hash_value, None).await;
+
+            let header = self.read_bytes::<9>(&mut reader)?;
+            if &header[..5] != b"REDIS" {
+                return Err(invalid_data("not an RDB file"));
+            }
+            let databases = state.get_databases().await;
+            let mut db_index = 0;
+            let mut expiration = None;
+            // the idle time in seconds and the LFU counter of the next key
+            let mut idle = None;
+            let mut freq = None;
+            loop {
+                let [opcode] = self.read_bytes::<1>(&mut reader)?;
+                match opcode {
+                    RDB_OPCODE_EOF => break,
+                    RDB_OPCODE_AUX => {
+                        self.string_encoded(&mut reader)?;
+                        self.string_encoded(&mut reader)?;
+                    }
+                    RDB_OPCODE_SELECTDB => {
+                        db_index = self.size_encoded_bytes(&mut reader)? as usize;
+                        if db_index >= databases {
+                            println!("Skipping the keys of DB {}, only {} databases are configured", db_index, databases);
+                        }
+                    }
+                    RDB_OPCODE_RESIZEDB => {
+                        self.size_encoded_bytes(&mut reader)?;
+                        self.size_encoded_bytes(&mut reader)?;
+                    }
+                    RDB_OPCODE_SLOT_INFO => {
+                        for _ in 0..3 {
+                            self.size_encoded_bytes(&mut reader)?;
+                        }
+                    }
+                    RDB_OPCODE_IDLE => {
+                        idle = Some(self.size_encoded_bytes(&mut reader)?);
                     }
-                    0xFC => {
-                        let mut expiration_as_miliseconds_bytes = [0u8; 8];
-                        reader
-                            .read_exact(&mut expiration_as_miliseconds_bytes)
-                            .unwrap();
-                        let ms = u64::from_le_bytes(expiration_as_miliseconds_bytes);
-                        reader.consume(1);
-                        let hash_key = self.string_encoded(&mut reader);
-                        let hash_value = self.string_encoded(&mut reader);
-                        let expiration_time = UNIX_EPOCH + Duration::from_millis(ms);
-                        
-                        state.set_string(
-                            hash_key,
-                            hash_value,
-                            Some(Instant::from(self.system_time_to_instant(expiration_time))),
-                        ).await;
+                    RDB_OPCODE_FREQ => {
+                        let [counter] = self.read_bytes::<1>(&mut reader)?;
+                        freq = Some(counter);
+                    }
+                    RDB_OPCODE_EXPIRETIME_MS => {
+                        let ms = u64::from_le_bytes(self.read_bytes(&mut reader)?);
+                        expiration = Some(self.system_time_to_instant(UNIX_EPOCH + Duration::from_millis(ms)));
+                    }
+                    RDB_OPCODE_EXPIRETIME => {
+                        let seconds = u32::from_le_bytes(self.read_bytes(&mut reader)?);
+                        expiration = Some(self.system_time_to_instant(UNIX_EPOCH + Duration::from_secs(seconds.into())));
+                    }
+                    value_type => {
+                        let key = self.key_encoded(&mut reader)?;
+                        let value = self.value_encoded(&mut reader, value_type)?;
+                        let expiration_timestamp = expiration.take();
+                        let access = KeyAccess {
+                            last_access: unix_millis_now().saturating_sub(idle.take().unwrap_or(0).saturating_mul(1000)),
+                            lfu_counter: freq.take().unwrap_or(LFU_INIT_VAL),
+                        };
+                        if db_index < databases {
+                            let value = ExpiringValue { value, expiration_timestamp };
+                            state.set_value_in_db(db_index, key, value, access).await;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Writes every database to dir/dbfilename. The dump goes to a temporary file
+    // first, so a failed save never leaves a truncated file behind.
+    pub fn save(
+        &mut self,
+        path: &Path,
+        databases: &[HashMap<String, (ExpiringValue<Value>, KeyAccess)>],
+        maxmemory: &MaxmemoryConfig,
+    ) -> Result<(), Error> {
+        let temporary_path = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+        let mut writer = BufWriter::new(File::create(&temporary_path)?);
+        writer.write_all(&encode_rdb(databases, maxmemory))?;
+        writer.into_inner().map_err(|error| error.into_error())?.sync_all()?;
+        fs::rename(&temporary_path, path)
+    }
+}
+
+// Where SAVE and BGSAVE write, Redis' ./dump.rdb unless configured
+pub async fn rdb_path(state: &State) -> std::path::PathBuf {
+    let (db_dir, db_file_name) = state.get_db_config().await;
+    Path::new(&db_dir.unwrap_or(".".to_string())).join(db_file_name.unwrap_or("dump.rdb".to_string()))
+}
+
+fn stream_id_from_bytes(bytes: &[u8]) -> StreamId {
+    let ms = u64::from_be_bytes(bytes[..8].try_into().unwrap_or_default());
+    let seq = u64::from_be_bytes(bytes[8..16].try_into().unwrap_or_default());
+    StreamId::new(ms, seq)
+}
+
+fn stream_id_to_bytes(id: &StreamId) -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&id.ms.to_be_bytes());
+    bytes[8..].copy_from_slice(&id.seq.to_be_bytes());
+    bytes
+}
+
+// The entries of one stream listpack, deleted ones left out
+fn stream_node_entries(master_id: StreamId, node: &[String]) -> Result<Vec<(StreamId, StreamFields)>, Error> {
+    let parse = |element: &String| element.parse::<i64>().map_err(|_| invalid_data("invalid stream listpack"));
+    let header = node.get(..3).ok_or_else(|| invalid_data("truncated stream listpack"))?;
+    let (count, deleted, master_field_count) = (parse(&header[0])?, parse(&header[1])?, parse(&header[2])? as usize);
+
+    let master_fields: Vec<&String> = node.iter().skip(3).take(master_field_count).collect();
+    let mut elements = node.iter().skip(3 + master_field_count + 1);
+    let mut next = || elements.next().ok_or_else(|| invalid_data("truncated stream listpack"));
+
+    let mut entries = Vec::new();
+    for _ in 0..count + deleted {
+        let flags = parse(next()?)?;
+        let ms = master_id.ms.wrapping_add(parse(next()?)? as u64);
+        let seq = master_id.seq.wrapping_add(parse(next()?)? as u64);
+        let mut fields = Vec::new();
+        if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            for field in &master_fields {
+                fields.push(((*field).clone(), next()?.clone()));
+            }
+        } else {
+            let field_count = parse(next()?)?;
+            for _ in 0..field_count {
+                let field = next()?.clone();
+                fields.push((field, next()?.clone()));
+            }
+        }
+        // lp-count, used to walk the listpack backwards
+        next()?;
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            entries.push((StreamId::new(ms, seq), fields));
+        }
+    }
+    Ok(entries)
+}
+
+// A listpack: a 6 byte header, the elements and an end byte. Every element
+// is an encoding byte (integer or string of some width) with its data,
+// followed by its own length for backwards walks. Integers are returned in
+// their decimal form.
+fn listpack_strings(listpack: &[u8]) -> Result<Vec<String>, Error> {
+    let truncated = || invalid_data("truncated listpack");
+    let mut elements = Vec::new();
+    let mut i = 6;
+    loop {
+        let byte = *listpack.get(i).ok_or_else(truncated)?;
+        if byte == 0xFF {
+            return Ok(elements);
+        }
+        let data = |start: usize, length: usize| listpack.get(i + start..i + start + length).ok_or_else(truncated);
+        let signed = |bytes: &[u8]| {
+            let mut buffer = [0u8; 8];
+            buffer[..bytes.len()].copy_from_slice(bytes);
+            let shift = 64 - 8 * bytes.len() as u32;
+            (i64::from_le_bytes(buffer) << shift) >> shift
+        };
+        let (element, length) = match byte {
+            0x00..=0x7F => ((byte as i64).to_string(), 1),
+            0x80..=0xBF => {
+                let length = (byte & 0x3F) as usize;
+                (String::from_utf8_lossy(data(1, length)?).to_string(), 1 + length)
+            }
+            0xC0..=0xDF => {
+                let value = (((byte & 0x1F) as i64) << 8) | data(1, 1)?[0] as i64;
+                let value = if value >= 1 << 12 { value - (1 << 13) } else { value };
+                (value.to_string(), 2)
+            }
+            0xE0..=0xEF => {
+                let length = (((byte & 0x0F) as usize) << 8) | data(1, 1)?[0] as usize;
+                (String::from_utf8_lossy(data(2, length)?).to_string(), 2 + length)
+            }
+            0xF0 => {
+                let length = u32::from_le_bytes(data(1, 4)?.try_into().unwrap_or_default()) as usize;
+                (String::from_utf8_lossy(data(5, length)?).to_string(), 5 + length)
+            }
+            0xF1 => (signed(data(1, 2)?).to_string(), 3),
+            0xF2 => (signed(data(1, 3)?).to_string(), 4),
+            0xF3 => (signed(data(1, 4)?).to_string(), 5),
+            0xF4 => (signed(data(1, 8)?).to_string(), 9),
+            _ => return Err(invalid_data("unknown listpack encoding")),
+        };
+        elements.push(element);
+        i += length + backlen_size(length);
+    }
+}
+
+fn backlen_size(length: usize) -> usize {
+    match length {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+// Builds listpacks in the encoding Redis uses, smallest form first
+#[derive(Default)]
+struct ListpackWriter {
+    elements: Vec<u8>,
+    count: usize,
+}
+
+impl ListpackWriter {
+    fn push_integer(&mut self, value: i64) {
+        let mut element = Vec::with_capacity(9);
+        match value {
+            0..=127 => element.push(value as u8),
+            -4096..=4095 => {
+                let value = (value as u16) & 0x1FFF;
+                element.extend([0xC0 | (value >> 8) as u8, value as u8]);
+            }
+            -32768..=32767 => {
+                element.push(0xF1);
+                element.extend((value as i16).to_le_bytes());
+            }
+            -8388608..=8388607 => {
+                element.push(0xF2);
+                element.extend(&(value as i32).to_le_bytes()[..3]);
+            }
+            -2147483648..=2147483647 => {
+                element.push(0xF3);
+                element.extend((value as i32).to_le_bytes());
+            }
+            _ => {
+                element.push(0xF4);
+                element.extend(value.to_le_bytes());
+            }
+        }
+        self.push_element(element);
+    }
+
+    fn push_string(&mut self, value: &str) {
+        let bytes = value.as_bytes();
+        let mut element = Vec::with_capacity(bytes.len() + 5);
+        match bytes.len() {
+            length @ 0..=63 => element.push(0x80 | length as u8),
+            length @ 64..=4095 => element.extend([0xE0 | (length >> 8) as u8, length as u8]),
+            length => {
+                element.push(0xF0);
+                element.extend((length as u32).to_le_bytes());
+            }
+        }
+        element.extend_from_slice(bytes);
+        self.push_element(element);
+    }
+
+    // The element's length goes after it, 7 bits per byte, most significant
+    // first, every byte but the first with the high bit set
+    fn push_element(&mut self, element: Vec<u8>) {
+        let length = element.len();
+        self.elements.extend(element);
+        let size = backlen_size(length);
+        for i in (0..size).rev() {
+            let bits = ((length >> (7 * i)) & 0x7F) as u8;
+            self.elements.push(if i + 1 == size { bits } else { bits | 0x80 });
+        }
+        self.count += 1;
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let total = 6 + self.elements.len() + 1;
+        let mut listpack = Vec::with_capacity(total);
+        listpack.extend((total as u32).to_le_bytes());
+        listpack.extend((self.count.min(u16::MAX as usize) as u16).to_le_bytes());
+        listpack.extend(self.elements);
+        listpack.push(0xFF);
+        listpack
+    }
+}
+
+fn write_length(out: &mut Vec<u8>, length: u64) {
+    match length {
+        0..=63 => out.push(length as u8),
+        64..=16383 => out.extend([0x40 | (length >> 8) as u8, length as u8]),
+        16384..=0xFFFF_FFFF => {
+            out.push(0x80);
+            out.extend((length as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(0x81);
+            out.extend(length.to_be_bytes());
+        }
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_length(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn write_stream_id(out: &mut Vec<u8>, id: &StreamId) {
+    write_length(out, id.ms);
+    write_length(out, id.seq);
+}
+
+fn write_stream(out: &mut Vec<u8>, stream: &Stream) {
+    let entries: Vec<(&StreamId, &StreamFields)> = stream.range(StreamId::MIN, StreamId::MAX).collect();
+    let nodes = entries.chunks(STREAM_NODE_MAX_ENTRIES);
+    write_length(out, nodes.len() as u64);
+    for node in nodes {
+        let (master_id, master_fields) = node[0];
+        let mut listpack = ListpackWriter::default();
+        listpack.push_integer(node.len() as i64);
+        listpack.push_integer(0);
+        listpack.push_integer(master_fields.len() as i64);
+        for (field, _) in master_fields {
+            listpack.push_string(field);
+        }
+        listpack.push_integer(0);
+        for (id, fields) in node {
+            let same_fields = fields.len() == master_fields.len()
+                && fields.iter().zip(master_fields.iter()).all(|((field, _), (master_field, _))| field == master_field);
+            listpack.push_integer(if same_fields { STREAM_ITEM_FLAG_SAMEFIELDS } else { 0 });
+            listpack.push_integer(id.ms.wrapping_sub(master_id.ms) as i64);
+            listpack.push_integer(id.seq.wrapping_sub(master_id.seq) as i64);
+            if same_fields {
+                for (_, value) in *fields {
+                    listpack.push_string(value);
+                }
+                listpack.push_integer(fields.len() as i64 + 3);
+            } else {
+                listpack.push_integer(fields.len() as i64);
+                for (field, value) in *fields {
+                    listpack.push_string(field);
+                    listpack.push_string(value);
+                }
+                listpack.push_integer(2 * fields.len() as i64 + 4);
+            }
+        }
+        write_string(out, &stream_id_to_bytes(master_id));
+        write_string(out, &listpack.finish());
+    }
+
+    write_length(out, stream.len() as u64);
+    write_stream_id(out, &stream.last_id);
+    write_stream_id(out, stream.first_entry().map_or(&StreamId::MIN, |(id, _)| id));
+    write_stream_id(out, &stream.max_deleted_id);
+    write_length(out, stream.entries_added);
+
+    write_length(out, stream.groups.len() as u64);
+    for (name, group) in &stream.groups {
+        write_string(out, name.as_bytes());
+        write_stream_id(out, &group.last_id);
+        write_length(out, group.entries_read.unwrap_or(u64::MAX));
+        write_length(out, group.pending.len() as u64);
+        for (id, pending) in &group.pending {
+            out.extend(stream_id_to_bytes(id));
+            out.extend((pending.delivery_time as i64).to_le_bytes());
+            write_length(out, pending.delivery_count);
+        }
+        write_length(out, group.consumers.len() as u64);
+        for (consumer_name, consumer) in &group.consumers {
+            write_string(out, consumer_name.as_bytes());
+            out.extend((consumer.seen_time as i64).to_le_bytes());
+            out.extend(consumer.active_time.map_or(-1, |time| time as i64).to_le_bytes());
+            write_length(out, consumer.pending.len() as u64);
+            for id in &consumer.pending {
+                out.extend(stream_id_to_bytes(id));
+            }
+        }
+    }
+}
+
+// An RDB version 11 dump of the databases, empty ones left out. The
+// checksum is left as zeros, which tells readers not to verify it. Like
+// Redis, keys get their idle time under an LRU policy and their LFU counter
+// under an LFU one.
+pub fn encode_rdb(databases: &[HashMap<String, (ExpiringValue<Value>, KeyAccess)>], maxmemory: &MaxmemoryConfig) -> Vec<u8> {
+    let mut out = b"REDIS0011".to_vec();
+    for (field, value) in [("redis-ver", "7.2.0"), ("redis-bits", "64")] {
+        out.push(RDB_OPCODE_AUX);
+        write_string(&mut out, field.as_bytes());
+        write_string(&mut out, value.as_bytes());
+    }
+    out.push(RDB_OPCODE_AUX);
+    write_string(&mut out, b"ctime");
+    write_string(&mut out, (unix_millis_now() / 1000).to_string().as_bytes());
+
+    for (db, data) in databases.iter().enumerate() {
+        if data.is_empty() {
+            continue;
+        }
+        out.push(RDB_OPCODE_SELECTDB);
+        write_length(&mut out, db as u64);
+        out.push(RDB_OPCODE_RESIZEDB);
+        write_length(&mut out, data.len() as u64);
+        write_length(&mut out, data.values().filter(|(value, _)| value.expiration_timestamp.is_some()).count() as u64);
+
+        for (key, (expiring_value, access)) in data {
+            if let Some(expiration) = expiring_value.expiration_timestamp {
+                out.push(RDB_OPCODE_EXPIRETIME_MS);
+                out.extend((instant_to_unix_millis(expiration) as u64).to_le_bytes());
+            }
+            if maxmemory.policy.uses_lru() {
+                out.push(RDB_OPCODE_IDLE);
+                write_length(&mut out, access.idle_millis() / 1000);
+            } else if maxmemory.policy.uses_lfu() {
+                out.push(RDB_OPCODE_FREQ);
+                out.push(access.lfu_count(maxmemory.lfu_decay_time));
+            }
+            match &expiring_value.value {
+                Value::String(s) => {
+                    out.push(RDB_TYPE_STRING);
+                    write_string(&mut out, key.as_bytes());
+                    write_string(&mut out, s);
+                }
+                Value::List(list) => {
+                    out.push(RDB_TYPE_LIST);
+                    write_string(&mut out, key.as_bytes());
+                    write_length(&mut out, list.len() as u64);
+                    for element in list {
+                        write_string(&mut out, element.as_bytes());
                     }
-                    0xFD => {
-                        let mut expiration_as_seconds_bytes = [0u8; 4];
-                        reader.read_exact(&mut expiration_as_seconds_bytes).unwrap();
-                        let seconds = u32::from_le_bytes(expiration_as_seconds_bytes);
-                        reader.consume(1);
-                        let hash_key = self.string_encoded(&mut reader);
-                        let hash_value = self.string_encoded(&mut reader);
-                        let expiration_time = UNIX_EPOCH + Duration::from_secs(seconds.into());
-
-                        state.set_string(
-                            hash_key,
-                            hash_value,
-                            Some(Instant::from(self.system_time_to_instant(expiration_time))),
-                        ).await;
+                }
+                Value::SortedSet(sorted_set) => {
+                    out.push(RDB_TYPE_ZSET_2);
+                    write_string(&mut out, key.as_bytes());
+                    write_length(&mut out, sorted_set.len() as u64);
+                    for scored in sorted_set.iter() {
+                        write_string(&mut out, scored.member.as_bytes());
+                        out.extend(scored.score.to_le_bytes());
                     }
-                    _ => {}
                 }
+                Value::Stream(stream) => {
+                    out.push(RDB_TYPE_STREAM_LISTPACKS_3);
+                    write_string(&mut out, key.as_bytes());
+                    write_stream(&mut out, stream);
+                }
+            }
+        }
+    }
+    out.push(RDB_OPCODE_EOF);
+    out.extend([0u8; 8]);
+    out
+}
+
+// LZF as used by Redis for compressed RDB strings: a control byte below 32
+// starts a run of literals, anything else is a back reference
+fn lzf_decompress(input: &[u8], length: usize) -> Option<Vec<u8>> {
+    let mut output: Vec<u8> = Vec::with_capacity(length);
+    let mut i = 0;
+    while i < input.len() {
+        let control = input[i] as usize;
+        i += 1;
+        if control < 32 {
+            let literal = input.get(i..i + control + 1)?;
+            output.extend_from_slice(literal);
+            i += control + 1;
+        } else {
+            let mut run = control >> 5;
+            if run == 7 {
+                run += *input.get(i)? as usize;
+                i += 1;
+            }
+            let back = ((control & 0x1F) << 8) + *input.get(i)? as usize + 1;
+            i += 1;
+            let start = output.len().checked_sub(back)?;
+            // the reference may overlap the bytes being written
+            for j in 0..run + 2 {
+                output.push(output[start + j]);
+            }
+        }
+    }
+    if output.len() == length { Some(output) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::classes::Stream::ConsumerGroup;
+    use std::io::Cursor;
+
+    #[test]
+    fn lengths_use_redis_encodings() {
+        let cases: [(u64, &[u8]); 7] = [
+            (0, &[0x00]),
+            (63, &[0x3F]),
+            (64, &[0x40, 0x40]),
+            (16383, &[0x7F, 0xFF]),
+            (16384, &[0x80, 0x00, 0x00, 0x40, 0x00]),
+            (u32::MAX as u64, &[0x80, 0xFF, 0xFF, 0xFF, 0xFF]),
+            (1 << 32, &[0x81, 0, 0, 0, 1, 0, 0, 0, 0]),
+        ];
+        for (length, bytes) in cases {
+            let mut out = Vec::new();
+            write_length(&mut out, length);
+            assert_eq!(out, bytes);
+            assert_eq!(Db {}.size_encoded_bytes(&mut Cursor::new(bytes)).unwrap(), length);
+        }
+    }
+
+    #[test]
+    fn strings_decode_integer_and_lzf_encodings() {
+        let decode = |bytes: &[u8]| Db {}.string_encoded(&mut Cursor::new(bytes)).unwrap();
+        // how Redis writes the redis-bits aux field
+        assert_eq!(decode(&[0xC0, 0x40]), b"64");
+        assert_eq!(decode(&[0xC0, 0xFF]), b"-1");
+        assert_eq!(decode(&[0xC1, 0x39, 0x30]), b"12345");
+        assert_eq!(decode(&[0xC2, 0x15, 0xCD, 0x5B, 0x07]), b"123456789");
+        // LZF: a literal "a", then 8 bytes copied from 1 byte back
+        assert_eq!(decode(&[0xC3, 0x04, 0x09, 0x00, b'a', 0xC0, 0x00]), b"aaaaaaaaa");
+        assert_eq!(decode(&[0x03, 0xFF, 0x00, 0xFE]), b"\xff\x00\xfe");
+    }
+
+    #[test]
+    fn listpacks_match_redis() {
+        // ["a", 1] as Redis lays it out
+        let mut writer = ListpackWriter::default();
+        writer.push_string("a");
+        writer.push_integer(1);
+        assert_eq!(writer.finish(), [0x0C, 0, 0, 0, 0x02, 0, 0x81, b'a', 0x02, 0x01, 0x01, 0xFF]);
+
+        let integers = [0, 127, 128, -1, 4095, -4096, 4096, 32767, -32768, 32768, 8388607, -8388608, 8388608, i32::MIN as i64, i64::MAX, i64::MIN];
+        let strings = [String::new(), "x".repeat(63), "x".repeat(64), "x".repeat(4095), "x".repeat(4096)];
+        let mut writer = ListpackWriter::default();
+        integers.iter().for_each(|value| writer.push_integer(*value));
+        strings.iter().for_each(|value| writer.push_string(value));
+        let expected: Vec<String> = integers.iter().map(|value| value.to_string()).chain(strings.iter().cloned()).collect();
+        assert_eq!(listpack_strings(&writer.finish()).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn dump_loads_back() {
+        let mut stream = Stream::new();
+        stream.insert(StreamId::new(1, 1), vec![("f".to_string(), "v1".to_string())]);
+        stream.insert(StreamId::new(1, 2), vec![("f".to_string(), "v2".to_string())]);
+        stream.insert(StreamId::new(2, 0), vec![("g".to_string(), "v3".to_string())]);
+        stream.delete(&StreamId::new(1, 2));
+        let mut group = ConsumerGroup::new(StreamId::new(1, 2), Some(2));
+        group.touch_consumer("alice", 1700000000000);
+        group.assign(StreamId::new(1, 1), "alice", 1700000000001, 3);
+        stream.groups.insert("group".to_string(), group);
+
+        let mut sorted_set = SortedSet::new();
+        sorted_set.insert("a", 1.5);
+        sorted_set.insert("b", f64::INFINITY);
+
+        let access = KeyAccess::new();
+        let expiration = Instant::now() + Duration::from_secs(100);
+        let value = |value, expiration_timestamp| (ExpiringValue { value, expiration_timestamp }, access);
+        let mut db0 = HashMap::new();
+        db0.insert("string".to_string(), value(Value::String(b"\xff\x00".to_vec()), Some(expiration)));
+        db0.insert("list".to_string(), value(Value::List(vec!["x".to_string(), String::new()]), None));
+        db0.insert("zset".to_string(), value(Value::SortedSet(sorted_set), None));
+        let mut db2 = HashMap::new();
+        db2.insert("stream".to_string(), value(Value::Stream(stream), None));
+
+        let dir = std::env::temp_dir().join(format!("rdb-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        Db {}.save(&dir.join("dump.rdb"), &[db0, HashMap::new(), db2], &MaxmemoryConfig::default()).unwrap();
+        let mut state = State::new();
+        let mut config = state.get_config().await;
+        config.db_dir = Some(dir.to_string_lossy().to_string());
+        config.db_file_name = Some("dump.rdb".to_string());
+        state.set_config(config).await;
+        let loaded = Db {}.load(&mut state).await;
+        fs::remove_dir_all(&dir).unwrap();
+        loaded.unwrap();
+
+        let databases = state.snapshot().await;
+        assert_eq!(databases.iter().map(HashMap::len).collect::<Vec<_>>()[..3], [3, 0, 1]);
+        let (string, _) = &databases[0]["string"];
+        assert_eq!(string.value.as_string().unwrap(), b"\xff\x00");
+        let expires_at = instant_to_unix_millis(string.expiration_timestamp.unwrap());
+        assert!((expires_at - instant_to_unix_millis(expiration)).abs() <= 1);
+        match &databases[0]["list"].0.value {
+            Value::List(list) => assert_eq!(list, &["x", ""]),
+            _ => panic!("list loaded as another type"),
+        }
+        match &databases[0]["zset"].0.value {
+            Value::SortedSet(sorted_set) => {
+                assert_eq!(sorted_set.score("a"), Some(1.5));
+                assert_eq!(sorted_set.score("b"), Some(f64::INFINITY));
             }
+            _ => panic!("sorted set loaded as another type"),
         }
-        return Ok(());
+        let Value::Stream(stream) = &databases[2]["stream"].0.value else {
+            panic!("stream loaded as another type");
+        };
+        let ids: Vec<StreamId> = stream.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [StreamId::new(1, 1), StreamId::new(2, 0)]);
+        assert_eq!(stream.get(&StreamId::new(2, 0)).unwrap(), &[("g".to_string(), "v3".to_string())]);
+        assert_eq!((stream.last_id, stream.max_deleted_id, stream.entries_added), (StreamId::new(2, 0), StreamId::new(1, 2), 3));
+        let group = &stream.groups["group"];
+        assert_eq!((group.last_id, group.entries_read), (StreamId::new(1, 2), Some(2)));
+        let pending = &group.pending[&StreamId::new(1, 1)];
+        assert_eq!((pending.consumer.as_str(), pending.delivery_time, pending.delivery_count), ("alice", 1700000000001, 3));
+        assert!(group.consumers["alice"].pending.contains(&StreamId::new(1, 1)));
     }
 }
@@ -0,0 +1,223 @@
+use crate::classes::ExpiringValue::{instant_to_unix_millis, unix_millis_now};
+use crate::classes::Keyspace::{random_u64, Keyspace};
+
+// What happens once the dataset outgrows `maxmemory`
+#[derive(Clone, Copy, PartialEq)]
+pub enum MaxmemoryPolicy {
+    NoEviction,
+    AllKeysLru,
+    VolatileLru,
+    AllKeysLfu,
+    VolatileLfu,
+    AllKeysRandom,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+impl MaxmemoryPolicy {
+    // In the order Redis lists them in its CONFIG SET error
+    pub const ALL: [MaxmemoryPolicy; 8] = [
+        MaxmemoryPolicy::VolatileLru,
+        MaxmemoryPolicy::VolatileLfu,
+        MaxmemoryPolicy::VolatileRandom,
+        MaxmemoryPolicy::VolatileTtl,
+        MaxmemoryPolicy::AllKeysLru,
+        MaxmemoryPolicy::AllKeysLfu,
+        MaxmemoryPolicy::AllKeysRandom,
+        MaxmemoryPolicy::NoEviction,
+    ];
+
+    pub fn parse(name: &str) -> Option<MaxmemoryPolicy> {
+        MaxmemoryPolicy::ALL
+            .into_iter()
+            .find(|policy| policy.as_str().eq_ignore_ascii_case(name))
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MaxmemoryPolicy::NoEviction => "noeviction",
+            MaxmemoryPolicy::AllKeysLru => "allkeys-lru",
+            MaxmemoryPolicy::VolatileLru => "volatile-lru",
+            MaxmemoryPolicy::AllKeysLfu => "allkeys-lfu",
+            MaxmemoryPolicy::VolatileLfu => "volatile-lfu",
+            MaxmemoryPolicy::AllKeysRandom => "allkeys-random",
+            MaxmemoryPolicy::VolatileRandom => "volatile-random",
+            MaxmemoryPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    pub fn uses_lru(self) -> bool {
+        matches!(self, MaxmemoryPolicy::AllKeysLru | MaxmemoryPolicy::VolatileLru)
+    }
+
+    pub fn uses_lfu(self) -> bool {
+        matches!(self, MaxmemoryPolicy::AllKeysLfu | MaxmemoryPolicy::VolatileLfu)
+    }
+
+    // Whether only keys with a TTL may be evicted
+    pub fn volatile_only(self) -> bool {
+        matches!(
+            self,
+            MaxmemoryPolicy::VolatileLru
+                | MaxmemoryPolicy::VolatileLfu
+                | MaxmemoryPolicy::VolatileRandom
+                | MaxmemoryPolicy::VolatileTtl
+        )
+    }
+}
+
+// The `maxmemory*` and `lfu-*` configuration parameters
+#[derive(Clone, Copy)]
+pub struct MaxmemoryConfig {
+    // in bytes, 0 for no limit
+    pub maxmemory: u64,
+    pub policy: MaxmemoryPolicy,
+    // how many keys of each database are sampled to pick one to evict
+    pub samples: usize,
+    pub lfu_log_factor: u32,
+    // in minutes
+    pub lfu_decay_time: u64,
+}
+
+impl Default for MaxmemoryConfig {
+    fn default() -> MaxmemoryConfig {
+        MaxmemoryConfig {
+            maxmemory: 0,
+            policy: MaxmemoryPolicy::NoEviction,
+            samples: 5,
+            lfu_log_factor: 10,
+            lfu_decay_time: 1,
+        }
+    }
+}
+
+// The counter new keys start with, so they aren't evicted before they had
+// a chance to be accessed
+pub const LFU_INIT_VAL: u8 = 5;
+
+// When a key was last accessed and how often it is, for the LRU and LFU
+// policies. Redis packs both in 24 bits of the object header; here each
+// gets a field of its own.
+#[derive(Clone, Copy)]
+pub struct KeyAccess {
+    // unix milliseconds
+    pub last_access: u64,
+    // logarithmic access frequency, see `lfu_log_incr`
+    pub lfu_counter: u8,
+}
+
+impl KeyAccess {
+    pub fn new() -> KeyAccess {
+        KeyAccess {
+            last_access: unix_millis_now(),
+            lfu_counter: LFU_INIT_VAL,
+        }
+    }
+
+    pub fn touch(&mut self, config: &MaxmemoryConfig) {
+        self.lfu_counter = lfu_log_incr(self.lfu_count(config.lfu_decay_time), config.lfu_log_factor);
+        self.last_access = unix_millis_now();
+    }
+
+    pub fn idle_millis(&self) -> u64 {
+        unix_millis_now().saturating_sub(self.last_access)
+    }
+
+    // The counter, decremented once for every `lfu_decay_time` minutes the
+    // key went without being accessed
+    pub fn lfu_count(&self, lfu_decay_time: u64) -> u8 {
+        if lfu_decay_time == 0 {
+            return self.lfu_counter;
+        }
+        let periods = self.idle_millis() / 60_000 / lfu_decay_time;
+        self.lfu_counter.saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+}
+
+// Increments the counter with a probability that shrinks as it grows, so
+// its 255 values cover up to millions of accesses
+fn lfu_log_incr(counter: u8, lfu_log_factor: u32) -> u8 {
+    if counter == u8::MAX {
+        return counter;
+    }
+    let r = random_u64() as f64 / u64::MAX as f64;
+    let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+    if r < 1.0 / (base * lfu_log_factor as f64 + 1.0) {
+        counter + 1
+    } else {
+        counter
+    }
+}
+
+// How many candidates the eviction pool remembers
+const EVICTION_POOL_SIZE: usize = 16;
+
+// The best eviction candidates seen so far, as (score, database, key) by
+// increasing score. Every eviction only samples `samples` keys of each
+// database; like Redis's eviction pool, remembering the good candidates of
+// earlier samples brings the choice much closer to true LRU or LFU.
+#[derive(Clone, Default)]
+pub struct EvictionPool {
+    candidates: Vec<(u64, usize, String)>,
+}
+
+impl EvictionPool {
+    // Picks the key to evict next, with its database
+    pub fn pick_victim(&mut self, databases: &[Keyspace], config: &MaxmemoryConfig) -> Option<(usize, String)> {
+        let volatile = config.policy.volatile_only();
+        if matches!(config.policy, MaxmemoryPolicy::NoEviction) || databases.is_empty() {
+            return None;
+        }
+        if matches!(config.policy, MaxmemoryPolicy::AllKeysRandom | MaxmemoryPolicy::VolatileRandom) {
+            let start = random_u64() as usize % databases.len();
+            return (0..databases.len())
+                .map(|offset| (start + offset) % databases.len())
+                .find_map(|db| databases[db].random_keys(1, volatile).pop().map(|key| (db, key.clone())));
+        }
+
+        // score the remembered candidates again: they may have been accessed,
+        // deleted or have lost their TTL since they were sampled
+        let remembered = std::mem::take(&mut self.candidates);
+        for (_, db, key) in remembered {
+            if let Some(score) = databases.get(db).and_then(|keyspace| eviction_score(keyspace, &key, config)) {
+                self.insert(score, db, &key);
+            }
+        }
+        for (db, keyspace) in databases.iter().enumerate() {
+            for key in keyspace.random_keys(config.samples, volatile) {
+                if let Some(score) = eviction_score(keyspace, key, config) {
+                    self.insert(score, db, key);
+                }
+            }
+        }
+        self.candidates.pop().map(|(_, db, key)| (db, key))
+    }
+
+    fn insert(&mut self, score: u64, db: usize, key: &str) {
+        self.candidates.retain(|(_, candidate_db, candidate)| *candidate_db != db || candidate != key);
+        let position = self.candidates.partition_point(|(candidate_score, ..)| *candidate_score < score);
+        self.candidates.insert(position, (score, db, key.to_string()));
+        if self.candidates.len() > EVICTION_POOL_SIZE {
+            self.candidates.remove(0);
+        }
+    }
+}
+
+// How good a candidate the key is: the higher, the sooner it goes
+// (None when the policy can't evict it)
+fn eviction_score(keyspace: &Keyspace, key: &str, config: &MaxmemoryConfig) -> Option<u64> {
+    let access = keyspace.access(key)?;
+    if config.policy.volatile_only() && keyspace.get(key)?.expiration_timestamp.is_none() {
+        return None;
+    }
+    Some(match config.policy {
+        MaxmemoryPolicy::AllKeysLfu | MaxmemoryPolicy::VolatileLfu => {
+            (u8::MAX - access.lfu_count(config.lfu_decay_time)) as u64
+        }
+        MaxmemoryPolicy::VolatileTtl => {
+            let expiration = keyspace.get(key)?.expiration_timestamp?;
+            u64::MAX - instant_to_unix_millis(expiration).max(0) as u64
+        }
+        _ => access.idle_millis(),
+    })
+}
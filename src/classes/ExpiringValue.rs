@@ -1,7 +1,43 @@
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[derive(Clone)]
 pub struct ExpiringValue<T> {
     pub value: T,
     pub expiration_timestamp: Option<Instant>,
 }
+
+impl<T> ExpiringValue<T> {
+    pub fn is_expired(&self) -> bool {
+        match self.expiration_timestamp {
+            Some(expiration_timestamp) => Instant::now() > expiration_timestamp,
+            None => false,
+        }
+    }
+}
+
+// Expiration times are kept as monotonic `Instant`s; these convert to and
+// from the absolute unix milliseconds used by EXPIREAT, SET PXAT and friends.
+pub fn unix_millis_to_instant(ms: i64) -> Instant {
+    let target = UNIX_EPOCH + Duration::from_millis(ms.max(0) as u64);
+    let now_sys = SystemTime::now();
+    let now_inst = Instant::now();
+    match target.duration_since(now_sys) {
+        Ok(delta) => now_inst + delta,
+        Err(err) => now_inst.checked_sub(err.duration()).unwrap_or(now_inst),
+    }
+}
+
+pub fn instant_to_unix_millis(instant: Instant) -> i64 {
+    let now_sys = SystemTime::now();
+    let now_inst = Instant::now();
+    let target = if instant >= now_inst {
+        now_sys + (instant - now_inst)
+    } else {
+        now_sys - (now_inst - instant)
+    };
+    target.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or(0)
+}
+
+pub fn unix_millis_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
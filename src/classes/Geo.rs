@@ -0,0 +1,401 @@
+// Geohash helpers, ported from Redis' geohash.c / geohash_helper.c so that
+// scores, distances and search results match a real server. A position is
+// stored in a sorted set as a 52-bit interleaved geohash: latitude bits in
+// the even positions, longitude bits in the odd ones.
+
+pub const GEO_STEP_MAX: u8 = 26;
+pub const GEO_LAT_MIN: f64 = -85.05112878;
+pub const GEO_LAT_MAX: f64 = 85.05112878;
+pub const GEO_LONG_MIN: f64 = -180.0;
+pub const GEO_LONG_MAX: f64 = 180.0;
+
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+const MERCATOR_MAX: f64 = 20037726.37;
+const GEOHASH_ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct GeoHashBits {
+    pub bits: u64,
+    pub step: u8,
+}
+
+impl GeoHashBits {
+    fn zero() -> GeoHashBits {
+        GeoHashBits { bits: 0, step: 0 }
+    }
+
+    fn is_zero(&self) -> bool {
+        self.bits == 0 && self.step == 0
+    }
+
+    // Scores always use 52 bits, whatever the precision of the hash
+    fn align_52_bits(&self) -> u64 {
+        self.bits << (52 - self.step as u32 * 2)
+    }
+}
+
+pub struct GeoHashArea {
+    pub longitude: (f64, f64),
+    pub latitude: (f64, f64),
+}
+
+// The shape a GEOSEARCH looks into, with sizes in meters
+pub enum GeoShape {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+fn interleave64(x: u32, y: u32) -> u64 {
+    const B: [u64; 5] = [
+        0x5555555555555555,
+        0x3333333333333333,
+        0x0F0F0F0F0F0F0F0F,
+        0x00FF00FF00FF00FF,
+        0x0000FFFF0000FFFF,
+    ];
+    const S: [u32; 5] = [1, 2, 4, 8, 16];
+    let mut x = x as u64;
+    let mut y = y as u64;
+    for i in (0..5).rev() {
+        x = (x | (x << S[i])) & B[i];
+        y = (y | (y << S[i])) & B[i];
+    }
+    x | (y << 1)
+}
+
+fn deinterleave64(interleaved: u64) -> (u32, u32) {
+    const B: [u64; 6] = [
+        0x5555555555555555,
+        0x3333333333333333,
+        0x0F0F0F0F0F0F0F0F,
+        0x00FF00FF00FF00FF,
+        0x0000FFFF0000FFFF,
+        0x00000000FFFFFFFF,
+    ];
+    const S: [u32; 6] = [0, 1, 2, 4, 8, 16];
+    let mut x = interleaved;
+    let mut y = interleaved >> 1;
+    for i in 0..6 {
+        x = (x | (x >> S[i])) & B[i];
+        y = (y | (y >> S[i])) & B[i];
+    }
+    (x as u32, y as u32)
+}
+
+// Encodes with explicit ranges: GEOHASH uses -90..90 for the latitude to
+// produce standard geohash strings
+fn encode_in_range(long_range: (f64, f64), lat_range: (f64, f64), longitude: f64, latitude: f64, step: u8) -> Option<GeoHashBits> {
+    if !(GEO_LONG_MIN..=GEO_LONG_MAX).contains(&longitude) || !(GEO_LAT_MIN..=GEO_LAT_MAX).contains(&latitude) {
+        return None;
+    }
+    if latitude < lat_range.0 || latitude > lat_range.1 || longitude < long_range.0 || longitude > long_range.1 {
+        return None;
+    }
+    let scale = (1u64 << step) as f64;
+    let lat_offset = (latitude - lat_range.0) / (lat_range.1 - lat_range.0) * scale;
+    let long_offset = (longitude - long_range.0) / (long_range.1 - long_range.0) * scale;
+    Some(GeoHashBits {
+        bits: interleave64(lat_offset as u32, long_offset as u32),
+        step,
+    })
+}
+
+pub fn encode(longitude: f64, latitude: f64, step: u8) -> Option<GeoHashBits> {
+    encode_in_range((GEO_LONG_MIN, GEO_LONG_MAX), (GEO_LAT_MIN, GEO_LAT_MAX), longitude, latitude, step)
+}
+
+pub fn decode(hash: GeoHashBits) -> GeoHashArea {
+    let (ilato, ilono) = deinterleave64(hash.bits);
+    let scale = (1u64 << hash.step) as f64;
+    let lat_scale = GEO_LAT_MAX - GEO_LAT_MIN;
+    let long_scale = GEO_LONG_MAX - GEO_LONG_MIN;
+    GeoHashArea {
+        latitude: (
+            GEO_LAT_MIN + (ilato as f64 / scale) * lat_scale,
+            GEO_LAT_MIN + ((ilato as f64 + 1.0) / scale) * lat_scale,
+        ),
+        longitude: (
+            GEO_LONG_MIN + (ilono as f64 / scale) * long_scale,
+            GEO_LONG_MIN + ((ilono as f64 + 1.0) / scale) * long_scale,
+        ),
+    }
+}
+
+// The centre of the area the hash covers
+fn decode_to_long_lat(hash: GeoHashBits) -> (f64, f64) {
+    let area = decode(hash);
+    let longitude = ((area.longitude.0 + area.longitude.1) / 2.0).clamp(GEO_LONG_MIN, GEO_LONG_MAX);
+    let latitude = ((area.latitude.0 + area.latitude.1) / 2.0).clamp(GEO_LAT_MIN, GEO_LAT_MAX);
+    (longitude, latitude)
+}
+
+// Sorted set score of a position
+pub fn score_from_long_lat(longitude: f64, latitude: f64) -> Option<f64> {
+    encode(longitude, latitude, GEO_STEP_MAX).map(|hash| hash.align_52_bits() as f64)
+}
+
+// (longitude, latitude) of a sorted set score
+pub fn long_lat_from_score(score: f64) -> (f64, f64) {
+    decode_to_long_lat(GeoHashBits { bits: score as u64, step: GEO_STEP_MAX })
+}
+
+// The 11 character standard geohash of a score
+pub fn geohash_string(score: f64) -> String {
+    let (longitude, latitude) = long_lat_from_score(score);
+    let bits = encode_in_range((-180.0, 180.0), (-90.0, 90.0), longitude, latitude, GEO_STEP_MAX)
+        .map_or(0, |hash| hash.bits);
+    (0..11)
+        .map(|i| {
+            // the 11th character would need 55 bits, Redis pads it with zero
+            let index = if i == 10 { 0 } else { (bits >> (52 - (i + 1) * 5)) & 0x1f };
+            GEOHASH_ALPHABET[index as usize] as char
+        })
+        .collect()
+}
+
+fn deg_rad(degrees: f64) -> f64 {
+    degrees * std::f64::consts::PI / 180.0
+}
+
+fn rad_deg(radians: f64) -> f64 {
+    radians / (std::f64::consts::PI / 180.0)
+}
+
+fn lat_distance(lat1: f64, lat2: f64) -> f64 {
+    EARTH_RADIUS_IN_METERS * (deg_rad(lat2) - deg_rad(lat1)).abs()
+}
+
+// Haversine distance in meters
+pub fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let (lat1r, lon1r, lat2r, lon2r) = (deg_rad(lat1), deg_rad(lon1), deg_rad(lat2), deg_rad(lon2));
+    let v = ((lon2r - lon1r) / 2.0).sin();
+    // same longitude: skip the expensive part
+    if v == 0.0 {
+        return lat_distance(lat1, lat2);
+    }
+    let u = ((lat2r - lat1r) / 2.0).sin();
+    let a = u * u + lat1r.cos() * lat2r.cos() * v * v;
+    2.0 * EARTH_RADIUS_IN_METERS * a.sqrt().asin()
+}
+
+// Distance from the centre when the point lies in the shape
+pub fn distance_if_in_shape(shape: &GeoShape, center: (f64, f64), point: (f64, f64)) -> Option<f64> {
+    match *shape {
+        GeoShape::Radius(radius) => {
+            let distance = distance(center.0, center.1, point.0, point.1);
+            if distance > radius { None } else { Some(distance) }
+        }
+        GeoShape::Box { width, height } => {
+            // the latitude check is the cheaper one
+            if lat_distance(point.1, center.1) > height / 2.0 {
+                return None;
+            }
+            if distance(point.0, point.1, center.0, point.1) > width / 2.0 {
+                return None;
+            }
+            Some(distance(center.0, center.1, point.0, point.1))
+        }
+    }
+}
+
+fn move_x(hash: &mut GeoHashBits, direction: i8) {
+    if direction == 0 {
+        return;
+    }
+    let mut x = hash.bits & 0xaaaaaaaaaaaaaaaa;
+    let y = hash.bits & 0x5555555555555555;
+    let zz = 0x5555555555555555u64 >> (64 - hash.step as u32 * 2);
+    if direction > 0 {
+        x = x.wrapping_add(zz + 1);
+    } else {
+        x |= zz;
+        x = x.wrapping_sub(zz + 1);
+    }
+    x &= 0xaaaaaaaaaaaaaaaau64 >> (64 - hash.step as u32 * 2);
+    hash.bits = x | y;
+}
+
+fn move_y(hash: &mut GeoHashBits, direction: i8) {
+    if direction == 0 {
+        return;
+    }
+    let x = hash.bits & 0xaaaaaaaaaaaaaaaa;
+    let mut y = hash.bits & 0x5555555555555555;
+    let zz = 0xaaaaaaaaaaaaaaaau64 >> (64 - hash.step as u32 * 2);
+    if direction > 0 {
+        y = y.wrapping_add(zz + 1);
+    } else {
+        y |= zz;
+        y = y.wrapping_sub(zz + 1);
+    }
+    y &= 0x5555555555555555u64 >> (64 - hash.step as u32 * 2);
+    hash.bits = x | y;
+}
+
+fn neighbor(hash: GeoHashBits, x: i8, y: i8) -> GeoHashBits {
+    let mut neighbor = hash;
+    move_x(&mut neighbor, x);
+    move_y(&mut neighbor, y);
+    neighbor
+}
+
+fn estimate_steps_by_radius(mut range_meters: f64, latitude: f64) -> u8 {
+    if range_meters == 0.0 {
+        return GEO_STEP_MAX;
+    }
+    let mut step: i32 = 1;
+    while range_meters < MERCATOR_MAX {
+        range_meters *= 2.0;
+        step += 1;
+    }
+    // make sure the range is included in most of the base cases
+    step -= 2;
+    // wider range towards the poles
+    if !(-66.0..=66.0).contains(&latitude) {
+        step -= 1;
+        if !(-80.0..=80.0).contains(&latitude) {
+            step -= 1;
+        }
+    }
+    step.clamp(1, GEO_STEP_MAX as i32) as u8
+}
+
+// [min longitude, min latitude, max longitude, max latitude]
+fn bounding_box(shape: &GeoShape, longitude: f64, latitude: f64) -> [f64; 4] {
+    let (width, height) = match *shape {
+        GeoShape::Radius(radius) => (radius, radius),
+        GeoShape::Box { width, height } => (width / 2.0, height / 2.0),
+    };
+    let lat_delta = rad_deg(height / EARTH_RADIUS_IN_METERS);
+    let long_delta_top = rad_deg(width / EARTH_RADIUS_IN_METERS / deg_rad(latitude + lat_delta).cos());
+    let long_delta_bottom = rad_deg(width / EARTH_RADIUS_IN_METERS / deg_rad(latitude - lat_delta).cos());
+    // the hemispheres widen in opposite directions
+    let long_delta = if latitude < 0.0 { long_delta_bottom } else { long_delta_top };
+    [longitude - long_delta, latitude - lat_delta, longitude + long_delta, latitude + lat_delta]
+}
+
+// The score ranges [min, max) of the cell holding the centre and of its 8
+// neighbours, at a precision where they cover the whole shape. Cells that
+// can't hold a match are left out.
+pub fn search_ranges(shape: &GeoShape, longitude: f64, latitude: f64) -> Vec<(f64, f64)> {
+    let [min_lon, min_lat, max_lon, max_lat] = bounding_box(shape, longitude, latitude);
+    let radius_meters = match *shape {
+        GeoShape::Radius(radius) => radius,
+        GeoShape::Box { width, height } => ((width / 2.0).powi(2) + (height / 2.0).powi(2)).sqrt(),
+    };
+    let mut steps = estimate_steps_by_radius(radius_meters, latitude);
+
+    let mut hash = encode(longitude, latitude, steps).unwrap_or(GeoHashBits::zero());
+    // the estimated step may be too precise when the shape is close to an
+    // edge of the cell
+    let north = decode(neighbor(hash, 0, 1));
+    let south = decode(neighbor(hash, 0, -1));
+    let east = decode(neighbor(hash, 1, 0));
+    let west = decode(neighbor(hash, -1, 0));
+    let decrease_step = north.latitude.1 < max_lat
+        || south.latitude.0 > min_lat
+        || east.longitude.1 < max_lon
+        || west.longitude.0 > min_lon;
+    if steps > 1 && decrease_step {
+        steps -= 1;
+        hash = encode(longitude, latitude, steps).unwrap_or(GeoHashBits::zero());
+    }
+    let area = decode(hash);
+
+    // centre, north, south, east, west, north east, north west, south east,
+    // south west: the order Redis scans them in
+    let offsets: [(i8, i8); 9] = [(0, 0), (0, 1), (0, -1), (1, 0), (-1, 0), (1, 1), (-1, 1), (1, -1), (-1, -1)];
+    let mut cells: Vec<GeoHashBits> = offsets
+        .iter()
+        .map(|&(x, y)| {
+            if steps >= 2
+                && ((y < 0 && area.latitude.0 < min_lat)
+                    || (y > 0 && area.latitude.1 > max_lat)
+                    || (x < 0 && area.longitude.0 < min_lon)
+                    || (x > 0 && area.longitude.1 > max_lon))
+            {
+                GeoHashBits::zero()
+            } else {
+                neighbor(hash, x, y)
+            }
+        })
+        .collect();
+
+    let mut ranges = Vec::new();
+    let mut last_processed: Option<usize> = None;
+    for i in 0..cells.len() {
+        if cells[i].is_zero() {
+            continue;
+        }
+        // with a very low precision the neighbours can be the same cell
+        if let Some(last) = last_processed {
+            if cells[i] == cells[last] {
+                continue;
+            }
+        }
+        let min = cells[i].align_52_bits();
+        cells[i].bits += 1;
+        let max = cells[i].align_52_bits();
+        cells[i].bits -= 1;
+        ranges.push((min as f64, max as f64));
+        last_processed = Some(i);
+    }
+    ranges
+}
+
+// %.17Lf with the trailing zeros removed, like Redis' human long doubles
+pub fn format_coordinate(value: f64) -> String {
+    let formatted = format!("{:.17}", value);
+    formatted.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The Sicily examples from the GEOADD, GEOPOS, GEOHASH and GEODIST pages
+    const PALERMO: (f64, f64) = (13.361389, 38.115556);
+    const CATANIA: (f64, f64) = (15.087269, 37.502669);
+
+    #[test]
+    fn scores_match_redis() {
+        assert_eq!(score_from_long_lat(PALERMO.0, PALERMO.1), Some(3479099956230698.0));
+        assert_eq!(score_from_long_lat(CATANIA.0, CATANIA.1), Some(3479447370796909.0));
+        assert_eq!(score_from_long_lat(0.0, 86.0), None);
+        assert_eq!(score_from_long_lat(181.0, 0.0), None);
+    }
+
+    #[test]
+    fn positions_decode_like_redis() {
+        let (longitude, latitude) = long_lat_from_score(3479099956230698.0);
+        assert_eq!(format_coordinate(longitude), "13.36138933897018433");
+        assert_eq!(format_coordinate(latitude), "38.11555639549629859");
+        let (longitude, latitude) = long_lat_from_score(3479447370796909.0);
+        assert_eq!(format_coordinate(longitude), "15.08726745843887329");
+        assert_eq!(format_coordinate(latitude), "37.50266842333162032");
+    }
+
+    #[test]
+    fn geohash_strings_match_redis() {
+        assert_eq!(geohash_string(3479099956230698.0), "sqc8b49rny0");
+        assert_eq!(geohash_string(3479447370796909.0), "sqdtr74hyu0");
+    }
+
+    #[test]
+    fn encode_and_decode_round_trip() {
+        let hash = encode(PALERMO.0, PALERMO.1, GEO_STEP_MAX).unwrap();
+        let area = decode(hash);
+        assert!(area.longitude.0 <= PALERMO.0 && PALERMO.0 <= area.longitude.1);
+        assert!(area.latitude.0 <= PALERMO.1 && PALERMO.1 <= area.latitude.1);
+        assert_eq!(deinterleave64(interleave64(0x3ffffff, 0x1234567)), (0x3ffffff, 0x1234567));
+    }
+
+    #[test]
+    fn distances_match_redis() {
+        let (palermo, catania) = (long_lat_from_score(3479099956230698.0), long_lat_from_score(3479447370796909.0));
+        assert_eq!(format!("{:.4}", distance(palermo.0, palermo.1, catania.0, catania.1)), "166274.1516");
+        // GEORADIUS Sicily 15 37 200 km WITHDIST
+        assert_eq!(format!("{:.4}", distance(15.0, 37.0, palermo.0, palermo.1) / 1000.0), "190.4424");
+        assert_eq!(format!("{:.4}", distance(15.0, 37.0, catania.0, catania.1) / 1000.0), "56.4413");
+    }
+}
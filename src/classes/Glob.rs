@@ -0,0 +1,149 @@
+// Redis-style glob matching (the semantics of `stringmatchlen`): `*`, `?`,
+// `[...]` classes with ranges and `^` negation, and `\` escapes.
+pub fn glob_match(pattern: &str, string: &str) -> bool {
+    glob_match_bytes(pattern.as_bytes(), string.as_bytes(), false)
+}
+
+// Every token but `*` matches exactly one byte, so on a mismatch it is
+// enough to let the last `*` swallow one more byte and retry from there.
+// That keeps patterns like `a*a*a*a*b` linear instead of exponential.
+pub fn glob_match_bytes(pattern: &[u8], string: &[u8], nocase: bool) -> bool {
+    let mut p = 0;
+    let mut s = 0;
+    // the pattern right after the last `*`, and where in the string that
+    // `*` currently stops
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    loop {
+        if p < pattern.len() {
+            if pattern[p] == b'*' {
+                while p < pattern.len() && pattern[p] == b'*' {
+                    p += 1;
+                }
+                if p == pattern.len() {
+                    return true;
+                }
+                backtrack = Some((p, s));
+                continue;
+            }
+            if s < string.len() {
+                let (matched, next) = match_token(pattern, p, string[s], nocase);
+                if matched {
+                    p = next;
+                    s += 1;
+                    continue;
+                }
+            }
+        } else if s == string.len() {
+            return true;
+        }
+
+        match backtrack {
+            Some((star_p, star_s)) if star_s < string.len() => {
+                backtrack = Some((star_p, star_s + 1));
+                p = star_p;
+                s = star_s + 1;
+            }
+            _ => return false,
+        }
+    }
+}
+
+// Matches the token at `pattern[p]` (anything but `*`) against one byte.
+// Returns whether it matched and where the next token starts.
+fn match_token(pattern: &[u8], p: usize, c: u8, nocase: bool) -> (bool, usize) {
+    match pattern[p] {
+        b'?' => (true, p + 1),
+        b'[' => match_class(pattern, p + 1, c, nocase),
+        b'\\' if p + 1 < pattern.len() => (eq_byte(pattern[p + 1], c, nocase), p + 2),
+        literal => (eq_byte(literal, c, nocase), p + 1),
+    }
+}
+
+// A `[...]` class starting right after the `[`. An unterminated class ends
+// with the pattern, like in Redis.
+fn match_class(pattern: &[u8], mut p: usize, c: u8, nocase: bool) -> (bool, usize) {
+    let not = p < pattern.len() && pattern[p] == b'^';
+    if not {
+        p += 1;
+    }
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            p += 1;
+            matched |= pattern[p] == c;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' {
+            let (mut start, mut end) = (pattern[p], pattern[p + 2]);
+            let mut c = c;
+            if start > end {
+                std::mem::swap(&mut start, &mut end);
+            }
+            if nocase {
+                start = start.to_ascii_lowercase();
+                end = end.to_ascii_lowercase();
+                c = c.to_ascii_lowercase();
+            }
+            p += 2;
+            matched |= (start..=end).contains(&c);
+        } else {
+            matched |= eq_byte(pattern[p], c, nocase);
+        }
+        p += 1;
+    }
+    (matched != not, (p + 1).min(pattern.len()))
+}
+
+fn eq_byte(a: u8, b: u8, nocase: bool) -> bool {
+    if nocase {
+        a.eq_ignore_ascii_case(&b)
+    } else {
+        a == b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_redis_keys_examples() {
+        // the examples from the KEYS documentation
+        assert!(glob_match("h?llo", "hello") && glob_match("h?llo", "hallo") && glob_match("h?llo", "hxllo"));
+        assert!(glob_match("h*llo", "hllo") && glob_match("h*llo", "heeeello"));
+        assert!(glob_match("h[ae]llo", "hello") && glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("h[^e]llo", "hallo") && glob_match("h[^e]llo", "hbllo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("h[a-b]llo", "hallo") && glob_match("h[a-b]llo", "hbllo"));
+        assert!(!glob_match("h[a-b]llo", "hcllo"));
+    }
+
+    #[test]
+    fn escapes_and_classes() {
+        assert!(glob_match("h\\*llo", "h*llo"));
+        assert!(!glob_match("h\\*llo", "hello"));
+        assert!(glob_match("[\\]]", "]"));
+        // reversed ranges match like in Redis
+        assert!(glob_match("[z-a]", "m"));
+        // an unterminated class ends with the pattern
+        assert!(glob_match("a[bc", "ab"));
+        assert!(!glob_match("a[bc", "ad"));
+        assert!(glob_match("*", "") && !glob_match("?", ""));
+        assert!(glob_match("", "") && !glob_match("", "a"));
+    }
+
+    #[test]
+    fn nocase_folds_ascii() {
+        assert!(glob_match_bytes(b"H[A-C]LLO", b"hbllo", true));
+        assert!(glob_match_bytes(b"h*O", b"HELLO", true));
+        assert!(!glob_match_bytes(b"h*O", b"HELLO", false));
+    }
+
+    #[test]
+    fn many_stars_stay_linear() {
+        // Redis' regression for exponential backtracking in stringmatchlen
+        let pattern = "a*".repeat(50) + "b";
+        assert!(!glob_match(&pattern, &"a".repeat(1000)));
+        assert!(glob_match(&pattern, &("a".repeat(1000) + "b")));
+    }
+}
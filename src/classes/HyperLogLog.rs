@@ -0,0 +1,396 @@
+// HyperLogLog with the same string layout as Redis, so the values can be
+// copied between servers: a 16 byte header ("HYLL", encoding, 3 unused
+// bytes, 8 bytes of cached cardinality) followed by 16384 6-bit registers,
+// either packed (dense) or run-length encoded (sparse).
+
+const HLL_P: u32 = 14;
+const HLL_Q: u32 = 64 - HLL_P;
+const HLL_REGISTERS: usize = 1 << HLL_P;
+const HLL_P_MASK: u64 = HLL_REGISTERS as u64 - 1;
+const HLL_BITS: usize = 6;
+const HLL_REGISTER_MAX: u8 = (1 << HLL_BITS) - 1;
+const HLL_HDR_SIZE: usize = 16;
+const HLL_DENSE_SIZE: usize = HLL_HDR_SIZE + (HLL_REGISTERS * HLL_BITS).div_ceil(8);
+const HLL_DENSE: u8 = 0;
+const HLL_SPARSE: u8 = 1;
+const HLL_ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+
+// Sparse opcodes: ZERO 00xxxxxx, XZERO 01xxxxxx yyyyyyyy, VAL 1vvvvvxx
+const HLL_SPARSE_ZERO_MAX_LEN: usize = 64;
+const HLL_SPARSE_XZERO_MAX_LEN: usize = 16384;
+const HLL_SPARSE_VAL_MAX_VALUE: u8 = 32;
+const HLL_SPARSE_VAL_MAX_LEN: usize = 4;
+// hll-sparse-max-bytes default: bigger sparse values are stored dense
+const HLL_SPARSE_MAX_BYTES: usize = 3000;
+
+pub const INVALID_HLL_ERROR: &str = "WRONGTYPE Key is not a valid HyperLogLog string value.";
+pub const CORRUPTED_HLL_ERROR: &str = "INVALIDOBJ Corrupted HLL object detected";
+
+// Registers are kept unpacked while a command works on them and encoded
+// again with `to_bytes`
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+    dense: bool,
+    cached_cardinality: [u8; 8],
+}
+
+// MurmurHash64A, the hash Redis uses for HyperLogLog elements
+fn murmurhash64a(key: &[u8], seed: u32) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+    let mut h: u64 = (seed as u64) ^ (key.len() as u64).wrapping_mul(M);
+
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, byte) in tail.iter().enumerate() {
+            h ^= (*byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+// Register index of the element, and the length of the run of zeros (+1)
+// in the remaining hash bits
+fn pattern_len(element: &[u8]) -> (usize, u8) {
+    let mut hash = murmurhash64a(element, 0xadc83b19);
+    let index = (hash & HLL_P_MASK) as usize;
+    hash >>= HLL_P;
+    hash |= 1 << HLL_Q;
+    (index, hash.trailing_zeros() as u8 + 1)
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let z_prime = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z_prime == z {
+            return z / 3.0;
+        }
+    }
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let z_prime = z;
+        z += x * y;
+        y += y;
+        if z_prime == z {
+            return z;
+        }
+    }
+}
+
+impl HyperLogLog {
+    pub fn new() -> HyperLogLog {
+        HyperLogLog {
+            registers: vec![0; HLL_REGISTERS],
+            dense: false,
+            cached_cardinality: [0; 8],
+        }
+    }
+
+    // Err carries the reply for values that aren't a HyperLogLog
+    pub fn from_bytes(bytes: &[u8]) -> Result<HyperLogLog, &'static str> {
+        if bytes.len() < HLL_HDR_SIZE || &bytes[0..4] != b"HYLL" {
+            return Err(INVALID_HLL_ERROR);
+        }
+        let mut hll = HyperLogLog::new();
+        hll.cached_cardinality.copy_from_slice(&bytes[8..16]);
+        let payload = &bytes[HLL_HDR_SIZE..];
+
+        match bytes[4] {
+            HLL_DENSE => {
+                if bytes.len() != HLL_DENSE_SIZE {
+                    return Err(INVALID_HLL_ERROR);
+                }
+                hll.dense = true;
+                for (index, register) in hll.registers.iter_mut().enumerate() {
+                    let byte = index * HLL_BITS / 8;
+                    let first_bit = (index * HLL_BITS) & 7;
+                    let b0 = payload[byte] as u16;
+                    let b1 = payload.get(byte + 1).copied().unwrap_or(0) as u16;
+                    *register = (((b0 >> first_bit) | (b1 << (8 - first_bit))) as u8) & HLL_REGISTER_MAX;
+                }
+            }
+            HLL_SPARSE => {
+                let mut index = 0;
+                let mut i = 0;
+                while i < payload.len() {
+                    let opcode = payload[i];
+                    let (value, run) = if opcode & 0xC0 == 0x00 {
+                        (0, (opcode & 0x3F) as usize + 1)
+                    } else if opcode & 0xC0 == 0x40 {
+                        i += 1;
+                        let low = *payload.get(i).ok_or(CORRUPTED_HLL_ERROR)? as usize;
+                        (0, ((((opcode & 0x3F) as usize) << 8) | low) + 1)
+                    } else {
+                        (((opcode >> 2) & 0x1F) + 1, (opcode & 0x3) as usize + 1)
+                    };
+                    if index + run > HLL_REGISTERS {
+                        return Err(CORRUPTED_HLL_ERROR);
+                    }
+                    hll.registers[index..index + run].fill(value);
+                    index += run;
+                    i += 1;
+                }
+                if index != HLL_REGISTERS {
+                    return Err(CORRUPTED_HLL_ERROR);
+                }
+            }
+            _ => return Err(INVALID_HLL_ERROR),
+        }
+        Ok(hll)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let sparse = if self.dense { None } else { self.encode_sparse() };
+        let mut bytes = b"HYLL".to_vec();
+        bytes.push(if sparse.is_some() { HLL_SPARSE } else { HLL_DENSE });
+        bytes.extend_from_slice(&[0; 3]);
+        bytes.extend_from_slice(&self.cached_cardinality);
+        match sparse {
+            Some(payload) => bytes.extend(payload),
+            None => bytes.extend(self.encode_dense()),
+        }
+        bytes
+    }
+
+    fn encode_dense(&self) -> Vec<u8> {
+        let mut payload = vec![0u8; HLL_DENSE_SIZE - HLL_HDR_SIZE];
+        for (index, register) in self.registers.iter().enumerate() {
+            let byte = index * HLL_BITS / 8;
+            let first_bit = (index * HLL_BITS) & 7;
+            let value = (*register as u16) << first_bit;
+            payload[byte] |= value as u8;
+            if let Some(next) = payload.get_mut(byte + 1) {
+                *next |= (value >> 8) as u8;
+            }
+        }
+        payload
+    }
+
+    // None when a register doesn't fit a VAL opcode or the result would be
+    // bigger than hll-sparse-max-bytes; the value is then stored dense
+    fn encode_sparse(&self) -> Option<Vec<u8>> {
+        let mut payload = Vec::new();
+        let mut index = 0;
+        while index < HLL_REGISTERS {
+            let value = self.registers[index];
+            let mut run = 1;
+            while index + run < HLL_REGISTERS && self.registers[index + run] == value {
+                run += 1;
+            }
+            index += run;
+
+            if value > HLL_SPARSE_VAL_MAX_VALUE {
+                return None;
+            }
+            while run > 0 {
+                if value != 0 {
+                    let len = run.min(HLL_SPARSE_VAL_MAX_LEN);
+                    payload.push(0x80 | ((value - 1) << 2) | (len - 1) as u8);
+                    run -= len;
+                } else if run > HLL_SPARSE_ZERO_MAX_LEN {
+                    let len = run.min(HLL_SPARSE_XZERO_MAX_LEN);
+                    payload.push(0x40 | ((len - 1) >> 8) as u8);
+                    payload.push(((len - 1) & 0xFF) as u8);
+                    run -= len;
+                } else {
+                    payload.push((run - 1) as u8);
+                    run = 0;
+                }
+            }
+        }
+        if HLL_HDR_SIZE + payload.len() > HLL_SPARSE_MAX_BYTES {
+            return None;
+        }
+        Some(payload)
+    }
+
+    // Returns true when a register changed
+    pub fn add(&mut self, element: &[u8]) -> bool {
+        let (index, count) = pattern_len(element);
+        if count <= self.registers[index] {
+            return false;
+        }
+        self.registers[index] = count;
+        if count > HLL_SPARSE_VAL_MAX_VALUE {
+            self.dense = true;
+        }
+        self.invalidate_cache();
+        true
+    }
+
+    // Keeps the maximum of every register; the result is dense as soon as
+    // one of the inputs is
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (register, other) in self.registers.iter_mut().zip(other.registers.iter()) {
+            *register = (*register).max(*other);
+        }
+        self.dense |= other.dense;
+        self.invalidate_cache();
+    }
+
+    pub fn invalidate_cache(&mut self) {
+        self.cached_cardinality[7] |= 1 << 7;
+    }
+
+    pub fn cached_count(&self) -> Option<u64> {
+        if self.cached_cardinality[7] & (1 << 7) != 0 {
+            None
+        } else {
+            Some(u64::from_le_bytes(self.cached_cardinality))
+        }
+    }
+
+    // Otmar Ertl's estimator ("New cardinality estimation algorithms for
+    // HyperLogLog sketches"), as used by Redis. Refreshes the cached value.
+    pub fn count(&mut self) -> u64 {
+        let m = HLL_REGISTERS as f64;
+        let mut histogram = [0u32; 64];
+        for register in &self.registers {
+            histogram[*register as usize] += 1;
+        }
+
+        let mut z = m * tau((m - histogram[HLL_Q as usize + 1] as f64) / m);
+        for j in (1..=HLL_Q as usize).rev() {
+            z += histogram[j] as f64;
+            z *= 0.5;
+        }
+        z += m * sigma(histogram[0] as f64 / m);
+        let estimate = (HLL_ALPHA_INF * m * m / z).round() as u64;
+
+        self.cached_cardinality = estimate.to_le_bytes();
+        estimate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Header of a sparse value whose cached cardinality is invalid, as
+    // Redis leaves it after PFADD
+    fn sparse_header() -> Vec<u8> {
+        b"HYLL\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x80".to_vec()
+    }
+
+    #[test]
+    fn murmurhash_matches_redis() {
+        // MurmurHash64A from Redis's hyperloglog.c, with its HLL seed
+        assert_eq!(murmurhash64a(b"", 0xadc83b19), 0xd8dfea6585bc9732);
+        assert_eq!(murmurhash64a(b"a", 0xadc83b19), 0x53d2470a9b43b1a7);
+        assert_eq!(murmurhash64a(b"hello", 0xadc83b19), 0x0f656f01eecfe400);
+        assert_eq!(murmurhash64a(b"foobar123", 0xadc83b19), 0xce43c1791ca0c27e);
+        assert_eq!(murmurhash64a(b"\xff\x00\xfe", 0xadc83b19), 0x9919acd489b9e0dd);
+    }
+
+    #[test]
+    fn pattern_len_takes_index_from_low_bits() {
+        assert_eq!(pattern_len(b"a"), (12711, 2));
+        assert_eq!(pattern_len(b"hello"), (9216, 1));
+        assert_eq!(pattern_len(b"\xff\x00\xfe"), (8413, 1));
+    }
+
+    #[test]
+    fn empty_value_matches_redis() {
+        // What PFADD with no elements stores: one XZERO covering every register
+        let empty = b"HYLL\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x7f\xff";
+        assert_eq!(HyperLogLog::new().to_bytes(), empty);
+        let mut hll = HyperLogLog::from_bytes(empty).unwrap();
+        assert_eq!(hll.cached_count(), Some(0));
+        assert_eq!(hll.count(), 0);
+    }
+
+    #[test]
+    fn counts_sparse_value() {
+        // XZERO:100, VAL:3,2, XZERO:16282
+        let mut bytes = sparse_header();
+        bytes.extend_from_slice(&[0x40, 0x63, 0x89, 0x7f, 0x99]);
+        let mut hll = HyperLogLog::from_bytes(&bytes).unwrap();
+        assert_eq!(&hll.registers[99..103], &[0, 3, 3, 0]);
+        assert_eq!(hll.cached_count(), None);
+        assert_eq!(hll.count(), 2);
+        assert_eq!(hll.to_bytes()[HLL_HDR_SIZE..], bytes[HLL_HDR_SIZE..]);
+    }
+
+    #[test]
+    fn counts_dense_value() {
+        // Register 0 is 1 and register 1, which straddles the first two
+        // bytes, is 2
+        let mut bytes = b"HYLL\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x80".to_vec();
+        bytes.resize(HLL_DENSE_SIZE, 0);
+        bytes[HLL_HDR_SIZE] = 0x81;
+        let mut hll = HyperLogLog::from_bytes(&bytes).unwrap();
+        assert_eq!(&hll.registers[0..3], &[1, 2, 0]);
+        assert_eq!(hll.count(), 2);
+        assert_eq!(hll.to_bytes()[HLL_HDR_SIZE..], bytes[HLL_HDR_SIZE..]);
+    }
+
+    #[test]
+    fn sparse_and_dense_count_the_same() {
+        let mut sparse = HyperLogLog::new();
+        for i in 0..1000 {
+            sparse.add(format!("element:{}", i).as_bytes());
+        }
+        let mut dense = HyperLogLog::from_bytes(&sparse.to_bytes()).unwrap();
+        dense.dense = true;
+        let dense_bytes = dense.to_bytes();
+        assert_eq!(dense_bytes.len(), HLL_DENSE_SIZE);
+        let count = sparse.count();
+        assert_eq!(HyperLogLog::from_bytes(&dense_bytes).unwrap().count(), count);
+        assert!((980..=1020).contains(&count));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert_eq!(HyperLogLog::from_bytes(b"0123\x01").err(), Some(INVALID_HLL_ERROR));
+        let mut bytes = sparse_header();
+        bytes[4] = HLL_DENSE;
+        bytes.extend_from_slice(&[0x7f, 0xff]);
+        assert_eq!(HyperLogLog::from_bytes(&bytes).err(), Some(INVALID_HLL_ERROR));
+        // Extra bytes after the last register
+        let mut bytes = sparse_header();
+        bytes.extend_from_slice(b"\x7f\xffhello");
+        assert_eq!(HyperLogLog::from_bytes(&bytes).err(), Some(CORRUPTED_HLL_ERROR));
+    }
+
+    #[test]
+    fn rejects_sparse_runs_past_the_registers() {
+        // Redis's overflow regression: XZERO runs adding up to more than
+        // 4GB registers, then a VAL opcode
+        let mut bytes = sparse_header();
+        for _ in 0..132072 {
+            bytes.extend_from_slice(&[0x7f, 0xff]);
+        }
+        bytes.push(0xff);
+        assert_eq!(HyperLogLog::from_bytes(&bytes).err(), Some(CORRUPTED_HLL_ERROR));
+    }
+}
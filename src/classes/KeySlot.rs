@@ -0,0 +1,71 @@
+// CRC16/XMODEM (polynomial 0x1021), the same checksum Redis Cluster uses to
+// map keys and shard channels onto its 16384 hash slots.
+pub const CLUSTER_SLOTS: u16 = 16384;
+
+pub fn crc16(buf: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in buf {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+// Only the part between the first `{` and the following `}` is hashed when
+// it is non-empty, so `{user1000}.following` and `{user1000}.followers`
+// land in the same slot.
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    if let Some(open) = key.iter().position(|&b| b == b'{') {
+        if let Some(close) = key[open + 1..].iter().position(|&b| b == b'}') {
+            if close > 0 {
+                return crc16(&key[open + 1..open + 1 + close]) & (CLUSTER_SLOTS - 1);
+            }
+        }
+    }
+    crc16(key) & (CLUSTER_SLOTS - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_matches_the_redis_cluster_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(key_hash_slot(b"123456789"), 0x31C3 & (CLUSTER_SLOTS - 1));
+    }
+
+    #[test]
+    fn key_hash_slot_matches_redis() {
+        // CLUSTER KEYSLOT of a Redis server
+        assert_eq!(key_hash_slot(b"foo"), 12182);
+        assert_eq!(key_hash_slot(b"somekey"), 11058);
+        assert_eq!(key_hash_slot(b""), 0);
+    }
+
+    #[test]
+    fn key_hash_slot_hashes_only_the_first_hashtag() {
+        let slot = key_hash_slot(b"user1000");
+        assert_eq!(key_hash_slot(b"{user1000}.following"), slot);
+        assert_eq!(key_hash_slot(b"{user1000}.followers"), slot);
+        assert_eq!(key_hash_slot(b"foo{user1000}{bar}"), slot);
+        // only up to the first `}` after the first `{`
+        assert_eq!(key_hash_slot(b"foo{{bar}}zap"), key_hash_slot(b"{bar"));
+        assert_eq!(key_hash_slot(b"foo{bar}{zap}"), key_hash_slot(b"bar"));
+    }
+
+    #[test]
+    fn key_hash_slot_hashes_the_whole_key_without_a_hashtag() {
+        // an empty tag, or an unclosed one, isn't a tag
+        assert_eq!(key_hash_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") & (CLUSTER_SLOTS - 1));
+        assert_eq!(key_hash_slot(b"{}"), crc16(b"{}") & (CLUSTER_SLOTS - 1));
+        assert_eq!(key_hash_slot(b"foo{bar"), crc16(b"foo{bar") & (CLUSTER_SLOTS - 1));
+        assert_ne!(key_hash_slot(b"foo{}{bar}"), key_hash_slot(b"bar"));
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{BuildHasher, DefaultHasher, Hash, Hasher};
+use std::time::Instant;
+
+use crate::classes::Eviction::{KeyAccess, MaxmemoryConfig};
+use crate::classes::ExpiringValue::ExpiringValue;
+use crate::classes::Memory::{key_memory, volatile_key_memory, DEFAULT_SAMPLES};
+use crate::classes::State::Value;
+
+// The keys of one database. Next to the map, the keys are kept ordered by
+// their scan position (see `scan_position`), so SCAN can walk them the way
+// Redis walks the buckets of its hash table. The keys with a TTL get the
+// same ordering of their own, for sampling them alone like Redis samples
+// its expires dictionary.
+#[derive(Clone, Default)]
+pub struct Keyspace {
+    entries: HashMap<String, Entry>,
+    scan_order: BTreeSet<(u64, String)>,
+    volatile_order: BTreeSet<(u64, String)>,
+    // the sum of the entries' `memory`, and of what the TTLs add
+    used_memory: usize,
+}
+
+#[derive(Clone)]
+struct Entry {
+    value: ExpiringValue<Value>,
+    access: KeyAccess,
+    // the estimated size of the key, see `Memory::key_memory`
+    memory: usize,
+}
+
+impl Keyspace {
+    pub fn new() -> Keyspace {
+        Keyspace::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn used_memory(&self) -> usize {
+        self.used_memory
+    }
+
+    // How many keys have a TTL
+    pub fn volatile_len(&self) -> usize {
+        self.volatile_order.len()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&ExpiringValue<Value>> {
+        self.entries.get(key).map(|entry| &entry.value)
+    }
+
+    // Callers that change the size of the value call `refresh_memory`
+    // afterwards. TTLs only change through `set_expiration`.
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut ExpiringValue<Value>> {
+        self.entries.get_mut(key).map(|entry| &mut entry.value)
+    }
+
+    pub fn access(&self, key: &str) -> Option<KeyAccess> {
+        self.entries.get(key).map(|entry| entry.access)
+    }
+
+    // Replaces what is known about the accesses to `key`, for loading RDB files
+    pub fn set_access(&mut self, key: &str, access: KeyAccess) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.access = access;
+        }
+    }
+
+    // Records an access to `key` for the LRU and LFU policies
+    pub fn touch(&mut self, key: &str, config: &MaxmemoryConfig) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.access.touch(config);
+        }
+    }
+
+    // Overwriting a key keeps what is known about its accesses, like Redis
+    // does for its LFU counter
+    pub fn insert(&mut self, key: String, value: ExpiringValue<Value>) -> Option<ExpiringValue<Value>> {
+        let previous = self.take(&key);
+        let position = scan_position(&key);
+        let memory = key_memory(&key, &value.value, DEFAULT_SAMPLES);
+        if value.expiration_timestamp.is_some() {
+            self.volatile_order.insert((position, key.clone()));
+            self.used_memory += volatile_key_memory(&key);
+        }
+        self.scan_order.insert((position, key.clone()));
+        self.used_memory += memory;
+        let access = previous.as_ref().map_or_else(KeyAccess::new, |previous| previous.access);
+        self.entries.insert(key, Entry { value, access, memory });
+        previous.map(|previous| previous.value)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<ExpiringValue<Value>> {
+        self.take(key).map(|entry| entry.value)
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&String, &mut ExpiringValue<Value>) -> bool) {
+        let mut removed = Vec::new();
+        self.entries.retain(|key, entry| {
+            let kept = keep(key, &mut entry.value);
+            if !kept {
+                removed.push((key.clone(), entry.memory, entry.value.expiration_timestamp.is_some()));
+            }
+            kept
+        });
+        for (key, memory, volatile) in removed {
+            let position = (scan_position(&key), key);
+            if volatile {
+                self.volatile_order.remove(&position);
+                self.used_memory -= volatile_key_memory(&position.1);
+            }
+            self.scan_order.remove(&position);
+            self.used_memory -= memory;
+        }
+    }
+
+    // Sets (or with `None`, clears) the TTL of `key`. Returns false when the
+    // key doesn't exist.
+    pub fn set_expiration(&mut self, key: &str, expiration: Option<Instant>) -> bool {
+        let Some(entry) = self.entries.get_mut(key) else {
+            return false;
+        };
+        let was_volatile = std::mem::replace(&mut entry.value.expiration_timestamp, expiration).is_some();
+        let position = (scan_position(key), key.to_string());
+        match (was_volatile, expiration.is_some()) {
+            (false, true) => {
+                self.volatile_order.insert(position);
+                self.used_memory += volatile_key_memory(key);
+            }
+            (true, false) => {
+                self.volatile_order.remove(&position);
+                self.used_memory -= volatile_key_memory(key);
+            }
+            _ => {}
+        }
+        true
+    }
+
+    // Estimates the size of `key` again after its value changed in place
+    pub fn refresh_memory(&mut self, key: &str) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            let memory = key_memory(key, &entry.value.value, DEFAULT_SAMPLES);
+            self.used_memory = self.used_memory - entry.memory + memory;
+            entry.memory = memory;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &ExpiringValue<Value>)> {
+        self.entries.iter().map(|(key, entry)| (key, &entry.value))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &ExpiringValue<Value>> + Clone {
+        self.entries.values().map(|entry| &entry.value)
+    }
+
+    // `count` keys picked at random, repeats included; only among the keys
+    // with a TTL when `volatile`. Empty when there is no such key.
+    pub fn random_keys(&self, count: usize, volatile: bool) -> Vec<&String> {
+        let order = if volatile { &self.volatile_order } else { &self.scan_order };
+        if order.is_empty() {
+            return Vec::new();
+        }
+        (0..count)
+            .filter_map(|_| {
+                let start = (random_u64(), String::new());
+                order.range(start..).next().or_else(|| order.first()).map(|(_, key)| key)
+            })
+            .collect()
+    }
+
+    // One SCAN call: the keys of the buckets from `cursor` on, and the
+    // cursor to continue from (0 once every bucket was visited)
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<&String>) {
+        let table_size = table_size(self.len());
+        let start = (cursor_position(cursor, table_size), String::new());
+        let ordered = self.scan_order.range(start..).map(|(position, key)| (*position, key));
+        scan_step(table_size, count, ordered)
+    }
+
+    fn take(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        let position = (scan_position(key), key.to_string());
+        if entry.value.expiration_timestamp.is_some() {
+            self.volatile_order.remove(&position);
+            self.used_memory -= volatile_key_memory(key);
+        }
+        self.scan_order.remove(&position);
+        self.used_memory -= entry.memory;
+        Some(entry)
+    }
+}
+
+// A random number, drawn from the random keys std seeds its hash maps with
+pub fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// Where a key sits in SCAN order: its hash with the bits reversed. A bucket
+// of a table of 2^k buckets holds the keys sharing the k low bits of their
+// hash, which are the keys sharing the k high bits of this position, and the
+// reverse binary cursor visits those buckets in increasing position order
+// whatever k is. So resizing between calls never makes SCAN miss a key.
+pub fn scan_position(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish().reverse_bits()
+}
+
+// The size of the hash table Redis would use for `len` entries
+pub fn table_size(len: usize) -> u64 {
+    (len as u64).next_power_of_two().max(4)
+}
+
+// The first position of the bucket `cursor` points at
+pub fn cursor_position(cursor: u64, table_size: u64) -> u64 {
+    (cursor & (table_size - 1)).reverse_bits()
+}
+
+// Collects whole buckets from `ordered` (items by increasing position,
+// starting at the cursor's bucket) until at least `count` items were taken.
+// Returns the cursor of the next bucket with items, 0 when there is none.
+pub fn scan_step<T>(table_size: u64, count: usize, ordered: impl Iterator<Item = (u64, T)>) -> (u64, Vec<T>) {
+    let bucket_span = 1u128 << (64 - table_size.trailing_zeros());
+    let bucket_start = |position: u64| (position as u128 / bucket_span) * bucket_span;
+    let mut ordered = ordered.peekable();
+    let mut items = Vec::new();
+    while let Some((position, _)) = ordered.peek() {
+        let start = bucket_start(*position);
+        if items.len() >= count {
+            return ((start as u64).reverse_bits(), items);
+        }
+        let end = start + bucket_span;
+        while let Some((_, item)) = ordered.next_if(|(position, _)| (*position as u128) < end) {
+            items.push(item);
+        }
+    }
+    (0, items)
+}
@@ -0,0 +1,123 @@
+use crate::classes::State::Value;
+use crate::classes::Stream::Stream;
+
+// Approximate memory accounting. Nothing here asks the allocator: sizes are
+// estimated from the shape of the data with rough per-allocation overheads
+// of a 64-bit build, which is close enough to enforce `maxmemory`.
+
+// How many elements of a collection are looked at to estimate its size
+// when no other count is asked for, like Redis's MEMORY USAGE
+pub const DEFAULT_SAMPLES: usize = 5;
+
+// The keyspace entry of a key (value header, TTL, access data) and its
+// place in the scan order, besides the key bytes themselves
+const ENTRY_OVERHEAD: usize = 160;
+// A key with a TTL also has a node in the keyspace's volatile order
+const VOLATILE_ENTRY_OVERHEAD: usize = 48;
+const STRING_OVERHEAD: usize = 24;
+const ZSET_MEMBER_OVERHEAD: usize = 96;
+const STREAM_ENTRY_OVERHEAD: usize = 56;
+const STREAM_GROUP_OVERHEAD: usize = 128;
+const STREAM_CONSUMER_OVERHEAD: usize = 96;
+const PENDING_ENTRY_OVERHEAD: usize = 96;
+
+// What a key costs in total: the entry, the key (stored twice, in the map
+// and in the scan order) and the value
+pub fn key_memory(key: &str, value: &Value, samples: usize) -> usize {
+    ENTRY_OVERHEAD + 2 * key.len() + value_memory(value, samples)
+}
+
+// What a TTL adds to a key
+pub fn volatile_key_memory(key: &str) -> usize {
+    VOLATILE_ENTRY_OVERHEAD + key.len()
+}
+
+// The bookkeeping of a database, without the keys and values themselves:
+// the part of its entries MEMORY STATS reports as overhead
+pub fn keyspace_overhead(keys: usize, volatile_keys: usize) -> (usize, usize) {
+    (keys * ENTRY_OVERHEAD, volatile_keys * VOLATILE_ENTRY_OVERHEAD)
+}
+
+// The value alone. Collections are estimated from their first `samples`
+// elements, or from all of them with 0.
+pub fn value_memory(value: &Value, samples: usize) -> usize {
+    match value {
+        Value::String(s) => STRING_OVERHEAD + s.len(),
+        Value::List(list) => {
+            STRING_OVERHEAD + estimate(list.len(), list.iter().map(|element| STRING_OVERHEAD + element.len()), samples)
+        }
+        Value::SortedSet(set) => {
+            let members = set.iter().map(|scored| ZSET_MEMBER_OVERHEAD + 2 * scored.member.len());
+            2 * STRING_OVERHEAD + estimate(set.len(), members, samples)
+        }
+        Value::Stream(stream) => stream_memory(stream, samples),
+    }
+}
+
+fn stream_memory(stream: &Stream, samples: usize) -> usize {
+    let entries = stream.iter().map(|(_, fields)| {
+        let fields: usize = fields
+            .iter()
+            .map(|(field, value)| 2 * STRING_OVERHEAD + field.len() + value.len())
+            .sum();
+        STREAM_ENTRY_OVERHEAD + fields
+    });
+    let groups: usize = stream
+        .groups
+        .iter()
+        .map(|(name, group)| {
+            let consumers: usize = group
+                .consumers
+                .iter()
+                .map(|(name, consumer)| STREAM_CONSUMER_OVERHEAD + name.len() + 16 * consumer.pending.len())
+                .sum();
+            STREAM_GROUP_OVERHEAD + name.len() + PENDING_ENTRY_OVERHEAD * group.pending.len() + consumers
+        })
+        .sum();
+    STREAM_GROUP_OVERHEAD + estimate(stream.len(), entries, samples) + groups
+}
+
+// The size of `len` elements extrapolated from the first `samples` sizes
+fn estimate(len: usize, sizes: impl Iterator<Item = usize>, samples: usize) -> usize {
+    let samples = if samples == 0 { len } else { samples.min(len) };
+    if samples == 0 {
+        return 0;
+    }
+    let sampled: usize = sizes.take(samples).sum();
+    sampled * len / samples
+}
+
+// Parses a memory amount like Redis does in its configuration: a number of
+// bytes with an optional unit, where `k`, `m` and `g` are powers of 1000 and
+// `kb`, `mb` and `gb` powers of 1024
+pub fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_lowercase();
+    let digits_end = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(digits_end);
+    let multiplier: u64 = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+// Bytes in the `1.50M` form of INFO's `*_human` fields
+pub fn bytes_to_human(bytes: u64) -> String {
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut amount = bytes as f64;
+    for unit in ["K", "M", "G", "T"] {
+        amount /= 1024.0;
+        if amount < 1024.0 {
+            return format!("{:.2}{}", amount, unit);
+        }
+    }
+    format!("{:.2}P", amount / 1024.0)
+}
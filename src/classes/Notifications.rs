@@ -0,0 +1,75 @@
+// Keyspace event classes, mirroring the character flags Redis accepts for
+// `notify-keyspace-events`.
+pub const NOTIFY_KEYSPACE: u32 = 1 << 0; // K
+pub const NOTIFY_KEYEVENT: u32 = 1 << 1; // E
+pub const NOTIFY_GENERIC: u32 = 1 << 2; // g
+pub const NOTIFY_STRING: u32 = 1 << 3; // $
+pub const NOTIFY_LIST: u32 = 1 << 4; // l
+pub const NOTIFY_SET: u32 = 1 << 5; // s
+pub const NOTIFY_HASH: u32 = 1 << 6; // h
+pub const NOTIFY_ZSET: u32 = 1 << 7; // z
+pub const NOTIFY_EXPIRED: u32 = 1 << 8; // x
+pub const NOTIFY_EVICTED: u32 = 1 << 9; // e
+pub const NOTIFY_STREAM: u32 = 1 << 10; // t
+pub const NOTIFY_KEY_MISS: u32 = 1 << 11; // m
+pub const NOTIFY_MODULE: u32 = 1 << 12; // d
+pub const NOTIFY_NEW: u32 = 1 << 13; // n
+
+// "A" is an alias for every class except key-miss and new-key events
+pub const NOTIFY_ALL: u32 = NOTIFY_GENERIC
+    | NOTIFY_STRING
+    | NOTIFY_LIST
+    | NOTIFY_SET
+    | NOTIFY_HASH
+    | NOTIFY_ZSET
+    | NOTIFY_EXPIRED
+    | NOTIFY_EVICTED
+    | NOTIFY_STREAM
+    | NOTIFY_MODULE;
+
+const CLASS_CHARS: [(char, u32); 10] = [
+    ('g', NOTIFY_GENERIC),
+    ('$', NOTIFY_STRING),
+    ('l', NOTIFY_LIST),
+    ('s', NOTIFY_SET),
+    ('h', NOTIFY_HASH),
+    ('z', NOTIFY_ZSET),
+    ('x', NOTIFY_EXPIRED),
+    ('e', NOTIFY_EVICTED),
+    ('t', NOTIFY_STREAM),
+    ('d', NOTIFY_MODULE),
+];
+
+pub fn parse_keyspace_events(flags: &str) -> Option<u32> {
+    let mut result = 0;
+    for c in flags.chars() {
+        result |= match c {
+            'A' => NOTIFY_ALL,
+            'K' => NOTIFY_KEYSPACE,
+            'E' => NOTIFY_KEYEVENT,
+            'm' => NOTIFY_KEY_MISS,
+            'n' => NOTIFY_NEW,
+            _ => CLASS_CHARS.iter().find(|(flag, _)| *flag == c)?.1,
+        };
+    }
+    Some(result)
+}
+
+pub fn keyspace_events_to_string(flags: u32) -> String {
+    let mut result = String::new();
+    if flags & NOTIFY_ALL == NOTIFY_ALL {
+        result.push('A');
+    } else {
+        for (c, flag) in CLASS_CHARS {
+            if flags & flag != 0 {
+                result.push(c);
+            }
+        }
+    }
+    for (c, flag) in [('K', NOTIFY_KEYSPACE), ('E', NOTIFY_KEYEVENT), ('m', NOTIFY_KEY_MISS), ('n', NOTIFY_NEW)] {
+        if flags & flag != 0 {
+            result.push(c);
+        }
+    }
+    result
+}
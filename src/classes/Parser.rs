@@ -1,47 +1,98 @@
-use std::{iter::Peekable, str::Chars};
+use std::ops::Deref;
 
 pub struct Parser {}
 
 impl Parser {
-    fn read_until_next_line(&mut self, chars: &mut Peekable<Chars<'_>>) -> String {
-        let mut res = String::new();
-        while let Some(&c) = chars.peek() {
-            if c == '\r' {
-                break;
-            }
-            res.push(c);
-            chars.next();
+    fn read_until_next_line(&mut self, input: &[u8], pos: &mut usize) -> String {
+        let start = *pos;
+        while *pos < input.len() && input[*pos] != b'\r' {
+            *pos += 1;
         }
-        return res;
+        String::from_utf8_lossy(&input[start..*pos]).to_string()
     }
 
-    fn read_n_characters(&mut self, chars: &mut Peekable<Chars<'_>>, n: u32) -> String {
-        let mut res = String::new();
-        for _i in 0..n {
-            res.push(chars.next().unwrap());
-        }
-        return res;
-    }
+    // Works on bytes so bulk lengths are byte counts, as RESP defines them,
+    // and arguments keep every byte the client sent
+    pub fn parse(&mut self, input: &[u8]) -> Command {
+        let mut pos = 0;
+        let mut args = Vec::new();
 
-    pub fn parse(&mut self, input: &str) -> Vec<String> {
-        let mut chars = input.chars().peekable();
-        let mut commands = Vec::new();
-        
-        while let Some(ch) = chars.next() {
-            if ch == '*' {
-                let command_size_as_string = self.read_until_next_line(&mut chars);
+        while pos < input.len() {
+            let ch = input[pos];
+            pos += 1;
+            if ch == b'*' {
+                let command_size_as_string = self.read_until_next_line(input, &mut pos);
                 let _command_size = command_size_as_string.parse::<u32>().unwrap();
-            } else if ch == '$' {
-                let bulk_string_size_as_string = self.read_until_next_line(&mut chars);
-                let bulk_string_size = bulk_string_size_as_string.parse::<u32>().unwrap();
-                chars.next();
-                chars.next();
-                let simple_string = self.read_n_characters(&mut chars, bulk_string_size);
-                chars.next();
-                chars.next();
-                commands.push(simple_string);
+            } else if ch == b'$' {
+                let bulk_string_size_as_string = self.read_until_next_line(input, &mut pos);
+                let bulk_string_size = bulk_string_size_as_string.parse::<usize>().unwrap();
+                pos += 2;
+                let end = (pos + bulk_string_size).min(input.len());
+                args.push(input[pos..end].to_vec());
+                pos = end + 2;
             }
         }
-        return commands;
+        Command::new(args)
+    }
+}
+
+// A command as the client sent it. Handlers read names, keys and options
+// as text through `Deref`, and take values, which may be any bytes, with
+// `bytes`; an argument that isn't UTF-8 only reads lossily as text.
+#[derive(Clone, Default)]
+pub struct Command {
+    args: Vec<Vec<u8>>,
+    text: Vec<String>,
+}
+
+impl Command {
+    pub fn new(args: Vec<Vec<u8>>) -> Command {
+        let text = args.iter().map(|arg| String::from_utf8_lossy(arg).to_string()).collect();
+        Command { args, text }
+    }
+
+    // The exact bytes of the argument at `index`
+    pub fn bytes(&self, index: usize) -> &[u8] {
+        &self.args[index]
+    }
+
+    pub fn args(&self) -> &[Vec<u8>] {
+        &self.args
+    }
+}
+
+impl Deref for Command {
+    type Target = [String];
+
+    fn deref(&self) -> &[String] {
+        &self.text
+    }
+}
+
+// Commands the server builds itself, like the ones it replicates
+impl From<Vec<String>> for Command {
+    fn from(args: Vec<String>) -> Command {
+        Command::new(args.into_iter().map(String::into_bytes).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_keeps_arguments_binary() {
+        let command = Parser {}.parse(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$3\r\n\xff\x00\xfe\r\n");
+        assert_eq!(command.len(), 3);
+        assert_eq!(&command[0], "SET");
+        assert_eq!(&command[1], "k");
+        assert_eq!(command.bytes(2), b"\xff\x00\xfe");
+    }
+
+    #[test]
+    fn parse_counts_bulk_lengths_in_bytes() {
+        let command = Parser {}.parse("*2\r\n$4\r\nECHO\r\n$4\r\nh\u{e9}!\r\n".as_bytes());
+        assert_eq!(&command[1], "h\u{e9}!");
+        assert_eq!(command.bytes(1), "h\u{e9}!".as_bytes());
     }
 }
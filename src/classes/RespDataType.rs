@@ -7,9 +7,11 @@ pub enum RespDataType {
     Integer(i64),
     Array(Vec<RespDataType>),
     BulkString(String),
-    Stream(String),
+    // binary-safe bulk string, serialized with `to_bytes`
+    BulkBytes(Vec<u8>),
     Nil,
-    Boolean(bool),
+    // the null array, for replies that are arrays when there is something
+    NilArray,
     // Double(f32),
 }
 
@@ -27,9 +29,32 @@ impl fmt::Display for RespDataType {
                 }
                 Ok(())
             }
+            RespDataType::BulkBytes(b) => write!(f, "${}\r\n{}\r\n", b.len(), String::from_utf8_lossy(b)),
             RespDataType::Nil => write!(f, "$-1\r\n"),
-            // Handle other variants as needed
-            _ => Err(fmt::Error),
+            RespDataType::NilArray => write!(f, "*-1\r\n"),
+        }
+    }
+}
+
+impl RespDataType {
+    // Like `to_string` but keeps the exact bytes of binary bulk strings,
+    // which a `String` can't hold
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            RespDataType::BulkBytes(b) => {
+                let mut out = format!("${}\r\n", b.len()).into_bytes();
+                out.extend_from_slice(b);
+                out.extend_from_slice(b"\r\n");
+                out
+            }
+            RespDataType::Array(arr) => {
+                let mut out = format!("*{}\r\n", arr.len()).into_bytes();
+                for el in arr {
+                    out.extend(el.to_bytes());
+                }
+                out
+            }
+            other => other.to_string().into_bytes(),
         }
     }
 }
@@ -0,0 +1,130 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Bound;
+
+use crate::classes::Keyspace::{cursor_position, scan_position, scan_step, table_size};
+
+// Members ordered by (score, member) like a Redis zset
+#[derive(Clone)]
+pub struct ScoredMember {
+    pub score: f64,
+    pub member: String,
+}
+
+impl PartialEq for ScoredMember {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ScoredMember {}
+
+impl PartialOrd for ScoredMember {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ScoredMember {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then_with(|| self.member.cmp(&other.member))
+    }
+}
+
+// The ordered set answers range queries, the map answers score lookups
+#[derive(Clone, Default)]
+pub struct SortedSet {
+    scores: HashMap<String, f64>,
+    ordered: BTreeSet<ScoredMember>,
+}
+
+impl SortedSet {
+    pub fn new() -> SortedSet {
+        SortedSet::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    // Adds or updates a member. Returns true when the member is new.
+    pub fn insert(&mut self, member: &str, score: f64) -> bool {
+        let previous = self.scores.insert(member.to_string(), score);
+        if let Some(previous) = previous {
+            self.ordered.remove(&ScoredMember { score: previous, member: member.to_string() });
+        }
+        self.ordered.insert(ScoredMember { score, member: member.to_string() });
+        previous.is_none()
+    }
+
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.ordered.remove(&ScoredMember { score, member: member.to_string() });
+                true
+            }
+            None => false,
+        }
+    }
+
+    // 0-based position in score order
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = self.score(member)?;
+        let target = ScoredMember { score, member: member.to_string() };
+        Some(self.ordered.range(..target).count())
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &ScoredMember> {
+        self.ordered.iter()
+    }
+
+    // One ZSCAN call, with the cursor semantics of SCAN. Members carry no
+    // scan order of their own, so it is worked out on every call.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<&ScoredMember>) {
+        let table_size = table_size(self.len());
+        let start = cursor_position(cursor, table_size);
+        let mut ordered: Vec<(u64, &ScoredMember)> = self
+            .ordered
+            .iter()
+            .map(|entry| (scan_position(&entry.member), entry))
+            .filter(|(position, _)| *position >= start)
+            .collect();
+        ordered.sort_unstable_by_key(|(position, _)| *position);
+        scan_step(table_size, count, ordered.into_iter())
+    }
+
+    // Members with min <= score < max, in score order
+    pub fn range_by_score(&self, min: f64, max: f64) -> impl Iterator<Item = &ScoredMember> {
+        let start = ScoredMember { score: min, member: String::new() };
+        self.ordered
+            .range((Bound::Included(start), Bound::Unbounded))
+            .take_while(move |entry| entry.score < max)
+    }
+}
+
+// Scores accept "inf", "+inf" and "-inf" but never NaN
+pub fn parse_score(value: &str) -> Option<f64> {
+    match value.to_lowercase().as_str() {
+        "inf" | "+inf" => Some(f64::INFINITY),
+        "-inf" => Some(f64::NEG_INFINITY),
+        lower if lower.contains("inf") || lower.contains("nan") => None,
+        _ => value.parse::<f64>().ok(),
+    }
+}
+
+pub fn format_score(score: f64) -> String {
+    if score.is_infinite() {
+        return if score > 0.0 { "inf".to_string() } else { "-inf".to_string() };
+    }
+    format!("{}", score)
+}
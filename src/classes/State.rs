@@ -1,31 +1,57 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{cell::{Cell, RefCell}, collections::HashMap, future::Future, sync::Arc};
 use tokio::{
     io::AsyncWriteExt,
-    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
-    sync::Mutex,
+    sync::{Mutex, Notify},
 };
-use std::collections::VecDeque;
 use std::time::Instant;
 
-use crate::classes::ExpiringValue::ExpiringValue;
+use crate::classes::Acl::Acl;
+use crate::classes::Blocking::BlockedClients;
+use crate::classes::Connection::{SharedReader, SharedWriter};
+use crate::classes::Eviction::{EvictionPool, KeyAccess, MaxmemoryConfig};
+use crate::classes::Tls::TlsAuthClients;
+use crate::classes::Configuration::ProtectedAction;
+use crate::classes::ExpiringValue::{unix_millis_now, ExpiringValue};
+use crate::classes::Notifications::{
+    NOTIFY_EVICTED, NOTIFY_EXPIRED, NOTIFY_GENERIC, NOTIFY_KEYEVENT, NOTIFY_KEYSPACE, NOTIFY_LIST, NOTIFY_NEW,
+};
+use crate::classes::Glob::glob_match;
+use crate::classes::Keyspace::Keyspace;
+use crate::classes::RespDataType::RespDataType;
+use crate::classes::SortedSet::SortedSet;
+use crate::classes::Stream::Stream;
+use crate::classes::string_commands::parse_strict_i64;
 
 #[derive(Clone)]
 pub struct Replica {
-    pub reader: Arc<Mutex<OwnedReadHalf>>,
-    pub writer: Arc<Mutex<OwnedWriteHalf>>,
+    pub reader: SharedReader,
+    pub writer: SharedWriter,
     pub last_ack: u64,
 }
 
+// Up to how many bytes a list stays a listpack (`list-max-listpack-size -2`),
+// and up to how many members of which size a sorted set does
+// (`zset-max-listpack-entries` and `zset-max-listpack-value`)
+const LIST_MAX_LISTPACK_BYTES: usize = 8192;
+const ZSET_MAX_LISTPACK_ENTRIES: usize = 128;
+const ZSET_MAX_LISTPACK_VALUE: usize = 64;
+
+// Strings up to this long are allocated together with their object header
+const EMBSTR_SIZE_LIMIT: usize = 44;
+
+pub const WRONGTYPE_ERROR: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
 // Unified value representation for all Redis data types
 #[derive(Clone)]
 pub enum Value {
-    String(String),
-    Stream(Vec<Vec<(String, String)>>),
+    // strings are binary safe, bitmaps and HyperLogLogs live in them too
+    String(Vec<u8>),
+    Stream(Stream),
     List(Vec<String>),
+    SortedSet(SortedSet),
     // Future data types can be added here:
     // Set(HashSet<String>),
     // Hash(HashMap<String, String>),
-    // SortedSet(Vec<(String, f64)>),
 }
 
 impl Value {
@@ -34,62 +60,138 @@ impl Value {
             Value::String(_) => "string",
             Value::Stream(_) => "stream",
             Value::List(_) => "list",
+            Value::SortedSet(_) => "zset",
         }
     }
 
-    pub fn as_string(&self) -> Option<&String> {
+    // The internal representation Redis would pick for the value, as
+    // reported by OBJECT ENCODING
+    pub fn encoding(&self) -> &'static str {
         match self {
-            Value::String(s) => Some(s),
-            _ => None,
+            Value::String(s) if s.len() <= 20 && parse_strict_i64(s).is_some() => "int",
+            Value::String(s) if s.len() <= EMBSTR_SIZE_LIMIT => "embstr",
+            Value::String(_) => "raw",
+            Value::List(list) => {
+                // every listpack entry takes about two bytes besides its data
+                let bytes: usize = list.iter().map(|element| element.len() + 2).sum();
+                if bytes <= LIST_MAX_LISTPACK_BYTES { "listpack" } else { "quicklist" }
+            }
+            Value::SortedSet(set) => {
+                let small = set.len() <= ZSET_MAX_LISTPACK_ENTRIES
+                    && set.iter().all(|scored| scored.member.len() <= ZSET_MAX_LISTPACK_VALUE);
+                if small { "listpack" } else { "skiplist" }
+            }
+            Value::Stream(_) => "stream",
         }
     }
 
-    pub fn as_stream(&self) -> Option<&Vec<Vec<(String, String)>>> {
+    // The length of the value in the unit of its type: bytes of a string,
+    // elements of a list, members of a sorted set, entries of a stream
+    pub fn size(&self) -> usize {
         match self {
-            Value::Stream(s) => Some(s),
-            _ => None,
+            Value::String(s) => s.len(),
+            Value::List(list) => list.len(),
+            Value::SortedSet(set) => set.len(),
+            Value::Stream(stream) => stream.len(),
         }
     }
 
-    pub fn as_list(&self) -> Option<&Vec<String>> {
+    pub fn as_string(&self) -> Option<&Vec<u8>> {
         match self {
-            Value::List(l) => Some(l),
+            Value::String(s) => Some(s),
             _ => None,
         }
     }
+}
 
-    pub fn as_list_mut(&mut self) -> Option<&mut Vec<String>> {
-        match self {
-            Value::List(l) => Some(l),
-            _ => None,
-        }
-    }
+// Unified data storage with proper typing: one keyspace per database
+pub struct DataStorage {
+    pub databases: Vec<Keyspace>,
+    pub eviction_pool: EvictionPool,
+}
 
-    pub fn as_stream_mut(&mut self) -> Option<&mut Vec<Vec<(String, String)>>> {
-        match self {
-            Value::Stream(s) => Some(s),
-            _ => None,
-        }
+impl DataStorage {
+    // The keyspace of the database selected by the current connection
+    pub fn selected(&self) -> &Keyspace {
+        &self.databases[selected_db()]
     }
-}
 
-// Unified data storage with proper typing
-pub struct DataStorage {
-    pub data: HashMap<String, ExpiringValue<Value>>,
+    pub fn selected_mut(&mut self) -> &mut Keyspace {
+        &mut self.databases[selected_db()]
+    }
 }
 
 impl Clone for DataStorage {
     fn clone(&self) -> DataStorage {
         DataStorage {
-            data: self.data.clone(),
+            databases: self.databases.clone(),
+            eviction_pool: self.eviction_pool.clone(),
         }
     }
 }
 
+pub const DEFAULT_DATABASES: usize = 16;
+
+tokio::task_local! {
+    // The database selected by the connection the current task serves
+    static SELECTED_DB: Cell<usize>;
+    // Whether that connection passed AUTH, or needed none when it connected
+    static AUTHENTICATED: Cell<bool>;
+    // The ACL user it runs commands as, None for the link to the master
+    static CONNECTION_USER: RefCell<Option<String>>;
+    // Whether it comes from the loopback interface or a Unix socket
+    static LOCAL_CONNECTION: bool;
+}
+
+// Runs a connection's command loop, starting on database 0
+pub async fn with_connection<F: Future>(user: Option<String>, authenticated: bool, local: bool, future: F) -> F::Output {
+    let future = AUTHENTICATED.scope(Cell::new(authenticated), SELECTED_DB.scope(Cell::new(0), future));
+    let future = LOCAL_CONNECTION.scope(local, future);
+    CONNECTION_USER.scope(RefCell::new(user), future).await
+}
+
+// Commands only ever run inside with_connection, so a missing scope is a bug
+pub fn is_authenticated() -> bool {
+    AUTHENTICATED.with(Cell::get)
+}
+
+pub fn is_local_connection() -> bool {
+    LOCAL_CONNECTION.with(|local| *local)
+}
+
+pub fn connection_user() -> Option<String> {
+    CONNECTION_USER.try_with(|user| user.borrow().clone()).ok().flatten()
+}
+
+pub fn set_authenticated(username: &str) {
+    let _ = AUTHENTICATED.try_with(|authenticated| authenticated.set(true));
+    let _ = CONNECTION_USER.try_with(|user| *user.borrow_mut() = Some(username.to_string()));
+}
+
+// RESET logs the connection back in as the default user, authenticated
+// only when that user needs no password
+pub fn reset_authentication(authenticated: bool) {
+    AUTHENTICATED.with(|current| current.set(authenticated));
+    CONNECTION_USER.with(|user| *user.borrow_mut() = Some("default".to_string()));
+}
+
+// The database of the current connection. Work done outside of one, like
+// loading the RDB file at startup, sees database 0.
+pub fn selected_db() -> usize {
+    SELECTED_DB.try_with(Cell::get).unwrap_or(0)
+}
+
+pub fn select_db(db: usize) {
+    let _ = SELECTED_DB.try_with(|selected| selected.set(db));
+}
+
 // Separate replication state with its own lock
 pub struct ReplicationState {
     pub replicas: Vec<Replica>,
     pub offset: usize,
+    // the database the replication stream works on, None when the replicas
+    // may disagree about it
+    pub selected_db: Option<usize>,
 }
 
 impl Clone for ReplicationState {
@@ -97,17 +199,83 @@ impl Clone for ReplicationState {
         ReplicationState {
             replicas: self.replicas.clone(),
             offset: self.offset,
+            selected_db: self.selected_db,
         }
     }
 }
 
-// Separate configuration with its own lock
+// Separate configuration with its own lock. The parameters are read and
+// written through `Configuration::PARAMETERS`.
 pub struct Config {
     pub db_file_name: Option<String>,
     pub db_dir: Option<String>,
     pub role: String,
     pub master_host: Option<String>,
     pub master_port: Option<String>,
+    // what a replica authenticates to its master with
+    pub masteruser: Option<String>,
+    pub masterauth: Option<String>,
+    pub notify_keyspace_events: u32,
+    pub maxmemory: MaxmemoryConfig,
+    pub port: u16,
+    // `*` is every IPv4 interface, `::*` every IPv6 one, and a `-` prefix
+    // makes an address optional
+    pub bind: Vec<String>,
+    pub protected_mode: bool,
+    // who may CONFIG SET the protected parameters
+    pub enable_protected_configs: ProtectedAction,
+    // the password of the default user, None when it needs none
+    pub requirepass: Option<String>,
+    // where ACL LOAD and SAVE keep the users
+    pub aclfile: Option<String>,
+    pub acllog_max_len: usize,
+    pub unixsocket: Option<String>,
+    // octal file mode of the socket, 0 to leave it to the umask
+    pub unixsocketperm: u32,
+    // 0 when not listening for TLS clients
+    pub tls_port: u16,
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
+    pub tls_ca_cert_file: Option<String>,
+    pub tls_auth_clients: TlsAuthClients,
+    // whether a replica reaches its master over TLS
+    pub tls_replication: bool,
+    pub databases: usize,
+    // the file the configuration was loaded from, for CONFIG REWRITE
+    pub config_file: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            db_file_name: None,
+            db_dir: None,
+            role: "master".to_string(),
+            master_host: None,
+            master_port: None,
+            masteruser: None,
+            masterauth: None,
+            notify_keyspace_events: 0,
+            maxmemory: MaxmemoryConfig::default(),
+            port: 6379,
+            bind: vec!["*".to_string(), "-::*".to_string()],
+            protected_mode: true,
+            enable_protected_configs: ProtectedAction::No,
+            requirepass: None,
+            aclfile: None,
+            acllog_max_len: 128,
+            unixsocket: None,
+            unixsocketperm: 0,
+            tls_port: 0,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_ca_cert_file: None,
+            tls_auth_clients: TlsAuthClients::Yes,
+            tls_replication: false,
+            databases: DEFAULT_DATABASES,
+            config_file: None,
+        }
+    }
 }
 
 impl Clone for Config {
@@ -118,14 +286,77 @@ impl Clone for Config {
             role: self.role.clone(),
             master_host: self.master_host.clone(),
             master_port: self.master_port.clone(),
+            masteruser: self.masteruser.clone(),
+            masterauth: self.masterauth.clone(),
+            notify_keyspace_events: self.notify_keyspace_events,
+            maxmemory: self.maxmemory,
+            port: self.port,
+            bind: self.bind.clone(),
+            protected_mode: self.protected_mode,
+            enable_protected_configs: self.enable_protected_configs,
+            requirepass: self.requirepass.clone(),
+            aclfile: self.aclfile.clone(),
+            acllog_max_len: self.acllog_max_len,
+            unixsocket: self.unixsocket.clone(),
+            unixsocketperm: self.unixsocketperm,
+            tls_port: self.tls_port,
+            tls_cert_file: self.tls_cert_file.clone(),
+            tls_key_file: self.tls_key_file.clone(),
+            tls_ca_cert_file: self.tls_ca_cert_file.clone(),
+            tls_auth_clients: self.tls_auth_clients,
+            tls_replication: self.tls_replication,
+            databases: self.databases,
+            config_file: self.config_file.clone(),
         }
     }
 }
 
-pub struct BlockedBlpopClient {
-    pub writer: Arc<Mutex<OwnedWriteHalf>>,
-    pub start_time: Instant,
-    pub timeout: f64, // in seconds
+#[derive(Clone, Copy, PartialEq)]
+pub enum SubscriptionKind {
+    Channel,
+    Pattern,
+    ShardChannel,
+}
+
+// RDB snapshots: when the last one succeeded (unix seconds) and whether a
+// BGSAVE is writing one right now
+pub struct PersistenceState {
+    pub last_save: u64,
+    pub bgsave_in_progress: bool,
+}
+
+// Counters reported by INFO stats and MEMORY STATS
+#[derive(Default)]
+pub struct ServerStats {
+    pub evicted_keys: u64,
+    // the highest `used_memory` seen before a command
+    pub peak_memory: u64,
+}
+
+// Pub/Sub subscriptions, each map going from a channel (or pattern) to the
+// writers of the connections subscribed to it
+pub struct PubSubState {
+    pub channels: HashMap<String, Vec<SharedWriter>>,
+    pub patterns: HashMap<String, Vec<SharedWriter>>,
+    pub shard_channels: HashMap<String, Vec<SharedWriter>>,
+}
+
+impl PubSubState {
+    fn subscriptions(&self, kind: SubscriptionKind) -> &HashMap<String, Vec<SharedWriter>> {
+        match kind {
+            SubscriptionKind::Channel => &self.channels,
+            SubscriptionKind::Pattern => &self.patterns,
+            SubscriptionKind::ShardChannel => &self.shard_channels,
+        }
+    }
+
+    fn subscriptions_mut(&mut self, kind: SubscriptionKind) -> &mut HashMap<String, Vec<SharedWriter>> {
+        match kind {
+            SubscriptionKind::Channel => &mut self.channels,
+            SubscriptionKind::Pattern => &mut self.patterns,
+            SubscriptionKind::ShardChannel => &mut self.shard_channels,
+        }
+    }
 }
 
 // Main state structure that holds separate locked components
@@ -133,7 +364,11 @@ pub struct State {
     pub data: Arc<Mutex<DataStorage>>,
     pub replication: Arc<Mutex<ReplicationState>>,
     pub config: Arc<Mutex<Config>>,
-    pub blocked_blpop_clients: Arc<Mutex<std::collections::HashMap<String, VecDeque<BlockedBlpopClient>>>>,
+    pub blocked_clients: Arc<Mutex<BlockedClients>>,
+    pub pubsub: Arc<Mutex<PubSubState>>,
+    pub persistence: Arc<Mutex<PersistenceState>>,
+    pub stats: Arc<Mutex<ServerStats>>,
+    pub acl: Arc<Mutex<Acl>>,
 }
 
 impl Clone for State {
@@ -142,7 +377,11 @@ impl Clone for State {
             data: self.data.clone(),
             replication: self.replication.clone(),
             config: self.config.clone(),
-            blocked_blpop_clients: self.blocked_blpop_clients.clone(),
+            blocked_clients: self.blocked_clients.clone(),
+            pubsub: self.pubsub.clone(),
+            persistence: self.persistence.clone(),
+            stats: self.stats.clone(),
+            acl: self.acl.clone(),
         }
     }
 }
@@ -150,16 +389,24 @@ impl Clone for State {
 impl State {
     pub fn new() -> State {
         State {
-            data: Arc::new(Mutex::new(DataStorage { data: std::collections::HashMap::new() })),
-            replication: Arc::new(Mutex::new(ReplicationState { replicas: vec![], offset: 0 })),
-            config: Arc::new(Mutex::new(Config {
-                db_file_name: None,
-                db_dir: None,
-                role: "master".to_string(),
-                master_host: None,
-                master_port: None,
+            data: Arc::new(Mutex::new(DataStorage {
+                databases: vec![Keyspace::new(); DEFAULT_DATABASES],
+                eviction_pool: EvictionPool::default(),
             })),
-            blocked_blpop_clients: Arc::new(Mutex::new(std::collections::HashMap::new())),
+            replication: Arc::new(Mutex::new(ReplicationState { replicas: vec![], offset: 0, selected_db: Some(0) })),
+            config: Arc::new(Mutex::new(Config::default())),
+            blocked_clients: Arc::new(Mutex::new(BlockedClients::default())),
+            pubsub: Arc::new(Mutex::new(PubSubState {
+                channels: HashMap::new(),
+                patterns: HashMap::new(),
+                shard_channels: HashMap::new(),
+            })),
+            persistence: Arc::new(Mutex::new(PersistenceState {
+                last_save: unix_millis_now() / 1000,
+                bgsave_in_progress: false,
+            })),
+            stats: Arc::new(Mutex::new(ServerStats::default())),
+            acl: Arc::new(Mutex::new(Acl::new())),
         }
     }
 
@@ -185,10 +432,22 @@ impl State {
     }
 
     pub async fn add_replica(&self, replica: Replica) {
-        self.replication.lock().await.replicas.push(replica);
+        let mut replication = self.replication.lock().await;
+        replication.replicas.push(replica);
+        // a new link starts on database 0, so unless the stream is there too
+        // the next propagated command selects its database again
+        if replication.selected_db != Some(0) {
+            replication.selected_db = None;
+        }
     }
 
-    pub async fn update_replica_ack(&self, reader: Arc<Mutex<OwnedReadHalf>>, offset: u64) {
+    // Records that the replication stream now works on database `db`.
+    // Returns false when it already did.
+    pub async fn select_replication_db(&self, db: usize) -> bool {
+        self.replication.lock().await.selected_db.replace(db) != Some(db)
+    }
+
+    pub async fn update_replica_ack(&self, reader: SharedReader, offset: u64) {
         let mut guard = self.replication.lock().await;
         for replica in &mut guard.replicas {
             if Arc::ptr_eq(&replica.reader, &reader) {
@@ -198,28 +457,162 @@ impl State {
         }
     }
 
+    // Publishes a keyspace notification for `key` if the event class is
+    // enabled through `notify-keyspace-events`
+    pub async fn notify_keyspace_event(&self, class: u32, event: &str, key: &str) {
+        self.notify_keyspace_event_in_db(selected_db(), class, event, key).await;
+    }
+
+    // Like `notify_keyspace_event`, for a key of database `db`
+    pub async fn notify_keyspace_event_in_db(&self, db: usize, class: u32, event: &str, key: &str) {
+        let flags = self.config.lock().await.notify_keyspace_events;
+        if flags & class == 0 {
+            return;
+        }
+        if flags & NOTIFY_KEYSPACE != 0 {
+            let channel = format!("__keyspace@{}__:{}", db, key);
+            self.publish(SubscriptionKind::Channel, &channel, event.as_bytes()).await;
+        }
+        if flags & NOTIFY_KEYEVENT != 0 {
+            let channel = format!("__keyevent@{}__:{}", db, event);
+            self.publish(SubscriptionKind::Channel, &channel, key.as_bytes()).await;
+        }
+    }
+
+    // Lazily removes `key` once its TTL has passed, so every read and write
+    // path sees expired keys as missing. Since every path goes through here,
+    // this is also where live keys get their accesses recorded.
+    async fn expire_if_needed(&self, key: &str) {
+        self.expire_key_if_needed(key, true).await;
+    }
+
+    async fn expire_key_if_needed(&self, key: &str, touch: bool) {
+        let maxmemory = self.config.lock().await.maxmemory;
+        let expired = {
+            let mut data = self.data.lock().await;
+            let keyspace = data.selected_mut();
+            match keyspace.get(key) {
+                Some(expiring_value) if expiring_value.is_expired() => {
+                    keyspace.remove(key);
+                    true
+                }
+                Some(_) if touch => {
+                    keyspace.touch(key, &maxmemory);
+                    false
+                }
+                _ => false,
+            }
+        };
+        if expired {
+            self.notify_keyspace_event(NOTIFY_EXPIRED, "expired", key).await;
+        }
+    }
+
+    // Removes every key whose TTL has passed, in every database; run
+    // periodically so keys that are never touched again still expire
+    pub async fn active_expire_cycle(&self) {
+        let expired_keys: Vec<(usize, String)> = {
+            let mut data = self.data.lock().await;
+            let mut expired_keys = Vec::new();
+            for (db, keyspace) in data.databases.iter_mut().enumerate() {
+                keyspace.retain(|key, expiring_value| {
+                    if expiring_value.is_expired() {
+                        expired_keys.push((db, key.clone()));
+                    }
+                    !expiring_value.is_expired()
+                });
+            }
+            expired_keys
+        };
+        for (db, key) in expired_keys {
+            self.notify_keyspace_event_in_db(db, NOTIFY_EXPIRED, "expired", &key).await;
+        }
+    }
+
     // Unified data access methods
     pub async fn get_value(&self, key: &str) -> Option<ExpiringValue<Value>> {
-        self.data.lock().await.data.get(key).cloned()
+        self.expire_if_needed(key).await;
+        self.data.lock().await.selected().get(key).cloned()
     }
 
     pub async fn set_value(&self, key: String, value: ExpiringValue<Value>) {
-        self.data.lock().await.data.insert(key, value);
+        self.expire_if_needed(&key).await;
+        let is_new = self.data.lock().await.selected_mut().insert(key.clone(), value).is_none();
+        if is_new {
+            self.notify_keyspace_event(NOTIFY_NEW, "new", &key).await;
+        }
+        self.signal_key_as_ready(&key).await;
     }
 
-    pub async fn get_string(&self, key: &str) -> Option<ExpiringValue<String>> {
-        if let Some(expiring_value) = self.get_value(key).await {
-            if let Value::String(s) = expiring_value.value {
-                return Some(ExpiringValue {
-                    value: s,
-                    expiration_timestamp: expiring_value.expiration_timestamp,
-                });
+    // Runs `f` on the value of `key` and what is known about its accesses.
+    // Unlike every other lookup, this doesn't count as an access, so OBJECT
+    // and MEMORY can report on a key without changing what it reports.
+    pub async fn inspect_key<R>(&self, key: &str, f: impl FnOnce(&Value, KeyAccess) -> R) -> Option<R> {
+        self.expire_key_if_needed(key, false).await;
+        let data = self.data.lock().await;
+        let keyspace = data.selected();
+        Some(f(&keyspace.get(key)?.value, keyspace.access(key)?))
+    }
+
+    pub async fn exists(&self, key: &str) -> bool {
+        self.get_value(key).await.is_some()
+    }
+
+    pub async fn del(&self, key: &str) -> bool {
+        self.expire_if_needed(key).await;
+        let removed = self.data.lock().await.selected_mut().remove(key).is_some();
+        if removed {
+            self.notify_keyspace_event(NOTIFY_GENERIC, "del", key).await;
+        }
+        removed
+    }
+
+    // Sets (or with `None`, clears) the TTL of an existing key. Returns false
+    // when the key doesn't exist.
+    pub async fn set_expiration(&self, key: &str, expiration: Option<Instant>) -> bool {
+        self.expire_if_needed(key).await;
+        let updated = self.data.lock().await.selected_mut().set_expiration(key, expiration);
+        if updated {
+            let event = if expiration.is_some() { "expire" } else { "persist" };
+            self.notify_keyspace_event(NOTIFY_GENERIC, event, key).await;
+        }
+        updated
+    }
+
+    // Moves the value (and TTL) of `key` to `new_key`, replacing whatever
+    // `new_key` held. Returns false when `key` doesn't exist.
+    pub async fn rename(&self, key: &str, new_key: &str) -> bool {
+        self.expire_if_needed(key).await;
+        self.expire_if_needed(new_key).await;
+        {
+            let mut data = self.data.lock().await;
+            let keyspace = data.selected_mut();
+            match keyspace.remove(key) {
+                Some(expiring_value) => {
+                    keyspace.insert(new_key.to_string(), expiring_value);
+                }
+                None => return false,
+            }
+        }
+        self.notify_keyspace_event(NOTIFY_GENERIC, "rename_from", key).await;
+        self.notify_keyspace_event(NOTIFY_GENERIC, "rename_to", new_key).await;
+        self.signal_key_as_ready(new_key).await;
+        true
+    }
+
+    // Ok(None) for a missing key, Err with the WRONGTYPE error when the key
+    // holds another type
+    pub async fn get_string(&self, key: &str) -> Result<Option<ExpiringValue<Vec<u8>>>, String> {
+        match self.get_value(key).await {
+            None => Ok(None),
+            Some(ExpiringValue { value: Value::String(s), expiration_timestamp }) => {
+                Ok(Some(ExpiringValue { value: s, expiration_timestamp }))
             }
+            Some(_) => Err(WRONGTYPE_ERROR.to_string()),
         }
-        None
     }
 
-    pub async fn set_string(&self, key: String, value: String, expiration: Option<std::time::Instant>) {
+    pub async fn set_string(&self, key: String, value: Vec<u8>, expiration: Option<std::time::Instant>) {
         let expiring_value = ExpiringValue {
             value: Value::String(value),
             expiration_timestamp: expiration,
@@ -227,16 +620,33 @@ impl State {
         self.set_value(key, expiring_value).await;
     }
 
-    pub async fn get_stream(&self, key: &str) -> Option<Vec<Vec<(String, String)>>> {
-        if let Some(expiring_value) = self.get_value(key).await {
-            if let Value::Stream(s) = expiring_value.value {
-                return Some(s);
-            }
+    // Runs `f` on the stream at `key` without copying it out. Ok(None) for a
+    // missing key, Err with the WRONGTYPE error when the key holds another type.
+    pub async fn with_stream<R>(&self, key: &str, f: impl FnOnce(&Stream) -> R) -> Result<Option<R>, String> {
+        self.expire_if_needed(key).await;
+        match self.data.lock().await.selected().get(key) {
+            None => Ok(None),
+            Some(ExpiringValue { value: Value::Stream(s), .. }) => Ok(Some(f(s))),
+            Some(_) => Err(WRONGTYPE_ERROR.to_string()),
         }
-        None
     }
 
-    pub async fn set_stream(&self, key: String, value: Vec<Vec<(String, String)>>) {
+    // Like `with_stream`, but `f` may change the stream in place. Callers
+    // that add entries wake the blocked readers of the key themselves.
+    pub async fn with_stream_mut<R>(&self, key: &str, f: impl FnOnce(&mut Stream) -> R) -> Result<Option<R>, String> {
+        self.expire_if_needed(key).await;
+        let mut data = self.data.lock().await;
+        let keyspace = data.selected_mut();
+        let result = match keyspace.get_mut(key) {
+            None => Ok(None),
+            Some(ExpiringValue { value: Value::Stream(s), .. }) => Ok(Some(f(s))),
+            Some(_) => Err(WRONGTYPE_ERROR.to_string()),
+        };
+        keyspace.refresh_memory(key);
+        result
+    }
+
+    pub async fn set_stream(&self, key: String, value: Stream) {
         let expiring_value = ExpiringValue {
             value: Value::Stream(value),
             expiration_timestamp: None, // Streams don't expire
@@ -244,46 +654,116 @@ impl State {
         self.set_value(key, expiring_value).await;
     }
 
-    pub async fn get_list(&self, key: &str) -> Option<Vec<String>> {
-        if let Some(expiring_value) = self.get_value(key).await {
-            if let Value::List(l) = expiring_value.value {
-                return Some(l);
+    // Ok(None) for a missing key, Err with the WRONGTYPE error when the key
+    // holds another type
+    pub async fn get_list(&self, key: &str) -> Result<Option<ExpiringValue<Vec<String>>>, String> {
+        match self.get_value(key).await {
+            None => Ok(None),
+            Some(ExpiringValue { value: Value::List(l), expiration_timestamp }) => {
+                Ok(Some(ExpiringValue { value: l, expiration_timestamp }))
             }
+            Some(_) => Err(WRONGTYPE_ERROR.to_string()),
         }
-        None
     }
 
-    pub async fn set_list(&self, key: String, value: Vec<String>) {
+    // Runs `f` on the list at `key` without copying it out. Ok(None) for a
+    // missing key, Err with the WRONGTYPE error when the key holds another type.
+    pub async fn with_list<R>(&self, key: &str, f: impl FnOnce(&Vec<String>) -> R) -> Result<Option<R>, String> {
+        self.expire_if_needed(key).await;
+        match self.data.lock().await.selected().get(key) {
+            None => Ok(None),
+            Some(ExpiringValue { value: Value::List(l), .. }) => Ok(Some(f(l))),
+            Some(_) => Err(WRONGTYPE_ERROR.to_string()),
+        }
+    }
+
+    // Like `with_list`, but `f` may change the list in place. Callers remove
+    // the key when they empty the list, and wake its blocked clients when
+    // they push.
+    pub async fn with_list_mut<R>(&self, key: &str, f: impl FnOnce(&mut Vec<String>) -> R) -> Result<Option<R>, String> {
+        self.expire_if_needed(key).await;
+        let mut data = self.data.lock().await;
+        let keyspace = data.selected_mut();
+        let result = match keyspace.get_mut(key) {
+            None => Ok(None),
+            Some(ExpiringValue { value: Value::List(l), .. }) => Ok(Some(f(l))),
+            Some(_) => Err(WRONGTYPE_ERROR.to_string()),
+        };
+        keyspace.refresh_memory(key);
+        result
+    }
+
+    // Ok(None) for a missing key, Err with the WRONGTYPE error when the key
+    // holds another type
+    pub async fn get_sorted_set(&self, key: &str) -> Result<Option<ExpiringValue<SortedSet>>, String> {
+        match self.get_value(key).await {
+            None => Ok(None),
+            Some(ExpiringValue { value: Value::SortedSet(z), expiration_timestamp }) => {
+                Ok(Some(ExpiringValue { value: z, expiration_timestamp }))
+            }
+            Some(_) => Err(WRONGTYPE_ERROR.to_string()),
+        }
+    }
+
+    // Stores the set, or removes the key once the set is empty (emitting "del")
+    pub async fn set_sorted_set(&self, key: String, value: SortedSet, expiration: Option<Instant>) {
+        if value.is_empty() {
+            self.del(&key).await;
+            return;
+        }
+        let expiring_value = ExpiringValue {
+            value: Value::SortedSet(value),
+            expiration_timestamp: expiration,
+        };
+        self.set_value(key, expiring_value).await;
+    }
+
+    // Stores the list, or removes the key once the list is empty (emitting "del")
+    pub async fn set_list(&self, key: String, value: Vec<String>, expiration: Option<Instant>) {
+        if value.is_empty() {
+            self.del(&key).await;
+            return;
+        }
         let expiring_value = ExpiringValue {
             value: Value::List(value),
-            expiration_timestamp: None, // Lists don't expire for now
+            expiration_timestamp: expiration,
         };
         self.set_value(key, expiring_value).await;
     }
 
     
 
-    pub async fn rpush(&self, key: String, elements: Vec<String>) -> usize {
+    pub async fn rpush(&self, key: String, elements: Vec<String>) -> Result<usize, String> {
+        self.expire_if_needed(&key).await;
         let mut data = self.data.lock().await;
         
-        if let Some(expiring_value) = data.data.get_mut(&key) {
+        if let Some(expiring_value) = data.selected_mut().get_mut(&key) {
             if let Value::List(list) = &mut expiring_value.value {
                 list.extend(elements);
-                return list.len();
+                let len = list.len();
+                data.selected_mut().refresh_memory(&key);
+                drop(data);
+                self.notify_keyspace_event(NOTIFY_LIST, "rpush", &key).await;
+                self.signal_key_as_ready(&key).await;
+                return Ok(len);
             }
+            return Err(WRONGTYPE_ERROR.to_string());
         }
         
-        // Key doesn't exist or is not a list, create new list
-        data.data.insert(key, ExpiringValue {
-            value: Value::List(elements.clone()),
+        // Key doesn't exist, create new list
+        let len = elements.len();
+        drop(data);
+        self.set_value(key.clone(), ExpiringValue {
+            value: Value::List(elements),
             expiration_timestamp: None,
-        });
+        }).await;
+        self.notify_keyspace_event(NOTIFY_LIST, "rpush", &key).await;
         
-        elements.len()
+        Ok(len)
     }
 
-    pub async fn lrange(&self, key: &str, start: i64, stop: i64) -> Vec<String> {
-        if let Some(list) = self.get_list(key).await {
+    pub async fn lrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<String>, String> {
+        if let Some(ExpiringValue { value: list, .. }) = self.get_list(key).await? {
             let len = list.len() as i64;
             
             // Convert negative indexes to positive
@@ -303,90 +783,311 @@ impl State {
             
             // Handle edge cases
             if start_idx >= list.len() || start_idx > stop_idx {
-                return Vec::new();
+                return Ok(Vec::new());
             }
             
             // Extract the range (inclusive)
-            list[start_idx..=stop_idx].to_vec()
+            Ok(list[start_idx..=stop_idx].to_vec())
         } else {
             // List doesn't exist, return empty array
-            Vec::new()
+            Ok(Vec::new())
         }
     }
 
-    pub async fn lpush(&self, key: String, elements: Vec<String>) -> usize {
+    pub async fn lpush(&self, key: String, elements: Vec<String>) -> Result<usize, String> {
+        self.expire_if_needed(&key).await;
         let mut data = self.data.lock().await;
         
-        if let Some(expiring_value) = data.data.get_mut(&key) {
+        if let Some(expiring_value) = data.selected_mut().get_mut(&key) {
             if let Value::List(list) = &mut expiring_value.value {
                 // Prepend elements in the order they appear in the command
                 for element in elements.iter() {
                     list.insert(0, element.clone());
                 }
-                return list.len();
+                let len = list.len();
+                data.selected_mut().refresh_memory(&key);
+                drop(data);
+                self.notify_keyspace_event(NOTIFY_LIST, "lpush", &key).await;
+                self.signal_key_as_ready(&key).await;
+                return Ok(len);
             }
+            return Err(WRONGTYPE_ERROR.to_string());
         }
         
-        // Key doesn't exist or is not a list, create new list
+        // Key doesn't exist, create new list
         // For LPUSH, we insert elements in the order they appear in the command
         let mut new_list = Vec::new();
         for element in elements.iter() {
             new_list.insert(0, element.clone());
         }
         
-        data.data.insert(key, ExpiringValue {
-            value: Value::List(new_list.clone()),
+        let len = new_list.len();
+        drop(data);
+        self.set_value(key.clone(), ExpiringValue {
+            value: Value::List(new_list),
             expiration_timestamp: None,
-        });
+        }).await;
+        self.notify_keyspace_event(NOTIFY_LIST, "lpush", &key).await;
         
-        new_list.len()
+        Ok(len)
     }
 
-    pub async fn llen(&self, key: &str) -> usize {
-        if let Some(list) = self.get_list(key).await {
-            list.len()
-        } else {
-            0
+    pub async fn llen(&self, key: &str) -> Result<usize, String> {
+        Ok(self.get_list(key).await?.map_or(0, |list| list.value.len()))
+    }
+
+    pub async fn get_type(&self, key: &str) -> &'static str {
+        if let Some(expiring_value) = self.get_value(key).await {
+            return expiring_value.value.get_type();
         }
+        "none"
     }
 
-    pub async fn lpop(&self, key: &str) -> Option<String> {
-        let mut data = self.data.lock().await;
-        
-        if let Some(expiring_value) = data.data.get_mut(key) {
-            if let Value::List(list) = &mut expiring_value.value {
-                if !list.is_empty() {
-                    return Some(list.remove(0));
-                }
+    pub async fn get_all_keys(&self) -> Vec<String> {
+        self.data
+            .lock()
+            .await
+            .selected()
+            .iter()
+            .filter(|(_, expiring_value)| !expiring_value.is_expired())
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    // One SCAN call on the selected database: the live keys of the buckets
+    // from `cursor` on, with their types, and the cursor to continue from
+    pub async fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(String, &'static str)>) {
+        let data = self.data.lock().await;
+        let keyspace = data.selected();
+        let (next_cursor, keys) = keyspace.scan(cursor, count);
+        let keys = keys
+            .into_iter()
+            .filter_map(|key| {
+                let expiring_value = keyspace.get(key).filter(|expiring_value| !expiring_value.is_expired())?;
+                Some((key.clone(), expiring_value.value.get_type()))
+            })
+            .collect();
+        (next_cursor, keys)
+    }
+
+    // (key, type, size) of every live key of the selected database, see
+    // `Value::size`
+    pub async fn key_sizes(&self) -> Vec<(String, &'static str, usize)> {
+        self.data
+            .lock()
+            .await
+            .selected()
+            .iter()
+            .filter(|(_, expiring_value)| !expiring_value.is_expired())
+            .map(|(key, expiring_value)| (key.clone(), expiring_value.value.get_type(), expiring_value.value.size()))
+            .collect()
+    }
+
+    // A copy of every live key of every database with its access data, for
+    // writing an RDB file without holding the keyspace lock
+    pub async fn snapshot(&self) -> Vec<HashMap<String, (ExpiringValue<Value>, KeyAccess)>> {
+        self.data
+            .lock()
+            .await
+            .databases
+            .iter()
+            .map(|keyspace| {
+                keyspace
+                    .iter()
+                    .filter(|(_, expiring_value)| !expiring_value.is_expired())
+                    .map(|(key, expiring_value)| {
+                        let access = keyspace.access(key).unwrap_or_else(KeyAccess::new);
+                        (key.clone(), (expiring_value.clone(), access))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    pub async fn get_databases(&self) -> usize {
+        self.data.lock().await.databases.len()
+    }
+
+    // The number of keys in the selected database
+    pub async fn dbsize(&self) -> usize {
+        self.data.lock().await.selected().len()
+    }
+
+    // Sets how many databases there are; only meant for startup
+    pub async fn set_databases(&self, databases: usize) {
+        self.data.lock().await.databases.resize_with(databases, Keyspace::new);
+    }
+
+    // Stores a key in database `db`, for loading RDB files
+    pub async fn set_value_in_db(&self, db: usize, key: String, value: ExpiringValue<Value>, access: KeyAccess) {
+        let keyspace = &mut self.data.lock().await.databases[db];
+        keyspace.insert(key.clone(), value);
+        keyspace.set_access(&key, access);
+    }
+
+    // Moves `key` (with its TTL) from the selected database to `db`. Returns
+    // false when it doesn't exist or `db` already has that key.
+    pub async fn move_key(&self, key: &str, db: usize) -> bool {
+        self.expire_if_needed(key).await;
+        let source = selected_db();
+        {
+            let mut data = self.data.lock().await;
+            if data.databases[db].get(key).is_some_and(|expiring_value| !expiring_value.is_expired()) {
+                return false;
             }
+            let Some(expiring_value) = data.selected_mut().remove(key) else {
+                return false;
+            };
+            data.databases[db].insert(key.to_string(), expiring_value);
         }
-        
-        None
+        self.notify_keyspace_event_in_db(source, NOTIFY_GENERIC, "move_from", key).await;
+        self.notify_keyspace_event_in_db(db, NOTIFY_GENERIC, "move_to", key).await;
+        self.blocked_clients.lock().await.signal_key_as_ready(db, key);
+        true
+    }
+
+    // Swaps the contents of two databases; clients connected to one now see
+    // the other's keys, so the ones blocked on either get a chance to retry
+    pub async fn swap_databases(&self, first: usize, second: usize) {
+        self.data.lock().await.databases.swap(first, second);
+        let blocked_clients = self.blocked_clients.lock().await;
+        blocked_clients.signal_database_as_ready(first);
+        blocked_clients.signal_database_as_ready(second);
     }
 
-    pub async fn lpop_multiple(&self, key: &str, count: usize) -> Vec<String> {
+    // Empties database `db`, or every database with None. Returns what was
+    // removed so the caller decides where it gets freed.
+    pub async fn flush(&self, db: Option<usize>) -> Vec<Keyspace> {
         let mut data = self.data.lock().await;
-        let mut result = Vec::new();
-        if let Some(expiring_value) = data.data.get_mut(key) {
-            if let Value::List(list) = &mut expiring_value.value {
-                let n = count.min(list.len());
-                for _ in 0..n {
-                    result.push(list.remove(0));
-                }
+        match db {
+            Some(db) => vec![std::mem::take(&mut data.databases[db])],
+            None => data.databases.iter_mut().map(std::mem::take).collect(),
+        }
+    }
+
+    // (database, keys, keys with a TTL, average TTL in milliseconds) for
+    // every database with keys
+    pub async fn keyspace_stats(&self) -> Vec<(usize, usize, usize, u64)> {
+        let data = self.data.lock().await;
+        let now = Instant::now();
+        let mut stats = Vec::new();
+        for (db, keyspace) in data.databases.iter().enumerate() {
+            let live = keyspace.values().filter(|expiring_value| !expiring_value.is_expired());
+            let ttls: Vec<u64> = live
+                .clone()
+                .filter_map(|expiring_value| expiring_value.expiration_timestamp)
+                .map(|expiration| expiration.saturating_duration_since(now).as_millis() as u64)
+                .collect();
+            let keys = live.count();
+            if keys > 0 {
+                let avg_ttl = if ttls.is_empty() { 0 } else { ttls.iter().sum::<u64>() / ttls.len() as u64 };
+                stats.push((db, keys, ttls.len(), avg_ttl));
             }
         }
-        result
+        stats
     }
 
-    pub async fn get_type(&self, key: &str) -> &'static str {
-        if let Some(expiring_value) = self.get_value(key).await {
-            return expiring_value.value.get_type();
+    // The estimated size of every database, see `Memory::key_memory`
+    pub async fn used_memory(&self) -> u64 {
+        let data = self.data.lock().await;
+        data.databases.iter().map(|keyspace| keyspace.used_memory() as u64).sum()
+    }
+
+    // (database, keys, keys with a TTL) for every database with keys,
+    // counting the expired ones not reclaimed yet since they still use memory
+    pub async fn key_counts(&self) -> Vec<(usize, usize, usize)> {
+        let data = self.data.lock().await;
+        data.databases
+            .iter()
+            .enumerate()
+            .filter(|(_, keyspace)| !keyspace.is_empty())
+            .map(|(db, keyspace)| (db, keyspace.len(), keyspace.volatile_len()))
+            .collect()
+    }
+
+    // Called before every command, so the peak is at most one command late
+    pub async fn record_peak_memory(&self) {
+        let used_memory = self.used_memory().await;
+        let mut stats = self.stats.lock().await;
+        stats.peak_memory = stats.peak_memory.max(used_memory);
+    }
+
+    // CONFIG RESETSTAT: the counters start over, and the peak from now
+    pub async fn reset_stats(&self) {
+        let used_memory = self.used_memory().await;
+        let mut stats = self.stats.lock().await;
+        stats.evicted_keys = 0;
+        stats.peak_memory = used_memory;
+    }
+
+    pub async fn get_peak_memory(&self) -> u64 {
+        let used_memory = self.used_memory().await;
+        self.stats.lock().await.peak_memory.max(used_memory)
+    }
+
+    pub async fn get_maxmemory_config(&self) -> MaxmemoryConfig {
+        self.config.lock().await.maxmemory
+    }
+
+    pub async fn get_evicted_keys(&self) -> u64 {
+        self.stats.lock().await.evicted_keys
+    }
+
+    // Evicts keys following `maxmemory-policy` until the dataset fits in
+    // `maxmemory` again. Returns whether it does, and the evicted keys with
+    // their database so the caller can replicate their deletion. Replicas
+    // leave eviction to their master.
+    pub async fn perform_evictions(&self) -> (bool, Vec<(usize, String)>) {
+        let mut evicted = Vec::new();
+        let (maxmemory, role) = {
+            let config = self.config.lock().await;
+            (config.maxmemory, config.role.clone())
+        };
+        if maxmemory.maxmemory == 0 || role == "slave" {
+            return (true, evicted);
+        }
+        loop {
+            let victim = {
+                let data = &mut *self.data.lock().await;
+                let used_memory: usize = data.databases.iter().map(Keyspace::used_memory).sum();
+                if used_memory as u64 <= maxmemory.maxmemory {
+                    return (true, evicted);
+                }
+                let victim = data.eviction_pool.pick_victim(&data.databases, &maxmemory);
+                if let Some((db, key)) = &victim {
+                    data.databases[*db].remove(key);
+                }
+                victim
+            };
+            let Some((db, key)) = victim else {
+                return (false, evicted);
+            };
+            self.stats.lock().await.evicted_keys += 1;
+            self.notify_keyspace_event_in_db(db, NOTIFY_EVICTED, "evicted", &key).await;
+            evicted.push((db, key));
         }
-        "none"
     }
 
-    pub async fn get_all_keys(&self) -> Vec<String> {
-        self.data.lock().await.data.keys().cloned().collect()
+    pub async fn get_last_save(&self) -> u64 {
+        self.persistence.lock().await.last_save
+    }
+
+    pub async fn set_last_save(&self, last_save: u64) {
+        self.persistence.lock().await.last_save = last_save;
+    }
+
+    // Marks a BGSAVE as started. Returns false when one already is.
+    pub async fn start_bgsave(&self) -> bool {
+        let mut persistence = self.persistence.lock().await;
+        !std::mem::replace(&mut persistence.bgsave_in_progress, true)
+    }
+
+    pub async fn finish_bgsave(&self, saved: bool) {
+        let mut persistence = self.persistence.lock().await;
+        persistence.bgsave_in_progress = false;
+        if saved {
+            persistence.last_save = unix_millis_now() / 1000;
+        }
     }
 
     pub async fn get_db_config(&self) -> (Option<String>, Option<String>) {
@@ -394,39 +1095,209 @@ impl State {
         (config.db_dir.clone(), config.db_file_name.clone())
     }
 
-    pub async fn set_db_config(&self, db_dir: Option<String>, db_file_name: Option<String>) {
+    // Protected mode turns away the clients that don't connect from the
+    // loopback interface while the server listens on every interface (the
+    // default `bind`) without a password
+    pub async fn is_protected(&self) -> bool {
+        let config = self.config.lock().await;
+        config.protected_mode && config.bind == Config::default().bind && !self.acl.lock().await.requires_auth()
+    }
+
+    // Whether connections must AUTH before running commands
+    pub async fn requires_auth(&self) -> bool {
+        self.acl.lock().await.requires_auth()
+    }
+
+    pub async fn check_password(&self, username: &str, password: &str) -> bool {
+        self.acl.lock().await.authenticate(username, password)
+    }
+
+    // Runs `f` on the users, see `Acl`
+    pub async fn with_acl<R>(&self, f: impl FnOnce(&mut Acl) -> R) -> R {
+        f(&mut *self.acl.lock().await)
+    }
+
+    // Records a denied command, key, channel or AUTH for ACL LOG
+    pub async fn log_acl_denial(&self, reason: &'static str, context: &'static str, object: String, username: String, command: &str) {
+        let max_len = self.config.lock().await.acllog_max_len;
+        let client_info = format!(
+            "user={} db={} cmd={}",
+            connection_user().unwrap_or_default(),
+            selected_db(),
+            command
+        );
+        self.acl.lock().await.log_denial(reason, context, object, username, client_info, max_len);
+    }
+
+    pub async fn get_config(&self) -> Config {
+        self.config.lock().await.clone()
+    }
+
+    // `requirepass` is the password of the default user
+    pub async fn set_config(&self, config: Config) {
+        let mut current = self.config.lock().await;
+        if config.requirepass != current.requirepass {
+            self.acl.lock().await.set_default_password(config.requirepass.as_deref());
+        }
+        *current = config;
+    }
+
+    // ACL changes to the default user show in `requirepass`: the newest of
+    // the passwords in clear it still has, or none
+    pub async fn sync_requirepass(&self, new_passwords: &[&str]) {
         let mut config = self.config.lock().await;
-        config.db_dir = db_dir;
-        config.db_file_name = db_file_name;
-    }
-
-    pub async fn register_blpop_blocked_client(&self, key: &str, writer: Arc<Mutex<OwnedWriteHalf>>) {
-        let mut blocked = self.blocked_blpop_clients.lock().await;
-        blocked.entry(key.to_string()).or_default().push_back(BlockedBlpopClient {
-            writer,
-            start_time: Instant::now(),
-            timeout: 30.0, // Default timeout
-        });
-    }
-
-    pub async fn wake_blpop_blocked_client(&self, key: &str, value: String) -> bool {
-        let mut blocked = self.blocked_blpop_clients.lock().await;
-        if let Some(queue) = blocked.get_mut(key) {
-            if let Some(client) = queue.pop_front() {
-                // Check if the client has timed out
-                if client.start_time.elapsed().as_secs_f64() > client.timeout {
-                    // If timed out, remove the client and return false
-                    return false;
+        let candidates = new_passwords.iter().rev().copied().chain(config.requirepass.as_deref());
+        let requirepass = self.acl.lock().await.default_password(candidates);
+        config.requirepass = requirepass;
+    }
+
+        // Blocking helpers, see `Blocking::BlockedClients`
+    pub async fn register_blocked_client(&self, keys: &[String]) -> (u64, Arc<Notify>) {
+        self.blocked_clients.lock().await.register(selected_db(), keys)
+    }
+
+    pub async fn unregister_blocked_client(&self, keys: &[String], id: u64) {
+        self.blocked_clients.lock().await.unregister(selected_db(), keys, id);
+    }
+
+    pub async fn is_first_blocked_client(&self, key: &str, id: u64) -> bool {
+        self.blocked_clients.lock().await.is_first(selected_db(), key, id)
+    }
+
+    pub async fn has_blocked_clients(&self, key: &str) -> bool {
+        self.blocked_clients.lock().await.has_clients(selected_db(), key)
+    }
+
+    // Called by every write that may give a blocked client something to do
+    pub async fn signal_key_as_ready(&self, key: &str) {
+        self.blocked_clients.lock().await.signal_key_as_ready(selected_db(), key);
+    }
+
+    // Pub/Sub helpers. Connections are identified by their writer, the same
+    // way replicas are matched in `update_replica_ack`.
+    pub async fn subscribe(&self, kind: SubscriptionKind, name: &str, writer: SharedWriter) {
+        let mut pubsub = self.pubsub.lock().await;
+        let subscribers = pubsub.subscriptions_mut(kind).entry(name.to_string()).or_default();
+        if !subscribers.iter().any(|w| Arc::ptr_eq(w, &writer)) {
+            subscribers.push(writer);
+        }
+    }
+
+    pub async fn unsubscribe(&self, kind: SubscriptionKind, name: &str, writer: &SharedWriter) {
+        let mut pubsub = self.pubsub.lock().await;
+        let subscriptions = pubsub.subscriptions_mut(kind);
+        if let Some(subscribers) = subscriptions.get_mut(name) {
+            subscribers.retain(|w| !Arc::ptr_eq(w, writer));
+            if subscribers.is_empty() {
+                subscriptions.remove(name);
+            }
+        }
+    }
+
+    pub async fn get_subscriptions(&self, kind: SubscriptionKind, writer: &SharedWriter) -> Vec<String> {
+        let pubsub = self.pubsub.lock().await;
+        pubsub
+            .subscriptions(kind)
+            .iter()
+            .filter(|(_, subscribers)| subscribers.iter().any(|w| Arc::ptr_eq(w, writer)))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    // Number of subscriptions reported back by (P)SUBSCRIBE and
+    // (P)UNSUBSCRIBE: channels and patterns count together, shard channels
+    // are counted on their own.
+    pub async fn subscription_count(&self, kind: SubscriptionKind, writer: &SharedWriter) -> usize {
+        match kind {
+            SubscriptionKind::ShardChannel => self.get_subscriptions(kind, writer).await.len(),
+            _ => {
+                self.get_subscriptions(SubscriptionKind::Channel, writer).await.len()
+                    + self.get_subscriptions(SubscriptionKind::Pattern, writer).await.len()
+            }
+        }
+    }
+
+    pub async fn is_subscriber(&self, writer: &SharedWriter) -> bool {
+        let pubsub = self.pubsub.lock().await;
+        [&pubsub.channels, &pubsub.patterns, &pubsub.shard_channels]
+            .iter()
+            .any(|subscriptions| {
+                subscriptions
+                    .values()
+                    .any(|subscribers| subscribers.iter().any(|w| Arc::ptr_eq(w, writer)))
+            })
+    }
+
+    pub async fn remove_subscriber(&self, writer: &SharedWriter) {
+        let mut pubsub = self.pubsub.lock().await;
+        for kind in [SubscriptionKind::Channel, SubscriptionKind::Pattern, SubscriptionKind::ShardChannel] {
+            let subscriptions = pubsub.subscriptions_mut(kind);
+            for subscribers in subscriptions.values_mut() {
+                subscribers.retain(|w| !Arc::ptr_eq(w, writer));
+            }
+            subscriptions.retain(|_, subscribers| !subscribers.is_empty());
+        }
+    }
+
+    pub async fn get_active_channels(&self, kind: SubscriptionKind, pattern: Option<&str>) -> Vec<String> {
+        let pubsub = self.pubsub.lock().await;
+        pubsub
+            .subscriptions(kind)
+            .keys()
+            .filter(|name| pattern.is_none_or(|p| glob_match(p, name)))
+            .cloned()
+            .collect()
+    }
+
+    pub async fn get_subscriber_count(&self, kind: SubscriptionKind, name: &str) -> usize {
+        let pubsub = self.pubsub.lock().await;
+        pubsub.subscriptions(kind).get(name).map_or(0, |subscribers| subscribers.len())
+    }
+
+    // Delivers `message` to every connection subscribed to `channel` (and,
+    // for regular channels, to every matching pattern) and returns the
+    // number of receivers.
+    pub async fn publish(&self, kind: SubscriptionKind, channel: &str, message: &[u8]) -> usize {
+        let mut deliveries = Vec::new();
+        {
+            let pubsub = self.pubsub.lock().await;
+            let message_type = if kind == SubscriptionKind::ShardChannel { "smessage" } else { "message" };
+            if let Some(subscribers) = pubsub.subscriptions(kind).get(channel) {
+                let payload = RespDataType::Array(vec![
+                    RespDataType::BulkString(message_type.to_string()),
+                    RespDataType::BulkString(channel.to_string()),
+                    RespDataType::BulkBytes(message.to_vec()),
+                ])
+                .to_bytes();
+                for writer in subscribers {
+                    deliveries.push((writer.clone(), payload.clone()));
                 }
-                // Compose the response: [key, value]
-                let resp = crate::classes::RespDataType::RespDataType::Array(vec![
-                    crate::classes::RespDataType::RespDataType::BulkString(key.to_string()),
-                    crate::classes::RespDataType::RespDataType::BulkString(value),
-                ]);
-                let _ = client.writer.lock().await.write_all(resp.to_string().as_bytes()).await;
-                return true;
             }
+            if kind == SubscriptionKind::Channel {
+                for (pattern, subscribers) in &pubsub.patterns {
+                    if !glob_match(pattern, channel) {
+                        continue;
+                    }
+                    let payload = RespDataType::Array(vec![
+                        RespDataType::BulkString("pmessage".to_string()),
+                        RespDataType::BulkString(pattern.clone()),
+                        RespDataType::BulkString(channel.to_string()),
+                        RespDataType::BulkBytes(message.to_vec()),
+                    ])
+                    .to_bytes();
+                    for writer in subscribers {
+                        deliveries.push((writer.clone(), payload.clone()));
+                    }
+                }
+            }
+        }
+
+        // write outside of the pubsub lock so a slow subscriber can't stall
+        // (un)subscribes on other connections
+        let receivers = deliveries.len();
+        for (writer, payload) in deliveries {
+            let _ = writer.lock().await.write_all(&payload).await;
         }
-        false
+        receivers
     }
 }
@@ -0,0 +1,270 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+use crate::classes::StreamId::StreamId;
+
+// How many entries Redis packs in one radix tree node. Approximate trimming
+// only drops whole nodes, which we emulate with runs of this many entries.
+pub const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+// An entry's field-value pairs, in the order they were added
+pub type StreamFields = Vec<(String, String)>;
+
+// Entries ordered by ID, plus the metadata Redis keeps next to them. The
+// metadata outlives the entries: a stream whose entries were all deleted
+// still refuses IDs at or below `last_id`.
+#[derive(Clone, Default)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, StreamFields>,
+    // the greatest ID ever added
+    pub last_id: StreamId,
+    // how many entries were ever added, deleted ones included
+    pub entries_added: u64,
+    // the greatest ID ever deleted
+    pub max_deleted_id: StreamId,
+    pub groups: BTreeMap<String, ConsumerGroup>,
+}
+
+// What XTRIM and XADD's trimming options keep: at most `MaxLen` entries, or
+// the entries with an ID of at least `MinId`
+#[derive(Clone, Copy)]
+pub enum TrimStrategy {
+    MaxLen(u64),
+    MinId(StreamId),
+}
+
+// An entry delivered to a consumer of a group and not acknowledged yet
+#[derive(Clone)]
+pub struct PendingEntry {
+    pub consumer: String,
+    // unix milliseconds of the last delivery
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Clone, Default)]
+pub struct Consumer {
+    // unix milliseconds of the last interaction, and of the last one that
+    // actually read or claimed entries
+    pub seen_time: u64,
+    pub active_time: Option<u64>,
+    pub pending: BTreeSet<StreamId>,
+}
+
+#[derive(Clone, Default)]
+pub struct ConsumerGroup {
+    // the last entry delivered to the group with `>`
+    pub last_id: StreamId,
+    // the logical position of `last_id` in the stream, when it is known
+    pub entries_read: Option<u64>,
+    // the pending entries list (PEL) of the whole group
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: BTreeMap<String, Consumer>,
+}
+
+impl Stream {
+    pub fn new() -> Stream {
+        Stream::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, id: &StreamId) -> Option<&StreamFields> {
+        self.entries.get(id)
+    }
+
+    pub fn first_entry(&self) -> Option<(&StreamId, &StreamFields)> {
+        self.entries.first_key_value()
+    }
+
+    pub fn last_entry(&self) -> Option<(&StreamId, &StreamFields)> {
+        self.entries.last_key_value()
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&StreamId, &StreamFields)> {
+        self.entries.iter()
+    }
+
+    // Appends an entry. The caller makes sure `id` is above `last_id`.
+    pub fn insert(&mut self, id: StreamId, fields: StreamFields) {
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        self.entries_added += 1;
+    }
+
+    // Removes an entry. Returns false when there was none with that ID.
+    pub fn delete(&mut self, id: &StreamId) -> bool {
+        if self.entries.remove(id).is_none() {
+            return false;
+        }
+        self.max_deleted_id = self.max_deleted_id.max(*id);
+        true
+    }
+
+    // Drops entries from the head of the stream, returning how many. An
+    // approximate trim only drops whole runs of STREAM_NODE_MAX_ENTRIES and
+    // at most `limit` entries (0 for no limit).
+    pub fn trim(&mut self, strategy: TrimStrategy, approx: bool, limit: usize) -> usize {
+        let mut count = match strategy {
+            TrimStrategy::MaxLen(max_len) => self.len().saturating_sub(max_len.try_into().unwrap_or(usize::MAX)),
+            TrimStrategy::MinId(min_id) => self.entries.range(..min_id).count(),
+        };
+        if approx {
+            count -= count % STREAM_NODE_MAX_ENTRIES;
+            if limit > 0 {
+                count = count.min(limit - limit % STREAM_NODE_MAX_ENTRIES);
+            }
+        } else if limit > 0 {
+            count = count.min(limit);
+        }
+        for _ in 0..count {
+            self.entries.pop_first();
+        }
+        count
+    }
+
+    // How many radix tree nodes Redis would use for the entries
+    pub fn node_count(&self) -> usize {
+        self.len().div_ceil(STREAM_NODE_MAX_ENTRIES)
+    }
+
+    // Entries with start <= ID <= end
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+    ) -> impl DoubleEndedIterator<Item = (&StreamId, &StreamFields)> {
+        // BTreeMap::range panics on a reversed range
+        let end = if start > end { Bound::Excluded(start) } else { Bound::Included(end) };
+        self.entries.range((Bound::Included(start), end))
+    }
+
+    // Entries with an ID above `id`
+    pub fn after(&self, id: StreamId) -> impl DoubleEndedIterator<Item = (&StreamId, &StreamFields)> {
+        self.entries.range((Bound::Excluded(id), Bound::Unbounded))
+    }
+
+    // Whether an entry at or after `id` was ever deleted
+    fn has_tombstones_from(&self, id: StreamId) -> bool {
+        !self.is_empty() && self.max_deleted_id != StreamId::MIN && id <= self.max_deleted_id
+    }
+
+    // How many entries were added up to and including `id`, when that can be
+    // told without walking the stream
+    pub fn estimate_entries_read(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        if self.is_empty() && id <= self.last_id {
+            return Some(self.entries_added);
+        }
+        if id == self.last_id {
+            return Some(self.entries_added);
+        }
+        if id > self.last_id {
+            return None;
+        }
+        let first_id = self.first_entry().map(|(id, _)| *id).unwrap_or_default();
+        if self.max_deleted_id == StreamId::MIN || self.max_deleted_id < first_id {
+            // everything before the first entry was trimmed away
+            let trimmed = self.entries_added - self.len() as u64;
+            if id < first_id {
+                return Some(trimmed);
+            }
+            if id == first_id {
+                return Some(trimmed + 1);
+            }
+        }
+        None
+    }
+
+    // How many entries the group has yet to read, when that can be told
+    pub fn lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        let entries_read = match group.entries_read {
+            Some(entries_read) if !self.has_tombstones_from(group.last_id) => Some(entries_read),
+            _ => self.estimate_entries_read(group.last_id),
+        };
+        entries_read.map(|entries_read| self.entries_added.saturating_sub(entries_read))
+    }
+
+    // Moves the group past `id`, which was just delivered with `>`
+    pub fn advance_group(&mut self, group: &str, id: StreamId) {
+        let has_tombstones = self.has_tombstones_from(id);
+        let estimate = self.estimate_entries_read(id);
+        let entries_added = self.entries_added;
+        if let Some(group) = self.groups.get_mut(group) {
+            if id <= group.last_id {
+                return;
+            }
+            group.entries_read = match group.entries_read {
+                Some(entries_read) if !has_tombstones => Some(entries_read + 1),
+                entries_read if entries_added == 0 => entries_read,
+                _ => estimate,
+            };
+            group.last_id = id;
+        }
+    }
+}
+
+impl ConsumerGroup {
+    pub fn new(last_id: StreamId, entries_read: Option<u64>) -> ConsumerGroup {
+        ConsumerGroup { last_id, entries_read, ..Default::default() }
+    }
+
+    // Looks a consumer up, creating it when needed, and marks it as seen.
+    // Returns true when it was created.
+    pub fn touch_consumer(&mut self, name: &str, now: u64) -> bool {
+        let created = !self.consumers.contains_key(name);
+        self.consumers.entry(name.to_string()).or_default().seen_time = now;
+        created
+    }
+
+    // Makes `consumer` (which must exist) the owner of a pending entry,
+    // adding the entry to the PEL when needed
+    pub fn assign(&mut self, id: StreamId, consumer: &str, delivery_time: u64, delivery_count: u64) {
+        let previous = self.pending.insert(
+            id,
+            PendingEntry { consumer: consumer.to_string(), delivery_time, delivery_count },
+        );
+        if let Some(previous) = previous {
+            if let Some(owner) = self.consumers.get_mut(&previous.consumer) {
+                owner.pending.remove(&id);
+            }
+        }
+        if let Some(owner) = self.consumers.get_mut(consumer) {
+            owner.pending.insert(id);
+        }
+    }
+
+    // Removes an entry from the PEL. Returns false when it wasn't pending.
+    pub fn acknowledge(&mut self, id: &StreamId) -> bool {
+        match self.pending.remove(id) {
+            Some(entry) => {
+                if let Some(owner) = self.consumers.get_mut(&entry.consumer) {
+                    owner.pending.remove(id);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Deletes a consumer along with its pending entries. Returns how many
+    // entries it had pending, None when it didn't exist.
+    pub fn delete_consumer(&mut self, name: &str) -> Option<usize> {
+        let consumer = self.consumers.remove(name)?;
+        for id in &consumer.pending {
+            self.pending.remove(id);
+        }
+        Some(consumer.pending.len())
+    }
+}
@@ -0,0 +1,116 @@
+use std::fmt;
+
+pub const INVALID_STREAM_ID_ERROR: &str = "ERR Invalid stream ID specified as stream command argument";
+
+// A stream entry ID, `<milliseconds>-<sequence>`. Ordering is numeric:
+// first by milliseconds, then by sequence number.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Debug)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId { ms: u64::MAX, seq: u64::MAX };
+
+    pub fn new(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    // Parses `ms-seq`, or `ms` alone with `missing_seq` as the sequence.
+    // `-` and `+` stand for the smallest and the greatest ID.
+    pub fn parse(value: &str, missing_seq: u64) -> Result<StreamId, String> {
+        match value {
+            "-" => Ok(StreamId::MIN),
+            "+" => Ok(StreamId::MAX),
+            _ => StreamId::parse_strict(value, missing_seq),
+        }
+    }
+
+    // Like `parse` but without the `-` and `+` shorthands
+    pub fn parse_strict(value: &str, missing_seq: u64) -> Result<StreamId, String> {
+        let invalid = || INVALID_STREAM_ID_ERROR.to_string();
+        let (ms, seq) = match value.split_once('-') {
+            Some((ms, seq)) => (ms, Some(seq)),
+            None => (value, None),
+        };
+        let ms = parse_u64(ms).ok_or_else(invalid)?;
+        let seq = match seq {
+            Some(seq) => parse_u64(seq).ok_or_else(invalid)?,
+            None => missing_seq,
+        };
+        Ok(StreamId { ms, seq })
+    }
+
+    // The next ID, None past the greatest one
+    pub fn incr(&self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => self.ms.checked_add(1).map(|ms| StreamId { ms, seq: 0 }),
+        }
+    }
+
+    // The previous ID, None before the smallest one
+    pub fn decr(&self) -> Option<StreamId> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => self.ms.checked_sub(1).map(|ms| StreamId { ms, seq: u64::MAX }),
+        }
+    }
+}
+
+// Digits only, like Redis' string2ull: no sign, no spaces
+fn parse_u64(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse::<u64>().ok()
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ids_and_shorthands() {
+        assert_eq!(StreamId::parse("1526919030474-55", 0), Ok(StreamId::new(1526919030474, 55)));
+        assert_eq!(StreamId::parse("1526919030474", 0), Ok(StreamId::new(1526919030474, 0)));
+        assert_eq!(StreamId::parse("1526919030474", u64::MAX), Ok(StreamId::new(1526919030474, u64::MAX)));
+        assert_eq!(StreamId::parse("-", 0), Ok(StreamId::MIN));
+        assert_eq!(StreamId::parse("+", 0), Ok(StreamId::MAX));
+        assert_eq!(
+            StreamId::parse("18446744073709551615-18446744073709551615", 0),
+            Ok(StreamId::MAX)
+        );
+        assert_eq!(StreamId::parse_strict("-", 0), Err(INVALID_STREAM_ID_ERROR.to_string()));
+    }
+
+    #[test]
+    fn rejects_what_string2ull_rejects() {
+        for value in ["", "1-", "-1", "+1", "1-2-3", " 1", "1 ", "0x10", "a-1", "18446744073709551616", "1-18446744073709551616"] {
+            assert_eq!(StreamId::parse(value, 0), Err(INVALID_STREAM_ID_ERROR.to_string()), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn orders_numerically() {
+        assert!(StreamId::parse("10-0", 0).unwrap() > StreamId::parse("9-5", 0).unwrap());
+        assert!(StreamId::parse("1-10", 0).unwrap() > StreamId::parse("1-9", 0).unwrap());
+        assert_eq!(StreamId::new(3, 7).to_string(), "3-7");
+    }
+
+    #[test]
+    fn incr_and_decr_carry() {
+        assert_eq!(StreamId::new(1, u64::MAX).incr(), Some(StreamId::new(2, 0)));
+        assert_eq!(StreamId::new(2, 0).decr(), Some(StreamId::new(1, u64::MAX)));
+        assert_eq!(StreamId::MAX.incr(), None);
+        assert_eq!(StreamId::MIN.decr(), None);
+    }
+}
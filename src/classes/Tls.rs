@@ -0,0 +1,115 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio_rustls::rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+
+use crate::classes::State::Config;
+
+// Whether TLS clients must present a certificate signed by `tls-ca-cert-file`
+#[derive(Clone, Copy, PartialEq)]
+pub enum TlsAuthClients {
+    Yes,
+    No,
+    Optional,
+}
+
+impl TlsAuthClients {
+    pub fn parse(value: &str) -> Option<TlsAuthClients> {
+        match value.to_lowercase().as_str() {
+            "yes" => Some(TlsAuthClients::Yes),
+            "no" => Some(TlsAuthClients::No),
+            "optional" => Some(TlsAuthClients::Optional),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TlsAuthClients::Yes => "yes",
+            TlsAuthClients::No => "no",
+            TlsAuthClients::Optional => "optional",
+        }
+    }
+}
+
+// What the TLS port serves clients with: the server certificate, and the
+// CA that signs client certificates unless `tls-auth-clients` is no
+pub fn server_config(config: &Config) -> Result<Arc<ServerConfig>, String> {
+    let (certificates, key) = certificate_and_key(config)?;
+    let builder = match config.tls_auth_clients {
+        TlsAuthClients::No => ServerConfig::builder().with_no_client_auth(),
+        auth_clients => {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(ca_roots(config)?));
+            let verifier = if auth_clients == TlsAuthClients::Optional {
+                verifier.allow_unauthenticated()
+            } else {
+                verifier
+            };
+            let verifier = verifier.build().map_err(|error| format!("Failed to configure client authentication: {}", error))?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+    };
+    let server_config = builder
+        .with_single_cert(certificates, key)
+        .map_err(|error| format!("Failed to configure the server certificate: {}", error))?;
+    Ok(Arc::new(server_config))
+}
+
+// What a replica reaches its master with under `tls-replication`: the CA
+// its certificate must be signed by, and our own certificate in case the
+// master authenticates its clients
+pub fn client_config(config: &Config) -> Result<Arc<ClientConfig>, String> {
+    let builder = ClientConfig::builder().with_root_certificates(ca_roots(config)?);
+    let client_config = match (&config.tls_cert_file, &config.tls_key_file) {
+        (Some(_), Some(_)) => {
+            let (certificates, key) = certificate_and_key(config)?;
+            builder
+                .with_client_auth_cert(certificates, key)
+                .map_err(|error| format!("Failed to configure the client certificate: {}", error))?
+        }
+        _ => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(client_config))
+}
+
+fn certificate_and_key(config: &Config) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), String> {
+    let (Some(cert_file), Some(key_file)) = (&config.tls_cert_file, &config.tls_key_file) else {
+        return Err("Both tls-cert-file and tls-key-file must be specified".to_string());
+    };
+    let certificates = load_certificates(cert_file)?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(open(key_file)?))
+        .map_err(|error| format!("Failed to load private key: {}: {}", key_file, error))?
+        .ok_or_else(|| format!("Failed to load private key: {}: no key found", key_file))?;
+    Ok((certificates, key))
+}
+
+fn ca_roots(config: &Config) -> Result<RootCertStore, String> {
+    let Some(ca_cert_file) = &config.tls_ca_cert_file else {
+        return Err("tls-ca-cert-file must be specified when tls-replication or tls-auth-clients are enabled".to_string());
+    };
+    let mut roots = RootCertStore::empty();
+    for certificate in load_certificates(ca_cert_file)? {
+        roots
+            .add(certificate)
+            .map_err(|error| format!("Failed to load CA certificate(s) file: {}: {}", ca_cert_file, error))?;
+    }
+    Ok(roots)
+}
+
+fn load_certificates(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(open(path)?))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| format!("Failed to load certificate: {}: {}", path, error))?;
+    if certificates.is_empty() {
+        return Err(format!("Failed to load certificate: {}: no certificate found", path));
+    }
+    Ok(certificates)
+}
+
+fn open(path: &str) -> Result<File, String> {
+    File::open(path).map_err(|error| format!("Failed to open {}: {}", path, error))
+}
@@ -0,0 +1,244 @@
+use crate::classes::{
+    Acl::{find_command, Acl, CATEGORIES, COMMANDS},
+    ExpiringValue::unix_millis_now,
+    Parser::Command,
+    RespDataType::RespDataType,
+    State::{connection_user, State},
+};
+use std::fs;
+use std::sync::Arc;
+use crate::classes::Connection::{write_response, wrong_arity, SharedWriter};
+use tokio::sync::Mutex;
+
+// ACL CAT | WHOAMI | USERS | LIST | SETUSER | GETUSER | DELUSER | DRYRUN | LOG | LOAD | SAVE | HELP
+pub async fn handle_acl(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 2 {
+        return write_response(&stream, wrong_arity("acl")).await;
+    }
+    let subcommand = commands[1].to_uppercase();
+    let arity_ok = match subcommand.as_str() {
+        "CAT" | "LOG" => commands.len() <= 3,
+        "WHOAMI" | "USERS" | "LIST" | "LOAD" | "SAVE" | "HELP" => commands.len() == 2,
+        "SETUSER" | "DELUSER" => commands.len() >= 3,
+        "GETUSER" => commands.len() == 3,
+        "DRYRUN" => commands.len() >= 4,
+        _ => {
+            let error = format!("ERR unknown subcommand '{}'. Try ACL HELP.", commands[1]);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+    if !arity_ok {
+        return write_response(&stream, wrong_arity(&format!("acl|{}", subcommand.to_lowercase()))).await;
+    }
+
+    let ok = || RespDataType::SimpleString("OK".to_string());
+    let bulk = |value: &str| RespDataType::BulkString(value.to_string());
+    let state_guard = state.lock().await;
+    let response = match subcommand.as_str() {
+        "CAT" => acl_cat(commands.get(2)),
+        "WHOAMI" => bulk(&connection_user().unwrap_or_else(|| "default".to_string())),
+        "USERS" => RespDataType::Array(state_guard.with_acl(|acl| acl.users().map(|user| bulk(&user.name)).collect()).await),
+        "LIST" => RespDataType::Array(state_guard.with_acl(|acl| acl.users().map(|user| bulk(&user.describe())).collect()).await),
+        "SETUSER" => match state_guard.with_acl(|acl| acl.set_user(&commands[2], &commands[3..])).await {
+            Ok(()) => {
+                if commands[2] == "default" {
+                    let passwords: Vec<&str> = commands[3..].iter().filter_map(|rule| rule.strip_prefix('>')).collect();
+                    state_guard.sync_requirepass(&passwords).await;
+                }
+                ok()
+            }
+            Err(error) => RespDataType::SimpleError(format!("ERR {}", error)),
+        },
+        "GETUSER" => state_guard.with_acl(|acl| acl_getuser(acl, &commands[2])).await,
+        "DELUSER" => {
+            if commands[2..].iter().any(|name| name == "default") {
+                RespDataType::SimpleError("ERR The 'default' user cannot be removed".to_string())
+            } else {
+                // connections of deleted users are closed by the command executor
+                let deleted = state_guard
+                    .with_acl(|acl| commands[2..].iter().filter(|name| acl.delete_user(name)).count())
+                    .await;
+                RespDataType::Integer(deleted as i64)
+            }
+        }
+        "DRYRUN" => state_guard.with_acl(|acl| acl_dryrun(acl, &commands[2], &commands[3..])).await,
+        "LOG" => match commands.get(2) {
+            Some(argument) if argument.eq_ignore_ascii_case("RESET") => {
+                state_guard.with_acl(Acl::reset_log).await;
+                ok()
+            }
+            Some(count) => match count.parse::<i64>() {
+                Ok(count) if count >= 0 => state_guard.with_acl(|acl| acl_log(acl, count as usize)).await,
+                Ok(_) => RespDataType::SimpleError("ERR value is out of range, must be positive".to_string()),
+                Err(_) => RespDataType::SimpleError("ERR value is not an integer or out of range".to_string()),
+            },
+            None => state_guard.with_acl(|acl| acl_log(acl, 10)).await,
+        },
+        "LOAD" => {
+            let aclfile = state_guard.get_config().await.aclfile;
+            match aclfile {
+                Some(path) => match fs::read_to_string(&path) {
+                    Ok(text) => match state_guard.with_acl(|acl| acl.load(&text, &path)).await {
+                        Ok(()) => {
+                            state_guard.sync_requirepass(&[]).await;
+                            ok()
+                        }
+                        Err(error) => RespDataType::SimpleError(format!("ERR {}", error)),
+                    },
+                    Err(error) => RespDataType::SimpleError(format!("ERR Error loading ACLs, opening file '{}': {}", path, error)),
+                },
+                None => no_aclfile_error(),
+            }
+        }
+        "SAVE" => {
+            let aclfile = state_guard.get_config().await.aclfile;
+            match aclfile {
+                Some(path) => {
+                    let text = state_guard.with_acl(|acl| acl.to_file()).await;
+                    // written aside first so a failure leaves the old file whole
+                    let temp_path = format!("{}.tmp-{}", path, std::process::id());
+                    match fs::write(&temp_path, text).and_then(|_| fs::rename(&temp_path, &path)) {
+                        Ok(()) => ok(),
+                        Err(error) => {
+                            eprintln!("Opening temp ACL file for ACL SAVE: {}", error);
+                            let _ = fs::remove_file(&temp_path);
+                            RespDataType::SimpleError(
+                                "ERR There was an error trying to save the ACLs. Please check the server logs for more information".to_string(),
+                            )
+                        }
+                    }
+                }
+                None => no_aclfile_error(),
+            }
+        }
+        _ => {
+            let lines = [
+                "ACL <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                "CAT [<category>]",
+                "    List all commands that belong to <category>, or all command categories",
+                "    when no category is specified.",
+                "DELUSER <username> [<username> ...]",
+                "    Delete a list of users.",
+                "DRYRUN <username> <command> [<arg> ...]",
+                "    Returns whether the user can execute the given command without executing the command.",
+                "GETUSER <username>",
+                "    Get the user's details.",
+                "LIST",
+                "    Show users details in config file format.",
+                "LOAD",
+                "    Reload users from the ACL file.",
+                "LOG [<count> | RESET]",
+                "    Show the ACL log entries.",
+                "SAVE",
+                "    Save the current config to the ACL file.",
+                "SETUSER <username> <attribute> [<attribute> ...]",
+                "    Create or modify a user with the specified attributes.",
+                "USERS",
+                "    List all the registered usernames.",
+                "WHOAMI",
+                "    Return the current connection username.",
+                "HELP",
+                "    Print this help.",
+            ];
+            RespDataType::Array(lines.iter().map(|line| RespDataType::SimpleString(line.to_string())).collect())
+        }
+    };
+    drop(state_guard);
+    write_response(&stream, response).await;
+}
+
+fn no_aclfile_error() -> RespDataType {
+    RespDataType::SimpleError("ERR This Redis instance is not configured to use an ACL file. You may want to specify users via the ACL SETUSER command and then issue a CONFIG REWRITE (assuming you have a Redis configuration file set) in order to store users in the Redis configuration.".to_string())
+}
+
+// The categories, or the commands and subcommands of one
+fn acl_cat(category: Option<&String>) -> RespDataType {
+    let bulk = |value: &str| RespDataType::BulkString(value.to_string());
+    let Some(category) = category else {
+        return RespDataType::Array(CATEGORIES.iter().map(|category| bulk(category)).collect());
+    };
+    let category = category.to_lowercase();
+    if !CATEGORIES.contains(&category.as_str()) {
+        return RespDataType::SimpleError(format!("ERR Unknown category '{}'", category));
+    }
+    let commands = COMMANDS
+        .iter()
+        .filter(|spec| spec.categories.contains(&category.as_str()))
+        .map(|spec| bulk(spec.name))
+        .collect();
+    RespDataType::Array(commands)
+}
+
+fn acl_getuser(acl: &Acl, name: &str) -> RespDataType {
+    let Some(user) = acl.user(name) else {
+        return RespDataType::Nil;
+    };
+    let bulk = |value: &str| RespDataType::BulkString(value.to_string());
+    RespDataType::Array(vec![
+        bulk("flags"),
+        RespDataType::Array(user.flags().into_iter().map(bulk).collect()),
+        bulk("passwords"),
+        RespDataType::Array(user.password_hashes().iter().map(|hash| bulk(hash)).collect()),
+        bulk("commands"),
+        bulk(&user.describe_commands()),
+        bulk("keys"),
+        bulk(&user.describe_keys()),
+        bulk("channels"),
+        bulk(&user.describe_channels()),
+        bulk("selectors"),
+        RespDataType::Array(Vec::new()),
+    ])
+}
+
+// Whether the user could run the command, without running it
+fn acl_dryrun(acl: &Acl, username: &str, args: &[String]) -> RespDataType {
+    let Some(user) = acl.user(username) else {
+        return RespDataType::SimpleError(format!("ERR User '{}' not found", username));
+    };
+    let Some(spec) = find_command(args) else {
+        return RespDataType::SimpleError(format!("ERR Command '{}' not found", args[0]));
+    };
+    match user.check(spec, args) {
+        Ok(()) => RespDataType::SimpleString("OK".to_string()),
+        Err(denial) => RespDataType::BulkString(denial.message(username, spec.name)),
+    }
+}
+
+// The `count` most recent entries
+fn acl_log(acl: &Acl, count: usize) -> RespDataType {
+    let now = unix_millis_now();
+    let bulk = |value: &str| RespDataType::BulkString(value.to_string());
+    let entries = acl
+        .log_entries()
+        .take(count)
+        .map(|entry| {
+            RespDataType::Array(vec![
+                bulk("count"),
+                RespDataType::Integer(entry.count as i64),
+                bulk("reason"),
+                bulk(entry.reason),
+                bulk("context"),
+                bulk(entry.context),
+                bulk("object"),
+                bulk(&entry.object),
+                bulk("username"),
+                bulk(&entry.username),
+                bulk("age-seconds"),
+                bulk(&format!("{:.3}", now.saturating_sub(entry.created) as f64 / 1000.0)),
+                bulk("client-info"),
+                bulk(&entry.client_info),
+                bulk("entry-id"),
+                RespDataType::Integer(entry.entry_id as i64),
+                bulk("timestamp-created"),
+                RespDataType::Integer(entry.created as i64),
+                bulk("timestamp-last-updated"),
+                RespDataType::Integer(entry.updated as i64),
+            ])
+        })
+        .collect();
+    RespDataType::Array(entries)
+}
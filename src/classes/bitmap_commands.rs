@@ -0,0 +1,527 @@
+use crate::classes::{
+    Notifications::NOTIFY_STRING,
+    Parser::Command,
+    RespDataType::RespDataType,
+    State::State,
+    string_commands::MAX_STRING_LENGTH,
+};
+use std::sync::Arc;
+use crate::classes::Connection::{write_response, wrong_arity, SharedWriter};
+use tokio::sync::Mutex;
+
+const BIT_OFFSET_ERROR: &str = "ERR bit offset is not an integer or out of range";
+const NOT_AN_INTEGER_ERROR: &str = "ERR value is not an integer or out of range";
+
+// Bits are numbered from the most significant bit of the first byte, so
+// offset 0 is the leftmost bit of the string
+fn get_bit(bytes: &[u8], offset: u64) -> u8 {
+    let byte = (offset >> 3) as usize;
+    match bytes.get(byte) {
+        Some(b) => (b >> (7 - (offset & 7))) & 1,
+        None => 0,
+    }
+}
+
+fn set_bit(bytes: &mut [u8], offset: u64, on: bool) {
+    let byte = (offset >> 3) as usize;
+    let mask = 1 << (7 - (offset & 7));
+    if on {
+        bytes[byte] |= mask;
+    } else {
+        bytes[byte] &= !mask;
+    }
+}
+
+// Offsets must address a bit inside the largest string Redis accepts
+fn parse_bit_offset(value: &str) -> Result<u64, String> {
+    match value.parse::<i64>() {
+        Ok(offset) if offset >= 0 && ((offset as u64) >> 3) < MAX_STRING_LENGTH as u64 => Ok(offset as u64),
+        _ => Err(BIT_OFFSET_ERROR.to_string()),
+    }
+}
+
+// Turns a start/end pair into an inclusive range inside `0..length`, with
+// negative values counting from the end. None means the range is empty.
+fn clamp_range(mut start: i64, mut end: i64, length: i64) -> Option<(i64, i64)> {
+    if start < 0 {
+        start = (length + start).max(0);
+    }
+    if end < 0 {
+        end = (length + end).max(0);
+    }
+    end = end.min(length - 1);
+    if start > end { None } else { Some((start, end)) }
+}
+
+// Parses the optional `start end [BYTE | BIT]` arguments of BITCOUNT and
+// BITPOS into a bit range over a string of `byte_length` bytes. Ok(None)
+// stands for an empty range.
+fn parse_bit_range(args: &[String], byte_length: usize, default_end: bool) -> Result<Option<(u64, u64)>, String> {
+    let start = match args.first() {
+        Some(start) => start.parse::<i64>().map_err(|_| NOT_AN_INTEGER_ERROR.to_string())?,
+        None => 0,
+    };
+    let end = match args.get(1) {
+        Some(end) => Some(end.parse::<i64>().map_err(|_| NOT_AN_INTEGER_ERROR.to_string())?),
+        None if default_end => None,
+        None => return Err("ERR syntax error".to_string()),
+    };
+    let in_bits = match args.get(2).map(|unit| unit.to_uppercase()) {
+        None => false,
+        Some(unit) if unit == "BYTE" => false,
+        Some(unit) if unit == "BIT" => true,
+        Some(_) => return Err("ERR syntax error".to_string()),
+    };
+
+    let length = if in_bits { byte_length as i64 * 8 } else { byte_length as i64 };
+    Ok(clamp_range(start, end.unwrap_or(-1), length).map(|(start, end)| {
+        if in_bits {
+            (start as u64, end as u64)
+        } else {
+            (start as u64 * 8, end as u64 * 8 + 7)
+        }
+    }))
+}
+
+// SETBIT key offset value
+pub async fn handle_setbit(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 4 {
+        return write_response(&stream, wrong_arity("setbit")).await;
+    }
+    let key = commands[1].clone();
+    let offset = match parse_bit_offset(&commands[2]) {
+        Ok(offset) => offset,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+    let on = match commands[3].as_str() {
+        "0" => false,
+        "1" => true,
+        _ => return write_response(&stream, RespDataType::SimpleError("ERR bit is not an integer or out of range".to_string())).await,
+    };
+
+    let state_guard = state.lock().await;
+    let (mut bytes, expiration) = match state_guard.get_string(&key).await {
+        Ok(Some(v)) => (v.value, v.expiration_timestamp),
+        Ok(None) => (Vec::new(), None),
+        Err(error) => {
+            drop(state_guard);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+
+    let needed = (offset >> 3) as usize + 1;
+    if bytes.len() < needed {
+        bytes.resize(needed, 0);
+    }
+    let old_bit = get_bit(&bytes, offset);
+    set_bit(&mut bytes, offset, on);
+    state_guard.set_string(key.clone(), bytes, expiration).await;
+    state_guard.notify_keyspace_event(NOTIFY_STRING, "setbit", &key).await;
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, RespDataType::Integer(old_bit as i64)).await;
+    }
+}
+
+// GETBIT key offset
+pub async fn handle_getbit(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 3 {
+        return write_response(&stream, wrong_arity("getbit")).await;
+    }
+    let offset = match parse_bit_offset(&commands[2]) {
+        Ok(offset) => offset,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+    let response = match state.lock().await.get_string(&commands[1]).await {
+        Ok(value) => RespDataType::Integer(value.map_or(0, |v| get_bit(&v.value, offset)) as i64),
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    write_response(&stream, response).await;
+}
+
+// BITCOUNT key [start end [BYTE | BIT]]
+pub async fn handle_bitcount(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 2 || commands.len() > 5 {
+        return write_response(&stream, wrong_arity("bitcount")).await;
+    }
+    let bytes = match state.lock().await.get_string(&commands[1]).await {
+        Ok(value) => value.map(|v| v.value).unwrap_or_default(),
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+    let range = match parse_bit_range(&commands[2..], bytes.len(), commands.len() == 2) {
+        Ok(range) => range,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+
+    let count = match range {
+        None => 0,
+        Some((start, end)) => {
+            let (first_byte, last_byte) = ((start >> 3) as usize, (end >> 3) as usize);
+            let mut count: u64 = bytes[first_byte..=last_byte].iter().map(|b| b.count_ones() as u64).sum();
+            // drop the bits of the edge bytes that fall outside a BIT range
+            let head_mask = !(0xFFu8 >> (start & 7));
+            let tail_mask = ((1u16 << (7 - (end & 7))) - 1) as u8;
+            count -= (bytes[first_byte] & head_mask).count_ones() as u64;
+            count -= (bytes[last_byte] & tail_mask).count_ones() as u64;
+            count
+        }
+    };
+    write_response(&stream, RespDataType::Integer(count as i64)).await;
+}
+
+// BITPOS key bit [start [end [BYTE | BIT]]]
+pub async fn handle_bitpos(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 3 || commands.len() > 6 {
+        return write_response(&stream, wrong_arity("bitpos")).await;
+    }
+    let bit = match commands[2].as_str() {
+        "0" => 0,
+        "1" => 1,
+        _ => return write_response(&stream, RespDataType::SimpleError("ERR The bit argument must be 1 or 0.".to_string())).await,
+    };
+    let value = match state.lock().await.get_string(&commands[1]).await {
+        Ok(value) => value,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+    let range = parse_bit_range(&commands[3..], value.as_ref().map_or(0, |v| v.value.len()), true);
+    let range = match (range, value) {
+        (Err(error), _) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+        // a missing key is an empty string: no set bits, all clear bits
+        (Ok(_), None) => return write_response(&stream, RespDataType::Integer(if bit == 1 { -1 } else { 0 })).await,
+        (Ok(range), Some(v)) => range.map(|range| (range, v.value)),
+    };
+
+    let position = match range {
+        None => -1,
+        Some(((start, end), bytes)) => {
+            let skip = if bit == 1 { 0x00 } else { 0xFF };
+            let mut offset = start;
+            let mut found = None;
+            while offset <= end {
+                // whole bytes without the wanted bit are skipped at once
+                if offset & 7 == 0 && offset + 7 <= end && bytes[(offset >> 3) as usize] == skip {
+                    offset += 8;
+                    continue;
+                }
+                if get_bit(&bytes, offset) == bit {
+                    found = Some(offset as i64);
+                    break;
+                }
+                offset += 1;
+            }
+            // Looking for a clear bit without an explicit end treats the
+            // string as padded with zeros on the right
+            match found {
+                Some(position) => position,
+                None if bit == 0 && commands.len() <= 4 => end as i64 + 1,
+                None => -1,
+            }
+        }
+    };
+    write_response(&stream, RespDataType::Integer(position)).await;
+}
+
+// BITOP <AND | OR | XOR | NOT> destkey key [key ...]
+pub async fn handle_bitop(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 4 {
+        return write_response(&stream, wrong_arity("bitop")).await;
+    }
+    let operation = commands[1].to_uppercase();
+    if !matches!(operation.as_str(), "AND" | "OR" | "XOR" | "NOT") {
+        return write_response(&stream, RespDataType::SimpleError("ERR syntax error".to_string())).await;
+    }
+    if operation == "NOT" && commands.len() != 4 {
+        return write_response(&stream, RespDataType::SimpleError("ERR BITOP NOT must be called with a single source key.".to_string())).await;
+    }
+    let destination = commands[2].clone();
+
+    let state_guard = state.lock().await;
+    let mut sources = Vec::new();
+    for key in commands[3..].iter() {
+        match state_guard.get_string(key).await {
+            Ok(value) => sources.push(value.map(|v| v.value).unwrap_or_default()),
+            Err(error) => {
+                drop(state_guard);
+                return write_response(&stream, RespDataType::SimpleError(error)).await;
+            }
+        }
+    }
+
+    // shorter strings behave as if padded with zero bytes
+    let length = sources.iter().map(|s| s.len()).max().unwrap_or(0);
+    let result: Vec<u8> = (0..length)
+        .map(|i| {
+            let mut bytes = sources.iter().map(|s| s.get(i).copied().unwrap_or(0));
+            let first = bytes.next().unwrap_or(0);
+            match operation.as_str() {
+                "AND" => bytes.fold(first, |acc, b| acc & b),
+                "OR" => bytes.fold(first, |acc, b| acc | b),
+                "XOR" => bytes.fold(first, |acc, b| acc ^ b),
+                _ => !first,
+            }
+        })
+        .collect();
+
+    if result.is_empty() {
+        state_guard.del(&destination).await;
+    } else {
+        state_guard.set_string(destination.clone(), result, None).await;
+        state_guard.notify_keyspace_event(NOTIFY_STRING, "set", &destination).await;
+    }
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, RespDataType::Integer(length as i64)).await;
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+#[derive(Clone, Copy)]
+struct BitfieldType {
+    signed: bool,
+    bits: u32,
+}
+
+enum BitfieldOp {
+    Get(BitfieldType, u64),
+    Set(BitfieldType, u64, i64, Overflow),
+    IncrBy(BitfieldType, u64, i64, Overflow),
+}
+
+// i1 .. i64 and u1 .. u63; u64 can't be represented in the integer reply
+fn parse_bitfield_type(value: &str) -> Result<BitfieldType, String> {
+    let error = || "ERR Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.".to_string();
+    let signed = match value.chars().next() {
+        Some('i') | Some('I') => true,
+        Some('u') | Some('U') => false,
+        _ => return Err(error()),
+    };
+    let bits = value[1..].parse::<u32>().map_err(|_| error())?;
+    if bits < 1 || (signed && bits > 64) || (!signed && bits > 63) {
+        return Err(error());
+    }
+    Ok(BitfieldType { signed, bits })
+}
+
+// Offsets prefixed with '#' are multiplied by the type width
+fn parse_bitfield_offset(value: &str, field_type: BitfieldType) -> Result<u64, String> {
+    let offset = match value.strip_prefix('#') {
+        Some(index) => index
+            .parse::<i64>()
+            .ok()
+            .and_then(|index| index.checked_mul(field_type.bits as i64))
+            .ok_or_else(|| BIT_OFFSET_ERROR.to_string())?,
+        None => value.parse::<i64>().map_err(|_| BIT_OFFSET_ERROR.to_string())?,
+    };
+    parse_bit_offset(&offset.to_string())
+}
+
+fn read_bitfield(bytes: &[u8], offset: u64, field_type: BitfieldType) -> i64 {
+    let mut value: u64 = 0;
+    for i in 0..field_type.bits as u64 {
+        value = (value << 1) | get_bit(bytes, offset + i) as u64;
+    }
+    // sign extend negative values
+    if field_type.signed && field_type.bits < 64 && value & (1 << (field_type.bits - 1)) != 0 {
+        value |= u64::MAX << field_type.bits;
+    }
+    value as i64
+}
+
+fn write_bitfield(bytes: &mut [u8], offset: u64, field_type: BitfieldType, value: i64) {
+    let value = value as u64;
+    for i in 0..field_type.bits as u64 {
+        let on = value & (1 << (field_type.bits as u64 - 1 - i)) != 0;
+        set_bit(bytes, offset + i, on);
+    }
+}
+
+// Applies `increment` to `value` for the given type. Returns None when the
+// FAIL policy rejects the overflow.
+fn bitfield_add(value: i64, increment: i64, field_type: BitfieldType, overflow: Overflow) -> Option<i64> {
+    let bits = field_type.bits;
+    let (min, max): (i128, i128) = if field_type.signed {
+        (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1)
+    } else {
+        (0, (1i128 << bits) - 1)
+    };
+    // unsigned values are taken as their two's complement bit pattern, like Redis does
+    let current = if field_type.signed { value as i128 } else { value as u64 as i128 };
+    let result = current + increment as i128;
+    if (min..=max).contains(&result) {
+        return Some(result as i64);
+    }
+    match overflow {
+        Overflow::Fail => None,
+        Overflow::Sat => Some(if result > max { max as i64 } else { min as i64 }),
+        Overflow::Wrap => {
+            let mask = u64::MAX >> (64 - bits);
+            let mut wrapped = (result as u64) & mask;
+            if field_type.signed && wrapped & (1 << (bits - 1)) != 0 {
+                wrapped |= !mask;
+            }
+            Some(wrapped as i64)
+        }
+    }
+}
+
+fn parse_bitfield_ops(args: &[String], read_only: bool) -> Result<Vec<BitfieldOp>, String> {
+    let syntax_error = || "ERR syntax error".to_string();
+    let mut ops = Vec::new();
+    let mut overflow = Overflow::Wrap;
+    let mut i = 0;
+    while i < args.len() {
+        let subcommand = args[i].to_uppercase();
+        let arity = match subcommand.as_str() {
+            "OVERFLOW" => 1,
+            "GET" => 2,
+            "SET" | "INCRBY" => 3,
+            _ => return Err(syntax_error()),
+        };
+        if i + arity >= args.len() {
+            return Err(syntax_error());
+        }
+        if subcommand == "OVERFLOW" {
+            overflow = match args[i + 1].to_uppercase().as_str() {
+                "WRAP" => Overflow::Wrap,
+                "SAT" => Overflow::Sat,
+                "FAIL" => Overflow::Fail,
+                _ => return Err("ERR Invalid OVERFLOW type specified".to_string()),
+            };
+            i += 2;
+            continue;
+        }
+        if read_only && subcommand != "GET" {
+            return Err("ERR BITFIELD_RO only supports the GET subcommand".to_string());
+        }
+
+        let field_type = parse_bitfield_type(&args[i + 1])?;
+        let offset = parse_bitfield_offset(&args[i + 2], field_type)?;
+        if subcommand == "GET" {
+            ops.push(BitfieldOp::Get(field_type, offset));
+        } else {
+            let value = args[i + 3].parse::<i64>().map_err(|_| NOT_AN_INTEGER_ERROR.to_string())?;
+            ops.push(if subcommand == "SET" {
+                BitfieldOp::Set(field_type, offset, value, overflow)
+            } else {
+                BitfieldOp::IncrBy(field_type, offset, value, overflow)
+            });
+        }
+        i += arity + 1;
+    }
+    Ok(ops)
+}
+
+// BITFIELD key [GET type offset] [SET type offset value]
+//   [INCRBY type offset increment] [OVERFLOW WRAP | SAT | FAIL] ...
+// BITFIELD_RO only accepts GET.
+pub async fn handle_bitfield(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    let command_name = commands[0].to_lowercase();
+    if commands.len() < 2 {
+        return write_response(&stream, wrong_arity(&command_name)).await;
+    }
+    let key = commands[1].clone();
+    let ops = match parse_bitfield_ops(&commands[2..], command_name == "bitfield_ro") {
+        Ok(ops) => ops,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+
+    let state_guard = state.lock().await;
+    let (mut bytes, expiration) = match state_guard.get_string(&key).await {
+        Ok(Some(v)) => (v.value, v.expiration_timestamp),
+        Ok(None) => (Vec::new(), None),
+        Err(error) => {
+            drop(state_guard);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+
+    // like Redis, the string is grown to fit every write up front, even if
+    // a FAIL overflow ends up skipping some of them
+    let write_end = ops
+        .iter()
+        .filter_map(|op| match op {
+            BitfieldOp::Set(t, offset, ..) | BitfieldOp::IncrBy(t, offset, ..) => Some(offset + t.bits as u64),
+            BitfieldOp::Get(..) => None,
+        })
+        .max();
+    if let Some(write_end) = write_end {
+        let needed = write_end.div_ceil(8) as usize;
+        if bytes.len() < needed {
+            bytes.resize(needed, 0);
+        }
+    }
+
+    let mut replies = Vec::new();
+    let mut changed = false;
+    for op in ops {
+        replies.push(match op {
+            BitfieldOp::Get(field_type, offset) => RespDataType::Integer(read_bitfield(&bytes, offset, field_type)),
+            BitfieldOp::Set(field_type, offset, value, overflow) => {
+                let old_value = read_bitfield(&bytes, offset, field_type);
+                match bitfield_add(value, 0, field_type, overflow) {
+                    Some(new_value) => {
+                        write_bitfield(&mut bytes, offset, field_type, new_value);
+                        changed = true;
+                        RespDataType::Integer(old_value)
+                    }
+                    None => RespDataType::Nil,
+                }
+            }
+            BitfieldOp::IncrBy(field_type, offset, increment, overflow) => {
+                let old_value = read_bitfield(&bytes, offset, field_type);
+                match bitfield_add(old_value, increment, field_type, overflow) {
+                    Some(new_value) => {
+                        write_bitfield(&mut bytes, offset, field_type, new_value);
+                        changed = true;
+                        RespDataType::Integer(new_value)
+                    }
+                    None => RespDataType::Nil,
+                }
+            }
+        });
+    }
+
+    if write_end.is_some() {
+        state_guard.set_string(key.clone(), bytes, expiration).await;
+        if changed {
+            state_guard.notify_keyspace_event(NOTIFY_STRING, "setbit", &key).await;
+        }
+    }
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" || write_end.is_none() {
+        write_response(&stream, RespDataType::Array(replies)).await;
+    }
+}
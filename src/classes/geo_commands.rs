@@ -0,0 +1,459 @@
+use crate::classes::{
+    Geo::{self, GeoShape},
+    Notifications::NOTIFY_ZSET,
+    Parser::Command,
+    RespDataType::RespDataType,
+    SortedSet::SortedSet,
+    State::State,
+};
+use std::sync::Arc;
+use crate::classes::Connection::{write_response, wrong_arity, SharedWriter};
+use tokio::sync::Mutex;
+
+const SYNTAX_ERROR: &str = "ERR syntax error";
+const NOT_A_FLOAT_ERROR: &str = "ERR value is not a valid float";
+const UNIT_ERROR: &str = "ERR unsupported unit provided. please use M, KM, FT, MI";
+
+// Meters per unit
+fn parse_unit(unit: &str) -> Result<f64, String> {
+    match unit.to_lowercase().as_str() {
+        "m" => Ok(1.0),
+        "km" => Ok(1000.0),
+        "ft" => Ok(0.3048),
+        "mi" => Ok(1609.34),
+        _ => Err(UNIT_ERROR.to_string()),
+    }
+}
+
+fn parse_long_lat(longitude: &str, latitude: &str) -> Result<(f64, f64), String> {
+    let (longitude, latitude) = match (longitude.parse::<f64>(), latitude.parse::<f64>()) {
+        (Ok(longitude), Ok(latitude)) => (longitude, latitude),
+        _ => return Err(NOT_A_FLOAT_ERROR.to_string()),
+    };
+    if !(Geo::GEO_LONG_MIN..=Geo::GEO_LONG_MAX).contains(&longitude)
+        || !(Geo::GEO_LAT_MIN..=Geo::GEO_LAT_MAX).contains(&latitude)
+    {
+        return Err(format!("ERR invalid longitude,latitude pair {:.6},{:.6}", longitude, latitude));
+    }
+    Ok((longitude, latitude))
+}
+
+fn parse_distance(value: &str, error: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(distance) if distance < 0.0 => Err(error.to_string()),
+        Ok(distance) => Ok(distance),
+        Err(_) => Err(NOT_A_FLOAT_ERROR.to_string()),
+    }
+}
+
+fn coordinates_reply(score: f64) -> RespDataType {
+    let (longitude, latitude) = Geo::long_lat_from_score(score);
+    RespDataType::Array(vec![
+        RespDataType::BulkString(Geo::format_coordinate(longitude)),
+        RespDataType::BulkString(Geo::format_coordinate(latitude)),
+    ])
+}
+
+// GEOADD key [NX | XX] [CH] longitude latitude member [longitude latitude member ...]
+pub async fn handle_geoadd(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 5 {
+        return write_response(&stream, wrong_arity("geoadd")).await;
+    }
+    let key = commands[1].clone();
+
+    let (mut nx, mut xx, mut ch) = (false, false, false);
+    let mut i = 2;
+    while i < commands.len() {
+        match commands[i].to_uppercase().as_str() {
+            "NX" => nx = true,
+            "XX" => xx = true,
+            "CH" => ch = true,
+            _ => break,
+        }
+        i += 1;
+    }
+    let triplets = &commands[i..];
+    if triplets.is_empty() || !triplets.len().is_multiple_of(3) {
+        return write_response(&stream, RespDataType::SimpleError(SYNTAX_ERROR.to_string())).await;
+    }
+    if nx && xx {
+        return write_response(&stream, RespDataType::SimpleError("ERR XX and NX options at the same time are not compatible".to_string())).await;
+    }
+
+    let mut elements = Vec::new();
+    for triplet in triplets.chunks(3) {
+        match parse_long_lat(&triplet[0], &triplet[1]) {
+            Ok((longitude, latitude)) => {
+                let score = Geo::score_from_long_lat(longitude, latitude).unwrap_or(0.0);
+                elements.push((score, triplet[2].clone()));
+            }
+            Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+        }
+    }
+
+    let state_guard = state.lock().await;
+    let (mut zset, expiration) = match state_guard.get_sorted_set(&key).await {
+        Ok(Some(v)) => (v.value, v.expiration_timestamp),
+        Ok(None) => (SortedSet::new(), None),
+        Err(error) => {
+            drop(state_guard);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+
+    let (mut added, mut updated) = (0, 0);
+    for (score, member) in elements {
+        match zset.score(&member) {
+            Some(_) if nx => continue,
+            None if xx => continue,
+            Some(current) if current == score => continue,
+            Some(_) => updated += 1,
+            None => added += 1,
+        }
+        zset.insert(&member, score);
+    }
+
+    if added + updated > 0 {
+        state_guard.set_sorted_set(key.clone(), zset, expiration).await;
+        state_guard.notify_keyspace_event(NOTIFY_ZSET, "zadd", &key).await;
+    }
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        let count = if ch { added + updated } else { added };
+        write_response(&stream, RespDataType::Integer(count)).await;
+    }
+}
+
+// GEOPOS key [member [member ...]]
+pub async fn handle_geopos(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 2 {
+        return write_response(&stream, wrong_arity("geopos")).await;
+    }
+    let zset = match state.lock().await.get_sorted_set(&commands[1]).await {
+        Ok(zset) => zset.map(|z| z.value).unwrap_or_default(),
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+    let positions = commands[2..]
+        .iter()
+        .map(|member| zset.score(member).map_or(RespDataType::Nil, coordinates_reply))
+        .collect();
+    write_response(&stream, RespDataType::Array(positions)).await;
+}
+
+// GEODIST key member1 member2 [M | KM | FT | MI]
+pub async fn handle_geodist(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 4 || commands.len() > 5 {
+        return write_response(&stream, wrong_arity("geodist")).await;
+    }
+    let conversion = match commands.get(4).map_or(Ok(1.0), |unit| parse_unit(unit)) {
+        Ok(conversion) => conversion,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+    let zset = match state.lock().await.get_sorted_set(&commands[1]).await {
+        Ok(zset) => zset.map(|z| z.value).unwrap_or_default(),
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+    let response = match (zset.score(&commands[2]), zset.score(&commands[3])) {
+        (Some(first), Some(second)) => {
+            let (lon1, lat1) = Geo::long_lat_from_score(first);
+            let (lon2, lat2) = Geo::long_lat_from_score(second);
+            let distance = Geo::distance(lon1, lat1, lon2, lat2) / conversion;
+            RespDataType::BulkString(format!("{:.4}", distance))
+        }
+        _ => RespDataType::Nil,
+    };
+    write_response(&stream, response).await;
+}
+
+// GEOHASH key [member [member ...]]
+pub async fn handle_geohash(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 2 {
+        return write_response(&stream, wrong_arity("geohash")).await;
+    }
+    let zset = match state.lock().await.get_sorted_set(&commands[1]).await {
+        Ok(zset) => zset.map(|z| z.value).unwrap_or_default(),
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+    let hashes = commands[2..]
+        .iter()
+        .map(|member| {
+            zset.score(member)
+                .map_or(RespDataType::Nil, |score| RespDataType::BulkString(Geo::geohash_string(score)))
+        })
+        .collect();
+    write_response(&stream, RespDataType::Array(hashes)).await;
+}
+
+enum GeoOrigin {
+    Member(String),
+    LongLat(f64, f64),
+}
+
+#[derive(PartialEq)]
+enum GeoSort {
+    None,
+    Asc,
+    Desc,
+}
+
+struct GeoSearchOptions {
+    origin: GeoOrigin,
+    // sizes in the unit of the request
+    shape: GeoShape,
+    conversion: f64,
+    sort: GeoSort,
+    count: Option<usize>,
+    any: bool,
+    with_coord: bool,
+    with_dist: bool,
+    with_hash: bool,
+    store_dist: bool,
+}
+
+// Parses everything after the key(s). WITH* options only exist for
+// GEOSEARCH, STOREDIST only for GEOSEARCHSTORE.
+fn parse_search_options(args: &[String], name: &str, store: bool) -> Result<GeoSearchOptions, String> {
+    let from_error = format!("ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for {}", name);
+    let by_error = format!("ERR exactly one of BYRADIUS and BYBOX can be specified for {}", name);
+
+    let mut origin = None;
+    let mut shape = None;
+    let mut conversion = 1.0;
+    let mut sort = GeoSort::None;
+    let mut count = None;
+    let (mut any, mut with_coord, mut with_dist, mut with_hash, mut store_dist) = (false, false, false, false, false);
+
+    let mut i = 0;
+    while i < args.len() {
+        let remaining = args.len() - i - 1;
+        match args[i].to_uppercase().as_str() {
+            "FROMMEMBER" if remaining >= 1 => {
+                if origin.is_some() {
+                    return Err(from_error);
+                }
+                origin = Some(GeoOrigin::Member(args[i + 1].clone()));
+                i += 1;
+            }
+            "FROMLONLAT" if remaining >= 2 => {
+                if origin.is_some() {
+                    return Err(from_error);
+                }
+                let (longitude, latitude) = parse_long_lat(&args[i + 1], &args[i + 2])?;
+                origin = Some(GeoOrigin::LongLat(longitude, latitude));
+                i += 2;
+            }
+            "BYRADIUS" if remaining >= 2 => {
+                if shape.is_some() {
+                    return Err(by_error);
+                }
+                let radius = parse_distance(&args[i + 1], "ERR radius cannot be negative")?;
+                conversion = parse_unit(&args[i + 2])?;
+                shape = Some(GeoShape::Radius(radius));
+                i += 2;
+            }
+            "BYBOX" if remaining >= 3 => {
+                if shape.is_some() {
+                    return Err(by_error);
+                }
+                let width = parse_distance(&args[i + 1], "ERR height or width cannot be negative")?;
+                let height = parse_distance(&args[i + 2], "ERR height or width cannot be negative")?;
+                conversion = parse_unit(&args[i + 3])?;
+                shape = Some(GeoShape::Box { width, height });
+                i += 3;
+            }
+            "ANY" => any = true,
+            "ASC" => sort = GeoSort::Asc,
+            "DESC" => sort = GeoSort::Desc,
+            "COUNT" if remaining >= 1 => {
+                let value = args[i + 1]
+                    .parse::<i64>()
+                    .map_err(|_| "ERR value is not an integer or out of range".to_string())?;
+                if value <= 0 {
+                    return Err("ERR COUNT must be > 0".to_string());
+                }
+                count = Some(value as usize);
+                i += 1;
+                if args.get(i + 1).is_some_and(|arg| arg.eq_ignore_ascii_case("ANY")) {
+                    any = true;
+                    i += 1;
+                }
+            }
+            "WITHCOORD" if !store => with_coord = true,
+            "WITHDIST" if !store => with_dist = true,
+            "WITHHASH" if !store => with_hash = true,
+            "STOREDIST" if store => store_dist = true,
+            _ => return Err(SYNTAX_ERROR.to_string()),
+        }
+        i += 1;
+    }
+
+    let origin = origin.ok_or(from_error)?;
+    let shape = shape.ok_or(by_error)?;
+    if any && count.is_none() {
+        return Err("ERR the ANY argument requires COUNT argument".to_string());
+    }
+    // the closest N entries need sorting; ANY takes whatever is found first
+    if count.is_some() && !any && sort == GeoSort::None {
+        sort = GeoSort::Asc;
+    }
+    Ok(GeoSearchOptions { origin, shape, conversion, sort, count, any, with_coord, with_dist, with_hash, store_dist })
+}
+
+// (member, distance in meters, score) of the members inside the shape
+fn search(zset: &SortedSet, options: &GeoSearchOptions, center: (f64, f64)) -> Vec<(String, f64, f64)> {
+    let shape = match options.shape {
+        GeoShape::Radius(radius) => GeoShape::Radius(radius * options.conversion),
+        GeoShape::Box { width, height } => GeoShape::Box {
+            width: width * options.conversion,
+            height: height * options.conversion,
+        },
+    };
+    let limit = if options.any { options.count } else { None };
+
+    let mut found = Vec::new();
+    'ranges: for (min, max) in Geo::search_ranges(&shape, center.0, center.1) {
+        for entry in zset.range_by_score(min, max) {
+            let point = Geo::long_lat_from_score(entry.score);
+            if let Some(distance) = Geo::distance_if_in_shape(&shape, center, point) {
+                found.push((entry.member.clone(), distance, entry.score));
+                if limit.is_some_and(|limit| found.len() >= limit) {
+                    break 'ranges;
+                }
+            }
+        }
+    }
+
+    match options.sort {
+        GeoSort::Asc => found.sort_by(|a, b| a.1.total_cmp(&b.1)),
+        GeoSort::Desc => found.sort_by(|a, b| b.1.total_cmp(&a.1)),
+        GeoSort::None => {}
+    }
+    if let Some(count) = options.count {
+        found.truncate(count);
+    }
+    found
+}
+
+// GEOSEARCH key FROMMEMBER member | FROMLONLAT longitude latitude
+//   BYRADIUS radius unit | BYBOX width height unit
+//   [ASC | DESC] [COUNT count [ANY]] [WITHCOORD] [WITHDIST] [WITHHASH]
+// GEOSEARCHSTORE destination source ... [STOREDIST]
+async fn geosearch_generic(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    name: &str,
+    store: bool,
+) {
+    let first_option = if store { 3 } else { 2 };
+    if commands.len() < first_option + 5 {
+        return write_response(&stream, wrong_arity(name)).await;
+    }
+    let destination = if store { Some(commands[1].clone()) } else { None };
+    let key = commands[first_option - 1].clone();
+    let options = match parse_search_options(&commands[first_option..], name, store) {
+        Ok(options) => options,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+
+    let state_guard = state.lock().await;
+    let zset = match state_guard.get_sorted_set(&key).await {
+        Ok(zset) => zset.map(|z| z.value),
+        Err(error) => {
+            drop(state_guard);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+
+    let found = match zset {
+        Some(zset) => {
+            let center = match &options.origin {
+                GeoOrigin::LongLat(longitude, latitude) => Some((*longitude, *latitude)),
+                GeoOrigin::Member(member) => zset.score(member).map(Geo::long_lat_from_score),
+            };
+            let Some(center) = center else {
+                drop(state_guard);
+                return write_response(&stream, RespDataType::SimpleError("ERR could not decode requested zset member".to_string())).await;
+            };
+            search(&zset, &options, center)
+        }
+        None => Vec::new(),
+    };
+
+    let Some(destination) = destination else {
+        drop(state_guard);
+        let plain = !(options.with_coord || options.with_dist || options.with_hash);
+        let items = found
+            .into_iter()
+            .map(|(member, distance, score)| {
+                if plain {
+                    return RespDataType::BulkString(member);
+                }
+                let mut item = vec![RespDataType::BulkString(member)];
+                if options.with_dist {
+                    item.push(RespDataType::BulkString(format!("{:.4}", distance / options.conversion)));
+                }
+                if options.with_hash {
+                    item.push(RespDataType::Integer(score as i64));
+                }
+                if options.with_coord {
+                    item.push(coordinates_reply(score));
+                }
+                RespDataType::Array(item)
+            })
+            .collect();
+        return write_response(&stream, RespDataType::Array(items)).await;
+    };
+
+    // an empty result removes the destination
+    let stored = found.len();
+    let mut result = SortedSet::new();
+    for (member, distance, score) in found {
+        let score = if options.store_dist { distance / options.conversion } else { score };
+        result.insert(&member, score);
+    }
+    if stored > 0 {
+        state_guard.set_sorted_set(destination.clone(), result, None).await;
+        state_guard.notify_keyspace_event(NOTIFY_ZSET, "geosearchstore", &destination).await;
+    } else {
+        state_guard.del(&destination).await;
+    }
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, RespDataType::Integer(stored as i64)).await;
+    }
+}
+
+pub async fn handle_geosearch(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    geosearch_generic(commands, stream, state, "geosearch", false).await;
+}
+
+pub async fn handle_geosearchstore(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    geosearch_generic(commands, stream, state, "geosearchstore", true).await;
+}
@@ -0,0 +1,147 @@
+use crate::classes::{
+    HyperLogLog::HyperLogLog,
+    Notifications::NOTIFY_STRING,
+    Parser::Command,
+    RespDataType::RespDataType,
+    State::State,
+};
+use std::sync::Arc;
+use std::time::Instant;
+use crate::classes::Connection::{write_response, wrong_arity, SharedWriter};
+use tokio::sync::Mutex;
+
+// Loads `key` as a HyperLogLog. Ok(None) for a missing key, Err with the
+// reply for other types and for strings that aren't a valid HyperLogLog.
+async fn lookup_hll(state: &State, key: &str) -> Result<Option<(HyperLogLog, Option<Instant>)>, String> {
+    match state.get_string(key).await? {
+        None => Ok(None),
+        Some(v) => HyperLogLog::from_bytes(&v.value)
+            .map(|hll| Some((hll, v.expiration_timestamp)))
+            .map_err(|error| error.to_string()),
+    }
+}
+
+// PFADD key [element [element ...]]
+pub async fn handle_pfadd(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 2 {
+        return write_response(&stream, wrong_arity("pfadd")).await;
+    }
+    let key = commands[1].clone();
+
+    let state_guard = state.lock().await;
+    let (mut hll, expiration, mut updated) = match lookup_hll(&state_guard, &key).await {
+        Ok(Some((hll, expiration))) => (hll, expiration, false),
+        // creating the key counts as an update even without elements
+        Ok(None) => (HyperLogLog::new(), None, true),
+        Err(error) => {
+            drop(state_guard);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+    for element in commands.args()[2..].iter() {
+        updated |= hll.add(element);
+    }
+
+    if updated {
+        state_guard.set_string(key.clone(), hll.to_bytes(), expiration).await;
+        state_guard.notify_keyspace_event(NOTIFY_STRING, "pfadd", &key).await;
+    }
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, RespDataType::Integer(updated as i64)).await;
+    }
+}
+
+// PFCOUNT key [key ...]
+// A single key uses (and refreshes) the cardinality cached in the header,
+// several keys are counted on a temporary union.
+pub async fn handle_pfcount(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 2 {
+        return write_response(&stream, wrong_arity("pfcount")).await;
+    }
+
+    let state_guard = state.lock().await;
+    let response = if commands.len() == 2 {
+        let key = &commands[1];
+        match lookup_hll(&state_guard, key).await {
+            Ok(None) => RespDataType::Integer(0),
+            Ok(Some((mut hll, expiration))) => match hll.cached_count() {
+                Some(count) => RespDataType::Integer(count as i64),
+                None => {
+                    let count = hll.count();
+                    state_guard.set_string(key.clone(), hll.to_bytes(), expiration).await;
+                    RespDataType::Integer(count as i64)
+                }
+            },
+            Err(error) => RespDataType::SimpleError(error),
+        }
+    } else {
+        let mut union = HyperLogLog::new();
+        let mut error = None;
+        for key in commands[1..].iter() {
+            match lookup_hll(&state_guard, key).await {
+                Ok(Some((hll, _))) => union.merge(&hll),
+                Ok(None) => {}
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        match error {
+            Some(error) => RespDataType::SimpleError(error),
+            None => RespDataType::Integer(union.count() as i64),
+        }
+    };
+    drop(state_guard);
+    write_response(&stream, response).await;
+}
+
+// PFMERGE destkey [sourcekey [sourcekey ...]]
+// The destination takes part in the union when it already exists.
+pub async fn handle_pfmerge(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 2 {
+        return write_response(&stream, wrong_arity("pfmerge")).await;
+    }
+    let destination = commands[1].clone();
+
+    let state_guard = state.lock().await;
+    let mut union = HyperLogLog::new();
+    let mut expiration = None;
+    for (i, key) in commands[1..].iter().enumerate() {
+        match lookup_hll(&state_guard, key).await {
+            Ok(Some((hll, key_expiration))) => {
+                if i == 0 {
+                    expiration = key_expiration;
+                }
+                union.merge(&hll);
+            }
+            Ok(None) => {}
+            Err(error) => {
+                drop(state_guard);
+                return write_response(&stream, RespDataType::SimpleError(error)).await;
+            }
+        }
+    }
+
+    state_guard.set_string(destination.clone(), union.to_bytes(), expiration).await;
+    state_guard.notify_keyspace_event(NOTIFY_STRING, "pfadd", &destination).await;
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, RespDataType::SimpleString("OK".to_string())).await;
+    }
+}
@@ -0,0 +1,665 @@
+use crate::classes::{
+    ExpiringValue::instant_to_unix_millis,
+    Glob::glob_match,
+    Parser::Command,
+    RespDataType::RespDataType,
+    State::{select_db, selected_db, State},
+};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use crate::classes::Connection::{write_response, wrong_arity, SharedWriter};
+use tokio::sync::Mutex;
+
+pub async fn handle_del(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 2 {
+        stream.lock().await
+            .write_all(RespDataType::SimpleError(format!("ERR wrong number of arguments for '{}' command", commands[0].to_lowercase())).to_string().as_bytes())
+            .await
+            .unwrap();
+        return;
+    }
+
+    let mut deleted = 0;
+    for key in commands[1..].iter() {
+        if state.lock().await.del(key).await {
+            deleted += 1;
+        }
+    }
+
+    let role = state.lock().await.get_role().await;
+    if role == "master" {
+        stream.lock().await
+            .write_all(RespDataType::Integer(deleted).to_string().as_bytes())
+            .await
+            .unwrap();
+    }
+}
+
+pub async fn handle_exists(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 2 {
+        stream.lock().await
+            .write_all(RespDataType::SimpleError("ERR wrong number of arguments for 'exists' command".to_string()).to_string().as_bytes())
+            .await
+            .unwrap();
+        return;
+    }
+
+    // a key given several times is counted several times, like Redis does
+    let mut count = 0;
+    for key in commands[1..].iter() {
+        if state.lock().await.exists(key).await {
+            count += 1;
+        }
+    }
+    stream.lock().await
+        .write_all(RespDataType::Integer(count).to_string().as_bytes())
+        .await
+        .unwrap();
+}
+
+// Shared by EXPIRE, PEXPIRE, EXPIREAT and PEXPIREAT: `unit_ms` converts the
+// argument to milliseconds and `absolute` tells whether it is a unix time.
+async fn expire_generic(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    unit_ms: i64,
+    absolute: bool,
+) {
+    let command_name = commands[0].to_lowercase();
+    if commands.len() < 3 {
+        stream.lock().await
+            .write_all(RespDataType::SimpleError(format!("ERR wrong number of arguments for '{}' command", command_name)).to_string().as_bytes())
+            .await
+            .unwrap();
+        return;
+    }
+
+    let key = commands[1].clone();
+    let amount = match commands[2].parse::<i64>() {
+        Ok(n) => n,
+        Err(_) => {
+            stream.lock().await
+                .write_all(RespDataType::SimpleError("ERR value is not an integer or out of range".to_string()).to_string().as_bytes())
+                .await
+                .unwrap();
+            return;
+        }
+    };
+
+    let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+    for option in commands[3..].iter() {
+        match option.to_uppercase().as_str() {
+            "NX" => nx = true,
+            "XX" => xx = true,
+            "GT" => gt = true,
+            "LT" => lt = true,
+            _ => {
+                stream.lock().await
+                    .write_all(RespDataType::SimpleError(format!("ERR Unsupported option {}", option)).to_string().as_bytes())
+                    .await
+                    .unwrap();
+                return;
+            }
+        }
+    }
+    let option_error = if nx && (xx || gt || lt) {
+        Some("ERR NX and XX, GT or LT options at the same time are not compatible")
+    } else if gt && lt {
+        Some("ERR GT and LT options at the same time are not compatible")
+    } else {
+        None
+    };
+    if let Some(option_error) = option_error {
+        stream.lock().await
+            .write_all(RespDataType::SimpleError(option_error.to_string()).to_string().as_bytes())
+            .await
+            .unwrap();
+        return;
+    }
+
+    let now_ms = instant_to_unix_millis(Instant::now());
+    let target_ms = match amount.checked_mul(unit_ms).and_then(|ms| if absolute { Some(ms) } else { ms.checked_add(now_ms) }) {
+        Some(ms) => ms,
+        None => {
+            stream.lock().await
+                .write_all(RespDataType::SimpleError(format!("ERR invalid expire time in '{}' command", command_name)).to_string().as_bytes())
+                .await
+                .unwrap();
+            return;
+        }
+    };
+
+    let state_guard = state.lock().await;
+    let current = match state_guard.get_value(&key).await {
+        Some(current) => current,
+        None => {
+            drop(state_guard);
+            stream.lock().await.write_all(RespDataType::Integer(0).to_string().as_bytes()).await.unwrap();
+            return;
+        }
+    };
+
+    // a key without TTL counts as an infinite TTL for GT and LT
+    let current_ms = current.expiration_timestamp.map(instant_to_unix_millis);
+    let allowed = match current_ms {
+        _ if nx => current_ms.is_none(),
+        None if xx || gt => false,
+        Some(current_ms) if gt => target_ms > current_ms,
+        Some(current_ms) if lt => target_ms < current_ms,
+        _ => true,
+    };
+    if !allowed {
+        drop(state_guard);
+        stream.lock().await.write_all(RespDataType::Integer(0).to_string().as_bytes()).await.unwrap();
+        return;
+    }
+
+    // an expiration in the past deletes the key right away
+    if target_ms <= now_ms {
+        state_guard.del(&key).await;
+    } else {
+        let expiration = Instant::now() + Duration::from_millis((target_ms - now_ms) as u64);
+        state_guard.set_expiration(&key, Some(expiration)).await;
+    }
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        stream.lock().await.write_all(RespDataType::Integer(1).to_string().as_bytes()).await.unwrap();
+    }
+}
+
+pub async fn handle_expire(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    expire_generic(commands, stream, state, 1000, false).await;
+}
+
+pub async fn handle_pexpire(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    expire_generic(commands, stream, state, 1, false).await;
+}
+
+pub async fn handle_expireat(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    expire_generic(commands, stream, state, 1000, true).await;
+}
+
+pub async fn handle_pexpireat(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    expire_generic(commands, stream, state, 1, true).await;
+}
+
+pub async fn handle_persist(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 2 {
+        stream.lock().await
+            .write_all(RespDataType::SimpleError("ERR wrong number of arguments for 'persist' command".to_string()).to_string().as_bytes())
+            .await
+            .unwrap();
+        return;
+    }
+
+    let state_guard = state.lock().await;
+    let has_ttl = state_guard
+        .get_value(&commands[1])
+        .await
+        .is_some_and(|v| v.expiration_timestamp.is_some());
+    if has_ttl {
+        state_guard.set_expiration(&commands[1], None).await;
+    }
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        stream.lock().await
+            .write_all(RespDataType::Integer(has_ttl as i64).to_string().as_bytes())
+            .await
+            .unwrap();
+    }
+}
+
+// TTL/PTTL (relative, `absolute == false`) and EXPIRETIME/PEXPIRETIME
+async fn ttl_generic(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    in_ms: bool,
+    absolute: bool,
+) {
+    if commands.len() != 2 {
+        stream.lock().await
+            .write_all(RespDataType::SimpleError(format!("ERR wrong number of arguments for '{}' command", commands[0].to_lowercase())).to_string().as_bytes())
+            .await
+            .unwrap();
+        return;
+    }
+
+    let reply = match state.lock().await.get_value(&commands[1]).await {
+        None => -2,
+        Some(v) => match v.expiration_timestamp {
+            None => -1,
+            Some(expiration) if absolute => {
+                let ms = instant_to_unix_millis(expiration);
+                if in_ms { ms } else { ms / 1000 }
+            }
+            Some(expiration) => {
+                let ms = expiration.saturating_duration_since(Instant::now()).as_millis() as i64;
+                if in_ms { ms } else { (ms + 500) / 1000 }
+            }
+        },
+    };
+    stream.lock().await
+        .write_all(RespDataType::Integer(reply).to_string().as_bytes())
+        .await
+        .unwrap();
+}
+
+pub async fn handle_ttl(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    ttl_generic(commands, stream, state, false, false).await;
+}
+
+pub async fn handle_pttl(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    ttl_generic(commands, stream, state, true, false).await;
+}
+
+pub async fn handle_expiretime(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    ttl_generic(commands, stream, state, false, true).await;
+}
+
+pub async fn handle_pexpiretime(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    ttl_generic(commands, stream, state, true, true).await;
+}
+
+pub async fn handle_rename(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    let is_nx = commands[0].to_uppercase() == "RENAMENX";
+    if commands.len() != 3 {
+        stream.lock().await
+            .write_all(RespDataType::SimpleError(format!("ERR wrong number of arguments for '{}' command", commands[0].to_lowercase())).to_string().as_bytes())
+            .await
+            .unwrap();
+        return;
+    }
+
+    let (key, new_key) = (&commands[1], &commands[2]);
+    let state_guard = state.lock().await;
+    let response = if !state_guard.exists(key).await {
+        RespDataType::SimpleError("ERR no such key".to_string())
+    } else if is_nx && state_guard.exists(new_key).await {
+        RespDataType::Integer(0)
+    } else {
+        if key != new_key {
+            state_guard.rename(key, new_key).await;
+        }
+        if is_nx {
+            RespDataType::Integer(1)
+        } else {
+            RespDataType::SimpleString("OK".to_string())
+        }
+    };
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        stream.lock().await
+            .write_all(response.to_string().as_bytes())
+            .await
+            .unwrap();
+    }
+}
+
+// A database index argument, checked against the configured `databases`
+fn parse_db_index(value: &str, databases: usize) -> Result<usize, String> {
+    match value.parse::<i64>() {
+        Ok(db) if db < 0 || db as usize >= databases => Err("ERR DB index is out of range".to_string()),
+        Ok(db) => Ok(db as usize),
+        Err(_) => Err("ERR value is not an integer or out of range".to_string()),
+    }
+}
+
+// SELECT index
+pub async fn handle_select(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 2 {
+        return write_response(&stream, wrong_arity("select")).await;
+    }
+    let databases = state.lock().await.get_databases().await;
+    match parse_db_index(&commands[1], databases) {
+        Ok(db) => {
+            select_db(db);
+            write_response(&stream, RespDataType::SimpleString("OK".to_string())).await;
+        }
+        Err(error) => write_response(&stream, RespDataType::SimpleError(error)).await,
+    }
+}
+
+// DBSIZE
+// Expired keys not reclaimed yet are counted, like Redis does.
+pub async fn handle_dbsize(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 1 {
+        return write_response(&stream, wrong_arity("dbsize")).await;
+    }
+    let size = state.lock().await.dbsize().await;
+    write_response(&stream, RespDataType::Integer(size as i64)).await;
+}
+
+// MOVE key db
+pub async fn handle_move(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 3 {
+        return write_response(&stream, wrong_arity("move")).await;
+    }
+    let state_guard = state.lock().await;
+    let db = match parse_db_index(&commands[2], state_guard.get_databases().await) {
+        Ok(db) if db == selected_db() => Err("ERR source and destination objects are the same".to_string()),
+        result => result,
+    };
+    let response = match db {
+        Ok(db) => RespDataType::Integer(state_guard.move_key(&commands[1], db).await as i64),
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" || matches!(response, RespDataType::SimpleError(_)) {
+        write_response(&stream, response).await;
+    }
+}
+
+// SWAPDB index1 index2
+pub async fn handle_swapdb(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 3 {
+        return write_response(&stream, wrong_arity("swapdb")).await;
+    }
+    let state_guard = state.lock().await;
+    let databases = state_guard.get_databases().await;
+    let mut indexes = Vec::with_capacity(2);
+    for (value, position) in [(&commands[1], "first"), (&commands[2], "second")] {
+        match parse_db_index(value, databases) {
+            Ok(db) => indexes.push(db),
+            Err(error) => {
+                drop(state_guard);
+                let error = match value.parse::<i64>() {
+                    Ok(_) => error,
+                    Err(_) => format!("ERR invalid {} DB index", position),
+                };
+                return write_response(&stream, RespDataType::SimpleError(error)).await;
+            }
+        }
+    }
+    state_guard.swap_databases(indexes[0], indexes[1]).await;
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, RespDataType::SimpleString("OK".to_string())).await;
+    }
+}
+
+// FLUSHDB [ASYNC | SYNC]
+pub async fn handle_flushdb(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    flush_generic(commands, stream, state, Some(selected_db())).await;
+}
+
+// FLUSHALL [ASYNC | SYNC]
+pub async fn handle_flushall(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    flush_generic(commands, stream, state, None).await;
+}
+
+// Empties one database, or all of them with None. ASYNC frees the removed
+// keys on a blocking thread instead of before replying.
+async fn flush_generic(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    db: Option<usize>,
+) {
+    if commands.len() > 2 {
+        return write_response(&stream, wrong_arity(&commands[0].to_lowercase())).await;
+    }
+    let lazy = match commands.get(1).map(|option| option.to_uppercase()) {
+        None => false,
+        Some(option) if option == "SYNC" => false,
+        Some(option) if option == "ASYNC" => true,
+        Some(_) => return write_response(&stream, RespDataType::SimpleError("ERR syntax error".to_string())).await,
+    };
+
+    let state_guard = state.lock().await;
+    let flushed = state_guard.flush(db).await;
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if lazy {
+        tokio::task::spawn_blocking(move || drop(flushed));
+    } else {
+        drop(flushed);
+    }
+
+    if role == "master" {
+        write_response(&stream, RespDataType::SimpleString("OK".to_string())).await;
+    }
+}
+
+// The options shared by SCAN and ZSCAN
+pub struct ScanOptions {
+    pub count: usize,
+    pub pattern: Option<String>,
+    pub value_type: Option<String>,
+}
+
+pub fn parse_cursor(value: &str) -> Result<u64, String> {
+    value.parse::<u64>().map_err(|_| "ERR invalid cursor".to_string())
+}
+
+// [MATCH pattern] [COUNT count] [TYPE type], TYPE only for SCAN
+pub fn parse_scan_options(options: &[String], allow_type: bool) -> Result<ScanOptions, String> {
+    let mut parsed = ScanOptions { count: 10, pattern: None, value_type: None };
+    let mut i = 0;
+    while i < options.len() {
+        let value = options.get(i + 1).ok_or("ERR syntax error".to_string())?;
+        match options[i].to_uppercase().as_str() {
+            "COUNT" => {
+                parsed.count = match value.parse::<i64>() {
+                    Ok(count) if count < 1 => return Err("ERR syntax error".to_string()),
+                    Ok(count) => count as usize,
+                    Err(_) => return Err("ERR value is not an integer or out of range".to_string()),
+                };
+            }
+            // `*` matches everything, so there is nothing to filter
+            "MATCH" => parsed.pattern = Some(value.clone()).filter(|pattern| pattern != "*"),
+            "TYPE" if allow_type => {
+                if !["string", "list", "set", "zset", "hash", "stream"].contains(&value.to_lowercase().as_str()) {
+                    return Err(format!("ERR unknown type name '{}'", value));
+                }
+                parsed.value_type = Some(value.to_lowercase());
+            }
+            _ => return Err("ERR syntax error".to_string()),
+        }
+        i += 2;
+    }
+    Ok(parsed)
+}
+
+// [cursor, [element ...]], the reply of every SCAN variant
+pub fn scan_reply(cursor: u64, elements: Vec<RespDataType>) -> RespDataType {
+    RespDataType::Array(vec![RespDataType::BulkString(cursor.to_string()), RespDataType::Array(elements)])
+}
+
+// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]
+// Every key present from the first call to the last is returned at least
+// once; MATCH and TYPE filter what COUNT already picked.
+pub async fn handle_scan(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 2 {
+        return write_response(&stream, wrong_arity("scan")).await;
+    }
+    let cursor = parse_cursor(&commands[1]);
+    let options = cursor.and_then(|cursor| Ok((cursor, parse_scan_options(&commands[2..], true)?)));
+    let (cursor, options) = match options {
+        Ok(options) => options,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+
+    let (next_cursor, keys) = state.lock().await.scan(cursor, options.count).await;
+    let keys = keys
+        .into_iter()
+        .filter(|(key, _)| options.pattern.as_ref().is_none_or(|pattern| glob_match(pattern, key)))
+        .filter(|(_, value_type)| options.value_type.as_ref().is_none_or(|wanted| wanted == value_type))
+        .map(|(key, _)| RespDataType::BulkString(key))
+        .collect();
+    write_response(&stream, scan_reply(next_cursor, keys)).await;
+}
+
+// Integers below this are shared objects in Redis, unless an LRU or LFU
+// policy needs every key to have its own access data
+const OBJ_SHARED_INTEGERS: i64 = 10000;
+const OBJ_SHARED_REFCOUNT: i64 = i32::MAX as i64;
+
+// OBJECT ENCODING key
+// OBJECT FREQ key
+// OBJECT IDLETIME key
+// OBJECT REFCOUNT key
+// OBJECT HELP
+// Inspecting a key doesn't count as an access to it.
+pub async fn handle_object(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 2 {
+        return write_response(&stream, wrong_arity("object")).await;
+    }
+    let subcommand = commands[1].to_uppercase();
+    let arity_ok = match subcommand.as_str() {
+        "ENCODING" | "FREQ" | "IDLETIME" | "REFCOUNT" => commands.len() == 3,
+        "HELP" => commands.len() == 2,
+        _ => {
+            let error = format!("ERR unknown subcommand '{}'. Try OBJECT HELP.", commands[1]);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+    if !arity_ok {
+        return write_response(&stream, wrong_arity(&format!("object|{}", subcommand.to_lowercase()))).await;
+    }
+    if subcommand == "HELP" {
+        let lines = [
+            "OBJECT <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+            "ENCODING <key>",
+            "    Return the kind of internal representation used in order to store the value",
+            "    associated with a <key>.",
+            "FREQ <key>",
+            "    Return the access frequency index of the <key>. The returned integer is",
+            "    proportional to the logarithm of the recent access frequency of the key.",
+            "IDLETIME <key>",
+            "    Return the idle time of the <key>, that is the approximated number of",
+            "    seconds elapsed since the last access to the key.",
+            "REFCOUNT <key>",
+            "    Return the number of references of the value associated with the specified",
+            "    <key>.",
+            "HELP",
+            "    Print this help.",
+        ];
+        let lines = lines.iter().map(|line| RespDataType::SimpleString(line.to_string())).collect();
+        return write_response(&stream, RespDataType::Array(lines)).await;
+    }
+
+    let state_guard = state.lock().await;
+    let maxmemory = state_guard.get_maxmemory_config().await;
+    let adjust_note = "Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.";
+    match subcommand.as_str() {
+        "IDLETIME" if maxmemory.policy.uses_lfu() => {
+            drop(state_guard);
+            let error = format!("ERR An LFU maxmemory policy is selected, idle time not tracked. {}", adjust_note);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+        "FREQ" if !maxmemory.policy.uses_lfu() => {
+            drop(state_guard);
+            let error = format!("ERR An LFU maxmemory policy is not selected, access frequency not tracked. {}", adjust_note);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+        _ => {}
+    }
+    let shares_integers = maxmemory.maxmemory == 0 || !(maxmemory.policy.uses_lru() || maxmemory.policy.uses_lfu());
+    let response = state_guard
+        .inspect_key(&commands[2], |value, access| match subcommand.as_str() {
+            "ENCODING" => RespDataType::BulkString(value.encoding().to_string()),
+            "FREQ" => RespDataType::Integer(access.lfu_count(maxmemory.lfu_decay_time) as i64),
+            "IDLETIME" => RespDataType::Integer((access.idle_millis() / 1000) as i64),
+            _ => {
+                let shared = value.encoding() == "int"
+                    && shares_integers
+                    && value
+                        .as_string()
+                        .and_then(|s| std::str::from_utf8(s).ok()?.parse::<i64>().ok())
+                        .is_some_and(|number| (0..OBJ_SHARED_INTEGERS).contains(&number));
+                RespDataType::Integer(if shared { OBJ_SHARED_REFCOUNT } else { 1 })
+            }
+        })
+        .await
+        .unwrap_or(RespDataType::Nil);
+    drop(state_guard);
+    write_response(&stream, response).await;
+}
@@ -1,14 +1,20 @@
-use crate::classes::{RespDataType::RespDataType, State::State};
+use crate::classes::{
+    Blocking::{wait_until_ready, WakeReason},
+    CommandExecutor::CommandExecutor,
+    Notifications::NOTIFY_LIST,
+    Parser::Command,
+    RespDataType::RespDataType,
+    State::State,
+};
 use std::sync::Arc;
-use tokio::net::tcp::OwnedWriteHalf;
+use crate::classes::Connection::{write_response, wrong_arity, SharedReader, SharedWriter};
 use tokio::sync::Mutex;
 use tokio::io::AsyncWriteExt;
-use tokio::time::{timeout, Duration};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 pub async fn handle_rpush(
-    commands: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+    commands: &Command,
+    stream: SharedWriter,
     state: Arc<Mutex<State>>,
 ) {
     if commands.len() < 3 {
@@ -22,17 +28,20 @@ pub async fn handle_rpush(
     let key = &commands[1];
     let elements: Vec<String> = commands[2..].to_vec();
     
-    let list_length = state.lock().await.rpush(key.clone(), elements).await;
+    let response = match state.lock().await.rpush(key.clone(), elements).await {
+        Ok(list_length) => RespDataType::Integer(list_length as i64),
+        Err(error) => RespDataType::SimpleError(error),
+    };
     
     stream.lock().await
-        .write_all(RespDataType::Integer(list_length as i64).to_string().as_bytes())
+        .write_all(response.to_string().as_bytes())
         .await
         .unwrap();
 }
 
 pub async fn handle_lrange(
-    commands: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+    commands: &Command,
+    stream: SharedWriter,
     state: Arc<Mutex<State>>,
 ) {
     if commands.len() != 4 {
@@ -65,22 +74,21 @@ pub async fn handle_lrange(
         }
     };
     
-    let elements = state.lock().await.lrange(key, start, stop).await;
-    
-    // Convert elements to RESP BulkString array
-    let resp_elements: Vec<RespDataType> = elements.into_iter()
-        .map(|element| RespDataType::BulkString(element))
-        .collect();
+    let response = match state.lock().await.lrange(key, start, stop).await {
+        // Convert elements to RESP BulkString array
+        Ok(elements) => RespDataType::Array(elements.into_iter().map(RespDataType::BulkString).collect()),
+        Err(error) => RespDataType::SimpleError(error),
+    };
     
     stream.lock().await
-        .write_all(RespDataType::Array(resp_elements).to_string().as_bytes())
+        .write_all(response.to_string().as_bytes())
         .await
         .unwrap();
 }
 
 pub async fn handle_lpush(
-    commands: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+    commands: &Command,
+    stream: SharedWriter,
     state: Arc<Mutex<State>>,
 ) {
     if commands.len() < 3 {
@@ -94,17 +102,20 @@ pub async fn handle_lpush(
     let key = &commands[1];
     let elements: Vec<String> = commands[2..].to_vec();
     
-    let list_length = state.lock().await.lpush(key.clone(), elements).await;
+    let response = match state.lock().await.lpush(key.clone(), elements).await {
+        Ok(list_length) => RespDataType::Integer(list_length as i64),
+        Err(error) => RespDataType::SimpleError(error),
+    };
     
     stream.lock().await
-        .write_all(RespDataType::Integer(list_length as i64).to_string().as_bytes())
+        .write_all(response.to_string().as_bytes())
         .await
         .unwrap();
 }
 
 pub async fn handle_llen(
-    commands: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+    commands: &Command,
+    stream: SharedWriter,
     state: Arc<Mutex<State>>,
 ) {
     if commands.len() != 2 {
@@ -115,129 +126,918 @@ pub async fn handle_llen(
         return;
     }
     let key = &commands[1];
-    let len = state.lock().await.llen(key).await;
+    let response = match state.lock().await.llen(key).await {
+        Ok(len) => RespDataType::Integer(len as i64),
+        Err(error) => RespDataType::SimpleError(error),
+    };
     stream.lock().await
-        .write_all(RespDataType::Integer(len as i64).to_string().as_bytes())
+        .write_all(response.to_string().as_bytes())
         .await
         .unwrap();
 }
 
-pub async fn handle_lpop(
-    commands: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+const NOT_AN_INTEGER_ERROR: &str = "ERR value is not an integer or out of range";
+const SYNTAX_ERROR: &str = "ERR syntax error";
+const TIMEOUT_OUT_OF_RANGE_ERROR: &str = "ERR timeout is out of range";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+impl ListEnd {
+    pub fn parse(value: &str) -> Option<ListEnd> {
+        match value.to_uppercase().as_str() {
+            "LEFT" => Some(ListEnd::Left),
+            "RIGHT" => Some(ListEnd::Right),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            ListEnd::Left => "LEFT",
+            ListEnd::Right => "RIGHT",
+        }
+    }
+
+    fn pop_event(&self) -> &'static str {
+        match self {
+            ListEnd::Left => "lpop",
+            ListEnd::Right => "rpop",
+        }
+    }
+
+    fn push_event(&self) -> &'static str {
+        match self {
+            ListEnd::Left => "lpush",
+            ListEnd::Right => "rpush",
+        }
+    }
+}
+
+// Integers for COUNT-like arguments, where Redis rejects negative values
+// with its own message
+fn parse_positive(value: &str) -> Result<usize, String> {
+    match value.parse::<i64>() {
+        Ok(n) if n < 0 => Err("ERR value is out of range, must be positive".to_string()),
+        Ok(n) => Ok(n as usize),
+        Err(_) => Err(NOT_AN_INTEGER_ERROR.to_string()),
+    }
+}
+
+// Pops up to `count` elements from one end of the list at `key`, emitting
+// the pop event (and "del" when the list is emptied). Ok(None) when the key
+// doesn't exist.
+pub async fn pop_elements(state: &State, key: &str, end: ListEnd, count: usize) -> Result<Option<Vec<String>>, String> {
+    let popped = state
+        .with_list_mut(key, |elements| {
+            let count = count.min(elements.len());
+            let popped: Vec<String> = match end {
+                ListEnd::Left => elements.drain(..count).collect(),
+                ListEnd::Right => (0..count).filter_map(|_| elements.pop()).collect(),
+            };
+            (popped, elements.is_empty())
+        })
+        .await?;
+    let Some((popped, emptied)) = popped else {
+        return Ok(None);
+    };
+    if !popped.is_empty() {
+        state.notify_keyspace_event(NOTIFY_LIST, end.pop_event(), key).await;
+    }
+    if emptied {
+        state.del(key).await;
+    }
+    Ok(Some(popped))
+}
+
+// Atomically pops an element from `source` and pushes it to `destination`,
+// which may be the same list. Ok(None) when `source` doesn't exist.
+pub async fn move_element(
+    state: &State,
+    source: &str,
+    destination: &str,
+    from: ListEnd,
+    to: ListEnd,
+) -> Result<Option<String>, String> {
+    if state.with_list(source, |_| ()).await?.is_none() {
+        return Ok(None);
+    }
+    // the destination type is checked before anything is popped
+    let destination_exists = state.with_list(destination, |_| ()).await?.is_some();
+
+    let pop = |elements: &mut Vec<String>| match from {
+        ListEnd::Left => elements.remove(0),
+        ListEnd::Right => elements.pop().unwrap_or_default(),
+    };
+    let push = |list: &mut Vec<String>, element: String| match to {
+        ListEnd::Left => list.insert(0, element),
+        ListEnd::Right => list.push(element),
+    };
+    let mut emptied = false;
+    let element = if source == destination {
+        state
+            .with_list_mut(source, |elements| {
+                let element = pop(elements);
+                push(elements, element.clone());
+                element
+            })
+            .await?
+            .unwrap_or_default()
+    } else {
+        let element = state
+            .with_list_mut(source, |elements| {
+                let element = pop(elements);
+                emptied = elements.is_empty();
+                element
+            })
+            .await?
+            .unwrap_or_default();
+        if destination_exists {
+            state.with_list_mut(destination, |target| push(target, element.clone())).await?;
+        } else {
+            state.set_list(destination.to_string(), vec![element.clone()], None).await;
+        }
+        element
+    };
+    state.signal_key_as_ready(destination).await;
+    state.notify_keyspace_event(NOTIFY_LIST, to.push_event(), destination).await;
+    state.notify_keyspace_event(NOTIFY_LIST, from.pop_event(), source).await;
+    if emptied {
+        state.del(source).await;
+    }
+    Ok(Some(element))
+}
+
+// LPOP key [count] / RPOP key [count]
+async fn pop_generic(
+    commands: &Command,
+    stream: SharedWriter,
     state: Arc<Mutex<State>>,
+    name: &str,
+    end: ListEnd,
 ) {
     if commands.len() < 2 || commands.len() > 3 {
-        stream.lock().await
-            .write_all(RespDataType::SimpleError("ERR wrong number of arguments for LPOP command".to_string()).to_string().as_bytes())
-            .await
-            .unwrap();
-        return;
+        return write_response(&stream, wrong_arity(name)).await;
     }
+    let count = match commands.get(2).map(|count| parse_positive(count)) {
+        Some(Ok(count)) => Some(count),
+        Some(Err(error)) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+        None => None,
+    };
 
-    let key = &commands[1];
-    if commands.len() == 3 {
-        // LPOP key count
-        let count = match commands[2].parse::<usize>() {
-            Ok(n) if n > 0 => n,
-            _ => {
-                stream.lock().await
-                    .write_all(RespDataType::SimpleError("ERR value is not an integer or out of range".to_string()).to_string().as_bytes())
-                    .await
-                    .unwrap();
-                return;
+    let state_guard = state.lock().await;
+    let response = match pop_elements(&state_guard, &commands[1], end, count.unwrap_or(1)).await {
+        Ok(None) if count.is_some() => RespDataType::NilArray,
+        Ok(None) => RespDataType::Nil,
+        Ok(Some(popped)) => match count {
+            Some(_) => RespDataType::Array(popped.into_iter().map(RespDataType::BulkString).collect()),
+            None => popped.into_iter().next().map_or(RespDataType::Nil, RespDataType::BulkString),
+        },
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, response).await;
+    }
+}
+
+pub async fn handle_lpop(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    pop_generic(commands, stream, state, "lpop", ListEnd::Left).await;
+}
+
+pub async fn handle_rpop(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    pop_generic(commands, stream, state, "rpop", ListEnd::Right).await;
+}
+
+// Resolves a possibly negative index against a list of `len` elements
+fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    if index < 0 || index >= len as i64 { None } else { Some(index as usize) }
+}
+
+// LINDEX key index
+pub async fn handle_lindex(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 3 {
+        return write_response(&stream, wrong_arity("lindex")).await;
+    }
+    let Ok(index) = commands[2].parse::<i64>() else {
+        return write_response(&stream, RespDataType::SimpleError(NOT_AN_INTEGER_ERROR.to_string())).await;
+    };
+    let element = state
+        .lock()
+        .await
+        .with_list(&commands[1], |list| resolve_index(index, list.len()).map(|i| list[i].clone()))
+        .await;
+    let response = match element {
+        Ok(element) => element.flatten().map_or(RespDataType::Nil, RespDataType::BulkString),
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    write_response(&stream, response).await;
+}
+
+// LSET key index element
+pub async fn handle_lset(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 4 {
+        return write_response(&stream, wrong_arity("lset")).await;
+    }
+    let key = commands[1].clone();
+    let Ok(index) = commands[2].parse::<i64>() else {
+        return write_response(&stream, RespDataType::SimpleError(NOT_AN_INTEGER_ERROR.to_string())).await;
+    };
+
+    let state_guard = state.lock().await;
+    let response = match state_guard.get_list(&key).await {
+        Ok(Some(mut list)) => match resolve_index(index, list.value.len()) {
+            Some(i) => {
+                list.value[i] = commands[3].clone();
+                state_guard.set_list(key.clone(), list.value, list.expiration_timestamp).await;
+                state_guard.notify_keyspace_event(NOTIFY_LIST, "lset", &key).await;
+                RespDataType::SimpleString("OK".to_string())
+            }
+            None => RespDataType::SimpleError("ERR index out of range".to_string()),
+        },
+        Ok(None) => RespDataType::SimpleError("ERR no such key".to_string()),
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, response).await;
+    }
+}
+
+// LINSERT key BEFORE | AFTER pivot element
+pub async fn handle_linsert(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 5 {
+        return write_response(&stream, wrong_arity("linsert")).await;
+    }
+    let key = commands[1].clone();
+    let after = match commands[2].to_uppercase().as_str() {
+        "BEFORE" => false,
+        "AFTER" => true,
+        _ => return write_response(&stream, RespDataType::SimpleError(SYNTAX_ERROR.to_string())).await,
+    };
+
+    let state_guard = state.lock().await;
+    let response = match state_guard.get_list(&key).await {
+        Ok(Some(mut list)) => match list.value.iter().position(|element| *element == commands[3]) {
+            Some(pivot) => {
+                list.value.insert(pivot + after as usize, commands[4].clone());
+                let len = list.value.len();
+                state_guard.set_list(key.clone(), list.value, list.expiration_timestamp).await;
+                state_guard.notify_keyspace_event(NOTIFY_LIST, "linsert", &key).await;
+                RespDataType::Integer(len as i64)
+            }
+            None => RespDataType::Integer(-1),
+        },
+        Ok(None) => RespDataType::Integer(0),
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, response).await;
+    }
+}
+
+// LREM key count element
+// A positive count removes from the head, a negative one from the tail and
+// 0 removes every occurrence.
+pub async fn handle_lrem(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 4 {
+        return write_response(&stream, wrong_arity("lrem")).await;
+    }
+    let key = commands[1].clone();
+    let Ok(count) = commands[2].parse::<i64>() else {
+        return write_response(&stream, RespDataType::SimpleError(NOT_AN_INTEGER_ERROR.to_string())).await;
+    };
+    let target = &commands[3];
+
+    let state_guard = state.lock().await;
+    let response = match state_guard.get_list(&key).await {
+        Ok(Some(list)) => {
+            let limit = if count == 0 { usize::MAX } else { count.unsigned_abs() as usize };
+            let mut removed = 0;
+            let mut elements = list.value;
+            if count < 0 {
+                elements.reverse();
+            }
+            elements.retain(|element| {
+                if removed < limit && element == target {
+                    removed += 1;
+                    false
+                } else {
+                    true
+                }
+            });
+            if count < 0 {
+                elements.reverse();
+            }
+            if removed > 0 {
+                state_guard.notify_keyspace_event(NOTIFY_LIST, "lrem", &key).await;
+                state_guard.set_list(key.clone(), elements, list.expiration_timestamp).await;
             }
+            RespDataType::Integer(removed as i64)
+        }
+        Ok(None) => RespDataType::Integer(0),
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, response).await;
+    }
+}
+
+// LTRIM key start stop
+pub async fn handle_ltrim(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 4 {
+        return write_response(&stream, wrong_arity("ltrim")).await;
+    }
+    let key = commands[1].clone();
+    let (Ok(start), Ok(stop)) = (commands[2].parse::<i64>(), commands[3].parse::<i64>()) else {
+        return write_response(&stream, RespDataType::SimpleError(NOT_AN_INTEGER_ERROR.to_string())).await;
+    };
+
+    let state_guard = state.lock().await;
+    let response = match state_guard.get_list(&key).await {
+        Ok(Some(list)) => {
+            let len = list.value.len() as i64;
+            let start = if start < 0 { (len + start).max(0) } else { start };
+            let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+            let kept = if start > stop || start >= len {
+                Vec::new()
+            } else {
+                list.value[start as usize..=stop as usize].to_vec()
+            };
+            state_guard.notify_keyspace_event(NOTIFY_LIST, "ltrim", &key).await;
+            state_guard.set_list(key.clone(), kept, list.expiration_timestamp).await;
+            RespDataType::SimpleString("OK".to_string())
+        }
+        Ok(None) => RespDataType::SimpleString("OK".to_string()),
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, response).await;
+    }
+}
+
+// LPOS key element [RANK rank] [COUNT num-matches] [MAXLEN len]
+pub async fn handle_lpos(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 3 {
+        return write_response(&stream, wrong_arity("lpos")).await;
+    }
+    let target = &commands[2];
+    let mut rank: i64 = 1;
+    let mut count: Option<usize> = None;
+    let mut max_len: usize = 0;
+
+    let mut i = 3;
+    while i < commands.len() {
+        let Some(value) = commands.get(i + 1) else {
+            return write_response(&stream, RespDataType::SimpleError(SYNTAX_ERROR.to_string())).await;
         };
-        let popped = state.lock().await.lpop_multiple(key, count).await;
-        let resp = RespDataType::Array(popped.into_iter().map(RespDataType::BulkString).collect());
-        stream.lock().await
-            .write_all(resp.to_string().as_bytes())
-            .await
-            .unwrap();
-    } else {
-        // LPOP key
-        let popped_element = state.lock().await.lpop(key).await;
-        match popped_element {
-            Some(element) => {
-                stream.lock().await
-                    .write_all(RespDataType::BulkString(element).to_string().as_bytes())
-                    .await
-                    .unwrap();
+        let Ok(value) = value.parse::<i64>() else {
+            return write_response(&stream, RespDataType::SimpleError(NOT_AN_INTEGER_ERROR.to_string())).await;
+        };
+        let error = match commands[i].to_uppercase().as_str() {
+            "RANK" if value == 0 || value == i64::MIN => Some(
+                "ERR RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list",
+            ),
+            "RANK" => {
+                rank = value;
+                None
             }
-            None => {
-                stream.lock().await
-                    .write_all(RespDataType::Nil.to_string().as_bytes())
-                    .await
-                    .unwrap();
+            "COUNT" if value < 0 => Some("ERR COUNT can't be negative"),
+            "COUNT" => {
+                count = Some(value as usize);
+                None
             }
+            "MAXLEN" if value < 0 => Some("ERR MAXLEN can't be negative"),
+            "MAXLEN" => {
+                max_len = value as usize;
+                None
+            }
+            _ => Some(SYNTAX_ERROR),
+        };
+        if let Some(error) = error {
+            return write_response(&stream, RespDataType::SimpleError(error.to_string())).await;
         }
+        i += 2;
     }
+
+    // COUNT 0 means every match; MAXLEN 0 means the whole list
+    let wanted = match count {
+        None => 1,
+        Some(0) => usize::MAX,
+        Some(count) => count,
+    };
+    let matches = state
+        .lock()
+        .await
+        .with_list(&commands[1], |elements| {
+            let scanned = if max_len == 0 { elements.len() } else { max_len.min(elements.len()) };
+            let indexes: Box<dyn Iterator<Item = usize>> = if rank > 0 {
+                Box::new(0..scanned)
+            } else {
+                Box::new((elements.len() - scanned..elements.len()).rev())
+            };
+            indexes
+                .filter(|&i| elements[i] == *target)
+                .skip(rank.unsigned_abs() as usize - 1)
+                .take(wanted)
+                .collect::<Vec<usize>>()
+        })
+        .await;
+    let matches = match matches {
+        Ok(matches) => matches.unwrap_or_default(),
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+
+    let response = match count {
+        Some(_) => RespDataType::Array(matches.into_iter().map(|i| RespDataType::Integer(i as i64)).collect()),
+        None => matches.first().map_or(RespDataType::Nil, |&i| RespDataType::Integer(i as i64)),
+    };
+    write_response(&stream, response).await;
 }
 
-pub async fn handle_blpop(
-    commands: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+// LPUSHX / RPUSHX key element [element ...]: push only onto existing lists
+async fn pushx_generic(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    name: &str,
+    end: ListEnd,
+) {
+    if commands.len() < 3 {
+        return write_response(&stream, wrong_arity(name)).await;
+    }
+    let key = commands[1].clone();
+
+    let state_guard = state.lock().await;
+    let response = match state_guard.get_list(&key).await {
+        Ok(Some(mut list)) => {
+            for element in commands[2..].iter() {
+                match end {
+                    ListEnd::Left => list.value.insert(0, element.clone()),
+                    ListEnd::Right => list.value.push(element.clone()),
+                }
+            }
+            let len = list.value.len();
+            state_guard.set_list(key.clone(), list.value, list.expiration_timestamp).await;
+            state_guard.notify_keyspace_event(NOTIFY_LIST, end.push_event(), &key).await;
+            RespDataType::Integer(len as i64)
+        }
+        Ok(None) => RespDataType::Integer(0),
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, response).await;
+    }
+}
+
+pub async fn handle_lpushx(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    pushx_generic(commands, stream, state, "lpushx", ListEnd::Left).await;
+}
+
+pub async fn handle_rpushx(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    pushx_generic(commands, stream, state, "rpushx", ListEnd::Right).await;
+}
+
+async fn move_generic(
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    source: &str,
+    destination: &str,
+    from: ListEnd,
+    to: ListEnd,
+) {
+    let state_guard = state.lock().await;
+    let response = match move_element(&state_guard, source, destination, from, to).await {
+        Ok(element) => element.map_or(RespDataType::Nil, RespDataType::BulkString),
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, response).await;
+    }
+}
+
+// LMOVE source destination LEFT | RIGHT LEFT | RIGHT
+pub async fn handle_lmove(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 5 {
+        return write_response(&stream, wrong_arity("lmove")).await;
+    }
+    let (Some(from), Some(to)) = (ListEnd::parse(&commands[3]), ListEnd::parse(&commands[4])) else {
+        return write_response(&stream, RespDataType::SimpleError(SYNTAX_ERROR.to_string())).await;
+    };
+    move_generic(stream, state, &commands[1], &commands[2], from, to).await;
+}
+
+// RPOPLPUSH source destination, the same as LMOVE source destination RIGHT LEFT
+pub async fn handle_rpoplpush(
+    commands: &Command,
+    stream: SharedWriter,
     state: Arc<Mutex<State>>,
 ) {
     if commands.len() != 3 {
-        stream.lock().await
-            .write_all(RespDataType::SimpleError("ERR wrong number of arguments for BLPOP command".to_string()).to_string().as_bytes())
-            .await
-            .unwrap();
-        return;
+        return write_response(&stream, wrong_arity("rpoplpush")).await;
     }
-    let key = &commands[1];
-    let timeout_str = &commands[2];
-    let timeout_secs: f64 = match timeout_str.parse() {
-        Ok(n) => n,
-        Err(_) => {
-            stream.lock().await
-                .write_all(RespDataType::SimpleError("ERR timeout is not a float".to_string()).to_string().as_bytes())
-                .await
-                .unwrap();
-            return;
+    move_generic(stream, state, &commands[1], &commands[2], ListEnd::Right, ListEnd::Left).await;
+}
+
+// Parses `numkeys key [key ...] LEFT | RIGHT [COUNT count]` as used by
+// LMPOP and BLMPOP
+pub fn parse_mpop_arguments(args: &[String]) -> Result<(Vec<String>, ListEnd, usize), String> {
+    const NUMKEYS_ERROR: &str = "ERR numkeys should be greater than 0";
+    const COUNT_ERROR: &str = "ERR count should be greater than 0";
+
+    let numkeys = match args.first().map(|n| n.parse::<i64>()) {
+        Some(Ok(n)) if n > 0 => n as usize,
+        _ => return Err(NUMKEYS_ERROR.to_string()),
+    };
+    if numkeys > args.len().saturating_sub(2) {
+        return Err(SYNTAX_ERROR.to_string());
+    }
+    let keys = args[1..=numkeys].to_vec();
+    let end = ListEnd::parse(&args[numkeys + 1]).ok_or(SYNTAX_ERROR.to_string())?;
+    let count = match &args[numkeys + 2..] {
+        [] => 1,
+        [option, count] if option.eq_ignore_ascii_case("COUNT") => match count.parse::<i64>() {
+            Ok(n) if n > 0 => n as usize,
+            _ => return Err(COUNT_ERROR.to_string()),
+        },
+        _ => return Err(SYNTAX_ERROR.to_string()),
+    };
+    Ok((keys, end, count))
+}
+
+// Pops from the first non-empty list among `keys`, as [key, [elements]]
+pub async fn mpop_first(state: &State, keys: &[String], end: ListEnd, count: usize) -> Result<Option<RespDataType>, String> {
+    for key in keys {
+        if let Some(popped) = pop_elements(state, key, end, count).await? {
+            return Ok(Some(RespDataType::Array(vec![
+                RespDataType::BulkString(key.clone()),
+                RespDataType::Array(popped.into_iter().map(RespDataType::BulkString).collect()),
+            ])));
         }
+    }
+    Ok(None)
+}
+
+// LMPOP numkeys key [key ...] LEFT | RIGHT [COUNT count]
+pub async fn handle_lmpop(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 4 {
+        return write_response(&stream, wrong_arity("lmpop")).await;
+    }
+    let (keys, end, count) = match parse_mpop_arguments(&commands[1..]) {
+        Ok(arguments) => arguments,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
     };
-    // Try to pop immediately
-    let popped = state.lock().await.lpop(key).await;
-    if let Some(element) = popped {
-        let resp = RespDataType::Array(vec![
-            RespDataType::BulkString(key.to_string()),
-            RespDataType::BulkString(element),
-        ]);
-        stream.lock().await
-            .write_all(resp.to_string().as_bytes())
-            .await
-            .unwrap();
-        return;
+
+    let state_guard = state.lock().await;
+    let response = match mpop_first(&state_guard, &keys, end, count).await {
+        Ok(result) => result.unwrap_or(RespDataType::NilArray),
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, response).await;
     }
-    // If timeout is 0, block indefinitely (old behavior)
-    if timeout_secs == 0.0 {
-        state.lock().await.register_blpop_blocked_client(key, stream.clone()).await;
-        return;
+}
+
+// What a blocked client does once one of its keys holds elements
+enum BlockingPop {
+    // BLPOP / BRPOP: one element, replied as [key, element]
+    Pop(ListEnd),
+    // BLMOVE / BRPOPLPUSH: the moved element
+    Move { destination: String, from: ListEnd, to: ListEnd },
+    // BLMPOP: up to `count` elements, replied as [key, [elements]]
+    MPop { end: ListEnd, count: usize },
+}
+
+impl BlockingPop {
+    // What the client gets when nothing was served: the null of its reply
+    fn timeout_reply(&self) -> RespDataType {
+        match self {
+            BlockingPop::Move { .. } => RespDataType::Nil,
+            BlockingPop::Pop(_) | BlockingPop::MPop { .. } => RespDataType::NilArray,
+        }
     }
-    // Otherwise, block for the specified timeout
-    let state_clone = state.clone();
-    let stream_clone = stream.clone();
-    let key_clone = key.to_string();
-    tokio::spawn(async move {
-        let start = Instant::now();
-        loop {
-            // Try to pop again
-            let popped = state_clone.lock().await.lpop(&key_clone).await;
-            if let Some(element) = popped {
-                let resp = RespDataType::Array(vec![
-                    RespDataType::BulkString(key_clone.clone()),
+}
+
+// Runs `operation` on `key`. When it succeeds, returns the reply together
+// with the non-blocking command replicas should apply.
+async fn serve_blocking_pop(
+    state: &State,
+    key: &str,
+    operation: &BlockingPop,
+) -> Result<Option<(RespDataType, Vec<String>)>, String> {
+    let served = match operation {
+        BlockingPop::Pop(end) => pop_elements(state, key, *end, 1).await?.and_then(|popped| {
+            let element = popped.into_iter().next()?;
+            let command = if *end == ListEnd::Left { "LPOP" } else { "RPOP" };
+            Some((
+                RespDataType::Array(vec![RespDataType::BulkString(key.to_string()), RespDataType::BulkString(element)]),
+                vec![command.to_string(), key.to_string()],
+            ))
+        }),
+        BlockingPop::Move { destination, from, to } => move_element(state, key, destination, *from, *to)
+            .await?
+            .map(|element| {
+                (
                     RespDataType::BulkString(element),
-                ]);
-                let _ = stream_clone.lock().await.write_all(resp.to_string().as_bytes()).await;
-                break;
+                    vec![
+                        "LMOVE".to_string(),
+                        key.to_string(),
+                        destination.clone(),
+                        from.as_str().to_string(),
+                        to.as_str().to_string(),
+                    ],
+                )
+            }),
+        BlockingPop::MPop { end, count } => pop_elements(state, key, *end, *count).await?.map(|popped| {
+            (
+                RespDataType::Array(vec![
+                    RespDataType::BulkString(key.to_string()),
+                    RespDataType::Array(popped.into_iter().map(RespDataType::BulkString).collect()),
+                ]),
+                vec![
+                    "LMPOP".to_string(),
+                    "1".to_string(),
+                    key.to_string(),
+                    end.as_str().to_string(),
+                    "COUNT".to_string(),
+                    count.to_string(),
+                ],
+            )
+        }),
+    };
+    Ok(served)
+}
+
+// Blocking timeouts are seconds given as a float; 0 blocks forever
+fn parse_timeout(value: &str) -> Result<Option<Duration>, String> {
+    match value.parse::<f64>() {
+        Ok(seconds) if seconds < 0.0 => Err("ERR timeout is negative".to_string()),
+        Ok(0.0) => Ok(None),
+        Ok(seconds) if seconds.is_finite() => match Duration::try_from_secs_f64(seconds) {
+            Ok(timeout) => Ok(Some(timeout)),
+            Err(_) => Err(TIMEOUT_OUT_OF_RANGE_ERROR.to_string()),
+        },
+        _ => Err("ERR timeout is not a float or out of range".to_string()),
+    }
+}
+
+// Serves the first of `keys` holding elements, or blocks the connection
+// until one does, the timeout expires or the client disconnects. Clients
+// are served in the order they blocked on a key. Inside MULTI (`can_block`
+// false) the command behaves like its non-blocking version and replies null
+// right away.
+async fn block_generic(
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    reader: SharedReader,
+    keys: Vec<String>,
+    timeout: Option<Duration>,
+    operation: BlockingPop,
+    can_block: bool,
+) {
+    // like Redis, the deadline counts from when the command arrives
+    let deadline = match timeout.map(|timeout| Instant::now().checked_add(timeout)) {
+        Some(None) => return write_response(&stream, RespDataType::SimpleError(TIMEOUT_OUT_OF_RANGE_ERROR.to_string())).await,
+        deadline => deadline.flatten(),
+    };
+    let mut served = None;
+    let mut blocked = None;
+    {
+        let state_guard = state.lock().await;
+        for key in keys.iter() {
+            // elements pushed while others wait belong to those clients
+            if state_guard.has_blocked_clients(key).await {
+                continue;
             }
-            if start.elapsed().as_secs_f64() >= timeout_secs {
-                let _ = stream_clone.lock().await.write_all(RespDataType::Nil.to_string().as_bytes()).await;
-                break;
+            match serve_blocking_pop(&state_guard, key, &operation).await {
+                Ok(Some(result)) => {
+                    served = Some(result);
+                    break;
+                }
+                Ok(None) => {}
+                Err(error) => {
+                    drop(state_guard);
+                    return write_response(&stream, RespDataType::SimpleError(error)).await;
+                }
             }
-            tokio::time::sleep(Duration::from_millis(10)).await;
         }
-    });
+        // registering under the same lock means no push can slip in between
+        if served.is_none() && can_block {
+            blocked = Some(state_guard.register_blocked_client(&keys).await);
+        }
+    }
+
+    if let Some((id, waker)) = blocked {
+        'waiting: loop {
+            match wait_until_ready(&waker, deadline, &reader).await {
+                WakeReason::Ready => {}
+                WakeReason::TimedOut => break,
+                WakeReason::Disconnected => {
+                    state.lock().await.unregister_blocked_client(&keys, id).await;
+                    return;
+                }
+            }
+            let state_guard = state.lock().await;
+            for key in keys.iter() {
+                if !state_guard.is_first_blocked_client(key, id).await {
+                    continue;
+                }
+                match serve_blocking_pop(&state_guard, key, &operation).await {
+                    Ok(Some(result)) => {
+                        served = Some(result);
+                        break 'waiting;
+                    }
+                    Ok(None) => {}
+                    Err(error) => {
+                        state_guard.unregister_blocked_client(&keys, id).await;
+                        drop(state_guard);
+                        return write_response(&stream, RespDataType::SimpleError(error)).await;
+                    }
+                }
+            }
+        }
+        state.lock().await.unregister_blocked_client(&keys, id).await;
+    }
+
+    match served {
+        Some((response, propagated)) => {
+            write_response(&stream, response).await;
+            if state.lock().await.get_role().await == "master" {
+                CommandExecutor { in_exec: false }
+                    .propogate_to_replicas(&Command::from(propagated), stream.clone(), state.clone())
+                    .await;
+            }
+        }
+        None => write_response(&stream, operation.timeout_reply()).await,
+    }
+}
+
+// BLPOP key [key ...] timeout / BRPOP key [key ...] timeout
+async fn bpop_generic(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    reader: SharedReader,
+    name: &str,
+    end: ListEnd,
+    can_block: bool,
+) {
+    if commands.len() < 3 {
+        return write_response(&stream, wrong_arity(name)).await;
+    }
+    let timeout = match parse_timeout(&commands[commands.len() - 1]) {
+        Ok(timeout) => timeout,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+    let keys = commands[1..commands.len() - 1].to_vec();
+    block_generic(stream, state, reader, keys, timeout, BlockingPop::Pop(end), can_block).await;
+}
+
+pub async fn handle_blpop(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    reader: SharedReader,
+    can_block: bool,
+) {
+    bpop_generic(commands, stream, state, reader, "blpop", ListEnd::Left, can_block).await;
+}
+
+pub async fn handle_brpop(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    reader: SharedReader,
+    can_block: bool,
+) {
+    bpop_generic(commands, stream, state, reader, "brpop", ListEnd::Right, can_block).await;
+}
+
+// BLMOVE source destination LEFT | RIGHT LEFT | RIGHT timeout
+pub async fn handle_blmove(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    reader: SharedReader,
+    can_block: bool,
+) {
+    if commands.len() != 6 {
+        return write_response(&stream, wrong_arity("blmove")).await;
+    }
+    let (Some(from), Some(to)) = (ListEnd::parse(&commands[3]), ListEnd::parse(&commands[4])) else {
+        return write_response(&stream, RespDataType::SimpleError(SYNTAX_ERROR.to_string())).await;
+    };
+    let timeout = match parse_timeout(&commands[5]) {
+        Ok(timeout) => timeout,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+    let operation = BlockingPop::Move { destination: commands[2].clone(), from, to };
+    block_generic(stream, state, reader, vec![commands[1].clone()], timeout, operation, can_block).await;
+}
+
+// BRPOPLPUSH source destination timeout
+pub async fn handle_brpoplpush(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    reader: SharedReader,
+    can_block: bool,
+) {
+    if commands.len() != 4 {
+        return write_response(&stream, wrong_arity("brpoplpush")).await;
+    }
+    let timeout = match parse_timeout(&commands[3]) {
+        Ok(timeout) => timeout,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+    let operation = BlockingPop::Move { destination: commands[2].clone(), from: ListEnd::Right, to: ListEnd::Left };
+    block_generic(stream, state, reader, vec![commands[1].clone()], timeout, operation, can_block).await;
+}
+
+// BLMPOP timeout numkeys key [key ...] LEFT | RIGHT [COUNT count]
+pub async fn handle_blmpop(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    reader: SharedReader,
+    can_block: bool,
+) {
+    if commands.len() < 5 {
+        return write_response(&stream, wrong_arity("blmpop")).await;
+    }
+    let timeout = match parse_timeout(&commands[1]) {
+        Ok(timeout) => timeout,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+    let (keys, end, count) = match parse_mpop_arguments(&commands[2..]) {
+        Ok(arguments) => arguments,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+    block_generic(stream, state, reader, keys, timeout, BlockingPop::MPop { end, count }, can_block).await;
 }
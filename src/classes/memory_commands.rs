@@ -0,0 +1,233 @@
+use crate::classes::{
+    Eviction::MaxmemoryPolicy,
+    Memory::{key_memory, keyspace_overhead, DEFAULT_SAMPLES},
+    Parser::Command,
+    RespDataType::RespDataType,
+    State::State,
+};
+use std::sync::Arc;
+use crate::classes::Connection::{write_response, wrong_arity, SharedWriter};
+use tokio::sync::Mutex;
+
+// Below this MEMORY DOCTOR has too little to go on
+const DOCTOR_MIN_MEMORY: u64 = 5 * 1024 * 1024;
+
+// MEMORY USAGE key [SAMPLES count] | STATS | DOCTOR | BIGKEYS | MALLOC-STATS | PURGE | HELP
+pub async fn handle_memory(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 2 {
+        return write_response(&stream, wrong_arity("memory")).await;
+    }
+    let subcommand = commands[1].to_uppercase();
+    let arity_ok = match subcommand.as_str() {
+        "USAGE" => commands.len() >= 3,
+        "STATS" | "DOCTOR" | "BIGKEYS" | "MALLOC-STATS" | "PURGE" | "HELP" => commands.len() == 2,
+        _ => {
+            let error = format!("ERR unknown subcommand '{}'. Try MEMORY HELP.", commands[1]);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+    if !arity_ok {
+        return write_response(&stream, wrong_arity(&format!("memory|{}", subcommand.to_lowercase()))).await;
+    }
+
+    let response = match subcommand.as_str() {
+        "USAGE" => memory_usage(commands, &state).await,
+        "STATS" => memory_stats(&state).await,
+        "DOCTOR" => RespDataType::BulkString(memory_doctor(&state).await),
+        "BIGKEYS" => RespDataType::BulkString(bigkeys_report(&state).await),
+        // there is no allocator to ask or to purge, see `Memory`
+        "MALLOC-STATS" => RespDataType::BulkString("Stats not supported for the current allocator".to_string()),
+        "PURGE" => RespDataType::SimpleString("OK".to_string()),
+        _ => {
+            let lines = [
+                "MEMORY <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                "BIGKEYS",
+                "    Return the biggest key of each type in the current database, with how",
+                "    many keys of each type there are, like redis-cli --bigkeys.",
+                "DOCTOR",
+                "    Return memory problems reports.",
+                "MALLOC-STATS",
+                "    Return internal statistics report from the memory allocator.",
+                "PURGE",
+                "    Attempt to purge dirty pages for reclamation by the allocator.",
+                "STATS",
+                "    Return information about the memory usage of the server.",
+                "USAGE <key> [SAMPLES <count>]",
+                "    Return memory in bytes used by <key> and its value. Nested values are",
+                "    sampled up to <count> times (default: 5, 0 means sample all).",
+                "HELP",
+                "    Print this help.",
+            ];
+            RespDataType::Array(lines.iter().map(|line| RespDataType::SimpleString(line.to_string())).collect())
+        }
+    };
+    write_response(&stream, response).await;
+}
+
+// The estimated bytes of the key, its value and its keyspace entry. Like
+// OBJECT, this doesn't count as an access to the key.
+async fn memory_usage(commands: &[String], state: &Arc<Mutex<State>>) -> RespDataType {
+    let mut samples = DEFAULT_SAMPLES;
+    let mut options = commands[3..].iter();
+    while let Some(option) = options.next() {
+        let count = match options.next() {
+            Some(count) if option.eq_ignore_ascii_case("SAMPLES") => count,
+            _ => return RespDataType::SimpleError("ERR syntax error".to_string()),
+        };
+        samples = match count.parse::<i64>() {
+            // 0 samples every element
+            Ok(count) if count >= 0 => count as usize,
+            Ok(_) => return RespDataType::SimpleError("ERR syntax error".to_string()),
+            Err(_) => return RespDataType::SimpleError("ERR value is not an integer or out of range".to_string()),
+        };
+    }
+    let key = &commands[2];
+    let bytes = state
+        .lock()
+        .await
+        .inspect_key(key, |value, _| key_memory(key, value, samples))
+        .await;
+    bytes.map_or(RespDataType::Nil, |bytes| RespDataType::Integer(bytes as i64))
+}
+
+// The used memory split into the keyspace bookkeeping (overhead) and the
+// keys and values themselves (dataset), with the overhead of each database
+async fn memory_stats(state: &Arc<Mutex<State>>) -> RespDataType {
+    let state_guard = state.lock().await;
+    let used_memory = state_guard.used_memory().await;
+    let peak_memory = state_guard.get_peak_memory().await;
+    let key_counts = state_guard.key_counts().await;
+    drop(state_guard);
+
+    let field = |name: &str| RespDataType::BulkString(name.to_string());
+    let mut response = vec![
+        field("peak.allocated"),
+        RespDataType::Integer(peak_memory as i64),
+        field("total.allocated"),
+        RespDataType::Integer(used_memory as i64),
+        // nothing is allocated before the first key
+        field("startup.allocated"),
+        RespDataType::Integer(0),
+    ];
+    let mut overhead = 0;
+    let mut keys = 0;
+    for (db, db_keys, volatile_keys) in key_counts {
+        let (main, expires) = keyspace_overhead(db_keys, volatile_keys);
+        overhead += main + expires;
+        keys += db_keys;
+        response.push(field(&format!("db.{}", db)));
+        response.push(RespDataType::Array(vec![
+            field("overhead.hashtable.main"),
+            RespDataType::Integer(main as i64),
+            field("overhead.hashtable.expires"),
+            RespDataType::Integer(expires as i64),
+        ]));
+    }
+    let dataset = used_memory.saturating_sub(overhead as u64);
+    let percentage = |part: u64, total: u64| (part as f64 * 100.0 / total.max(1) as f64).to_string();
+    response.extend([
+        field("overhead.total"),
+        RespDataType::Integer(overhead as i64),
+        field("keys.count"),
+        RespDataType::Integer(keys as i64),
+        field("keys.bytes-per-key"),
+        RespDataType::Integer((used_memory / keys.max(1) as u64) as i64),
+        field("dataset.bytes"),
+        RespDataType::Integer(dataset as i64),
+        field("dataset.percentage"),
+        RespDataType::BulkString(percentage(dataset, used_memory)),
+        field("peak.percentage"),
+        RespDataType::BulkString(percentage(used_memory, peak_memory)),
+    ]);
+    RespDataType::Array(response)
+}
+
+// Looks for what may need attention in how memory is used, in the words of
+// Redis's own doctor where it has them
+async fn memory_doctor(state: &Arc<Mutex<State>>) -> String {
+    let state_guard = state.lock().await;
+    let used_memory = state_guard.used_memory().await;
+    let peak_memory = state_guard.get_peak_memory().await;
+    let maxmemory = state_guard.get_maxmemory_config().await;
+    drop(state_guard);
+
+    if used_memory < DOCTOR_MIN_MEMORY {
+        return "Hi Sam, this instance is empty or is using very little memory, my issues detector can't be used in these conditions. Please, leave for your mission on Earth and fill it with some data. The new Sam and I will be back to our programming as soon as I finished rebooting.".to_string();
+    }
+
+    let mut issues = Vec::new();
+    if peak_memory * 2 > used_memory * 3 {
+        issues.push(" * Peak memory: In the past this instance used more than 150% the memory that is currently using. The allocator is normally not able to release memory after a peak, so you can expect to see a big fragmentation ratio, however this is actually harmless and is only due to the memory peak, and if the Redis instance Resident Set Size (RSS) is currently bigger than expected, the memory will be used as soon as you fill the Redis instance with more data. If the memory peak was only occasional and you want to try to reclaim memory, please try the MEMORY PURGE command, otherwise the only other option is to shutdown and restart the instance.");
+    }
+    if maxmemory.maxmemory > 0
+        && maxmemory.policy == MaxmemoryPolicy::NoEviction
+        && used_memory * 10 >= maxmemory.maxmemory * 9
+    {
+        issues.push(" * Max memory: The dataset uses more than 90% of 'maxmemory' and the 'noeviction' policy is selected, so commands that add data will soon be refused with an OOM error. Raise 'maxmemory' or select a 'maxmemory-policy' that evicts keys.");
+    }
+    if issues.is_empty() {
+        return "Hi Sam, I can't find any memory issue in your instance. I can only account for what occurs on this base.".to_string();
+    }
+    let mut report = "Sam, I detected a few issues in this Redis instance memory implants:\n\n".to_string();
+    for issue in issues {
+        report.push_str(issue);
+        report.push_str("\n\n");
+    }
+    report.push_str("I'm here to keep you safe, Sam. I want to help you.\n");
+    report
+}
+
+// The summary redis-cli --bigkeys prints for the selected database: the
+// biggest key of each type, then how many keys of each type there are and
+// how big they are, in the unit of `Value::size`
+async fn bigkeys_report(state: &Arc<Mutex<State>>) -> String {
+    // (type, plural, unit)
+    let types = [
+        ("string", "strings", "bytes"),
+        ("list", "lists", "items"),
+        ("zset", "zsets", "members"),
+        ("stream", "streams", "entries"),
+    ];
+    let key_sizes = state.lock().await.key_sizes().await;
+    let total_keys = key_sizes.len();
+    let key_length: usize = key_sizes.iter().map(|(key, ..)| key.len()).sum();
+
+    let mut biggest = Vec::new();
+    let mut totals = Vec::new();
+    for (type_name, plural, unit) in types {
+        let of_type: Vec<&(String, &str, usize)> = key_sizes.iter().filter(|(_, key_type, _)| *key_type == type_name).collect();
+        let total_size: usize = of_type.iter().map(|(_, _, size)| size).sum();
+        if let Some((key, _, size)) = of_type.iter().max_by_key(|(_, _, size)| size) {
+            biggest.push(format!("Biggest {:>6} found '{}' has {} {}", type_name, key, size, unit));
+        }
+        let share = of_type.len() as f64 * 100.0 / total_keys.max(1) as f64;
+        let average = total_size as f64 / of_type.len().max(1) as f64;
+        totals.push(format!(
+            "{} {} with {} {} ({:05.2}% of keys, avg size {:.2})",
+            of_type.len(),
+            plural,
+            total_size,
+            unit,
+            share,
+            average
+        ));
+    }
+
+    let mut report = format!(
+        "Sampled {} keys in the keyspace!\nTotal key length in bytes is {} (avg len {:.2})\n\n",
+        total_keys,
+        key_length,
+        key_length as f64 / total_keys.max(1) as f64
+    );
+    if !biggest.is_empty() {
+        report.push_str(&biggest.join("\n"));
+        report.push_str("\n\n");
+    }
+    report.push_str(&totals.join("\n"));
+    report.push('\n');
+    report
+}
@@ -1,120 +1,373 @@
-use crate::classes::{RespDataType::RespDataType, State::State};
+use crate::classes::{
+    Configuration::{find_parameter, rewrite_config, PARAMETERS},
+    Glob::{glob_match, glob_match_bytes},
+    Memory::{bytes_to_human, keyspace_overhead},
+    KeySlot::key_hash_slot,
+    Parser::Command,
+    RespDataType::RespDataType,
+    State::{is_authenticated, is_local_connection, reset_authentication, select_db, set_authenticated, State},
+};
+use std::collections::HashSet;
 use std::sync::Arc;
-use tokio::net::tcp::OwnedWriteHalf;
+use crate::classes::Connection::{write_response, wrong_arity, SharedWriter};
 use tokio::sync::Mutex;
 use tokio::io::AsyncWriteExt;
 
+// INFO [section [section ...]]
+// Only the memory, stats, replication and keyspace sections exist so far.
 pub async fn handle_info(
-    _commands: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+    commands: &Command,
+    stream: SharedWriter,
     state: Arc<Mutex<State>>,
 ) {
-    let role = state.lock().await.get_role().await;
-    stream.lock().await
-        .write_all(
-            &RespDataType::BulkString(String::from(format!("role:{}\r\nmaster_repl_offset:0\r\nmaster_replid:8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb", role))).to_string().as_bytes(),
-        )
-        .await
-        .unwrap();
+    let requested: Vec<String> = commands[1..].iter().map(|section| section.to_lowercase()).collect();
+    let wants = |section: &str| {
+        requested.is_empty()
+            || requested.iter().any(|requested| ["all", "everything", "default", section].contains(&requested.as_str()))
+    };
+
+    let state_guard = state.lock().await;
+    let mut sections = Vec::new();
+    if wants("memory") {
+        let used_memory = state_guard.used_memory().await;
+        let peak_memory = state_guard.get_peak_memory().await;
+        let overhead: usize = state_guard
+            .key_counts()
+            .await
+            .into_iter()
+            .map(|(_, keys, volatile_keys)| {
+                let (main, expires) = keyspace_overhead(keys, volatile_keys);
+                main + expires
+            })
+            .sum();
+        let maxmemory = state_guard.get_maxmemory_config().await;
+        sections.push(format!(
+            "# Memory\r\nused_memory:{}\r\nused_memory_human:{}\r\nused_memory_peak:{}\r\nused_memory_peak_human:{}\r\nused_memory_overhead:{}\r\nused_memory_dataset:{}\r\nmaxmemory:{}\r\nmaxmemory_human:{}\r\nmaxmemory_policy:{}\r\n",
+            used_memory,
+            bytes_to_human(used_memory),
+            peak_memory,
+            bytes_to_human(peak_memory),
+            overhead,
+            used_memory.saturating_sub(overhead as u64),
+            maxmemory.maxmemory,
+            bytes_to_human(maxmemory.maxmemory),
+            maxmemory.policy.as_str()
+        ));
+    }
+    if wants("stats") {
+        let evicted_keys = state_guard.get_evicted_keys().await;
+        sections.push(format!("# Stats\r\nevicted_keys:{}\r\n", evicted_keys));
+    }
+    if wants("replication") {
+        let role = state_guard.get_role().await;
+        sections.push(format!(
+            "# Replication\r\nrole:{}\r\nmaster_repl_offset:0\r\nmaster_replid:8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb\r\n",
+            role
+        ));
+    }
+    if wants("keyspace") {
+        let mut section = "# Keyspace\r\n".to_string();
+        for (db, keys, expires, avg_ttl) in state_guard.keyspace_stats().await {
+            section.push_str(&format!("db{}:keys={},expires={},avg_ttl={}\r\n", db, keys, expires, avg_ttl));
+        }
+        sections.push(section);
+    }
+    drop(state_guard);
+    write_response(&stream, RespDataType::BulkString(sections.join("\r\n"))).await;
 }
 
-pub async fn handle_config_get(
-    commands: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+// CONFIG GET | SET | REWRITE | RESETSTAT | HELP
+pub async fn handle_config(
+    commands: &Command,
+    stream: SharedWriter,
     state: Arc<Mutex<State>>,
 ) {
-    if let Some(config_key) = commands.get(2) {
-        let (db_dir, db_file_name) = state.lock().await.get_db_config().await;
-        
-        match config_key.to_uppercase().as_str() {
-            "DIR" => {
-                if let Some(db_dir) = db_dir {
-                    let response = format!(
-                        "*2\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
-                        config_key.len(),
-                        config_key,
-                        db_dir.len(),
-                        db_dir
-                    );
-                    stream
-                        .lock()
-                        .await
-                        .write_all(response.as_bytes())
-                        .await
-                        .unwrap();
-                }
-            }
-            "DBFILENAME" => {
-                if let Some(db_file_name) = db_file_name {
-                    let response = format!(
-                        "*2\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
-                        config_key.len(),
-                        config_key,
-                        db_file_name.len(),
-                        db_file_name
-                    );
-                    stream
-                        .lock()
-                        .await
-                        .write_all(response.as_bytes())
-                        .await
-                        .unwrap();
-                }
-            }
-            _ => {
-                panic!("unknown config item")
+    if commands.len() < 2 {
+        return write_response(&stream, wrong_arity("config")).await;
+    }
+    let subcommand = commands[1].to_uppercase();
+    let arity_ok = match subcommand.as_str() {
+        "GET" => commands.len() >= 3,
+        "SET" => commands.len() >= 4 && commands.len().is_multiple_of(2),
+        "REWRITE" | "RESETSTAT" | "HELP" => commands.len() == 2,
+        _ => {
+            let error = format!("ERR unknown subcommand '{}'. Try CONFIG HELP.", commands[1]);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+    if !arity_ok {
+        return write_response(&stream, wrong_arity(&format!("config|{}", subcommand.to_lowercase()))).await;
+    }
+
+    let response = match subcommand.as_str() {
+        "GET" => config_get(&commands[2..], &state).await,
+        "SET" => config_set(&commands[2..], &state).await,
+        "REWRITE" => config_rewrite(&state).await,
+        "RESETSTAT" => {
+            state.lock().await.reset_stats().await;
+            RespDataType::SimpleString("OK".to_string())
+        }
+        _ => {
+            let lines = [
+                "CONFIG <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                "GET <pattern>",
+                "    Return parameters matching the glob-like <pattern> and their values.",
+                "SET <directive> <value>",
+                "    Set the configuration <directive> to <value>.",
+                "RESETSTAT",
+                "    Reset statistics reported by the INFO command.",
+                "REWRITE",
+                "    Rewrite the configuration file.",
+                "HELP",
+                "    Print this help.",
+            ];
+            RespDataType::Array(lines.iter().map(|line| RespDataType::SimpleString(line.to_string())).collect())
+        }
+    };
+    write_response(&stream, response).await;
+}
+
+// CONFIG GET parameter [parameter ...]
+// Every parameter may be a glob pattern, matched case-insensitively against
+// the parameter names; each matching parameter is returned once. Aliases
+// only match when asked for by their exact name.
+async fn config_get(patterns: &[String], state: &Arc<Mutex<State>>) -> RespDataType {
+    let config = state.lock().await.get_config().await;
+    let mut response = Vec::new();
+    for parameter in PARAMETERS {
+        let matches = |name: &str| patterns.iter().any(|pattern| glob_match_bytes(pattern.as_bytes(), name.as_bytes(), true));
+        let name = if matches(parameter.name) {
+            parameter.name
+        } else {
+            match parameter.alias.filter(|alias| patterns.iter().any(|pattern| pattern.eq_ignore_ascii_case(alias))) {
+                Some(alias) => alias,
+                None => continue,
             }
+        };
+        response.push(RespDataType::BulkString(name.to_string()));
+        response.push(RespDataType::BulkString((parameter.get)(&config)));
+    }
+    RespDataType::Array(response)
+}
+
+// CONFIG SET parameter value [parameter value ...]
+// Every pair is validated before any is applied, so either all of them
+// take effect or none does.
+async fn config_set(pairs: &[String], state: &Arc<Mutex<State>>) -> RespDataType {
+    let state_guard = state.lock().await;
+    let mut config = state_guard.get_config().await;
+    let mut seen = HashSet::new();
+    for pair in pairs.chunks(2) {
+        let Some(parameter) = find_parameter(&pair[0]) else {
+            let error = format!("ERR Unknown option or number of arguments for CONFIG SET - '{}'", pair[0]);
+            return RespDataType::SimpleError(error);
+        };
+        let reason = if !seen.insert(parameter.name) {
+            Err("duplicate parameter".to_string())
+        } else if !parameter.mutable {
+            Err("can't set immutable config".to_string())
+        } else if parameter.protected && !config.enable_protected_configs.allows(is_local_connection()) {
+            Err("can't set protected config".to_string())
+        } else {
+            (parameter.set)(&mut config, &pair[1])
+        };
+        if let Err(reason) = reason {
+            let error = format!("ERR CONFIG SET failed (possibly related to argument '{}') - {}", pair[0], reason);
+            return RespDataType::SimpleError(error);
         }
     }
+    state_guard.set_config(config).await;
+    RespDataType::SimpleString("OK".to_string())
 }
 
+// CONFIG REWRITE
+// Writes the current configuration back to the file it was loaded from,
+// through a temporary file so a failure leaves the old one intact.
+async fn config_rewrite(state: &Arc<Mutex<State>>) -> RespDataType {
+    let config = state.lock().await.get_config().await;
+    let Some(path) = config.config_file.clone() else {
+        return RespDataType::SimpleError("ERR The server is running without a config file".to_string());
+    };
+    let existing = match std::fs::read_to_string(&path) {
+        Ok(existing) => existing,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(error) => return RespDataType::SimpleError(format!("ERR Rewriting config file: {}", error)),
+    };
+    let temporary_path = format!("{}.rewrite-{}.tmp", path, std::process::id());
+    let written = std::fs::write(&temporary_path, rewrite_config(&existing, &config))
+        .and_then(|_| std::fs::rename(&temporary_path, &path));
+    match written {
+        Ok(()) => RespDataType::SimpleString("OK".to_string()),
+        Err(error) => {
+            let _ = std::fs::remove_file(&temporary_path);
+            RespDataType::SimpleError(format!("ERR Rewriting config file: {}", error))
+        }
+    }
+}
+
+// KEYS pattern
 pub async fn handle_keys(
-    commands: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+    commands: &Command,
+    stream: SharedWriter,
     state: Arc<Mutex<State>>,
 ) {
+    let Some(pattern) = commands.get(1).filter(|_| commands.len() == 2) else {
+        write_response(&stream, wrong_arity("keys")).await;
+        return;
+    };
     let all_keys = state.lock().await.get_all_keys().await;
-    let regex_match = &commands[1];
-    let matching_keys: Vec<String> = all_keys.into_iter()
-        .filter(|key| {
-            let parts: Vec<&str> = regex_match.split("*").collect();
-            key.starts_with(parts[0]) && key.ends_with(parts[1])
-        })
+    let matching_keys = all_keys
+        .into_iter()
+        .filter(|key| glob_match(pattern, key))
+        .map(RespDataType::BulkString)
         .collect();
-
-    let mut result = String::from(format!("*{}\r\n", matching_keys.len()));
-    for mk in matching_keys {
-        result.push_str(&format!("{}", RespDataType::BulkString(mk)));
-    }
-    stream
-        .lock()
-        .await
-        .write_all(result.as_bytes())
-        .await
-        .unwrap();
+    write_response(&stream, RespDataType::Array(matching_keys)).await;
 }
 
 pub async fn handle_echo(
-    commands: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+    commands: &Command,
+    stream: SharedWriter,
 ) {
-    if let Some(bulk_string) = commands.get(1) {
-        stream
-            .lock()
-            .await
-            .write_all(format!("+{}\r\n", bulk_string).as_bytes())
-            .await
-            .unwrap();
+    if commands.len() != 2 {
+        return write_response(&stream, wrong_arity("echo")).await;
     }
+    write_response(&stream, RespDataType::BulkBytes(commands.bytes(1).to_vec())).await;
 }
 
 pub async fn handle_ping(
-    _: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+    _: &Command,
+    stream: SharedWriter,
     state: Arc<Mutex<State>>,
 ) {
     let role = state.lock().await.get_role().await;
     if role == "master" {
         stream.lock().await.write_all(RespDataType::SimpleString("PONG".to_string()).to_string().as_bytes()).await.unwrap();
     }
-} 
\ No newline at end of file
+} 
+// AUTH [username] password
+pub async fn handle_auth(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    let response = match commands.len() {
+        2 if !state.lock().await.requires_auth().await => RespDataType::SimpleError(
+            "ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?".to_string(),
+        ),
+        2 => authenticate(&state, "default", &commands[1]).await,
+        3 => authenticate(&state, &commands[1], &commands[2]).await,
+        1 => wrong_arity("auth"),
+        _ => RespDataType::SimpleError("ERR syntax error".to_string()),
+    };
+    write_response(&stream, response).await;
+}
+
+async fn authenticate(state: &Arc<Mutex<State>>, username: &str, password: &str) -> RespDataType {
+    let state_guard = state.lock().await;
+    if !state_guard.check_password(username, password).await {
+        state_guard
+            .log_acl_denial("auth", "toplevel", "AUTH".to_string(), username.to_string(), "auth")
+            .await;
+        return RespDataType::SimpleError("WRONGPASS invalid username-password pair or user is disabled.".to_string());
+    }
+    set_authenticated(username);
+    RespDataType::SimpleString("OK".to_string())
+}
+
+// QUIT: the connection is closed once the reply is out
+pub async fn handle_quit(stream: SharedWriter) {
+    write_response(&stream, RespDataType::SimpleString("OK".to_string())).await;
+    let _ = stream.lock().await.shutdown().await;
+}
+
+// RESET: leaves MULTI and pub/sub, selects database 0 and goes back to the
+// default user
+pub async fn handle_reset(
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    queued: &mut Vec<Command>,
+    in_multi: &mut bool,
+) {
+    queued.clear();
+    *in_multi = false;
+    select_db(0);
+    let state_guard = state.lock().await;
+    state_guard.remove_subscriber(&stream).await;
+    reset_authentication(!state_guard.requires_auth().await);
+    drop(state_guard);
+    write_response(&stream, RespDataType::SimpleString("RESET".to_string())).await;
+}
+
+// HELLO [protover [AUTH username password]]
+// Only RESP2 is spoken, so HELLO 3 is refused and clients stay on RESP2.
+pub async fn handle_hello(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if let Some(protover) = commands.get(1) {
+        let error = match protover.parse::<i64>() {
+            Ok(2) => None,
+            Ok(_) => Some("NOPROTO unsupported protocol version"),
+            Err(_) => Some("ERR Protocol version is not an integer or out of range"),
+        };
+        if let Some(error) = error {
+            return write_response(&stream, RespDataType::SimpleError(error.to_string())).await;
+        }
+    }
+    let mut credentials = None;
+    let mut options = commands.iter().skip(2);
+    while let Some(option) = options.next() {
+        match (options.next(), options.next()) {
+            (Some(username), Some(password)) if option.eq_ignore_ascii_case("AUTH") => credentials = Some((username, password)),
+            _ => {
+                let error = format!("ERR Syntax error in HELLO option '{}'", option);
+                return write_response(&stream, RespDataType::SimpleError(error)).await;
+            }
+        }
+    }
+    if let Some((username, password)) = credentials {
+        let response = authenticate(&state, username, password).await;
+        if let RespDataType::SimpleError(_) = response {
+            return write_response(&stream, response).await;
+        }
+    } else if !is_authenticated() && state.lock().await.requires_auth().await {
+        let error = "NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time";
+        return write_response(&stream, RespDataType::SimpleError(error.to_string())).await;
+    }
+
+    let role = match state.lock().await.get_role().await.as_str() {
+        "master" => "master",
+        _ => "replica",
+    };
+    let field = |value: &str| RespDataType::BulkString(value.to_string());
+    let response = RespDataType::Array(vec![
+        field("server"),
+        field("redis"),
+        field("version"),
+        field("7.2.0"),
+        field("proto"),
+        RespDataType::Integer(2),
+        field("mode"),
+        field("standalone"),
+        field("role"),
+        field(role),
+        field("modules"),
+        RespDataType::Array(Vec::new()),
+    ]);
+    write_response(&stream, response).await;
+}
+
+pub async fn handle_cluster(
+    commands: &Command,
+    stream: SharedWriter,
+) {
+    let subcommand = commands.get(1).map(|s| s.to_uppercase()).unwrap_or_default();
+    let response = match subcommand.as_str() {
+        // KEYSLOT is answered even without cluster mode so clients can work
+        // out shard channel and key placement against this server
+        "KEYSLOT" if commands.len() == 3 => RespDataType::Integer(key_hash_slot(commands.bytes(2)) as i64),
+        _ => RespDataType::SimpleError("ERR This instance has cluster support disabled".to_string()),
+    };
+    write_response(&stream, response).await;
+}
@@ -1,13 +1,61 @@
+#[allow(non_snake_case)]
 pub mod CommandExecutor;
+#[allow(non_snake_case)]
 pub mod Db;
+#[allow(non_snake_case)]
 pub mod ExpiringValue;
+#[allow(non_snake_case)]
 pub mod Parser;
+#[allow(non_snake_case)]
 pub mod RespDataType;
+#[allow(non_snake_case)]
 pub mod State;
+#[allow(non_snake_case)]
 pub mod Constants;
+#[allow(non_snake_case)]
+pub mod Connection;
+#[allow(non_snake_case)]
+pub mod Glob;
+#[allow(non_snake_case)]
+pub mod KeySlot;
+#[allow(non_snake_case)]
+pub mod Notifications;
+#[allow(non_snake_case)]
+pub mod Blocking;
+#[allow(non_snake_case)]
+pub mod HyperLogLog;
+#[allow(non_snake_case)]
+pub mod SortedSet;
+#[allow(non_snake_case)]
+pub mod Geo;
+#[allow(non_snake_case)]
+pub mod StreamId;
+#[allow(non_snake_case)]
+pub mod Stream;
+#[allow(non_snake_case)]
+pub mod Keyspace;
+#[allow(non_snake_case)]
+pub mod Memory;
+#[allow(non_snake_case)]
+pub mod Eviction;
+#[allow(non_snake_case)]
+pub mod Configuration;
+#[allow(non_snake_case)]
+pub mod Tls;
+#[allow(non_snake_case)]
+pub mod Acl;
 pub mod string_commands;
+pub mod bitmap_commands;
+pub mod hyperloglog_commands;
+pub mod sorted_set_commands;
+pub mod geo_commands;
 pub mod stream_commands;
 pub mod replication_commands;
 pub mod meta_commands;
 pub mod transaction_commands;
-pub mod list_commands;
\ No newline at end of file
+pub mod list_commands;
+pub mod pubsub_commands;
+pub mod keyspace_commands;
+pub mod persistence_commands;
+pub mod memory_commands;
+pub mod acl_commands;
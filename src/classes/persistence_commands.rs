@@ -0,0 +1,92 @@
+use crate::classes::{
+    Db::{rdb_path, Db},
+    ExpiringValue::unix_millis_now,
+    Parser::Command,
+    RespDataType::RespDataType,
+    State::State,
+};
+use std::sync::Arc;
+use crate::classes::Connection::{write_response, wrong_arity, SharedWriter};
+use tokio::sync::Mutex;
+
+// SAVE
+// Writes the dump while holding the state lock, so like in Redis every other
+// client waits for it.
+pub async fn handle_save(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 1 {
+        return write_response(&stream, wrong_arity("save")).await;
+    }
+    let state_guard = state.lock().await;
+    if state_guard.persistence.lock().await.bgsave_in_progress {
+        drop(state_guard);
+        let error = "ERR Background save already in progress".to_string();
+        return write_response(&stream, RespDataType::SimpleError(error)).await;
+    }
+    let path = rdb_path(&state_guard).await;
+    let snapshot = state_guard.snapshot().await;
+    let maxmemory = state_guard.get_maxmemory_config().await;
+    let response = match (Db {}).save(&path, &snapshot, &maxmemory) {
+        Ok(()) => {
+            state_guard.set_last_save(unix_millis_now() / 1000).await;
+            RespDataType::SimpleString("OK".to_string())
+        }
+        Err(error) => {
+            println!("Failed saving the DB to {}: {}", path.display(), error);
+            RespDataType::SimpleError("ERR".to_string())
+        }
+    };
+    drop(state_guard);
+    write_response(&stream, response).await;
+}
+
+// BGSAVE [SCHEDULE]
+// The keyspace is copied under the lock and written out on a blocking thread.
+pub async fn handle_bgsave(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    match commands.get(1) {
+        None => {}
+        Some(option) if commands.len() == 2 && option.eq_ignore_ascii_case("SCHEDULE") => {}
+        Some(_) => return write_response(&stream, RespDataType::SimpleError("ERR syntax error".to_string())).await,
+    }
+    let state_guard = state.lock().await;
+    if !state_guard.start_bgsave().await {
+        drop(state_guard);
+        let error = "ERR Background save already in progress".to_string();
+        return write_response(&stream, RespDataType::SimpleError(error)).await;
+    }
+    let path = rdb_path(&state_guard).await;
+    let snapshot = state_guard.snapshot().await;
+    let maxmemory = state_guard.get_maxmemory_config().await;
+    drop(state_guard);
+
+    let background_state = state.clone();
+    tokio::spawn(async move {
+        let saved = tokio::task::spawn_blocking(move || {
+            (Db {}).save(&path, &snapshot, &maxmemory).map_err(|error| println!("Background saving to {} failed: {}", path.display(), error))
+        })
+        .await
+        .is_ok_and(|saved| saved.is_ok());
+        background_state.lock().await.finish_bgsave(saved).await;
+    });
+    write_response(&stream, RespDataType::SimpleString("Background saving started".to_string())).await;
+}
+
+// LASTSAVE
+pub async fn handle_lastsave(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 1 {
+        return write_response(&stream, wrong_arity("lastsave")).await;
+    }
+    let last_save = state.lock().await.get_last_save().await;
+    write_response(&stream, RespDataType::Integer(last_save as i64)).await;
+}
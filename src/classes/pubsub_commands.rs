@@ -0,0 +1,187 @@
+use crate::classes::{
+    Parser::Command,
+    RespDataType::RespDataType,
+    State::{State, SubscriptionKind},
+};
+use std::sync::Arc;
+use crate::classes::Connection::{write_response, wrong_arity, SharedWriter};
+use tokio::sync::Mutex;
+
+fn subscription_reply(kind: &str, name: Option<String>, count: usize) -> RespDataType {
+    RespDataType::Array(vec![
+        RespDataType::BulkString(kind.to_string()),
+        match name {
+            Some(name) => RespDataType::BulkString(name),
+            None => RespDataType::Nil,
+        },
+        RespDataType::Integer(count as i64),
+    ])
+}
+
+async fn subscribe(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    kind: SubscriptionKind,
+    reply_kind: &str,
+) {
+    if commands.len() < 2 {
+        return write_response(&stream, wrong_arity(reply_kind)).await;
+    }
+
+    for name in commands[1..].iter() {
+        let state_guard = state.lock().await;
+        state_guard.subscribe(kind, name, stream.clone()).await;
+        let count = state_guard.subscription_count(kind, &stream).await;
+        drop(state_guard);
+        write_response(&stream, subscription_reply(reply_kind, Some(name.clone()), count)).await;
+    }
+}
+
+async fn unsubscribe(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    kind: SubscriptionKind,
+    reply_kind: &str,
+) {
+    // without arguments every subscription of this kind is dropped
+    let names = if commands.len() > 1 {
+        commands[1..].to_vec()
+    } else {
+        state.lock().await.get_subscriptions(kind, &stream).await
+    };
+
+    if names.is_empty() {
+        let count = state.lock().await.subscription_count(kind, &stream).await;
+        return write_response(&stream, subscription_reply(reply_kind, None, count)).await;
+    }
+
+    for name in names {
+        let state_guard = state.lock().await;
+        state_guard.unsubscribe(kind, &name, &stream).await;
+        let count = state_guard.subscription_count(kind, &stream).await;
+        drop(state_guard);
+        write_response(&stream, subscription_reply(reply_kind, Some(name), count)).await;
+    }
+}
+
+pub async fn handle_subscribe(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    subscribe(commands, stream, state, SubscriptionKind::Channel, "subscribe").await;
+}
+
+pub async fn handle_unsubscribe(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    unsubscribe(commands, stream, state, SubscriptionKind::Channel, "unsubscribe").await;
+}
+
+pub async fn handle_psubscribe(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    subscribe(commands, stream, state, SubscriptionKind::Pattern, "psubscribe").await;
+}
+
+pub async fn handle_punsubscribe(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    unsubscribe(commands, stream, state, SubscriptionKind::Pattern, "punsubscribe").await;
+}
+
+// Without cluster mode, which this server doesn't have, shard channels
+// behave like plain channels in a separate namespace: SSUBSCRIBE takes
+// channels of any slots, as Redis does outside a cluster.
+pub async fn handle_ssubscribe(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    subscribe(commands, stream, state, SubscriptionKind::ShardChannel, "ssubscribe").await;
+}
+
+pub async fn handle_sunsubscribe(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    unsubscribe(commands, stream, state, SubscriptionKind::ShardChannel, "sunsubscribe").await;
+}
+
+async fn publish(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    kind: SubscriptionKind,
+    command_name: &str,
+) {
+    if commands.len() != 3 {
+        return write_response(&stream, wrong_arity(command_name)).await;
+    }
+
+    let receivers = state.lock().await.publish(kind, &commands[1], commands.bytes(2)).await;
+    let role = state.lock().await.get_role().await;
+    if role == "master" {
+        write_response(&stream, RespDataType::Integer(receivers as i64)).await;
+    }
+}
+
+pub async fn handle_publish(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    publish(commands, stream, state, SubscriptionKind::Channel, "publish").await;
+}
+
+pub async fn handle_spublish(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    publish(commands, stream, state, SubscriptionKind::ShardChannel, "spublish").await;
+}
+
+pub async fn handle_pubsub(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    let subcommand = commands.get(1).map(|s| s.to_uppercase()).unwrap_or_default();
+    let response = match subcommand.as_str() {
+        "CHANNELS" | "SHARDCHANNELS" if commands.len() <= 3 => {
+            let kind = if subcommand == "CHANNELS" { SubscriptionKind::Channel } else { SubscriptionKind::ShardChannel };
+            let pattern = commands.get(2).map(|s| s.as_str());
+            let channels = state.lock().await.get_active_channels(kind, pattern).await;
+            RespDataType::Array(channels.into_iter().map(RespDataType::BulkString).collect())
+        }
+        "NUMSUB" | "SHARDNUMSUB" => {
+            let kind = if subcommand == "NUMSUB" { SubscriptionKind::Channel } else { SubscriptionKind::ShardChannel };
+            let mut result = Vec::new();
+            for channel in commands[2..].iter() {
+                let count = state.lock().await.get_subscriber_count(kind, channel).await;
+                result.push(RespDataType::BulkString(channel.clone()));
+                result.push(RespDataType::Integer(count as i64));
+            }
+            RespDataType::Array(result)
+        }
+        "NUMPAT" if commands.len() == 2 => {
+            let patterns = state.lock().await.get_active_channels(SubscriptionKind::Pattern, None).await;
+            RespDataType::Integer(patterns.len() as i64)
+        }
+        _ => RespDataType::SimpleError(format!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try PUBSUB HELP.",
+            commands.get(1).cloned().unwrap_or_default()
+        )),
+    };
+    write_response(&stream, response).await;
+}
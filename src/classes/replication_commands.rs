@@ -1,14 +1,14 @@
-use crate::classes::{RespDataType::RespDataType, State::State, State::Replica, Constants::EMPTY_RDB_HEX_REPRESENTATION};
+use crate::classes::{Parser::Command, RespDataType::RespDataType, State::State, State::Replica, Constants::EMPTY_RDB_HEX_REPRESENTATION};
 use std::sync::Arc;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use crate::classes::Connection::{SharedReader, SharedWriter};
 use tokio::sync::Mutex;
 use tokio::io::AsyncWriteExt;
 use std::time::{Duration, Instant};
 use hex;
 
 pub async fn handle_psync(
-    reader: Arc<Mutex<OwnedReadHalf>>,
-    writer: Arc<Mutex<OwnedWriteHalf>>,
+    reader: SharedReader,
+    writer: SharedWriter,
     state: Arc<Mutex<State>>,
 ) {
     println!("received psync command");
@@ -19,7 +19,7 @@ pub async fn handle_psync(
     };
     state.lock().await.add_replica(replica).await;
     
-    let header = format!("FULLRESYNC 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb 0");
+    let header = "FULLRESYNC 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb 0".to_string();
     writer
         .lock()
         .await
@@ -39,10 +39,10 @@ pub async fn handle_psync(
 }
 
 pub async fn handle_replconf(
-    commands: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+    commands: &Command,
+    stream: SharedWriter,
     state: Arc<Mutex<State>>,
-    reader: Arc<Mutex<OwnedReadHalf>>,
+    reader: SharedReader,
 ) {
     if let Some(repl_conf_second) = commands.get(1) {
         println!("REPLCONF command received: {}", repl_conf_second);
@@ -89,8 +89,8 @@ pub async fn handle_replconf(
 }
 
 pub async fn handle_wait(
-    commands: &mut Vec<String>,
-    writer: Arc<Mutex<OwnedWriteHalf>>,
+    commands: &Command,
+    writer: SharedWriter,
     state: Arc<Mutex<State>>,
 ) {
     let num = commands.get(1).and_then(|bs| bs.parse::<usize>().ok()).unwrap_or(0);
@@ -0,0 +1,288 @@
+use crate::classes::{
+    Glob::glob_match,
+    keyspace_commands::{parse_cursor, parse_scan_options, scan_reply},
+    Notifications::NOTIFY_ZSET,
+    Parser::Command,
+    RespDataType::RespDataType,
+    SortedSet::{format_score, parse_score, SortedSet},
+    State::State,
+};
+use std::sync::Arc;
+use crate::classes::Connection::{write_response, wrong_arity, SharedWriter};
+use tokio::sync::Mutex;
+
+const NOT_A_FLOAT_ERROR: &str = "ERR value is not a valid float";
+
+// ZADD key [NX | XX] [GT | LT] [CH] [INCR] score member [score member ...]
+pub async fn handle_zadd(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 4 {
+        return write_response(&stream, wrong_arity("zadd")).await;
+    }
+    let key = commands[1].clone();
+
+    let (mut nx, mut xx, mut gt, mut lt, mut ch, mut incr) = (false, false, false, false, false, false);
+    let mut i = 2;
+    while i < commands.len() {
+        match commands[i].to_uppercase().as_str() {
+            "NX" => nx = true,
+            "XX" => xx = true,
+            "GT" => gt = true,
+            "LT" => lt = true,
+            "CH" => ch = true,
+            "INCR" => incr = true,
+            _ => break,
+        }
+        i += 1;
+    }
+    let pairs = &commands[i..];
+    let option_error = if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        Some("ERR syntax error")
+    } else if nx && xx {
+        Some("ERR XX and NX options at the same time are not compatible")
+    } else if (nx && (gt || lt)) || (gt && lt) {
+        Some("ERR GT, LT, and/or NX options at the same time are not compatible")
+    } else if incr && pairs.len() > 2 {
+        Some("ERR INCR option supports a single increment-element pair")
+    } else {
+        None
+    };
+    if let Some(option_error) = option_error {
+        return write_response(&stream, RespDataType::SimpleError(option_error.to_string())).await;
+    }
+
+    // every score is validated before anything is written
+    let mut elements = Vec::new();
+    for pair in pairs.chunks(2) {
+        match parse_score(&pair[0]) {
+            Some(score) => elements.push((score, pair[1].clone())),
+            None => return write_response(&stream, RespDataType::SimpleError(NOT_A_FLOAT_ERROR.to_string())).await,
+        }
+    }
+
+    let state_guard = state.lock().await;
+    let (mut zset, expiration) = match state_guard.get_sorted_set(&key).await {
+        Ok(Some(v)) => (v.value, v.expiration_timestamp),
+        Ok(None) => (SortedSet::new(), None),
+        Err(error) => {
+            drop(state_guard);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+
+    let (mut added, mut updated) = (0, 0);
+    let mut incr_result = None;
+    for (score, member) in elements {
+        let current = zset.score(&member);
+        if (nx && current.is_some()) || (xx && current.is_none()) {
+            continue;
+        }
+        let new_score = match (incr, current) {
+            (true, Some(current)) => current + score,
+            _ => score,
+        };
+        if new_score.is_nan() {
+            drop(state_guard);
+            return write_response(&stream, RespDataType::SimpleError("ERR resulting score is not a number (NaN)".to_string())).await;
+        }
+        if let Some(current) = current {
+            if (gt && new_score <= current) || (lt && new_score >= current) {
+                continue;
+            }
+            if new_score != current {
+                updated += 1;
+            }
+        } else {
+            added += 1;
+        }
+        zset.insert(&member, new_score);
+        incr_result = Some(new_score);
+    }
+
+    if added + updated > 0 {
+        state_guard.set_sorted_set(key.clone(), zset, expiration).await;
+        let event = if incr { "zincr" } else { "zadd" };
+        state_guard.notify_keyspace_event(NOTIFY_ZSET, event, &key).await;
+    }
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+
+    let response = if incr {
+        incr_result.map_or(RespDataType::Nil, |score| RespDataType::BulkString(format_score(score)))
+    } else if ch {
+        RespDataType::Integer(added + updated)
+    } else {
+        RespDataType::Integer(added)
+    };
+    if role == "master" {
+        write_response(&stream, response).await;
+    }
+}
+
+pub async fn handle_zscore(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 3 {
+        return write_response(&stream, wrong_arity("zscore")).await;
+    }
+    let response = match state.lock().await.get_sorted_set(&commands[1]).await {
+        Ok(zset) => match zset.and_then(|z| z.value.score(&commands[2])) {
+            Some(score) => RespDataType::BulkString(format_score(score)),
+            None => RespDataType::Nil,
+        },
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    write_response(&stream, response).await;
+}
+
+pub async fn handle_zcard(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 2 {
+        return write_response(&stream, wrong_arity("zcard")).await;
+    }
+    let response = match state.lock().await.get_sorted_set(&commands[1]).await {
+        Ok(zset) => RespDataType::Integer(zset.map_or(0, |z| z.value.len()) as i64),
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    write_response(&stream, response).await;
+}
+
+pub async fn handle_zrem(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 3 {
+        return write_response(&stream, wrong_arity("zrem")).await;
+    }
+    let key = commands[1].clone();
+
+    let state_guard = state.lock().await;
+    let response = match state_guard.get_sorted_set(&key).await {
+        Ok(Some(v)) => {
+            let mut zset = v.value;
+            let removed = commands[2..].iter().filter(|member| zset.remove(member)).count();
+            if removed > 0 {
+                state_guard.notify_keyspace_event(NOTIFY_ZSET, "zrem", &key).await;
+                state_guard.set_sorted_set(key.clone(), zset, v.expiration_timestamp).await;
+            }
+            RespDataType::Integer(removed as i64)
+        }
+        Ok(None) => RespDataType::Integer(0),
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, response).await;
+    }
+}
+
+// ZRANK key member
+pub async fn handle_zrank(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 3 {
+        return write_response(&stream, wrong_arity("zrank")).await;
+    }
+    let response = match state.lock().await.get_sorted_set(&commands[1]).await {
+        Ok(zset) => match zset.and_then(|z| z.value.rank(&commands[2])) {
+            Some(rank) => RespDataType::Integer(rank as i64),
+            None => RespDataType::Nil,
+        },
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    write_response(&stream, response).await;
+}
+
+// ZRANGE key start stop [REV] [WITHSCORES], by index
+pub async fn handle_zrange(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 4 {
+        return write_response(&stream, wrong_arity("zrange")).await;
+    }
+    let (mut rev, mut with_scores) = (false, false);
+    for option in commands[4..].iter() {
+        match option.to_uppercase().as_str() {
+            "REV" => rev = true,
+            "WITHSCORES" => with_scores = true,
+            _ => return write_response(&stream, RespDataType::SimpleError("ERR syntax error".to_string())).await,
+        }
+    }
+    let (mut start, mut stop) = match (commands[2].parse::<i64>(), commands[3].parse::<i64>()) {
+        (Ok(start), Ok(stop)) => (start, stop),
+        _ => return write_response(&stream, RespDataType::SimpleError("ERR value is not an integer or out of range".to_string())).await,
+    };
+
+    let zset = match state.lock().await.get_sorted_set(&commands[1]).await {
+        Ok(zset) => zset.map(|z| z.value).unwrap_or_default(),
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+    let length = zset.len() as i64;
+    if start < 0 {
+        start = (length + start).max(0);
+    }
+    if stop < 0 {
+        stop += length;
+    }
+    stop = stop.min(length - 1);
+
+    let mut result = Vec::new();
+    if start <= stop {
+        let entries: Box<dyn Iterator<Item = _>> = if rev { Box::new(zset.iter().rev()) } else { Box::new(zset.iter()) };
+        for entry in entries.skip(start as usize).take((stop - start + 1) as usize) {
+            result.push(RespDataType::BulkString(entry.member.clone()));
+            if with_scores {
+                result.push(RespDataType::BulkString(format_score(entry.score)));
+            }
+        }
+    }
+    write_response(&stream, RespDataType::Array(result)).await;
+}
+
+// ZSCAN key cursor [MATCH pattern] [COUNT count]
+pub async fn handle_zscan(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 3 {
+        return write_response(&stream, wrong_arity("zscan")).await;
+    }
+    let cursor = parse_cursor(&commands[2]);
+    let options = cursor.and_then(|cursor| Ok((cursor, parse_scan_options(&commands[3..], false)?)));
+    let (cursor, options) = match options {
+        Ok(options) => options,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+
+    let response = match state.lock().await.get_sorted_set(&commands[1]).await {
+        Ok(None) => scan_reply(0, vec![]),
+        Ok(Some(zset)) => {
+            let (next_cursor, entries) = zset.value.scan(cursor, options.count);
+            let mut elements = Vec::with_capacity(entries.len() * 2);
+            for entry in entries {
+                if options.pattern.as_ref().is_none_or(|pattern| glob_match(pattern, &entry.member)) {
+                    elements.push(RespDataType::BulkString(entry.member.clone()));
+                    elements.push(RespDataType::BulkString(format_score(entry.score)));
+                }
+            }
+            scan_reply(next_cursor, elements)
+        }
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    write_response(&stream, response).await;
+}
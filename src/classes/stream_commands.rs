@@ -1,444 +1,1717 @@
-use crate::classes::{RespDataType::RespDataType, State::State};
+use crate::classes::{
+    Blocking::{wait_until_ready, WakeReason},
+    CommandExecutor::CommandExecutor,
+    ExpiringValue::unix_millis_now,
+    Notifications::NOTIFY_STREAM,
+    Parser::Command,
+    RespDataType::RespDataType,
+    State::State,
+    Stream::{Consumer, ConsumerGroup, PendingEntry, Stream, StreamFields, TrimStrategy, STREAM_NODE_MAX_ENTRIES},
+    StreamId::StreamId,
+};
+use std::ops::Bound;
 use std::sync::Arc;
-use tokio::net::tcp::OwnedWriteHalf;
+use crate::classes::Connection::{write_response, wrong_arity, SharedReader, SharedWriter};
 use tokio::sync::Mutex;
-use tokio::io::AsyncWriteExt;
-use std::time::{SystemTime, UNIX_EPOCH, Instant, Duration};
-use tokio::time;
-
-fn populate_entries(values: Vec<String>, current_id: &String) -> Vec<(String, String)> {
-    let mut res: Vec<(String, String)> = Vec::new();
-    res.push(("id".to_string(), current_id.clone()));
-    let mut i = 0;
-    while i < values.len() {
-        let (key, value) = (&values[i], &values[i + 1]);
-        res.push((key.clone(), value.clone()));
-        i += 2;
-    }
-    res
+use std::time::{Instant, Duration};
+
+const XADD_ID_TOO_SMALL_ERROR: &str = "ERR The ID specified in XADD is equal or smaller than the target stream top item";
+
+fn populate_entries(values: &[String]) -> StreamFields {
+    values
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect()
 }
 
-fn generate_stream_id(latest_id: Option<&String>, current_id: String) -> String {
-    if current_id == "*" {
-        let start = SystemTime::now();
-        let since_the_epoch = start
-            .duration_since(UNIX_EPOCH)
-            .expect("time should go forward");
-        return format!("{}-{}", since_the_epoch.as_millis(), 0);
+// The ID a new entry gets. `*` uses the current time and `<ms>-*` the next
+// sequence number for `ms`; both stay above the last ID of the stream.
+// Nothing can be added after the greatest possible ID.
+fn generate_stream_id(last_id: Option<StreamId>, requested: &str) -> Result<StreamId, String> {
+    let last_id = last_id.unwrap_or(StreamId::MIN);
+    if last_id == StreamId::MAX {
+        return Err("ERR The stream has exhausted the last possible ID, unable to add more items".to_string());
     }
+    let id = if requested == "*" {
+        let now = unix_millis_now();
+        if now > last_id.ms {
+            StreamId::new(now, 0)
+        } else {
+            last_id.incr().ok_or(XADD_ID_TOO_SMALL_ERROR.to_string())?
+        }
+    } else if let Some(ms) = requested.strip_suffix("-*").filter(|ms| !ms.contains('-')) {
+        let ms = StreamId::parse_strict(ms, 0)?.ms;
+        match ms == last_id.ms {
+            true if last_id.seq == u64::MAX => return Err(XADD_ID_TOO_SMALL_ERROR.to_string()),
+            true => StreamId::new(ms, last_id.seq + 1),
+            false => StreamId::new(ms, 0),
+        }
+    } else {
+        let id = StreamId::parse_strict(requested, 0)?;
+        if id == StreamId::MIN {
+            return Err("ERR The ID specified in XADD must be greater than 0-0".to_string());
+        }
+        id
+    };
+    if id <= last_id {
+        return Err(XADD_ID_TOO_SMALL_ERROR.to_string());
+    }
+    Ok(id)
+}
 
-    let parts: Vec<&str> = current_id.split("-").collect();
-    let (miliseconds_time, sequence_number) = (parts[0], parts[1]);
-
-    if sequence_number == "*" {
-        match latest_id {
-            Some(latest_id) => {
-                let latest_id_parts: Vec<&str> = latest_id.split("-").collect();
-                let last_miliseconds_time = latest_id_parts[0];
-                let last_sequence_number = latest_id_parts[1];
+// The trimming options of XADD and XTRIM:
+// <MAXLEN | MINID> [= | ~] threshold [LIMIT count]
+#[derive(Default)]
+struct TrimArgs {
+    strategy: Option<TrimStrategy>,
+    approx: bool,
+    limit: Option<usize>,
+}
 
-                let mut current_sequence_number = 0;
-                if miliseconds_time <= last_miliseconds_time {
-                    current_sequence_number =
-                        last_sequence_number.parse::<u32>().unwrap() + 1u32
+impl TrimArgs {
+    // Consumes the trimming option at `commands[i]`, returning the index
+    // after it. Ok(None) means `commands[i]` is not a trimming option.
+    fn parse_option(&mut self, commands: &[String], i: usize) -> Result<Option<usize>, String> {
+        let option = commands[i].to_uppercase();
+        match option.as_str() {
+            "MAXLEN" | "MINID" => {
+                let mut i = i + 1;
+                self.approx = commands.get(i).is_some_and(|modifier| modifier == "~");
+                if commands.get(i).is_some_and(|modifier| modifier == "=" || modifier == "~") {
+                    i += 1;
                 }
+                let Some(threshold) = commands.get(i) else {
+                    return Err("ERR syntax error".to_string());
+                };
+                let strategy = if option == "MAXLEN" {
+                    match parse_integer(threshold)? {
+                        max_len if max_len < 0 => return Err("ERR The MAXLEN argument must be >= 0.".to_string()),
+                        max_len => TrimStrategy::MaxLen(max_len as u64),
+                    }
+                } else {
+                    TrimStrategy::MinId(StreamId::parse_strict(threshold, 0)?)
+                };
+                let mixed = matches!(
+                    (self.strategy, strategy),
+                    (Some(TrimStrategy::MaxLen(_)), TrimStrategy::MinId(_)) | (Some(TrimStrategy::MinId(_)), TrimStrategy::MaxLen(_))
+                );
+                if mixed {
+                    return Err("ERR syntax error, MAXLEN and MINID options at the same time are not compatible".to_string());
+                }
+                self.strategy = Some(strategy);
+                Ok(Some(i + 1))
+            }
+            "LIMIT" if i + 1 < commands.len() => {
+                self.limit = match parse_integer(&commands[i + 1])? {
+                    limit if limit < 0 => return Err("ERR The LIMIT argument must be >= 0.".to_string()),
+                    limit => Some(limit as usize),
+                };
+                Ok(Some(i + 2))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.limit.is_some() && !self.approx {
+            return Err("ERR syntax error, LIMIT cannot be used without the special ~ option".to_string());
+        }
+        Ok(())
+    }
+
+    // Trims the stream, returning how many entries went away
+    fn trim(&self, s: &mut Stream) -> usize {
+        let Some(strategy) = self.strategy else {
+            return 0;
+        };
+        // like Redis, an approximate trim defaults to 100 nodes at a time
+        let limit = match self.approx {
+            true => self.limit.unwrap_or(100 * STREAM_NODE_MAX_ENTRIES),
+            false => 0,
+        };
+        s.trim(strategy, self.approx, limit)
+    }
 
-                return format!("{}-{}", miliseconds_time, current_sequence_number);
+    // The options to send replicas so they trim exactly what the master did
+    fn to_exact_command(&self, s: &Stream) -> Vec<String> {
+        match self.strategy {
+            Some(TrimStrategy::MaxLen(max_len)) if !self.approx => to_command(&["MAXLEN", "=", &max_len.to_string()]),
+            Some(TrimStrategy::MaxLen(_)) => to_command(&["MAXLEN", "=", &s.len().to_string()]),
+            Some(TrimStrategy::MinId(min_id)) => {
+                let min_id = match self.approx {
+                    true => s.first_entry().map_or(min_id, |(id, _)| *id),
+                    false => min_id,
+                };
+                to_command(&["MINID", "=", &min_id.to_string()])
             }
-            None => {
-                let mut sequence_number = "0";
-                if miliseconds_time == "0" {
-                    sequence_number = "1";
-                }
-                println!("sequence number: {}", sequence_number);
-                return format!("{}-{}", miliseconds_time, sequence_number);
+            None => vec![],
+        }
+    }
+}
+
+// XADD key [NOMKSTREAM] [<MAXLEN | MINID> [= | ~] threshold [LIMIT count]]
+//   <* | id> field value [field value ...]
+// Replicas get the generated ID and an exact trim.
+pub async fn handle_xadd(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 5 {
+        return write_response(&stream, wrong_arity("xadd")).await;
+    }
+    let key = commands[1].clone();
+
+    let mut i = 2;
+    let mut nomkstream = false;
+    let mut trim_args = TrimArgs::default();
+    while i < commands.len() {
+        if commands[i].eq_ignore_ascii_case("NOMKSTREAM") {
+            nomkstream = true;
+            i += 1;
+            continue;
+        }
+        match trim_args.parse_option(commands, i) {
+            Ok(Some(next)) => i = next,
+            Ok(None) => break,
+            Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+        }
+    }
+    if let Err(error) = trim_args.validate() {
+        return write_response(&stream, RespDataType::SimpleError(error)).await;
+    }
+    let field_values = commands.get(i + 1..).unwrap_or_default();
+    if field_values.is_empty() || !field_values.len().is_multiple_of(2) {
+        return write_response(&stream, wrong_arity("xadd")).await;
+    }
+
+    let state_guard = state.lock().await;
+    let role = state_guard.get_role().await;
+    let last_id = match state_guard.with_stream(&key, |s| s.last_id).await {
+        Ok(None) if nomkstream => {
+            drop(state_guard);
+            if role == "master" {
+                write_response(&stream, RespDataType::Nil).await;
             }
+            return;
+        }
+        Ok(last_id) => last_id,
+        Err(error) => {
+            drop(state_guard);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
         }
+    };
+    let current_id = match generate_stream_id(last_id, &commands[i]) {
+        Ok(id) => id,
+        Err(error) => {
+            drop(state_guard);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+
+    let fields = populate_entries(field_values);
+    if last_id.is_none() {
+        state_guard.set_stream(key.clone(), Stream::new()).await;
     }
+    let (trimmed, trim_command) = state_guard
+        .with_stream_mut(&key, |s| {
+            s.insert(current_id, fields);
+            let trimmed = trim_args.trim(s);
+            (trimmed, trim_args.to_exact_command(s))
+        })
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    state_guard.signal_key_as_ready(&key).await;
+    state_guard.notify_keyspace_event(NOTIFY_STREAM, "xadd", &key).await;
+    if trimmed > 0 {
+        state_guard.notify_keyspace_event(NOTIFY_STREAM, "xtrim", &key).await;
+    }
+    drop(state_guard);
 
-    return current_id;
+    if role == "master" {
+        write_response(&stream, RespDataType::BulkString(current_id.to_string())).await;
+    }
+    let mut propagated = to_command(&["XADD", &key]);
+    if nomkstream {
+        propagated.push("NOMKSTREAM".to_string());
+    }
+    propagated.extend(trim_command);
+    propagated.push(current_id.to_string());
+    propagated.extend_from_slice(field_values);
+    propagate(&stream, &state, vec![propagated]).await;
 }
 
-async fn validate_stream_entry(
-    stream: Arc<Mutex<OwnedWriteHalf>>,
-    latest_entry_id: Option<&String>,
-    current_entry_id: String,
-) -> bool {
-    if current_entry_id == "0-0" {
-        stream
-            .lock()
-            .await
-            .write_all(RespDataType::SimpleError("ERR The ID specified in XADD must be greater than 0-0".to_string()).to_string().as_bytes())
-            .await
-            .unwrap();
-        return false;
-    } else if let Some(latest_entry_id) = latest_entry_id {
-        if current_entry_id <= *latest_entry_id {
-            stream
-            .lock()
-            .await
-            .write_all(RespDataType::SimpleError("ERR The ID specified in XADD is equal or smaller than the target stream top item".to_string()).to_string().as_bytes())
-            .await
-            .unwrap();
-            return false;
+// XTRIM key <MAXLEN | MINID> [= | ~] threshold [LIMIT count]
+pub async fn handle_xtrim(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 4 {
+        return write_response(&stream, wrong_arity("xtrim")).await;
+    }
+    let key = commands[1].clone();
+    let mut trim_args = TrimArgs::default();
+    let mut i = 2;
+    while i < commands.len() {
+        match trim_args.parse_option(commands, i) {
+            Ok(Some(next)) => i = next,
+            Ok(None) => return write_response(&stream, RespDataType::SimpleError("ERR syntax error".to_string())).await,
+            Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
         }
     }
+    if trim_args.strategy.is_none() {
+        let error = "ERR syntax error, XTRIM must be called with a trimming strategy".to_string();
+        return write_response(&stream, RespDataType::SimpleError(error)).await;
+    }
+    if let Err(error) = trim_args.validate() {
+        return write_response(&stream, RespDataType::SimpleError(error)).await;
+    }
 
-    return true;
+    let state_guard = state.lock().await;
+    let role = state_guard.get_role().await;
+    let trimmed = state_guard
+        .with_stream_mut(&key, |s| (trim_args.trim(s), trim_args.to_exact_command(s)))
+        .await;
+    let (trimmed, trim_command) = match trimmed {
+        Ok(trimmed) => trimmed.unwrap_or_default(),
+        Err(error) => {
+            drop(state_guard);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+    if trimmed > 0 {
+        state_guard.notify_keyspace_event(NOTIFY_STREAM, "xtrim", &key).await;
+    }
+    drop(state_guard);
+
+    if role == "master" {
+        write_response(&stream, RespDataType::Integer(trimmed as i64)).await;
+    }
+    if trimmed > 0 {
+        let mut propagated = to_command(&["XTRIM", &key]);
+        propagated.extend(trim_command);
+        propagate(&stream, &state, vec![propagated]).await;
+    }
 }
 
-pub async fn handle_xadd(
-    commands: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+// XDEL key id [id ...]
+pub async fn handle_xdel(
+    commands: &Command,
+    stream: SharedWriter,
     state: Arc<Mutex<State>>,
 ) {
-    if commands.len() >= 3 {
-        let key = commands[1].clone();
-        let mut current_id = commands[2].clone();
-        let state_guard = state.lock().await;
-        
-        let stream_data = state_guard.get_stream(&key).await;
-        let mut latest_entry_id = None;
-        if let Some(stream_vector) = &stream_data {
-            if let Some(last_entry) = stream_vector.last() {
-                for (k, v) in last_entry {
-                    if k == "id" {
-                        latest_entry_id = Some(v);
-                        break;
+    if commands.len() < 3 {
+        return write_response(&stream, wrong_arity("xdel")).await;
+    }
+    let mut ids = Vec::with_capacity(commands.len() - 2);
+    for id in &commands[2..] {
+        match StreamId::parse_strict(id, 0) {
+            Ok(id) => ids.push(id),
+            Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+        }
+    }
+
+    let state_guard = state.lock().await;
+    let role = state_guard.get_role().await;
+    // an emptied stream stays around, keeping its last ID
+    let deleted = state_guard
+        .with_stream_mut(&commands[1], |s| ids.iter().filter(|id| s.delete(id)).count())
+        .await;
+    let deleted = match deleted {
+        Ok(deleted) => deleted.unwrap_or(0),
+        Err(error) => {
+            drop(state_guard);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+    if deleted > 0 {
+        state_guard.notify_keyspace_event(NOTIFY_STREAM, "xdel", &commands[1]).await;
+    }
+    drop(state_guard);
+
+    if role == "master" {
+        write_response(&stream, RespDataType::Integer(deleted as i64)).await;
+    }
+}
+
+// XLEN key
+pub async fn handle_xlen(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 2 {
+        return write_response(&stream, wrong_arity("xlen")).await;
+    }
+    match state.lock().await.with_stream(&commands[1], |s| s.len()).await {
+        Ok(len) => write_response(&stream, RespDataType::Integer(len.unwrap_or(0) as i64)).await,
+        Err(error) => write_response(&stream, RespDataType::SimpleError(error)).await,
+    }
+}
+
+// XSETID key last-id [ENTRIESADDED entries-added] [MAXDELETEDID max-deleted-id]
+pub async fn handle_xsetid(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 3 {
+        return write_response(&stream, wrong_arity("xsetid")).await;
+    }
+    let last_id = match StreamId::parse_strict(&commands[2], 0) {
+        Ok(last_id) => last_id,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+    let mut entries_added = None;
+    let mut max_deleted_id = None;
+    let mut i = 3;
+    while i < commands.len() {
+        match commands[i].to_uppercase().as_str() {
+            "ENTRIESADDED" if i + 1 < commands.len() => {
+                entries_added = match parse_integer(&commands[i + 1]) {
+                    Ok(entries_added) if entries_added < 0 => {
+                        return write_response(&stream, RespDataType::SimpleError("ERR entries_added must be positive".to_string())).await;
                     }
-                }
+                    Ok(entries_added) => Some(entries_added as u64),
+                    Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+                };
+            }
+            "MAXDELETEDID" if i + 1 < commands.len() => {
+                max_deleted_id = match StreamId::parse_strict(&commands[i + 1], 0) {
+                    Ok(id) if id > last_id => {
+                        let error = "ERR The ID specified in XSETID is smaller than the provided max_deleted_entry_id";
+                        return write_response(&stream, RespDataType::SimpleError(error.to_string())).await;
+                    }
+                    Ok(id) => Some(id),
+                    Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+                };
             }
+            _ => return write_response(&stream, RespDataType::SimpleError("ERR syntax error".to_string())).await,
         }
-        current_id = generate_stream_id(latest_entry_id, current_id);
-        let entry = populate_entries(commands[3..].to_vec(), &current_id);
-        
-        if validate_stream_entry(stream.clone(), latest_entry_id, current_id.clone()).await {
-            let mut new_stream_data = stream_data.unwrap_or_default();
-            new_stream_data.push(entry);
-            drop(state_guard);
-            state.lock().await.set_stream(key, new_stream_data).await;
-            
-            let role = state.lock().await.get_role().await;
-            if role == "master" {
-                stream
-                    .lock()
-                    .await
-                    .write_all(RespDataType::BulkString(current_id).to_string().as_bytes())
-                    .await
-                    .unwrap();
+        i += 2;
+    }
+
+    let state_guard = state.lock().await;
+    let role = state_guard.get_role().await;
+    let result = state_guard
+        .with_stream_mut(&commands[1], |s| {
+            if last_id < s.max_deleted_id {
+                return Err("ERR The ID specified in XSETID is smaller than current max_deleted_entry_id".to_string());
             }
+            if let Some((top_id, _)) = s.last_entry() {
+                if last_id < *top_id {
+                    return Err("ERR The ID specified in XSETID is smaller than the target stream top item".to_string());
+                }
+                if entries_added.is_some_and(|entries_added| entries_added < s.len() as u64) {
+                    return Err("ERR The entries_added specified in XSETID is smaller than the target stream length".to_string());
+                }
+            }
+            s.last_id = last_id;
+            if let Some(entries_added) = entries_added {
+                s.entries_added = entries_added;
+            }
+            if let Some(max_deleted_id) = max_deleted_id.filter(|id| *id != StreamId::MIN) {
+                s.max_deleted_id = max_deleted_id;
+            }
+            Ok(())
+        })
+        .await;
+    let response = match result {
+        Ok(Some(Ok(()))) => {
+            state_guard.notify_keyspace_event(NOTIFY_STREAM, "xsetid", &commands[1]).await;
+            if role != "master" {
+                return;
+            }
+            RespDataType::SimpleString("OK".to_string())
         }
+        Ok(None) => RespDataType::SimpleError("ERR no such key".to_string()),
+        Ok(Some(Err(error))) | Err(error) => RespDataType::SimpleError(error),
+    };
+    drop(state_guard);
+    write_response(&stream, response).await;
+}
+
+// An XRANGE bound: an ID (a missing sequence number defaults to
+// `missing_seq`), `-` / `+`, or `(ID` to leave the ID itself out
+fn parse_range_bound(value: &str, missing_seq: u64, is_start: bool) -> Result<StreamId, String> {
+    match value.strip_prefix('(') {
+        Some(id) if !id.is_empty() => {
+            let id = StreamId::parse_strict(id, missing_seq)?;
+            let bound = if is_start { id.incr() } else { id.decr() };
+            let side = if is_start { "start" } else { "end" };
+            bound.ok_or(format!("ERR invalid {} ID for the interval", side))
+        }
+        _ => StreamId::parse(value, missing_seq),
+    }
+}
+
+fn entry_reply(id: &StreamId, fields: &StreamFields) -> RespDataType {
+    let mut field_value_pairs = Vec::with_capacity(fields.len() * 2);
+    for (k, v) in fields {
+        field_value_pairs.push(RespDataType::BulkString(k.clone()));
+        field_value_pairs.push(RespDataType::BulkString(v.clone()));
     }
+    RespDataType::Array(vec![
+        RespDataType::BulkString(id.to_string()),
+        RespDataType::Array(field_value_pairs),
+    ])
 }
 
+// XRANGE key start end [COUNT count]
 pub async fn handle_xrange(
-    commands: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    xrange_generic(commands, stream, state, false).await;
+}
+
+// XREVRANGE key end start [COUNT count]
+pub async fn handle_xrevrange(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    xrange_generic(commands, stream, state, true).await;
+}
+
+async fn xrange_generic(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    rev: bool,
+) {
+    if commands.len() < 4 {
+        return write_response(&stream, wrong_arity(if rev { "xrevrange" } else { "xrange" })).await;
+    }
+    let (start, end) = if rev { (&commands[3], &commands[2]) } else { (&commands[2], &commands[3]) };
+    let bounds = parse_range_bound(start, 0, true)
+        .and_then(|start| Ok((start, parse_range_bound(end, u64::MAX, false)?)));
+    let (start, end) = match bounds {
+        Ok(bounds) => bounds,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+    let mut count = usize::MAX;
+    let mut i = 4;
+    while i < commands.len() {
+        match commands[i].to_uppercase().as_str() {
+            "COUNT" if i + 1 < commands.len() => {
+                count = match parse_integer(&commands[i + 1]) {
+                    Ok(count) => count.max(0) as usize,
+                    Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+                };
+                i += 2;
+            }
+            _ => return write_response(&stream, RespDataType::SimpleError("ERR syntax error".to_string())).await,
+        }
+    }
+
+    let entries = state
+        .lock()
+        .await
+        .with_stream(&commands[1], |s| {
+            let range = s.range(start, end);
+            let entries: Box<dyn Iterator<Item = _>> = if rev { Box::new(range.rev()) } else { Box::new(range) };
+            entries.take(count).map(|(id, fields)| entry_reply(id, fields)).collect()
+        })
+        .await;
+    match entries {
+        Ok(entries) => write_response(&stream, RespDataType::Array(entries.unwrap_or_default())).await,
+        Err(error) => write_response(&stream, RespDataType::SimpleError(error)).await,
+    }
+}
+
+// The options XREAD and XREADGROUP share. GROUP and NOACK are XREADGROUP's.
+#[derive(Default)]
+struct ReadOptions {
+    block_time: Option<u64>,
+    count: Option<usize>,
+    noack: bool,
+    group: Option<(String, String)>,
+    // where the keys start, right after STREAMS
+    streams_at: Option<usize>,
+}
+
+fn parse_read_options(commands: &[String], is_group: bool) -> Result<ReadOptions, String> {
+    let mut options = ReadOptions::default();
+    let mut i = 1;
+    while i < commands.len() {
+        match commands[i].to_uppercase().as_str() {
+            "COUNT" if i + 1 < commands.len() => {
+                options.count = match parse_integer(&commands[i + 1])? {
+                    count if count > 0 => Some(count as usize),
+                    _ => None,
+                };
+                i += 2;
+            }
+            "BLOCK" if i + 1 < commands.len() => {
+                options.block_time = match commands[i + 1].parse::<i64>() {
+                    Ok(timeout) if timeout < 0 => return Err("ERR timeout is negative".to_string()),
+                    Ok(timeout) => Some(timeout as u64),
+                    Err(_) => return Err("ERR timeout is not an integer or out of range".to_string()),
+                };
+                i += 2;
+            }
+            "NOACK" if is_group => {
+                options.noack = true;
+                i += 1;
+            }
+            "GROUP" if is_group && i + 2 < commands.len() => {
+                options.group = Some((commands[i + 1].clone(), commands[i + 2].clone()));
+                i += 3;
+            }
+            "STREAMS" => {
+                options.streams_at = Some(i + 1);
+                break;
+            }
+            _ => return Err("ERR syntax error".to_string()),
+        }
+    }
+    Ok(options)
+}
+
+// XREAD [COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id [id ...]
+// Inside MULTI (`can_block` false) BLOCK is ignored.
+pub async fn handle_xread(
+    commands: &Command,
+    stream: SharedWriter,
     state: Arc<Mutex<State>>,
+    reader: SharedReader,
+    can_block: bool,
 ) {
-    if commands.len() >= 4 {
-        let key = commands[1].clone();
-        let mut start = commands[2].clone();
-        let mut end = commands[3].clone();
+    if commands.len() < 4 {
+        return write_response(&stream, wrong_arity("xread")).await;
+    }
+
+    let options = match parse_read_options(commands, false) {
+        Ok(options) => options,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+    let (block_time, count) = (options.block_time, options.count);
+
+    let keys_and_ids = &commands[options.streams_at.unwrap_or(commands.len())..];
+    if keys_and_ids.is_empty() || !keys_and_ids.len().is_multiple_of(2) {
+        return write_response(&stream, RespDataType::SimpleError("ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.".to_string())).await;
+    }
+    let (keys, ids) = keys_and_ids.split_at(keys_and_ids.len() / 2);
+
+    // `$` reads only entries added after the command started
+    let mut streams: Vec<(String, StreamId)> = Vec::new();
+    for (key, id) in keys.iter().zip(ids) {
+        let start = if id == "$" {
+            match state.lock().await.with_stream(key, |s| s.last_id).await {
+                Ok(last_id) => last_id.unwrap_or(StreamId::MIN),
+                Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+            }
+        } else {
+            match StreamId::parse_strict(id, 0) {
+                Ok(start) => start,
+                Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+            }
+        };
+        streams.push((key.clone(), start));
+    }
+
+    let keys = keys.to_vec();
+    let mut blocked = None;
+    let mut results = {
         let state_guard = state.lock().await;
-        
-        let stream_data = state_guard.get_stream(&key).await;
-        
-        if start == "-" {
-            start = "0-0".to_string();
-        } else if !start.contains("-") {
-            start.push_str("-0");
-        }
-        if end != "+" && !end.contains("-") {
-            end.push_str("-0");
-        }
-        
-        match stream_data {
-            Some(vec) => {
-                let mut result: Vec<&Vec<(String, String)>> = Vec::new();
-                for entry in &vec {
-                    let mut entry_id = None;
-                    for (k, v) in entry {
-                        if k == "id" {
-                            entry_id = Some(v);
-                            break;
-                        }
-                    }
-                    if let Some(entry_id) = entry_id {
-                        if start.as_str() <= entry_id.as_str() {
-                            if entry_id.as_str() <= end.as_str() || end == "+" {
-                                result.push(entry);
-                            } else {
-                                break;
-                            }
-                        }
-                    }
+        let results = read_new_entries(&state_guard, &streams, count).await;
+        // registering under the same lock means no XADD can slip in between
+        if results.as_ref().is_ok_and(|results| results.is_empty()) && block_time.is_some() && can_block {
+            blocked = Some(state_guard.register_blocked_client(&keys).await);
+        }
+        results
+    };
+
+    // Readers don't consume entries, so every woken client just reads again
+    if let Some((id, waker)) = blocked {
+        let deadline = match block_time {
+            Some(timeout) if timeout > 0 => Some(Instant::now() + Duration::from_millis(timeout)),
+            _ => None, // Infinite block
+        };
+        loop {
+            match wait_until_ready(&waker, deadline, &reader).await {
+                WakeReason::Ready => {}
+                WakeReason::TimedOut => break,
+                WakeReason::Disconnected => {
+                    state.lock().await.unregister_blocked_client(&keys, id).await;
+                    return;
                 }
-                let mut result_resp_string = String::from(format!("*{}\r\n", result.len()));
-                for entry in result {
-                    result_resp_string.push_str("*2\r\n");
-                    let mut entry_id = None;
-                    for (k, v) in entry {
-                        if k == "id" {
-                            entry_id = Some(v);
-                            break;
-                        }
+            }
+            results = read_new_entries(&*state.lock().await, &streams, count).await;
+            if !results.as_ref().is_ok_and(|results| results.is_empty()) {
+                break;
+            }
+        }
+        state.lock().await.unregister_blocked_client(&keys, id).await;
+    }
+
+    match results {
+        Ok(results) if results.is_empty() => write_response(&stream, RespDataType::Nil).await,
+        Ok(results) => write_response(&stream, RespDataType::Array(results)).await,
+        Err(error) => write_response(&stream, RespDataType::SimpleError(error)).await,
+    }
+}
+
+// [key, [entries]] for every stream with entries newer than its start ID
+async fn read_new_entries(
+    state: &State,
+    streams: &[(String, StreamId)],
+    count: Option<usize>,
+) -> Result<Vec<RespDataType>, String> {
+    let mut results = Vec::new();
+    for (key, exclusive_start) in streams {
+        let entries: Vec<RespDataType> = state
+            .with_stream(key, |s| s.after(*exclusive_start).take(count.unwrap_or(usize::MAX)).map(|(id, fields)| entry_reply(id, fields)).collect())
+            .await?
+            .unwrap_or_default();
+        if !entries.is_empty() {
+            results.push(RespDataType::Array(vec![RespDataType::BulkString(key.clone()), RespDataType::Array(entries)]));
+        }
+    }
+    Ok(results)
+}
+
+// Sends the commands that reproduce a consumer group change to the replicas
+async fn propagate(stream: &SharedWriter, state: &Arc<Mutex<State>>, commands: Vec<Vec<String>>) {
+    if commands.is_empty() || state.lock().await.get_role().await != "master" {
+        return;
+    }
+    let mut executor = CommandExecutor { in_exec: false };
+    for command in commands {
+        executor.propogate_to_replicas(&Command::from(command), stream.clone(), state.clone()).await;
+    }
+}
+
+fn to_command(parts: &[&str]) -> Vec<String> {
+    parts.iter().map(|part| part.to_string()).collect()
+}
+
+// Replicas replay deliveries and claims as an XCLAIM that leaves the
+// pending entry exactly as it is on the master
+fn xclaim_command(key: &str, group_name: &str, id: &StreamId, pending: &PendingEntry, last_id: &StreamId) -> Vec<String> {
+    to_command(&[
+        "XCLAIM", key, group_name, &pending.consumer, "0", &id.to_string(),
+        "TIME", &pending.delivery_time.to_string(), "RETRYCOUNT", &pending.delivery_count.to_string(),
+        "FORCE", "JUSTID", "LASTID", &last_id.to_string(),
+    ])
+}
+
+fn setid_command(key: &str, group_name: &str, group: &ConsumerGroup) -> Vec<String> {
+    let entries_read = group.entries_read.map_or("-1".to_string(), |entries_read| entries_read.to_string());
+    to_command(&["XGROUP", "SETID", key, group_name, &group.last_id.to_string(), "ENTRIESREAD", &entries_read])
+}
+
+fn createconsumer_command(key: &str, group_name: &str, consumer: &str) -> Vec<String> {
+    to_command(&["XGROUP", "CREATECONSUMER", key, group_name, consumer])
+}
+
+fn nogroup_error(key: &str, group_name: &str) -> String {
+    format!("NOGROUP No such key '{}' or consumer group '{}'", key, group_name)
+}
+
+fn parse_integer(value: &str) -> Result<i64, String> {
+    value.parse::<i64>().map_err(|_| "ERR value is not an integer or out of range".to_string())
+}
+
+fn idle_time(now: u64, pending: &PendingEntry) -> u64 {
+    now.saturating_sub(pending.delivery_time)
+}
+
+// XGROUP CREATE key group <id | $> [MKSTREAM] [ENTRIESREAD entries-read]
+// XGROUP SETID key group <id | $> [ENTRIESREAD entries-read]
+// XGROUP DESTROY key group
+// XGROUP CREATECONSUMER key group consumer
+// XGROUP DELCONSUMER key group consumer
+pub async fn handle_xgroup(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 2 {
+        return write_response(&stream, wrong_arity("xgroup")).await;
+    }
+    let subcommand = commands[1].to_uppercase();
+    let arity_ok = match subcommand.as_str() {
+        "CREATE" => (5..=8).contains(&commands.len()),
+        "SETID" => (5..=7).contains(&commands.len()),
+        "DESTROY" => commands.len() == 4,
+        "CREATECONSUMER" | "DELCONSUMER" => commands.len() == 5,
+        _ => {
+            let error = format!("ERR unknown subcommand '{}'. Try XGROUP HELP.", commands[1]);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+    if !arity_ok {
+        return write_response(&stream, wrong_arity(&format!("xgroup|{}", subcommand.to_lowercase()))).await;
+    }
+    let mut mkstream = false;
+    let mut entries_read = None;
+    // only CREATE and SETID take more than five arguments
+    let mut i = 5;
+    while i < commands.len() {
+        match commands[i].to_uppercase().as_str() {
+            "MKSTREAM" if subcommand == "CREATE" => {
+                mkstream = true;
+                i += 1;
+            }
+            "ENTRIESREAD" if i + 1 < commands.len() => {
+                entries_read = match parse_integer(&commands[i + 1]) {
+                    Ok(-1) => None,
+                    Ok(value) if value >= 0 => Some(value as u64),
+                    Ok(_) => {
+                        let error = "ERR value for ENTRIESREAD must be positive or -1".to_string();
+                        return write_response(&stream, RespDataType::SimpleError(error)).await;
                     }
-                    if let Some(entry_id) = entry_id {
-                        result_resp_string.push_str(&format!("{}", RespDataType::BulkString(entry_id.clone())));
+                    Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+                };
+                i += 2;
+            }
+            _ => return write_response(&stream, RespDataType::SimpleError("ERR syntax error".to_string())).await,
+        }
+    }
+
+    let state_guard = state.lock().await;
+    let result = xgroup_generic(&state_guard, &subcommand, &commands[2..], mkstream, entries_read).await;
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+
+    match result {
+        Ok(response) if role == "master" => write_response(&stream, response).await,
+        Ok(_) => {}
+        Err(error) => write_response(&stream, RespDataType::SimpleError(error)).await,
+    }
+}
+
+async fn xgroup_generic(
+    state: &State,
+    subcommand: &str,
+    arguments: &[String],
+    mkstream: bool,
+    entries_read: Option<u64>,
+) -> Result<RespDataType, String> {
+    let (key, group_name) = (&arguments[0], &arguments[1]);
+    let missing_key_error = "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.";
+    let last_id = match state.with_stream(key, |s| s.last_id).await? {
+        Some(last_id) => last_id,
+        None if subcommand == "CREATE" && mkstream => StreamId::MIN,
+        None => return Err(missing_key_error.to_string()),
+    };
+    let nogroup = || format!("NOGROUP No such consumer group '{}' for key name '{}'", group_name, key);
+    let parse_group_id = |value: &str| match value {
+        "$" => Ok(last_id),
+        _ => StreamId::parse_strict(value, 0),
+    };
+
+    match subcommand {
+        "CREATE" => {
+            let id = parse_group_id(&arguments[2])?;
+            if mkstream && !state.exists(key).await {
+                state.set_stream(key.clone(), Stream::new()).await;
+            }
+            let created = state
+                .with_stream_mut(key, |s| {
+                    if s.groups.contains_key(group_name) {
+                        return false;
                     }
-                    let mut field_value_pairs = Vec::new();
-                    for (k, v) in entry {
-                        if k != "id" {
-                            field_value_pairs.push(k.clone());
-                            field_value_pairs.push(v.clone());
-                        }
+                    s.groups.insert(group_name.clone(), ConsumerGroup::new(id, entries_read));
+                    true
+                })
+                .await?;
+            if created != Some(true) {
+                return Err("BUSYGROUP Consumer Group name already exists".to_string());
+            }
+            state.notify_keyspace_event(NOTIFY_STREAM, "xgroup-create", key).await;
+            Ok(RespDataType::SimpleString("OK".to_string()))
+        }
+        "SETID" => {
+            let id = parse_group_id(&arguments[2])?;
+            let updated = state
+                .with_stream_mut(key, |s| match s.groups.get_mut(group_name) {
+                    Some(group) => {
+                        group.last_id = id;
+                        group.entries_read = entries_read;
+                        true
                     }
-                    result_resp_string.push_str(&format!("{}", RespDataType::Array(field_value_pairs.iter().map(|s| RespDataType::BulkString(s.clone())).collect())));
-                }
-                stream
-                    .lock()
-                    .await
-                    .write_all(result_resp_string.as_bytes())
-                    .await
-                    .unwrap();
+                    None => false,
+                })
+                .await?;
+            if updated != Some(true) {
+                return Err(nogroup());
+            }
+            state.notify_keyspace_event(NOTIFY_STREAM, "xgroup-setid", key).await;
+            Ok(RespDataType::SimpleString("OK".to_string()))
+        }
+        "DESTROY" => {
+            let destroyed = state.with_stream_mut(key, |s| s.groups.remove(group_name).is_some()).await?;
+            if destroyed != Some(true) {
+                return Ok(RespDataType::Integer(0));
             }
-            None => {
-                stream.lock().await.write_all(RespDataType::Array(vec![]).to_string().as_bytes()).await.unwrap();
+            state.notify_keyspace_event(NOTIFY_STREAM, "xgroup-destroy", key).await;
+            // consumers blocked on the group get their error right away
+            state.signal_key_as_ready(key).await;
+            Ok(RespDataType::Integer(1))
+        }
+        "CREATECONSUMER" => {
+            let now = unix_millis_now();
+            let created = state
+                .with_stream_mut(key, |s| {
+                    let group = s.groups.get_mut(group_name)?;
+                    match group.consumers.contains_key(&arguments[2]) {
+                        true => Some(false),
+                        false => Some(group.touch_consumer(&arguments[2], now)),
+                    }
+                })
+                .await?
+                .flatten()
+                .ok_or_else(nogroup)?;
+            if created {
+                state.notify_keyspace_event(NOTIFY_STREAM, "xgroup-createconsumer", key).await;
             }
+            Ok(RespDataType::Integer(created as i64))
+        }
+        _ => {
+            let deleted = state
+                .with_stream_mut(key, |s| s.groups.get_mut(group_name).map(|group| group.delete_consumer(&arguments[2])))
+                .await?
+                .flatten()
+                .ok_or_else(nogroup)?;
+            if deleted.is_some() {
+                state.notify_keyspace_event(NOTIFY_STREAM, "xgroup-delconsumer", key).await;
+            }
+            Ok(RespDataType::Integer(deleted.unwrap_or(0) as i64))
         }
     }
 }
 
-pub async fn handle_xread(
-    commands: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+// XACK key group id [id ...]
+pub async fn handle_xack(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 4 {
+        return write_response(&stream, wrong_arity("xack")).await;
+    }
+    let mut ids = Vec::with_capacity(commands.len() - 3);
+    for id in &commands[3..] {
+        match StreamId::parse_strict(id, 0) {
+            Ok(id) => ids.push(id),
+            Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+        }
+    }
+
+    let state_guard = state.lock().await;
+    let acknowledged = state_guard
+        .with_stream_mut(&commands[1], |s| match s.groups.get_mut(&commands[2]) {
+            Some(group) => ids.iter().filter(|id| group.acknowledge(id)).count(),
+            None => 0,
+        })
+        .await;
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+
+    match acknowledged {
+        Ok(acknowledged) if role == "master" => {
+            write_response(&stream, RespDataType::Integer(acknowledged.unwrap_or(0) as i64)).await
+        }
+        Ok(_) => {}
+        Err(error) => write_response(&stream, RespDataType::SimpleError(error)).await,
+    }
+}
+
+// XPENDING key group [[IDLE min-idle-time] start end count [consumer]]
+// Without a range it replies with a summary of the group's pending entries.
+pub async fn handle_xpending(
+    commands: &Command,
+    stream: SharedWriter,
     state: Arc<Mutex<State>>,
 ) {
     if commands.len() < 3 {
-        return stream
-            .lock()
-            .await
-            .write_all(RespDataType::SimpleError("ERR wrong number of arguments for XREAD command".to_string()).to_string().as_bytes())
-            .await
-            .unwrap();
+        return write_response(&stream, wrong_arity("xpending")).await;
     }
+    let (key, group_name) = (&commands[1], &commands[2]);
 
-    let mut i = 1;
-    let mut stream_keys_and_starts = Vec::new();
-    let mut has_block = false;
-    let mut block_time: Option<u64> = None;
+    // start, end, count, consumer and the minimum idle time of the extended form
+    let mut extended = None;
+    if commands.len() > 3 {
+        let mut i = 3;
+        let mut min_idle = 0;
+        if commands[3].eq_ignore_ascii_case("IDLE") && commands.len() > 4 {
+            min_idle = match parse_integer(&commands[4]) {
+                Ok(min_idle) => min_idle.max(0) as u64,
+                Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+            };
+            i = 5;
+        }
+        if commands.len() - i != 3 && commands.len() - i != 4 {
+            return write_response(&stream, RespDataType::SimpleError("ERR syntax error".to_string())).await;
+        }
+        let arguments = parse_range_bound(&commands[i], 0, true).and_then(|start| {
+            let end = parse_range_bound(&commands[i + 1], u64::MAX, false)?;
+            let count = parse_integer(&commands[i + 2])?.max(0) as usize;
+            Ok((start, end, count))
+        });
+        let (start, end, count) = match arguments {
+            Ok(arguments) => arguments,
+            Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+        };
+        extended = Some((start, end, count, commands.get(i + 3).cloned(), min_idle));
+    }
 
-    while i < commands.len() {
-        let command = &commands[i];
-        if command.to_uppercase() == "BLOCK" {
-            has_block = true;
-            if i + 1 < commands.len() {
-                if let Ok(timeout) = commands[i + 1].parse::<u64>() {
-                    block_time = Some(timeout);
+    let now = unix_millis_now();
+    let response = state
+        .lock()
+        .await
+        .with_stream(key, |s| {
+            let group = s.groups.get(group_name)?;
+            let Some((start, end, count, consumer, min_idle)) = &extended else {
+                if group.pending.is_empty() {
+                    let nil = RespDataType::Nil;
+                    return Some(RespDataType::Array(vec![RespDataType::Integer(0), nil.clone(), nil.clone(), nil]));
                 }
+                let first = group.pending.keys().next().map(|id| id.to_string()).unwrap_or_default();
+                let last = group.pending.keys().next_back().map(|id| id.to_string()).unwrap_or_default();
+                let consumers = group
+                    .consumers
+                    .iter()
+                    .filter(|(_, consumer)| !consumer.pending.is_empty())
+                    .map(|(name, consumer)| {
+                        RespDataType::Array(vec![
+                            RespDataType::BulkString(name.clone()),
+                            RespDataType::BulkString(consumer.pending.len().to_string()),
+                        ])
+                    })
+                    .collect();
+                return Some(RespDataType::Array(vec![
+                    RespDataType::Integer(group.pending.len() as i64),
+                    RespDataType::BulkString(first),
+                    RespDataType::BulkString(last),
+                    RespDataType::Array(consumers),
+                ]));
+            };
+            if start > end {
+                return Some(RespDataType::Array(vec![]));
             }
-            i += 2;
-        } else if command.to_uppercase() == "STREAMS" {
-            i += 1;
-            break;
-        } else {
-            stream_keys_and_starts.push(command.clone());
-            i += 1;
-        }
+            let entries = group
+                .pending
+                .range(start..=end)
+                .filter(|(_, pending)| consumer.as_ref().is_none_or(|consumer| pending.consumer == *consumer))
+                .filter(|(_, pending)| idle_time(now, pending) >= *min_idle)
+                .take(*count)
+                .map(|(id, pending)| {
+                    RespDataType::Array(vec![
+                        RespDataType::BulkString(id.to_string()),
+                        RespDataType::BulkString(pending.consumer.clone()),
+                        RespDataType::Integer(idle_time(now, pending) as i64),
+                        RespDataType::Integer(pending.delivery_count as i64),
+                    ])
+                })
+                .collect();
+            Some(RespDataType::Array(entries))
+        })
+        .await;
+
+    match response {
+        Ok(Some(Some(response))) => write_response(&stream, response).await,
+        Ok(_) => write_response(&stream, RespDataType::SimpleError(nogroup_error(key, group_name))).await,
+        Err(error) => write_response(&stream, RespDataType::SimpleError(error)).await,
     }
+}
 
-    while i < commands.len() {
-        stream_keys_and_starts.push(commands[i].clone());
+// The outcome of XCLAIM and XAUTOCLAIM on a stream
+struct Claim {
+    response: RespDataType,
+    consumer_created: bool,
+    propagated: Vec<Vec<String>>,
+}
+
+// XCLAIM key group consumer min-idle-time id [id ...] [IDLE ms]
+//   [TIME unix-time-milliseconds] [RETRYCOUNT count] [FORCE] [JUSTID] [LASTID id]
+pub async fn handle_xclaim(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 6 {
+        return write_response(&stream, wrong_arity("xclaim")).await;
+    }
+    let (key, group_name, consumer) = (&commands[1], &commands[2], &commands[3]);
+    let invalid = |what: &str| RespDataType::SimpleError(format!("ERR Invalid {} argument for XCLAIM", what));
+    let min_idle = match commands[4].parse::<i64>() {
+        Ok(min_idle) => min_idle.max(0) as u64,
+        Err(_) => return write_response(&stream, invalid("min-idle-time")).await,
+    };
+
+    let mut i = 5;
+    let mut ids = Vec::new();
+    while let Some(id) = commands.get(i).and_then(|id| StreamId::parse_strict(id, 0).ok()) {
+        ids.push(id);
         i += 1;
     }
 
-    if stream_keys_and_starts.len() % 2 != 0 {
-        return stream
-            .lock()
-            .await
-            .write_all(RespDataType::SimpleError("ERR Unbalanced XREAD list of streams: for each stream key an ID or '$' must be specified.".to_string()).to_string().as_bytes())
-            .await
-            .unwrap();
-    }
-
-    let half = stream_keys_and_starts.len() / 2;
-    let mut restructured_keys_and_starts = Vec::new();
-    let mut j = 0;
-    while j < half {
-        let stream_key = stream_keys_and_starts[j].clone();
-        restructured_keys_and_starts.push(stream_key.clone());
-        let exclusive_start_key = stream_keys_and_starts[j + half].clone();
-        if exclusive_start_key == "$" {
-            let stream_data = state.lock().await.get_stream(&stream_key).await;
-            match stream_data {
-                Some(v) => {
-                    if let Some(last_entry) = v.last() {
-                        for (k, v) in last_entry {
-                            if k == "id" {
-                                restructured_keys_and_starts.push(v.clone());
-                                break;
-                            }
-                        }
-                    } else {
-                        restructured_keys_and_starts.push(String::from("0-0"));
-                    }
+    let now = unix_millis_now();
+    let mut delivery_time = now;
+    let mut retry_count = None;
+    let mut force = false;
+    let mut justid = false;
+    let mut last_id = None;
+    while i < commands.len() {
+        let value = commands.get(i + 1);
+        match commands[i].to_uppercase().as_str() {
+            "FORCE" => force = true,
+            "JUSTID" => justid = true,
+            "IDLE" if value.is_some() => {
+                match value.and_then(|value| value.parse::<i64>().ok()) {
+                    Some(idle) => delivery_time = now.saturating_sub(idle.max(0) as u64),
+                    None => return write_response(&stream, invalid("IDLE option")).await,
                 }
-                None => {
-                    restructured_keys_and_starts.push(String::from("0-0"));
+                i += 1;
+            }
+            "TIME" if value.is_some() => {
+                match value.and_then(|value| value.parse::<i64>().ok()) {
+                    Some(time) => delivery_time = time.max(0) as u64,
+                    None => return write_response(&stream, invalid("TIME option")).await,
                 }
+                i += 1;
+            }
+            "RETRYCOUNT" if value.is_some() => {
+                match value.and_then(|value| value.parse::<i64>().ok()) {
+                    Some(count) => retry_count = Some(count.max(0) as u64),
+                    None => return write_response(&stream, invalid("RETRYCOUNT option")).await,
+                }
+                i += 1;
+            }
+            "LASTID" if value.is_some() => {
+                match StreamId::parse_strict(&commands[i + 1], 0) {
+                    Ok(id) => last_id = Some(id),
+                    Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+                }
+                i += 1;
+            }
+            _ => {
+                let error = format!("ERR Unrecognized XCLAIM option '{}'", commands[i]);
+                return write_response(&stream, RespDataType::SimpleError(error)).await;
             }
-        } else {
-            restructured_keys_and_starts.push(exclusive_start_key);
         }
-        j += 1;
+        i += 1;
     }
-    
-    if has_block {
-        let deadline = if let Some(timeout) = block_time {
-            if timeout == 0 {
-                None // Infinite block
-            } else {
-                Some(Instant::now() + Duration::from_millis(timeout))
+    // claims can't be dated in the future
+    let delivery_time = delivery_time.min(now);
+
+    let state_guard = state.lock().await;
+    let claim = state_guard
+        .with_stream_mut(key, |s| {
+            let exists: Vec<bool> = ids.iter().map(|id| s.get(id).is_some()).collect();
+            let group = s.groups.get_mut(group_name)?;
+            let mut propagated = Vec::new();
+            if let Some(last_id) = last_id.filter(|last_id| *last_id > group.last_id) {
+                group.last_id = last_id;
+                propagated.push(setid_command(key, group_name, group));
             }
-        } else {
-            None
-        };
-        loop {
-            let mut results: Vec<(String, Vec<Vec<String>>)> = Vec::new();
-            let mut i = 0;
-            while i < restructured_keys_and_starts.len() {
-                let stream_key = restructured_keys_and_starts[i].clone();
-                let exclusive_start = restructured_keys_and_starts[i + 1].clone();
-                let mut result_vector_for_stream: Vec<Vec<String>> = Vec::new();
-                let stream_vector = state.lock().await.get_stream(&stream_key).await;
-                match stream_vector {
-                    Some(stream_vector) => {
-                        let mut valid_entries: Vec<&Vec<(String, String)>> = Vec::new();
-                        for entry in &stream_vector {
-                            let mut entry_id = None;
-                            for (k, v) in entry {
-                                if k == "id" {
-                                    entry_id = Some(v);
-                                    break;
-                                }
-                            }
-                            if let Some(entry_id) = entry_id {
-                                if entry_id.as_str() > exclusive_start.as_str() {
-                                    valid_entries.push(entry);
-                                }
-                            }
-                        }
-                        for entry in valid_entries {
-                            let mut result_vec_for_entry = Vec::new();
-                            for (key, value) in entry {
-                                if key != "id" {
-                                    result_vec_for_entry.push(key.clone());
-                                }
-                                result_vec_for_entry.push(value.clone());
-                            }
-                            result_vector_for_stream.push(result_vec_for_entry);
-                        }
+            let consumer_created = group.touch_consumer(consumer, now);
+            if consumer_created {
+                propagated.insert(0, createconsumer_command(key, group_name, consumer));
+            }
+
+            let mut claimed = Vec::new();
+            for (id, exists) in ids.iter().zip(exists) {
+                if !exists {
+                    // the entry is gone, so is any reason to keep it pending
+                    if group.acknowledge(id) {
+                        propagated.push(to_command(&["XACK", key, group_name, &id.to_string()]));
                     }
-                    None => {}
+                    continue;
                 }
-                if !result_vector_for_stream.is_empty() {
-                    results.push((stream_key.clone(), result_vector_for_stream));
+                let pending = match group.pending.get(id) {
+                    Some(pending) => pending.clone(),
+                    None if force => PendingEntry { consumer: consumer.clone(), delivery_time: now, delivery_count: 1 },
+                    None => continue,
+                };
+                if min_idle > 0 && idle_time(now, &pending) < min_idle {
+                    continue;
                 }
+                let delivery_count = match retry_count {
+                    Some(count) => count,
+                    None if justid => pending.delivery_count,
+                    None => pending.delivery_count + 1,
+                };
+                group.assign(*id, consumer, delivery_time, delivery_count);
+                claimed.push(*id);
+            }
+            if !claimed.is_empty() {
+                if let Some(owner) = group.consumers.get_mut(consumer) {
+                    owner.active_time = Some(now);
+                }
+            }
+            for id in &claimed {
+                propagated.push(xclaim_command(key, group_name, id, &group.pending[id], &group.last_id));
+            }
+
+            let response = claimed
+                .iter()
+                .map(|id| match justid {
+                    true => RespDataType::BulkString(id.to_string()),
+                    false => s.get(id).map_or(RespDataType::Nil, |fields| entry_reply(id, fields)),
+                })
+                .collect();
+            Some(Claim { response: RespDataType::Array(response), consumer_created, propagated })
+        })
+        .await;
+    finish_claim(stream, state.clone(), state_guard, key, group_name, claim).await;
+}
+
+// XAUTOCLAIM key group consumer min-idle-time start [COUNT count] [JUSTID]
+pub async fn handle_xautoclaim(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 6 {
+        return write_response(&stream, wrong_arity("xautoclaim")).await;
+    }
+    let (key, group_name, consumer) = (&commands[1], &commands[2], &commands[3]);
+    let min_idle = match commands[4].parse::<i64>() {
+        Ok(min_idle) => min_idle.max(0) as u64,
+        Err(_) => {
+            let error = "ERR Invalid min-idle-time argument for XAUTOCLAIM".to_string();
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+    let start = match parse_range_bound(&commands[5], 0, true) {
+        Ok(start) => start,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+
+    // Redis looks at no more than ten pending entries per entry it may claim
+    const ATTEMPTS_FACTOR: i64 = 10;
+    let mut count = 100;
+    let mut justid = false;
+    let mut i = 6;
+    while i < commands.len() {
+        match commands[i].to_uppercase().as_str() {
+            "COUNT" if i + 1 < commands.len() => {
+                count = match parse_integer(&commands[i + 1]) {
+                    Ok(count) if (1..=i64::MAX / ATTEMPTS_FACTOR).contains(&count) => count as usize,
+                    Ok(_) => return write_response(&stream, RespDataType::SimpleError("ERR COUNT must be > 0".to_string())).await,
+                    Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+                };
                 i += 2;
             }
-            if !results.is_empty() {
-                let mut result_resp_string = format!("*{}\r\n", results.len());
-                for (rk, rv) in results {
-                    result_resp_string.push_str("*2\r\n");
-                    result_resp_string.push_str(&format!("{}", RespDataType::BulkString(rk.clone())));
-                    result_resp_string.push_str(&format!("*{}\r\n", rv.len()));
-                    for entry in rv {
-                        result_resp_string.push_str("*2\r\n");
-                        result_resp_string.push_str(&format!("{}", RespDataType::BulkString(entry[0].clone())));
-                        result_resp_string.push_str(&format!("{}", RespDataType::Array(entry[1..].iter().map(|el| RespDataType::BulkString(el.clone())).collect())));
-                    }
+            "JUSTID" => {
+                justid = true;
+                i += 1;
+            }
+            _ => return write_response(&stream, RespDataType::SimpleError("ERR syntax error".to_string())).await,
+        }
+    }
+    let mut attempts = count * ATTEMPTS_FACTOR as usize;
+
+    let now = unix_millis_now();
+    let state_guard = state.lock().await;
+    let claim = state_guard
+        .with_stream_mut(key, |s| {
+            let group = s.groups.get(group_name)?;
+            // one more than can be examined, to know where the next call starts
+            let candidates: Vec<StreamId> = group.pending.range(start..).map(|(id, _)| *id).take(attempts + 1).collect();
+            let exists: Vec<bool> = candidates.iter().map(|id| s.get(id).is_some()).collect();
+            let group = s.groups.get_mut(group_name)?;
+
+            let mut propagated = Vec::new();
+            let consumer_created = group.touch_consumer(consumer, now);
+            if consumer_created {
+                propagated.push(createconsumer_command(key, group_name, consumer));
+            }
+            let mut claimed = Vec::new();
+            let mut deleted = Vec::new();
+            let mut examined = 0;
+            while attempts > 0 && count > 0 && examined < candidates.len() {
+                let id = candidates[examined];
+                attempts -= 1;
+                examined += 1;
+                if !exists[examined - 1] {
+                    group.acknowledge(&id);
+                    propagated.push(to_command(&["XACK", key, group_name, &id.to_string()]));
+                    deleted.push(RespDataType::BulkString(id.to_string()));
+                    continue;
+                }
+                let pending = &group.pending[&id];
+                if min_idle > 0 && idle_time(now, pending) < min_idle {
+                    continue;
+                }
+                let delivery_count = pending.delivery_count + if justid { 0 } else { 1 };
+                group.assign(id, consumer, now, delivery_count);
+                claimed.push(id);
+                count -= 1;
+            }
+            if !claimed.is_empty() {
+                if let Some(owner) = group.consumers.get_mut(consumer) {
+                    owner.active_time = Some(now);
                 }
-                return stream
-                    .lock()
-                    .await
-                    .write_all(result_resp_string.as_bytes())
-                    .await
-                    .unwrap();
-            }
-            if let Some(deadline) = deadline {
-                if Instant::now() >= deadline {
+            }
+            for id in &claimed {
+                propagated.push(xclaim_command(key, group_name, id, &group.pending[id], &group.last_id));
+            }
+            let next = candidates.get(examined).copied().unwrap_or(StreamId::MIN);
+
+            let entries = claimed
+                .iter()
+                .map(|id| match justid {
+                    true => RespDataType::BulkString(id.to_string()),
+                    false => s.get(id).map_or(RespDataType::Nil, |fields| entry_reply(id, fields)),
+                })
+                .collect();
+            let response = RespDataType::Array(vec![
+                RespDataType::BulkString(next.to_string()),
+                RespDataType::Array(entries),
+                RespDataType::Array(deleted),
+            ]);
+            Some(Claim { response, consumer_created, propagated })
+        })
+        .await;
+    finish_claim(stream, state.clone(), state_guard, key, group_name, claim).await;
+}
+
+async fn finish_claim(
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    state_guard: tokio::sync::MutexGuard<'_, State>,
+    key: &str,
+    group_name: &str,
+    claim: Result<Option<Option<Claim>>, String>,
+) {
+    let claim = match claim {
+        Ok(Some(Some(claim))) => claim,
+        Ok(_) => {
+            drop(state_guard);
+            return write_response(&stream, RespDataType::SimpleError(nogroup_error(key, group_name))).await;
+        }
+        Err(error) => {
+            drop(state_guard);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+    if claim.consumer_created {
+        state_guard.notify_keyspace_event(NOTIFY_STREAM, "xgroup-createconsumer", key).await;
+    }
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+
+    if role == "master" {
+        write_response(&stream, claim.response).await;
+    }
+    propagate(&stream, &state, claim.propagated).await;
+}
+
+// XREADGROUP GROUP group consumer [COUNT count] [BLOCK milliseconds] [NOACK]
+//   STREAMS key [key ...] id [id ...]
+// `>` reads entries never delivered to the group, any other ID re-reads the
+// consumer's own pending entries after it. Only `>` reads block.
+pub async fn handle_xreadgroup(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    reader: SharedReader,
+    can_block: bool,
+) {
+    if commands.len() < 7 {
+        return write_response(&stream, wrong_arity("xreadgroup")).await;
+    }
+
+    let options = match parse_read_options(commands, true) {
+        Ok(options) => options,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+    let (block_time, count, noack) = (options.block_time, options.count, options.noack);
+    let Some((group_name, consumer)) = options.group else {
+        return write_response(&stream, RespDataType::SimpleError("ERR Missing GROUP option for XREADGROUP".to_string())).await;
+    };
+    let keys_and_ids = &commands[options.streams_at.unwrap_or(commands.len())..];
+    if keys_and_ids.is_empty() || !keys_and_ids.len().is_multiple_of(2) {
+        return write_response(&stream, RespDataType::SimpleError("ERR Unbalanced 'xreadgroup' list of streams: for each stream key an ID or '>' must be specified.".to_string())).await;
+    }
+    let (keys, ids) = keys_and_ids.split_at(keys_and_ids.len() / 2);
+
+    // None stands for `>`
+    let mut streams: Vec<(String, Option<StreamId>)> = Vec::new();
+    for (key, id) in keys.iter().zip(ids) {
+        let start = match id.as_str() {
+            ">" => None,
+            "$" => {
+                let error = "ERR The $ ID is meaningless in the context of XREADGROUP: you want to read the history of this consumer by specifying a proper ID, or use the > ID to get new messages. The $ ID would just return an empty result set.";
+                return write_response(&stream, RespDataType::SimpleError(error.to_string())).await;
+            }
+            _ => match StreamId::parse_strict(id, 0) {
+                Ok(start) => Some(start),
+                Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+            },
+        };
+        streams.push((key.clone(), start));
+    }
+    let read = GroupRead { group_name: &group_name, consumer: &consumer, count, noack };
+
+    let keys = keys.to_vec();
+    let mut blocked = None;
+    let mut result = {
+        let state_guard = state.lock().await;
+        let result = read_group_entries(&state_guard, &streams, &read).await;
+        // registering under the same lock means no XADD can slip in between
+        if result.as_ref().is_ok_and(|(results, _)| results.is_empty()) && block_time.is_some() && can_block {
+            blocked = Some(state_guard.register_blocked_client(&keys).await);
+        }
+        result
+    };
+
+    if let Some((id, waker)) = blocked {
+        let deadline = match block_time {
+            Some(timeout) if timeout > 0 => Some(Instant::now() + Duration::from_millis(timeout)),
+            _ => None, // Infinite block
+        };
+        let mut propagated = result.map(|(_, propagated)| propagated).unwrap_or_default();
+        loop {
+            match wait_until_ready(&waker, deadline, &reader).await {
+                WakeReason::Ready => {}
+                WakeReason::TimedOut => {
+                    result = Ok((vec![], vec![]));
                     break;
                 }
+                WakeReason::Disconnected => {
+                    state.lock().await.unregister_blocked_client(&keys, id).await;
+                    return propagate(&stream, &state, propagated).await;
+                }
+            }
+            result = read_group_entries(&*state.lock().await, &streams, &read).await;
+            if !result.as_ref().is_ok_and(|(results, _)| results.is_empty()) {
+                break;
+            }
+        }
+        state.lock().await.unregister_blocked_client(&keys, id).await;
+        // the consumer was created by the first attempt
+        if let Ok((_, served)) = &mut result {
+            propagated.append(served);
+            *served = propagated;
+        }
+    }
+
+    match result {
+        Ok((results, propagated)) => {
+            if results.is_empty() {
+                write_response(&stream, RespDataType::Nil).await;
+            } else {
+                write_response(&stream, RespDataType::Array(results)).await;
             }
-            time::sleep(Duration::from_millis(10)).await;
-        }
-        // If we get here, it means we timed out (not BLOCK 0)
-        return stream.lock().await.write_all(RespDataType::Nil.to_string().as_bytes()).await.unwrap();
-    }
-    
-    let mut results: Vec<(String, Vec<Vec<String>>)> = Vec::new();
-    let mut i = 0;
-    while i < restructured_keys_and_starts.len() {
-        let stream_key = restructured_keys_and_starts[i].clone();
-        let exclusive_start = restructured_keys_and_starts[i + 1].clone();
-        let mut result_vector_for_stream: Vec<Vec<String>> = Vec::new();
-        let stream_vector = state.lock().await.get_stream(&stream_key).await;
-        match stream_vector {
-            Some(stream_vector) => {
-                let mut valid_entries: Vec<&Vec<(String, String)>> = Vec::new();
-                for entry in &stream_vector {
-                    let mut entry_id = None;
-                    for (k, v) in entry {
-                        if k == "id" {
-                            entry_id = Some(v);
-                            break;
+            propagate(&stream, &state, propagated).await;
+        }
+        Err(error) => write_response(&stream, RespDataType::SimpleError(error)).await,
+    }
+}
+
+struct GroupRead<'a> {
+    group_name: &'a str,
+    consumer: &'a str,
+    count: Option<usize>,
+    noack: bool,
+}
+
+// Serves an XREADGROUP: [key, [entries]] for every stream with something to
+// return, and the commands that replay the read on replicas
+async fn read_group_entries(
+    state: &State,
+    streams: &[(String, Option<StreamId>)],
+    read: &GroupRead<'_>,
+) -> Result<(Vec<RespDataType>, Vec<Vec<String>>), String> {
+    let missing_error = |key: &str| {
+        format!("NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option", key, read.group_name)
+    };
+    for (key, _) in streams {
+        if state.with_stream(key, |s| s.groups.contains_key(read.group_name)).await? != Some(true) {
+            return Err(missing_error(key));
+        }
+    }
+
+    let now = unix_millis_now();
+    let limit = read.count.unwrap_or(usize::MAX);
+    let mut results = Vec::new();
+    let mut propagated = Vec::new();
+    for (key, start) in streams {
+        let served = state
+            .with_stream_mut(key, |s| {
+                let mut propagated = Vec::new();
+                let group = s.groups.get_mut(read.group_name)?;
+                let consumer_created = group.touch_consumer(read.consumer, now);
+                if consumer_created {
+                    propagated.push(createconsumer_command(key, read.group_name, read.consumer));
+                }
+
+                let entries: Vec<RespDataType> = match start {
+                    None => {
+                        let last_id = group.last_id;
+                        let mut delivered = Vec::new();
+                        let entries = s
+                            .after(last_id)
+                            .take(limit)
+                            .map(|(id, fields)| {
+                                delivered.push(*id);
+                                entry_reply(id, fields)
+                            })
+                            .collect();
+                        for id in &delivered {
+                            s.advance_group(read.group_name, *id);
                         }
-                    }
-                    if let Some(entry_id) = entry_id {
-                        if entry_id.as_str() > exclusive_start.as_str() {
-                            valid_entries.push(entry);
+                        let group = s.groups.get_mut(read.group_name)?;
+                        for id in &delivered {
+                            if !read.noack {
+                                group.assign(*id, read.consumer, now, 1);
+                                propagated.push(xclaim_command(key, read.group_name, id, &group.pending[id], &group.last_id));
+                            }
                         }
+                        if read.noack && !delivered.is_empty() {
+                            propagated.push(setid_command(key, read.group_name, group));
+                        }
+                        entries
                     }
-                }
-                for entry in valid_entries {
-                    let mut result_vec_for_entry = Vec::new();
-                    for (key, value) in entry {
-                        if key != "id" {
-                            result_vec_for_entry.push(key.clone());
+                    Some(start) => {
+                        let history: Vec<StreamId> = group.consumers[read.consumer]
+                            .pending
+                            .range((Bound::Excluded(*start), Bound::Unbounded))
+                            .take(limit)
+                            .copied()
+                            .collect();
+                        let exists: Vec<bool> = history.iter().map(|id| s.get(id).is_some()).collect();
+                        let group = s.groups.get_mut(read.group_name)?;
+                        for (id, exists) in history.iter().zip(&exists) {
+                            if *exists {
+                                let pending = group.pending.get_mut(id)?;
+                                pending.delivery_time = now;
+                                pending.delivery_count += 1;
+                                propagated.push(xclaim_command(key, read.group_name, id, &group.pending[id], &group.last_id));
+                            }
                         }
-                        result_vec_for_entry.push(value.clone());
+                        // entries deleted since their delivery come back without fields
+                        history
+                            .iter()
+                            .map(|id| match s.get(id) {
+                                Some(fields) => entry_reply(id, fields),
+                                None => RespDataType::Array(vec![RespDataType::BulkString(id.to_string()), RespDataType::Nil]),
+                            })
+                            .collect()
                     }
-                    result_vector_for_stream.push(result_vec_for_entry);
+                };
+                if !entries.is_empty() {
+                    let consumer = s.groups.get_mut(read.group_name)?.consumers.get_mut(read.consumer)?;
+                    consumer.active_time = Some(now);
                 }
-            }
-            None => {}
+                Some((entries, consumer_created, propagated))
+            })
+            .await?
+            .flatten();
+        let Some((entries, consumer_created, mut served)) = served else {
+            return Err(missing_error(key));
+        };
+        if consumer_created {
+            state.notify_keyspace_event(NOTIFY_STREAM, "xgroup-createconsumer", key).await;
         }
-        if !result_vector_for_stream.is_empty() {
-            results.push((stream_key.clone(), result_vector_for_stream));
+        propagated.append(&mut served);
+        // history reads always name their stream, even with nothing pending
+        if !entries.is_empty() || start.is_some() {
+            results.push(RespDataType::Array(vec![RespDataType::BulkString(key.clone()), RespDataType::Array(entries)]));
         }
-        i += 2;
     }
-    
-    if results.is_empty() {
-        return stream.lock().await.write_all(RespDataType::Nil.to_string().as_bytes()).await.unwrap();
+    Ok((results, propagated))
+}
+
+// Builds the flat field-value array XINFO replies with
+fn info_reply(fields: Vec<(&str, RespDataType)>) -> RespDataType {
+    let mut reply = Vec::with_capacity(fields.len() * 2);
+    for (name, value) in fields {
+        reply.push(RespDataType::BulkString(name.to_string()));
+        reply.push(value);
     }
-    
-    let mut result_resp_string = format!("*{}\r\n", results.len());
-    for (rk, rv) in results {
-        result_resp_string.push_str("*2\r\n");
-        result_resp_string.push_str(&format!("{}", RespDataType::BulkString(rk.clone())));
-        result_resp_string.push_str(&format!("*{}\r\n", rv.len()));
-        for entry in rv {
-            result_resp_string.push_str("*2\r\n");
-            result_resp_string.push_str(&format!("{}", RespDataType::BulkString(entry[0].clone())));
-            result_resp_string.push_str(&format!("{}", RespDataType::Array(entry[1..].iter().map(|el| RespDataType::BulkString(el.clone())).collect())));
+    RespDataType::Array(reply)
+}
+
+fn optional_integer(value: Option<u64>) -> RespDataType {
+    value.map_or(RespDataType::Nil, |value| RespDataType::Integer(value as i64))
+}
+
+// XINFO STREAM key [FULL [COUNT count]]
+// XINFO GROUPS key
+// XINFO CONSUMERS key group
+// XINFO HELP
+pub async fn handle_xinfo(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 2 {
+        return write_response(&stream, wrong_arity("xinfo")).await;
+    }
+    let subcommand = commands[1].to_uppercase();
+    let arity_ok = match subcommand.as_str() {
+        "STREAM" => commands.len() >= 3,
+        "GROUPS" => commands.len() == 3,
+        "CONSUMERS" => commands.len() == 4,
+        "HELP" => commands.len() == 2,
+        _ => {
+            let error = format!("ERR unknown subcommand '{}'. Try XINFO HELP.", commands[1]);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+    if !arity_ok {
+        return write_response(&stream, wrong_arity(&format!("xinfo|{}", subcommand.to_lowercase()))).await;
+    }
+    if subcommand == "HELP" {
+        let lines = [
+            "XINFO <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+            "CONSUMERS <key> <groupname>",
+            "    Show consumers of <groupname>.",
+            "GROUPS <key>",
+            "    Show the stream consumer groups.",
+            "STREAM <key> [FULL [COUNT <count>]",
+            "    Show information about the stream.",
+            "HELP",
+            "    Print this help.",
+        ];
+        let lines = lines.iter().map(|line| RespDataType::SimpleString(line.to_string())).collect();
+        return write_response(&stream, RespDataType::Array(lines)).await;
+    }
+
+    // the entries and pending entries FULL shows at most, 0 for all
+    let mut full_count = None;
+    if subcommand == "STREAM" && commands.len() > 3 {
+        let mut i = 3;
+        full_count = Some(10);
+        if !commands[i].eq_ignore_ascii_case("FULL") {
+            return write_response(&stream, RespDataType::SimpleError("ERR syntax error".to_string())).await;
+        }
+        i += 1;
+        if i < commands.len() {
+            if !commands[i].eq_ignore_ascii_case("COUNT") || i + 2 != commands.len() {
+                return write_response(&stream, RespDataType::SimpleError("ERR syntax error".to_string())).await;
+            }
+            full_count = match parse_integer(&commands[i + 1]) {
+                Ok(count) => Some(count.max(0) as usize),
+                Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+            };
         }
     }
-    stream
+
+    let now = unix_millis_now();
+    let key = &commands[2];
+    let response = state
         .lock()
         .await
-        .write_all(result_resp_string.as_bytes())
-        .await
-        .unwrap();
-} 
\ No newline at end of file
+        .with_stream(key, |s| match subcommand.as_str() {
+            "STREAM" => match full_count {
+                Some(count) => Ok(xinfo_stream_full(s, count)),
+                None => Ok(xinfo_stream(s)),
+            },
+            "GROUPS" => Ok(RespDataType::Array(s.groups.iter().map(|(name, group)| xinfo_group(s, name, group)).collect())),
+            _ => match s.groups.get(&commands[3]) {
+                Some(group) => Ok(RespDataType::Array(
+                    group.consumers.iter().map(|(name, consumer)| xinfo_consumer(now, name, consumer)).collect(),
+                )),
+                None => Err(format!("NOGROUP No such consumer group '{}' for key name '{}'", commands[3], key)),
+            },
+        })
+        .await;
+    let response = match response {
+        Ok(Some(Ok(response))) => response,
+        Ok(None) => RespDataType::SimpleError("ERR no such key".to_string()),
+        Ok(Some(Err(error))) | Err(error) => RespDataType::SimpleError(error),
+    };
+    write_response(&stream, response).await;
+}
+
+// The fields XINFO STREAM shows with and without FULL
+fn xinfo_stream_header(s: &Stream) -> Vec<(&'static str, RespDataType)> {
+    let first_id = s.first_entry().map_or(StreamId::MIN, |(id, _)| *id);
+    vec![
+        ("length", RespDataType::Integer(s.len() as i64)),
+        ("radix-tree-keys", RespDataType::Integer(s.node_count() as i64)),
+        ("radix-tree-nodes", RespDataType::Integer(s.node_count().max(1) as i64)),
+        ("last-generated-id", RespDataType::BulkString(s.last_id.to_string())),
+        ("max-deleted-entry-id", RespDataType::BulkString(s.max_deleted_id.to_string())),
+        ("entries-added", RespDataType::Integer(s.entries_added as i64)),
+        ("recorded-first-entry-id", RespDataType::BulkString(first_id.to_string())),
+    ]
+}
+
+fn xinfo_stream(s: &Stream) -> RespDataType {
+    let mut fields = xinfo_stream_header(s);
+    fields.push(("groups", RespDataType::Integer(s.groups.len() as i64)));
+    fields.push(("first-entry", s.first_entry().map_or(RespDataType::Nil, |(id, entry)| entry_reply(id, entry))));
+    fields.push(("last-entry", s.last_entry().map_or(RespDataType::Nil, |(id, entry)| entry_reply(id, entry))));
+    info_reply(fields)
+}
+
+fn xinfo_stream_full(s: &Stream, count: usize) -> RespDataType {
+    let limit = if count == 0 { usize::MAX } else { count };
+    let mut fields = xinfo_stream_header(s);
+    let entries = s.range(StreamId::MIN, StreamId::MAX).take(limit).map(|(id, entry)| entry_reply(id, entry));
+    fields.push(("entries", RespDataType::Array(entries.collect())));
+
+    let groups = s.groups.iter().map(|(name, group)| {
+        let pending = group.pending.iter().take(limit).map(|(id, pending)| {
+            RespDataType::Array(vec![
+                RespDataType::BulkString(id.to_string()),
+                RespDataType::BulkString(pending.consumer.clone()),
+                RespDataType::Integer(pending.delivery_time as i64),
+                RespDataType::Integer(pending.delivery_count as i64),
+            ])
+        });
+        let consumers = group.consumers.iter().map(|(consumer_name, consumer)| {
+            let consumer_pending = consumer.pending.iter().take(limit).map(|id| {
+                let pending = &group.pending[id];
+                RespDataType::Array(vec![
+                    RespDataType::BulkString(id.to_string()),
+                    RespDataType::Integer(pending.delivery_time as i64),
+                    RespDataType::Integer(pending.delivery_count as i64),
+                ])
+            });
+            info_reply(vec![
+                ("name", RespDataType::BulkString(consumer_name.clone())),
+                ("seen-time", RespDataType::Integer(consumer.seen_time as i64)),
+                ("active-time", RespDataType::Integer(consumer.active_time.map_or(-1, |time| time as i64))),
+                ("pel-count", RespDataType::Integer(consumer.pending.len() as i64)),
+                ("pending", RespDataType::Array(consumer_pending.collect())),
+            ])
+        });
+        info_reply(vec![
+            ("name", RespDataType::BulkString(name.clone())),
+            ("last-delivered-id", RespDataType::BulkString(group.last_id.to_string())),
+            ("entries-read", optional_integer(group.entries_read)),
+            ("lag", optional_integer(s.lag(group))),
+            ("pel-count", RespDataType::Integer(group.pending.len() as i64)),
+            ("pending", RespDataType::Array(pending.collect())),
+            ("consumers", RespDataType::Array(consumers.collect())),
+        ])
+    });
+    fields.push(("groups", RespDataType::Array(groups.collect())));
+    info_reply(fields)
+}
+
+fn xinfo_group(s: &Stream, name: &str, group: &ConsumerGroup) -> RespDataType {
+    info_reply(vec![
+        ("name", RespDataType::BulkString(name.to_string())),
+        ("consumers", RespDataType::Integer(group.consumers.len() as i64)),
+        ("pending", RespDataType::Integer(group.pending.len() as i64)),
+        ("last-delivered-id", RespDataType::BulkString(group.last_id.to_string())),
+        ("entries-read", optional_integer(group.entries_read)),
+        ("lag", optional_integer(s.lag(group))),
+    ])
+}
+
+fn xinfo_consumer(now: u64, name: &str, consumer: &Consumer) -> RespDataType {
+    let inactive = consumer.active_time.map_or(-1, |time| now.saturating_sub(time) as i64);
+    info_reply(vec![
+        ("name", RespDataType::BulkString(name.to_string())),
+        ("pending", RespDataType::Integer(consumer.pending.len() as i64)),
+        ("idle", RespDataType::Integer(now.saturating_sub(consumer.seen_time) as i64)),
+        ("inactive", RespDataType::Integer(inactive)),
+    ])
+}
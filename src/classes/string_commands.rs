@@ -1,124 +1,930 @@
-use crate::classes::{RespDataType::RespDataType, State::State};
+use crate::classes::{
+    ExpiringValue::unix_millis_to_instant,
+    Notifications::{NOTIFY_GENERIC, NOTIFY_KEY_MISS, NOTIFY_STRING},
+    Parser::Command,
+    RespDataType::RespDataType,
+    State::{State, Value, WRONGTYPE_ERROR},
+};
 use std::sync::Arc;
-use tokio::net::tcp::OwnedWriteHalf;
+use crate::classes::Connection::{write_response, wrong_arity, SharedWriter};
 use tokio::sync::Mutex;
 use tokio::io::AsyncWriteExt;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+const NOT_AN_INTEGER_ERROR: &str = "ERR value is not an integer or out of range";
+const NOT_A_FLOAT_ERROR: &str = "ERR value is not a valid float";
+const STRING_TOO_LONG_ERROR: &str = "ERR string exceeds maximum allowed size (proto-max-bulk-len)";
+// proto-max-bulk-len default, the largest string value Redis accepts
+pub const MAX_STRING_LENGTH: usize = 512 * 1024 * 1024;
+
+struct SetOptions {
+    nx: bool,
+    xx: bool,
+    get: bool,
+    keep_ttl: bool,
+    expiration: Option<Instant>,
+}
+
+// Integers are accepted in their canonical form only: no sign other than a
+// leading '-', no leading zeros and no whitespace, exactly like string2ll
+pub fn parse_strict_i64(s: &[u8]) -> Option<i64> {
+    let s = std::str::from_utf8(s).ok()?;
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    let canonical = !digits.is_empty()
+        && digits.bytes().all(|b| b.is_ascii_digit())
+        && (digits == "0" || !digits.starts_with('0'))
+        && s != "-0";
+    if canonical { s.parse().ok() } else { None }
+}
+
+fn parse_float(s: &[u8]) -> Option<f64> {
+    // Rust also parses "inf" and "NaN", which Redis rejects
+    std::str::from_utf8(s).ok()?.parse::<f64>().ok().filter(|f| f.is_finite())
+}
+
+// INCRBYFLOAT replies like Redis' "%.17Lf" with the trailing zeros (and
+// a bare point) trimmed: plain decimal notation, rounded to 17 decimal
+// places. Redis' long double holds the decimal value a double only
+// approximates, so the digits come from the shortest representation of
+// the double rather than from its exact binary value.
+fn format_float(value: f64) -> String {
+    let scientific = format!("{:e}", value.abs());
+    let (mantissa, exponent) = scientific.split_once('e').expect("formatted with an exponent");
+    let exponent: i64 = exponent.parse().expect("integer exponent");
+    let significant: Vec<u8> = mantissa.bytes().filter(u8::is_ascii_digit).map(|b| b - b'0').collect();
+    // the digit of the place 10^place
+    let digit = |place: i64| {
+        usize::try_from(exponent - place).ok().and_then(|i| significant.get(i)).copied().unwrap_or(0)
+    };
+    let mut digits: Vec<u8> = (-17..=exponent.max(0)).rev().map(digit).collect();
+    if digit(-18) >= 5 {
+        match digits.iter().rposition(|d| *d != 9) {
+            Some(i) => {
+                digits[i] += 1;
+                digits[i + 1..].fill(0);
+            }
+            None => {
+                digits.fill(0);
+                digits.insert(0, 1);
+            }
+        }
+    }
+
+    let (integer, fraction) = digits.split_at(digits.len() - 17);
+    let text = |digits: &[u8]| digits.iter().map(|d| (b'0' + d) as char).collect::<String>();
+    let formatted = format!("{}.{}", text(integer), text(fraction));
+    let formatted = formatted.trim_end_matches('0').trim_end_matches('.');
+    // like Redis, a negative value that rounds to zero shows as 0
+    if value < 0.0 && formatted != "0" {
+        format!("-{}", formatted)
+    } else {
+        formatted.to_string()
+    }
+}
+
+// Converts the argument of EX/PX/EXAT/PXAT into an expiration instant,
+// rejecting non-positive and overflowing values the way Redis does
+fn parse_expiration(unit: &str, value: &str, command_name: &str) -> Result<Instant, String> {
+    let amount = value
+        .parse::<i64>()
+        .map_err(|_| "ERR value is not an integer or out of range".to_string())?;
+    let invalid = || format!("ERR invalid expire time in '{}' command", command_name);
+    if amount <= 0 {
+        return Err(invalid());
+    }
+    let ms = match unit {
+        "EX" | "EXAT" => amount.checked_mul(1000).ok_or_else(invalid)?,
+        _ => amount,
+    };
+    match unit {
+        "EXAT" | "PXAT" => Ok(unix_millis_to_instant(ms)),
+        _ => Instant::now()
+            .checked_add(Duration::from_millis(ms as u64))
+            .ok_or_else(invalid),
+    }
+}
+
+// SET key value [NX | XX] [GET] [EX seconds | PX milliseconds |
+//   EXAT unix-time-seconds | PXAT unix-time-milliseconds | KEEPTTL]
+fn parse_set_options(options: &[String]) -> Result<SetOptions, String> {
+    let syntax_error = || "ERR syntax error".to_string();
+    let mut parsed = SetOptions { nx: false, xx: false, get: false, keep_ttl: false, expiration: None };
+    let mut has_expire_option = false;
+
+    let mut i = 0;
+    while i < options.len() {
+        let option = options[i].to_uppercase();
+        match option.as_str() {
+            "NX" if !parsed.xx => parsed.nx = true,
+            "XX" if !parsed.nx => parsed.xx = true,
+            "GET" => parsed.get = true,
+            "KEEPTTL" if !has_expire_option => {
+                parsed.keep_ttl = true;
+                has_expire_option = true;
+            }
+            "EX" | "PX" | "EXAT" | "PXAT" if !has_expire_option => {
+                let value = options.get(i + 1).ok_or_else(syntax_error)?;
+                parsed.expiration = Some(parse_expiration(&option, value, "set")?);
+                has_expire_option = true;
+                i += 1;
+            }
+            _ => return Err(syntax_error()),
+        }
+        i += 1;
+    }
+    Ok(parsed)
+}
 
 pub async fn handle_set(
-    commands: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+    commands: &Command,
+    stream: SharedWriter,
     state: Arc<Mutex<State>>,
 ) {
-    let key = commands[1].clone();
-    let value = commands[2].clone();
-    let mut expiration: Option<Instant> = None;
-    
-    let mut options: Option<&[String]> = None;
-    if commands.len() > 3 {
-        options = Some(&commands[3..]);
+    if commands.len() < 3 {
+        stream.lock().await
+            .write_all(RespDataType::SimpleError("ERR wrong number of arguments for 'set' command".to_string()).to_string().as_bytes())
+            .await
+            .unwrap();
+        return;
     }
 
-    if let Some(op) = options {
-        let option_type = &op[0];
+    let key = commands[1].clone();
+    let value = commands.bytes(2).to_vec();
+    let options = match parse_set_options(&commands[3..]) {
+        Ok(options) => options,
+        Err(error) => {
+            stream.lock().await.write_all(RespDataType::SimpleError(error).to_string().as_bytes()).await.unwrap();
+            return;
+        }
+    };
+
+    let state_guard = state.lock().await;
+    let current = state_guard.get_value(&key).await;
+
+    // GET needs the old value to be a string, and fails before anything is written
+    let old_value = match &current {
+        Some(current) if options.get => match &current.value {
+            Value::String(s) => RespDataType::BulkBytes(s.clone()),
+            _ => {
+                drop(state_guard);
+                stream.lock().await
+                    .write_all(RespDataType::SimpleError(WRONGTYPE_ERROR.to_string()).to_string().as_bytes())
+                    .await
+                    .unwrap();
+                return;
+            }
+        },
+        _ => RespDataType::Nil,
+    };
 
-        match option_type.to_uppercase().as_str() {
-            "PX" => {
-                let option_value = &op[1];
-                expiration = Some(
-                    Instant::now()
-                        + std::time::Duration::from_millis(option_value.parse::<u64>().unwrap()),
-                );
+    let should_set = !((options.nx && current.is_some()) || (options.xx && current.is_none()));
+    match options.expiration {
+        // an EXAT/PXAT time in the past deletes the key right away
+        Some(expiration) if should_set && expiration <= Instant::now() => {
+            state_guard.del(&key).await;
+        }
+        _ if should_set => {
+            let expiration = if options.keep_ttl {
+                current.and_then(|current| current.expiration_timestamp)
+            } else {
+                options.expiration
+            };
+            state_guard.set_string(key.clone(), value, expiration).await;
+            state_guard.notify_keyspace_event(NOTIFY_STRING, "set", &key).await;
+            if options.expiration.is_some() {
+                state_guard.notify_keyspace_event(NOTIFY_GENERIC, "expire", &key).await;
             }
-            _ => {}
         }
+        _ => {}
     }
+    drop(state_guard);
 
-    state.lock().await.set_string(key, value, expiration).await;
+    let response = if options.get {
+        old_value
+    } else if should_set {
+        RespDataType::SimpleString("OK".to_string())
+    } else {
+        RespDataType::Nil
+    };
     let role = state.lock().await.get_role().await;
     if role == "master" {
-        stream.lock().await.write_all(RespDataType::SimpleString("OK".to_string()).to_string().as_bytes()).await.unwrap();
+        stream.lock().await.write_all(&response.to_bytes()).await.unwrap();
         // Note: propagation to replicas should be handled in the dispatcher for now
     }
 }
 
 pub async fn handle_get(
-    commands: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+    commands: &Command,
+    stream: SharedWriter,
     state: Arc<Mutex<State>>,
 ) {
+    if commands.len() != 2 {
+        return write_response(&stream, wrong_arity("get")).await;
+    }
+
     let key = &commands[1];
-    let value = state.lock().await.get_string(key).await;
+    let state_guard = state.lock().await;
+    let response = match state_guard.get_string(key).await {
+        Ok(Some(v)) => RespDataType::BulkBytes(v.value),
+        Ok(None) => {
+            state_guard.notify_keyspace_event(NOTIFY_KEY_MISS, "keymiss", key).await;
+            RespDataType::Nil
+        }
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    drop(state_guard);
+    write_response(&stream, response).await;
+}
 
-    match value {
-        Some(v) => {
-            if let Some(expiration_timestamp) = v.expiration_timestamp {
-                if Instant::now() > expiration_timestamp {
-                    return stream.lock().await.write_all(RespDataType::Nil.to_string().as_bytes()).await.unwrap();
-                }
+// INCR, INCRBY, DECR and DECRBY all end up here with the signed delta.
+// The stored value must be a canonical 64 bit integer and the result may not overflow.
+async fn incr_by(
+    key: &str,
+    delta: i64,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    let state_guard = state.lock().await;
+    let current = match state_guard.get_string(key).await {
+        Ok(current) => current,
+        Err(error) => {
+            drop(state_guard);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+
+    let (current_value, expiration) = match current {
+        Some(v) => match parse_strict_i64(&v.value) {
+            Some(n) => (n, v.expiration_timestamp),
+            None => {
+                drop(state_guard);
+                return write_response(&stream, RespDataType::SimpleError(NOT_AN_INTEGER_ERROR.to_string())).await;
             }
-            let bulk_response = RespDataType::BulkString(v.value).to_string();
-            return stream
-                .lock()
-                .await
-                .write_all(bulk_response.as_bytes())
-                .await
-                .unwrap();
+        },
+        None => (0, None),
+    };
+    let new_value = match current_value.checked_add(delta) {
+        Some(n) => n,
+        None => {
+            drop(state_guard);
+            return write_response(&stream, RespDataType::SimpleError("ERR increment or decrement would overflow".to_string())).await;
         }
-        None => stream.lock().await.write_all(RespDataType::Nil.to_string().as_bytes()).await.unwrap(),
+    };
+
+    state_guard.set_string(key.to_string(), new_value.to_string().into_bytes(), expiration).await;
+    state_guard.notify_keyspace_event(NOTIFY_STRING, "incrby", key).await;
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, RespDataType::Integer(new_value)).await;
     }
 }
 
 pub async fn handle_incr(
-    commands: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+    commands: &Command,
+    stream: SharedWriter,
     state: Arc<Mutex<State>>,
 ) {
+    if commands.len() != 2 {
+        return write_response(&stream, wrong_arity("incr")).await;
+    }
+    incr_by(&commands[1], 1, stream, state).await;
+}
+
+pub async fn handle_decr(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 2 {
+        return write_response(&stream, wrong_arity("decr")).await;
+    }
+    incr_by(&commands[1], -1, stream, state).await;
+}
+
+pub async fn handle_incrby(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 3 {
+        return write_response(&stream, wrong_arity("incrby")).await;
+    }
+    match parse_strict_i64(commands.bytes(2)) {
+        Some(delta) => incr_by(&commands[1], delta, stream, state).await,
+        None => write_response(&stream, RespDataType::SimpleError(NOT_AN_INTEGER_ERROR.to_string())).await,
+    }
+}
+
+pub async fn handle_decrby(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 3 {
+        return write_response(&stream, wrong_arity("decrby")).await;
+    }
+    let response = match parse_strict_i64(commands.bytes(2)) {
+        None => NOT_AN_INTEGER_ERROR,
+        // i64::MIN has no positive counterpart
+        Some(i64::MIN) => "ERR decrement would overflow",
+        Some(delta) => return incr_by(&commands[1], -delta, stream, state).await,
+    };
+    write_response(&stream, RespDataType::SimpleError(response.to_string())).await;
+}
+
+pub async fn handle_incrbyfloat(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 3 {
+        return write_response(&stream, wrong_arity("incrbyfloat")).await;
+    }
     let key = commands[1].clone();
+    let increment = match parse_float(commands.bytes(2)) {
+        Some(increment) => increment,
+        None => return write_response(&stream, RespDataType::SimpleError(NOT_A_FLOAT_ERROR.to_string())).await,
+    };
+
     let state_guard = state.lock().await;
-    
-    let current_value = state_guard.get_string(&key).await;
-    
-    match current_value {
-        Some(v) => {
-            match v.value.parse::<u64>() {
-                Ok(numeric_value) => {
-                    let new_value = (numeric_value + 1).to_string();
-                    let new_expiration = v.expiration_timestamp;
-                    drop(state_guard);
-                    state.lock().await.set_string(key.clone(), new_value, new_expiration).await;
-                    stream
-                        .lock()
-                        .await
-                        .write_all(format!(":{}\r\n", numeric_value + 1).as_bytes())
-                        .await
-                        .unwrap();
+    let current = match state_guard.get_string(&key).await {
+        Ok(current) => current,
+        Err(error) => {
+            drop(state_guard);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+    let (current_value, expiration) = match current {
+        Some(v) => match parse_float(&v.value) {
+            Some(f) => (f, v.expiration_timestamp),
+            None => {
+                drop(state_guard);
+                return write_response(&stream, RespDataType::SimpleError(NOT_A_FLOAT_ERROR.to_string())).await;
+            }
+        },
+        None => (0.0, None),
+    };
+    let new_value = current_value + increment;
+    if !new_value.is_finite() {
+        drop(state_guard);
+        return write_response(&stream, RespDataType::SimpleError("ERR increment would produce NaN or Infinity".to_string())).await;
+    }
+
+    let formatted = format_float(new_value);
+    state_guard.set_string(key.clone(), formatted.clone().into_bytes(), expiration).await;
+    state_guard.notify_keyspace_event(NOTIFY_STRING, "incrbyfloat", &key).await;
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, RespDataType::BulkString(formatted)).await;
+    }
+}
+
+pub async fn handle_append(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 3 {
+        return write_response(&stream, wrong_arity("append")).await;
+    }
+    let key = commands[1].clone();
+
+    let state_guard = state.lock().await;
+    let (mut value, expiration) = match state_guard.get_string(&key).await {
+        Ok(Some(v)) => (v.value, v.expiration_timestamp),
+        Ok(None) => (Vec::new(), None),
+        Err(error) => {
+            drop(state_guard);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+    if value.len() + commands.bytes(2).len() > MAX_STRING_LENGTH {
+        drop(state_guard);
+        return write_response(&stream, RespDataType::SimpleError(STRING_TOO_LONG_ERROR.to_string())).await;
+    }
+
+    value.extend_from_slice(commands.bytes(2));
+    let length = value.len();
+    state_guard.set_string(key.clone(), value, expiration).await;
+    state_guard.notify_keyspace_event(NOTIFY_STRING, "append", &key).await;
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, RespDataType::Integer(length as i64)).await;
+    }
+}
+
+pub async fn handle_strlen(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 2 {
+        return write_response(&stream, wrong_arity("strlen")).await;
+    }
+    let response = match state.lock().await.get_string(&commands[1]).await {
+        Ok(value) => RespDataType::Integer(value.map_or(0, |v| v.value.len()) as i64),
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    write_response(&stream, response).await;
+}
+
+pub async fn handle_getrange(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 4 {
+        return write_response(&stream, wrong_arity("getrange")).await;
+    }
+    let (mut start, mut end) = match (commands[2].parse::<i64>(), commands[3].parse::<i64>()) {
+        (Ok(start), Ok(end)) => (start, end),
+        _ => return write_response(&stream, RespDataType::SimpleError(NOT_AN_INTEGER_ERROR.to_string())).await,
+    };
+
+    let value = match state.lock().await.get_string(&commands[1]).await {
+        Ok(value) => value.map(|v| v.value).unwrap_or_default(),
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+
+    // same clamping rules as Redis, negative offsets count from the end
+    let bytes = &value;
+    let length = bytes.len() as i64;
+    let empty = (start < 0 && end < 0 && start > end) || length == 0;
+    if start < 0 {
+        start = (length + start).max(0);
+    }
+    if end < 0 {
+        end = (length + end).max(0);
+    }
+    end = end.min(length - 1);
+    let response = if empty || start > end {
+        Vec::new()
+    } else {
+        bytes[start as usize..=end as usize].to_vec()
+    };
+    write_response(&stream, RespDataType::BulkBytes(response)).await;
+}
+
+pub async fn handle_setrange(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 4 {
+        return write_response(&stream, wrong_arity("setrange")).await;
+    }
+    let key = commands[1].clone();
+    let offset = match commands[2].parse::<i64>() {
+        Ok(offset) if offset < 0 => {
+            return write_response(&stream, RespDataType::SimpleError("ERR offset is out of range".to_string())).await;
+        }
+        Ok(offset) => offset as usize,
+        Err(_) => return write_response(&stream, RespDataType::SimpleError(NOT_AN_INTEGER_ERROR.to_string())).await,
+    };
+    let patch = commands.bytes(3);
+
+    let state_guard = state.lock().await;
+    let current = match state_guard.get_string(&key).await {
+        Ok(current) => current,
+        Err(error) => {
+            drop(state_guard);
+            return write_response(&stream, RespDataType::SimpleError(error)).await;
+        }
+    };
+
+    // an empty patch never creates the key nor pads the value
+    let current_length = current.as_ref().map_or(0, |v| v.value.len());
+    if patch.is_empty() {
+        drop(state_guard);
+        return write_response(&stream, RespDataType::Integer(current_length as i64)).await;
+    }
+    if offset.saturating_add(patch.len()) > MAX_STRING_LENGTH {
+        drop(state_guard);
+        return write_response(&stream, RespDataType::SimpleError(STRING_TOO_LONG_ERROR.to_string())).await;
+    }
+
+    let expiration = current.as_ref().and_then(|v| v.expiration_timestamp);
+    let mut bytes = current.map(|v| v.value).unwrap_or_default();
+    if bytes.len() < offset + patch.len() {
+        bytes.resize(offset + patch.len(), 0);
+    }
+    bytes[offset..offset + patch.len()].copy_from_slice(patch);
+    let length = bytes.len();
+
+    state_guard.set_string(key.clone(), bytes, expiration).await;
+    state_guard.notify_keyspace_event(NOTIFY_STRING, "setrange", &key).await;
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, RespDataType::Integer(length as i64)).await;
+    }
+}
+
+pub async fn handle_getset(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 3 {
+        return write_response(&stream, wrong_arity("getset")).await;
+    }
+    let key = commands[1].clone();
+
+    let state_guard = state.lock().await;
+    let response = match state_guard.get_string(&key).await {
+        Ok(old_value) => {
+            state_guard.set_string(key.clone(), commands.bytes(2).to_vec(), None).await;
+            state_guard.notify_keyspace_event(NOTIFY_STRING, "set", &key).await;
+            old_value.map_or(RespDataType::Nil, |v| RespDataType::BulkBytes(v.value))
+        }
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, response).await;
+    }
+}
+
+pub async fn handle_getdel(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 2 {
+        return write_response(&stream, wrong_arity("getdel")).await;
+    }
+    let key = &commands[1];
+
+    let state_guard = state.lock().await;
+    let response = match state_guard.get_string(key).await {
+        Ok(Some(v)) => {
+            state_guard.del(key).await;
+            RespDataType::BulkBytes(v.value)
+        }
+        Ok(None) => RespDataType::Nil,
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, response).await;
+    }
+}
+
+// GETEX key [EX seconds | PX milliseconds | EXAT unix-time-seconds |
+//   PXAT unix-time-milliseconds | PERSIST]
+pub async fn handle_getex(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 2 {
+        return write_response(&stream, wrong_arity("getex")).await;
+    }
+    let key = commands[1].clone();
+
+    // None leaves the TTL alone, Some(None) removes it
+    let mut new_expiration: Option<Option<Instant>> = None;
+    let mut i = 2;
+    while i < commands.len() {
+        let option = commands[i].to_uppercase();
+        match option.as_str() {
+            "PERSIST" if new_expiration.is_none() => new_expiration = Some(None),
+            "EX" | "PX" | "EXAT" | "PXAT" if new_expiration.is_none() && i + 1 < commands.len() => {
+                match parse_expiration(&option, &commands[i + 1], "getex") {
+                    Ok(expiration) => new_expiration = Some(Some(expiration)),
+                    Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
                 }
-                Err(_) => {
-                    stream
-                        .lock()
-                        .await
-                        .write_all(RespDataType::SimpleError("ERR value is not an integer or out of range".to_string()).to_string().as_bytes())
-                        .await
-                        .unwrap();
+                i += 1;
+            }
+            _ => return write_response(&stream, RespDataType::SimpleError("ERR syntax error".to_string())).await,
+        }
+        i += 1;
+    }
+
+    let state_guard = state.lock().await;
+    let response = match state_guard.get_string(&key).await {
+        Ok(Some(v)) => {
+            match new_expiration {
+                // an absolute time in the past deletes the key right away
+                Some(Some(expiration)) if expiration <= Instant::now() => {
+                    state_guard.del(&key).await;
+                }
+                Some(Some(expiration)) => {
+                    state_guard.set_expiration(&key, Some(expiration)).await;
                 }
+                Some(None) if v.expiration_timestamp.is_some() => {
+                    state_guard.set_expiration(&key, None).await;
+                }
+                _ => {}
             }
+            RespDataType::BulkBytes(v.value)
         }
-        None => {
-            drop(state_guard);
-            state.lock().await.set_string(key.clone(), "1".to_string(), None).await;
-            stream.lock().await.write_all(RespDataType::Integer(1).to_string().as_bytes()).await.unwrap();
+        Ok(None) => RespDataType::Nil,
+        Err(error) => RespDataType::SimpleError(error),
+    };
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, response).await;
+    }
+}
+
+pub async fn handle_setnx(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() != 3 {
+        return write_response(&stream, wrong_arity("setnx")).await;
+    }
+    let key = commands[1].clone();
+
+    let state_guard = state.lock().await;
+    let created = !state_guard.exists(&key).await;
+    if created {
+        state_guard.set_string(key.clone(), commands.bytes(2).to_vec(), None).await;
+        state_guard.notify_keyspace_event(NOTIFY_STRING, "set", &key).await;
+    }
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, RespDataType::Integer(created as i64)).await;
+    }
+}
+
+// SETEX and PSETEX only differ in the unit of the TTL
+async fn setex_generic(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    unit: &str,
+) {
+    let command_name = commands[0].to_lowercase();
+    if commands.len() != 4 {
+        return write_response(&stream, wrong_arity(&command_name)).await;
+    }
+    let key = commands[1].clone();
+    let expiration = match parse_expiration(unit, &commands[2], &command_name) {
+        Ok(expiration) => expiration,
+        Err(error) => return write_response(&stream, RespDataType::SimpleError(error)).await,
+    };
+
+    let state_guard = state.lock().await;
+    state_guard.set_string(key.clone(), commands.bytes(3).to_vec(), Some(expiration)).await;
+    state_guard.notify_keyspace_event(NOTIFY_STRING, "set", &key).await;
+    state_guard.notify_keyspace_event(NOTIFY_GENERIC, "expire", &key).await;
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+    if role == "master" {
+        write_response(&stream, RespDataType::SimpleString("OK".to_string())).await;
+    }
+}
+
+pub async fn handle_setex(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    setex_generic(commands, stream, state, "EX").await;
+}
+
+pub async fn handle_psetex(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    setex_generic(commands, stream, state, "PX").await;
+}
+
+// MSET and MSETNX; with `only_if_none_exist` nothing is written as soon as one key exists
+async fn mset_generic(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+    only_if_none_exist: bool,
+) {
+    if commands.len() < 3 || commands.len().is_multiple_of(2) {
+        return write_response(&stream, wrong_arity(&commands[0].to_lowercase())).await;
+    }
+
+    let state_guard = state.lock().await;
+    let mut should_set = true;
+    if only_if_none_exist {
+        for pair in commands[1..].chunks(2) {
+            if state_guard.exists(&pair[0]).await {
+                should_set = false;
+                break;
+            }
         }
     }
-    let role = state.lock().await.get_role().await;
+    if should_set {
+        for (key, value) in commands[1..].iter().step_by(2).zip(commands.args()[2..].iter().step_by(2)) {
+            state_guard.set_string(key.clone(), value.clone(), None).await;
+            state_guard.notify_keyspace_event(NOTIFY_STRING, "set", key).await;
+        }
+    }
+    let role = state_guard.get_role().await;
+    drop(state_guard);
+
+    let response = if only_if_none_exist {
+        RespDataType::Integer(should_set as i64)
+    } else {
+        RespDataType::SimpleString("OK".to_string())
+    };
     if role == "master" {
-        // Note: propagation to replicas should be handled in the dispatcher for now
+        write_response(&stream, response).await;
+    }
+}
+
+pub async fn handle_mset(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    mset_generic(commands, stream, state, false).await;
+}
+
+pub async fn handle_msetnx(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    mset_generic(commands, stream, state, true).await;
+}
+
+pub async fn handle_mget(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 2 {
+        return write_response(&stream, wrong_arity("mget")).await;
+    }
+
+    // keys holding another type are reported as nil rather than as an error
+    let state_guard = state.lock().await;
+    let mut values = Vec::new();
+    for key in commands[1..].iter() {
+        values.push(match state_guard.get_string(key).await {
+            Ok(Some(v)) => RespDataType::BulkBytes(v.value),
+            _ => RespDataType::Nil,
+        });
     }
+    drop(state_guard);
+    write_response(&stream, RespDataType::Array(values)).await;
+}
+
+// LCS key1 key2 [LEN] [IDX] [MINMATCHLEN len] [WITHMATCHLEN]
+pub async fn handle_lcs(
+    commands: &Command,
+    stream: SharedWriter,
+    state: Arc<Mutex<State>>,
+) {
+    if commands.len() < 3 {
+        return write_response(&stream, wrong_arity("lcs")).await;
+    }
+
+    let (mut get_len, mut get_idx, mut with_match_len, mut min_match_len) = (false, false, false, 0i64);
+    let mut i = 3;
+    while i < commands.len() {
+        match commands[i].to_uppercase().as_str() {
+            "LEN" => get_len = true,
+            "IDX" => get_idx = true,
+            "WITHMATCHLEN" => with_match_len = true,
+            "MINMATCHLEN" if i + 1 < commands.len() => {
+                match commands[i + 1].parse::<i64>() {
+                    Ok(n) => min_match_len = n.max(0),
+                    Err(_) => return write_response(&stream, RespDataType::SimpleError(NOT_AN_INTEGER_ERROR.to_string())).await,
+                }
+                i += 1;
+            }
+            _ => return write_response(&stream, RespDataType::SimpleError("ERR syntax error".to_string())).await,
+        }
+        i += 1;
+    }
+    if get_len && get_idx {
+        return write_response(
+            &stream,
+            RespDataType::SimpleError("ERR If you want both the length and indexes, please just use IDX.".to_string()),
+        )
+        .await;
+    }
+
+    let state_guard = state.lock().await;
+    let mut strings = Vec::new();
+    for key in &commands[1..3] {
+        match state_guard.get_string(key).await {
+            Ok(value) => strings.push(value.map(|v| v.value).unwrap_or_default()),
+            Err(_) => {
+                drop(state_guard);
+                return write_response(
+                    &stream,
+                    RespDataType::SimpleError("ERR The specified keys must contain string values".to_string()),
+                )
+                .await;
+            }
+        }
+    }
+    drop(state_guard);
+
+    let (a, b) = (&strings[0], &strings[1]);
+    if (a.len() + 1).saturating_mul(b.len() + 1).saturating_mul(4) > MAX_STRING_LENGTH {
+        return write_response(
+            &stream,
+            RespDataType::SimpleError("ERR Insufficient memory, transient memory for LCS exceeds proto-max-bulk-len".to_string()),
+        )
+        .await;
+    }
+
+    // Classic dynamic programming table: lcs[i][j] is the LCS length of a[..i] and b[..j]
+    let width = b.len() + 1;
+    let mut lcs = vec![0u32; (a.len() + 1) * width];
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            lcs[i * width + j] = if a[i - 1] == b[j - 1] {
+                lcs[(i - 1) * width + j - 1] + 1
+            } else {
+                lcs[(i - 1) * width + j].max(lcs[i * width + j - 1])
+            };
+        }
+    }
+    let total = lcs[a.len() * width + b.len()] as usize;
+    if get_len {
+        return write_response(&stream, RespDataType::Integer(total as i64)).await;
+    }
+
+    // Walk the table backwards collecting the string and, for IDX, the
+    // matching ranges (reported from the end of the strings, like Redis)
+    let mut result = vec![0u8; total];
+    let mut matches = Vec::new();
+    let (mut i, mut j, mut idx) = (a.len(), b.len(), total);
+    let (mut a_start, mut a_end, mut b_start, mut b_end) = (a.len(), 0, 0, 0);
+    while i > 0 && j > 0 {
+        let mut emit_range = false;
+        if a[i - 1] == b[j - 1] {
+            result[idx - 1] = a[i - 1];
+            if a_start == a.len() {
+                (a_start, a_end, b_start, b_end) = (i - 1, i - 1, j - 1, j - 1);
+            } else if a_start == i && b_start == j {
+                a_start -= 1;
+                b_start -= 1;
+            } else {
+                emit_range = true;
+            }
+            if a_start == 0 || b_start == 0 {
+                emit_range = true;
+            }
+            idx -= 1;
+            i -= 1;
+            j -= 1;
+        } else {
+            if lcs[(i - 1) * width + j] > lcs[i * width + j - 1] {
+                i -= 1;
+            } else {
+                j -= 1;
+            }
+            if a_start != a.len() {
+                emit_range = true;
+            }
+        }
+
+        if emit_range {
+            let match_len = (a_end - a_start + 1) as i64;
+            if min_match_len == 0 || match_len >= min_match_len {
+                let range = |start: usize, end: usize| {
+                    RespDataType::Array(vec![RespDataType::Integer(start as i64), RespDataType::Integer(end as i64)])
+                };
+                let mut entry = vec![range(a_start, a_end), range(b_start, b_end)];
+                if with_match_len {
+                    entry.push(RespDataType::Integer(match_len));
+                }
+                matches.push(RespDataType::Array(entry));
+            }
+            a_start = a.len();
+        }
+    }
+
+    let response = if get_idx {
+        RespDataType::Array(vec![
+            RespDataType::BulkString("matches".to_string()),
+            RespDataType::Array(matches),
+            RespDataType::BulkString("len".to_string()),
+            RespDataType::Integer(total as i64),
+        ])
+    } else {
+        RespDataType::BulkBytes(result)
+    };
+    write_response(&stream, response).await;
 }
 
 pub async fn handle_type(
-    commands: &mut Vec<String>,
-    stream: Arc<Mutex<OwnedWriteHalf>>,
+    commands: &Command,
+    stream: SharedWriter,
     state: Arc<Mutex<State>>,
 ) {
     let key = &commands[1];
@@ -134,4 +940,30 @@ pub async fn handle_type(
     }
     
     stream.lock().await.write_all(RespDataType::SimpleString(value_type.to_string()).to_string().as_bytes()).await.unwrap();
-} 
\ No newline at end of file
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn incrbyfloat(current: &str, increment: &str) -> String {
+        format_float(parse_float(current.as_bytes()).unwrap() + parse_float(increment.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn format_float_matches_the_incrbyfloat_examples() {
+        assert_eq!(incrbyfloat("10.50", "0.1"), "10.6");
+        assert_eq!(incrbyfloat("10.6", "-5"), "5.6");
+        assert_eq!(incrbyfloat("5.0e3", "2.0e2"), "5200");
+        assert_eq!(incrbyfloat("3.0e3", "-3.0e3"), "0");
+    }
+
+    #[test]
+    fn format_float_rounds_to_17_decimal_places() {
+        assert_eq!(format_float(1e17), "100000000000000000");
+        assert_eq!(format_float(-1.5e-5), "-0.000015");
+        assert_eq!(format_float(1.2345678901234567e-10), "0.00000000012345679");
+        assert_eq!(format_float(9.999999999999999e-18), "0.00000000000000001");
+        assert_eq!(format_float(1.5e-20), "0");
+        assert_eq!(format_float(-1.5e-20), "0");
+    }
+}
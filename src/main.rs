@@ -1,18 +1,27 @@
-use core::panic;
 use core::str;
-use std::collections::HashMap;
-use std::env;
+use socket2::{Domain, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::{env, fs, io, process};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::OwnedReadHalf;
-use tokio::net::tcp::OwnedWriteHalf;
-use tokio::net::{TcpListener as TokioTcpListener, TcpStream as TokioTcpStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener as TokioTcpListener, TcpStream as TokioTcpStream, UnixListener};
 use tokio::sync::Mutex;
 use tokio::{signal, task};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 
 mod classes;
-use classes::{CommandExecutor::CommandExecutor, Db::Db, Parser::Parser, State::State};
-
+use classes::{
+    CommandExecutor::{is_no_multi, CommandExecutor},
+    Configuration::{command_line_config, load_config},
+    Db::Db,
+    Parser::{Command, Parser},
+    State::{with_connection, State},
+    Tls,
+};
+
+use crate::classes::Connection::{ConnectionReader, SharedReader, SharedWriter};
 use crate::classes::RespDataType::RespDataType;
 
 #[tokio::main]
@@ -20,177 +29,289 @@ async fn main() {
     // bootstrap shared state using the new State structure
     let mut state = State::new();
 
-    // parse args into a map
-    let mut args_map = HashMap::new();
+    // an optional redis.conf file, then `--name value` options overriding it
     let args: Vec<String> = env::args().collect();
-    let mut i = 1;
-    while i + 1 < args.len() {
-        args_map.insert(args[i].clone(), args[i + 1].clone());
-        i += 2;
+    let mut config = state.get_config().await;
+    let mut config_text = String::new();
+    let mut options = &args[1..];
+    if let Some(path) = options.first().filter(|arg| !arg.starts_with("--")) {
+        config_text = fs::read_to_string(path).unwrap_or_else(|error| {
+            eprintln!("Fatal error, can't open config file '{}': {}", path, error);
+            process::exit(1);
+        });
+        // CONFIG REWRITE must find it again whatever the directory
+        let absolute_path = fs::canonicalize(path).map(|path| path.to_string_lossy().to_string());
+        config.config_file = Some(absolute_path.unwrap_or_else(|_| path.clone()));
+        options = &options[1..];
+    }
+    if !config_text.is_empty() && !config_text.ends_with('\n') {
+        config_text.push('\n');
+    }
+    config_text.push_str(&command_line_config(options));
+    if let Err(error) = load_config(&mut config, &config_text) {
+        eprintln!("\n*** FATAL CONFIG FILE ERROR ***\n{}", error);
+        process::exit(1);
+    }
+    state.set_databases(config.databases).await;
+    let port = config.port;
+    let replica_of = config.master_host.clone().zip(config.master_port.clone());
+    let listeners = bind_tcp_listeners(&config.bind, port);
+    // TLS clients get a port of their own
+    let tls_listeners = if config.tls_port != 0 {
+        let server_config = Tls::server_config(&config).unwrap_or_else(|error| {
+            eprintln!("Failed to configure TLS: {}", error);
+            process::exit(1);
+        });
+        let acceptor = TlsAcceptor::from(server_config);
+        bind_tcp_listeners(&config.bind, config.tls_port)
+            .into_iter()
+            .map(|listener| (listener, acceptor.clone()))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let tls_connector = if config.tls_replication && replica_of.is_some() {
+        let client_config = Tls::client_config(&config).unwrap_or_else(|error| {
+            eprintln!("Failed to configure TLS replication: {}", error);
+            process::exit(1);
+        });
+        Some(TlsConnector::from(client_config))
+    } else {
+        None
+    };
+    // the master reaches a TLS replica back on its TLS port
+    let announced_port = if config.tls_replication { config.tls_port } else { port };
+    // AUTH [masteruser] masterauth, when the master wants a password
+    let master_auth: Vec<String> = match &config.masterauth {
+        Some(password) => config.masteruser.iter().chain([password]).cloned().collect(),
+        None => Vec::new(),
+    };
+    let unixsocket = config.unixsocket.clone();
+    let unix_listener = unixsocket.as_ref().map(|path| {
+        listen_unix(path, config.unixsocketperm).unwrap_or_else(|error| {
+            eprintln!("Failed opening Unix socket {}: {}", path, error);
+            process::exit(1);
+        })
+    });
+    let aclfile = config.aclfile.clone();
+    state.set_config(config).await;
+    if let Some(path) = aclfile {
+        let loaded = match fs::read_to_string(&path) {
+            Ok(text) => state.with_acl(|acl| acl.load(&text, &path)).await,
+            Err(error) => Err(format!("Error loading ACLs, opening file '{}': {}", path, error)),
+        };
+        if let Err(error) = loaded {
+            eprintln!("{}\nAborting startup because of ACL errors", error);
+            process::exit(1);
+        }
+        state.sync_requirepass(&[]).await;
     }
 
     // optional RDB load
-    let db_dir = args_map.get("--dir").cloned();
-    let db_file_name = args_map.get("--dbfilename").cloned();
-    state.set_db_config(db_dir.clone(), db_file_name.clone()).await;
-    
+    let (db_dir, db_file_name) = state.get_db_config().await;
     if db_dir.is_some() && db_file_name.is_some() {
         let _ = Db {}.load(&mut state).await;
     }
 
-    let port = args_map.get("--port").map(|s| s.as_str()).unwrap_or("6379");
-
-    let listener = TokioTcpListener::bind(format!("127.0.0.1:{}", port))
-        .await
-        .unwrap_or_else(|e| panic!("couldn't bind to port {}: {}", port, e));
-
     let shared_state = Arc::new(Mutex::new(state.clone()));
     let shared_state_for_listener = shared_state.clone();
 
-    if let Some(replica_of) = args_map.get("--replicaof") {
-        let mut parts = replica_of.split_whitespace();
-        let host = parts.next().expect("`--replicaof` requires `host port`");
-        let master_port = parts.next().expect("`--replicaof` requires `host port`");
+    if let Some((host, master_port)) = replica_of {
         let state_inside_replica = shared_state.clone();
         state_inside_replica.lock().await.set_role("slave".to_string()).await;
-        // Note: master_host and master_port are not used in the current implementation
-        // but we could add them to the Config if needed
 
-        let mut master_stream = TokioTcpStream::connect(format!("{}:{}", host, master_port))
+        let master_stream = TokioTcpStream::connect(format!("{}:{}", host, master_port))
             .await
             .expect("couldn't connect to master");
 
-        task::spawn(async move {
-            let port = args_map.get("--port").map(|s| s.as_str()).unwrap_or("6379");
-            let remaining_data = do_replication_handshake(&mut master_stream, port).await;
-            let (reader, writer) = master_stream.into_split();
-            let shared_reader = Arc::new(Mutex::new(reader));
-            let shared_writer = Arc::new(Mutex::new(writer));
-
-            handle_replication_loop(
-                shared_reader,
-                shared_writer,
-                shared_state.clone(),
-                remaining_data,
-            )
-            .await;
-        });
+        match tls_connector {
+            Some(connector) => {
+                let server_name = ServerName::try_from(host.clone()).expect("invalid master host name");
+                let master_stream = connector
+                    .connect(server_name, master_stream)
+                    .await
+                    .expect("couldn't establish TLS with master");
+                task::spawn(replicate(master_stream, announced_port, master_auth, shared_state.clone()));
+            }
+            None => {
+                task::spawn(replicate(master_stream, announced_port, master_auth, shared_state.clone()));
+            }
+        }
+    }
+    let shared_state_for_expiry = shared_state_for_listener.clone();
+    for listener in listeners {
+        tokio::spawn(listener_loop(listener, shared_state_for_listener.clone()));
+    }
+    for (listener, acceptor) in tls_listeners {
+        tokio::spawn(tls_listener_loop(listener, acceptor, shared_state_for_listener.clone()));
     }
+    if let Some(listener) = unix_listener {
+        tokio::spawn(unix_listener_loop(listener, shared_state_for_listener.clone()));
+    }
+
+    // actively expire keys that are never accessed again
     tokio::spawn(async move {
-        listener_loop(listener, shared_state_for_listener).await;
+        loop {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            shared_state_for_expiry.lock().await.active_expire_cycle().await;
+        }
     });
 
     signal::ctrl_c().await.expect("failed to listen for ctrl_c");
     println!("Shutdown signal received, exiting.");
+    if let Some(path) = unixsocket {
+        let _ = fs::remove_file(path);
+    }
 }
 
-// common client‐handling loop (for master clients only)
-async fn handle_client(stream: TokioTcpStream, state: Arc<Mutex<State>>) {
+// common client‐handling loop (for master clients only), over TCP or a Unix
+// socket alike
+async fn handle_client<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S, local: bool, state: Arc<Mutex<State>>) {
     let pending: Vec<u8> = Vec::new();
-    let (raw_reader, raw_writer) = stream.into_split();
+    let (raw_reader, raw_writer) = tokio::io::split(stream);
     let writer = Arc::new(Mutex::new(raw_writer));
-    let reader = Arc::new(Mutex::new(raw_reader));
-    handle_command_loop(state, reader, writer, pending).await;
+    let reader = Arc::new(Mutex::new(ConnectionReader::new(raw_reader)));
+    // connections made while the default user has no password need no AUTH
+    let authenticated = !state.lock().await.requires_auth().await;
+    let command_loop = handle_command_loop(state, reader, writer, pending);
+    with_connection(Some("default".to_string()), authenticated, local, command_loop).await;
+}
+
+// The replica's side of its master link, in plain TCP or TLS
+async fn replicate<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    mut master_stream: S,
+    port: u16,
+    auth: Vec<String>,
+    state: Arc<Mutex<State>>,
+) {
+    let remaining_data = match do_replication_handshake(&mut master_stream, &port.to_string(), &auth).await {
+        Ok(remaining_data) => remaining_data,
+        Err(error) => {
+            eprintln!("{}", error);
+            return;
+        }
+    };
+    let (reader, writer) = tokio::io::split(master_stream);
+    let shared_reader = Arc::new(Mutex::new(ConnectionReader::new(reader)));
+    let shared_writer = Arc::new(Mutex::new(writer));
+
+    handle_replication_loop(shared_reader, shared_writer, state, remaining_data).await;
 }
 
 async fn handle_replication_loop(
-    reader: Arc<Mutex<OwnedReadHalf>>,
-    writer: Arc<Mutex<OwnedWriteHalf>>,
-    mut state: Arc<Mutex<State>>,
-    mut remaining_data: Vec<u8>,
+    reader: SharedReader,
+    writer: SharedWriter,
+    state: Arc<Mutex<State>>,
+    remaining_data: Vec<u8>,
 ) {
-    handle_command_loop(state, reader, writer, remaining_data).await;
+    // the master's commands are trusted, whatever the ACL
+    with_connection(None, true, false, handle_command_loop(state, reader, writer, remaining_data)).await;
 }
 
 async fn handle_command_loop(
     state: Arc<Mutex<State>>,
-    reader: Arc<Mutex<OwnedReadHalf>>,
-    writer: Arc<Mutex<OwnedWriteHalf>>,
+    reader: SharedReader,
+    writer: SharedWriter,
     mut pending: Vec<u8>,
 ) {
     let mut parser = Parser {};
-    let mut exec = CommandExecutor {};
-    let mut queued: Vec<Vec<String>> = Vec::new();
+    let mut exec = CommandExecutor { in_exec: false };
+    let mut queued: Vec<Command> = Vec::new();
     let mut in_multi = false;
+    // a command refused while queueing fails the whole transaction
+    let mut transaction_failed = false;
 
-    loop {
+    'connection: loop {
         while let Some(frame_end) = find_complete_frame(&pending) {
             let frame_bytes: Vec<u8> = pending.drain(..frame_end).collect();
 
-            if let Ok(text) = std::str::from_utf8(&frame_bytes) {
-                let commands = parser.parse(text);
-                if !commands.is_empty() {
-                    let command_type = &commands[0];
-                    println!("command type: {:?}", command_type);
-                    if in_multi
-                        && command_type.to_uppercase() != "EXEC"
-                        && command_type.to_uppercase() != "DISCARD"
-                    {
+            let commands = parser.parse(&frame_bytes);
+            if !commands.is_empty() {
+                let command_type = &commands[0];
+                println!("command type: {:?}", command_type);
+                let command_name = command_type.to_uppercase();
+                // QUIT and RESET act right away, even inside MULTI
+                let runs_now = matches!(command_name.as_str(), "EXEC" | "DISCARD" | "MULTI" | "QUIT" | "RESET");
+                if in_multi && !runs_now {
+                    if is_no_multi(&command_name) {
+                        transaction_failed = true;
+                        let error = "ERR Command not allowed inside a transaction".to_string();
+                        writer.lock().await.write_all(RespDataType::SimpleError(error).to_string().as_bytes()).await.unwrap();
+                    } else if exec.check_permissions(&commands, &writer, &state).await {
                         queued.push(commands);
                         writer.lock().await.write_all(RespDataType::SimpleString("QUEUED".to_string()).to_string().as_bytes()).await.unwrap();
-                    } else if in_multi && command_type.to_uppercase() == "EXEC" {
-                        let mut queued_clone = queued.clone();
-
-                        exec.execute(
-                            commands,
-                            reader.clone(),
-                            writer.clone(),
-                            state.clone(),
-                            &mut queued,
-                            &mut in_multi,
-                        )
-                        .await;
-
-                        for command in &mut queued {
-                            exec.execute(
-                                command.clone(),
-                                reader.clone(),
-                                writer.clone(),
-                                state.clone(),
-                                &mut queued_clone,
-                                &mut in_multi,
-                            )
-                            .await;
-                        }
-
-                        in_multi = false;
-                        queued.clear();
                     } else {
-                        exec.execute(
-                            commands,
-                            reader.clone(),
-                            writer.clone(),
-                            state.clone(),
-                            &mut queued,
-                            &mut in_multi,
-                        )
-                        .await;
+                        transaction_failed = true;
+                    }
+                } else if in_multi && transaction_failed && command_name == "EXEC" {
+                    queued.clear();
+                    in_multi = false;
+                    let error = "EXECABORT Transaction discarded because of previous errors.".to_string();
+                    writer.lock().await.write_all(RespDataType::SimpleError(error).to_string().as_bytes()).await.unwrap();
+                } else {
+                    exec.execute(
+                        commands,
+                        reader.clone(),
+                        writer.clone(),
+                        state.clone(),
+                        &mut queued,
+                        &mut in_multi,
+                    )
+                    .await;
+                    if command_name == "QUIT" {
+                        break 'connection;
                     }
                 }
-                if state.lock().await.get_role().await == "slave" {
-                    state.lock().await.increment_offset(frame_bytes.len()).await;
+                if !in_multi {
+                    transaction_failed = false;
                 }
             }
+            if state.lock().await.get_role().await == "slave" {
+                state.lock().await.increment_offset(frame_bytes.len()).await;
+            }
         }
 
         let mut buf = [0u8; 2048];
 
         let n = match reader.lock().await.read(&mut buf).await {
-            Ok(0) | Err(_) => return, // connection closed or error
+            Ok(0) | Err(_) => break, // connection closed or error
             Ok(n) => n,
         };
 
         pending.extend_from_slice(&buf[..n]);
     }
+
+    // drop any pub/sub subscriptions held by the closed connection
+    state.lock().await.remove_subscriber(&writer).await;
 }
 
+// The reply protected mode closes the connection of a remote client with
+const PROTECTED_MODE_ERROR: &str = "-DENIED Redis is running in protected mode because protected mode is enabled and no password is set for the default user. In this mode connections are only accepted from the loopback interface. If you want to connect from external computers to Redis you may adopt one of the following solutions: 1) Just disable protected mode sending the command 'CONFIG SET protected-mode no' from the loopback interface by connecting to Redis from the same host the server is running, however MAKE SURE Redis is not publicly accessible from internet if you do so. Use CONFIG REWRITE to make this change permanent. 2) Alternatively you can just disable the protected mode by editing the Redis configuration file, and setting the protected mode option to 'no', and then restarting the server. 3) If you started the server manually just for testing, restart it with the '--protected-mode no' option. 4) Set up an authentication password for the default user. NOTE: You only need to do one of the above things in order for the server to start accepting connections from the outside.\r\n";
+
 async fn listener_loop(listener: TokioTcpListener, state: Arc<Mutex<State>>) {
     loop {
         match listener.accept().await {
-            Ok((stream, _)) => {
+            Ok((stream, peer)) => {
+                task::spawn(serve_tcp_client(stream, peer, state.clone()));
+            }
+            Err(e) => eprintln!("accept error: {}", e),
+        }
+    }
+}
+
+// Clients of the TLS port go through the handshake first, and a client
+// certificate when `tls-auth-clients` asks for one
+async fn tls_listener_loop(listener: TokioTcpListener, acceptor: TlsAcceptor, state: Arc<Mutex<State>>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let acceptor = acceptor.clone();
                 let state_clone = state.clone();
                 task::spawn(async move {
-                    handle_client(stream, state_clone).await;
+                    match acceptor.accept(stream).await {
+                        Ok(stream) => serve_tcp_client(stream, peer, state_clone).await,
+                        Err(error) => eprintln!("Error accepting a client connection: {} (addr={})", error, peer),
+                    }
                 });
             }
             Err(e) => eprintln!("accept error: {}", e),
@@ -198,13 +319,116 @@ async fn listener_loop(listener: TokioTcpListener, state: Arc<Mutex<State>>) {
     }
 }
 
-// PSYNC/REPLCONF handshake
-async fn do_replication_handshake(stream: &mut TokioTcpStream, port: &str) -> Vec<u8> {
+// Protected mode turns remote clients away before serving them
+async fn serve_tcp_client<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    mut stream: S,
+    peer: SocketAddr,
+    state: Arc<Mutex<State>>,
+) {
+    let loopback = match peer.ip() {
+        IpAddr::V4(ip) => ip.is_loopback(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.to_ipv4_mapped().is_some_and(|ip| ip.is_loopback()),
+    };
+    if !loopback && state.lock().await.is_protected().await {
+        let _ = stream.write_all(PROTECTED_MODE_ERROR.as_bytes()).await;
+        let _ = stream.shutdown().await;
+        return;
+    }
+    handle_client(stream, loopback, state).await;
+}
+
+// Unix socket clients are local, protected mode doesn't apply to them
+async fn unix_listener_loop(listener: UnixListener, state: Arc<Mutex<State>>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                task::spawn(handle_client(stream, true, state.clone()));
+            }
+            Err(e) => eprintln!("accept error: {}", e),
+        }
+    }
+}
+
+// A listener for every `bind` address: `*` is every IPv4 interface, `::*`
+// every IPv6 one, and the addresses with a `-` prefix are skipped when this
+// host doesn't have them. Port 0 disables TCP.
+fn bind_tcp_listeners(bind: &[String], port: u16) -> Vec<TokioTcpListener> {
+    if port == 0 {
+        return Vec::new();
+    }
+    let mut listeners = Vec::new();
+    for address in bind {
+        let (optional, address) = match address.strip_prefix('-') {
+            Some(address) => (true, address),
+            None => (false, address.as_str()),
+        };
+        let ip = match address {
+            "*" => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            "::*" => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            address => address.parse().expect("checked by the `bind` parameter"),
+        };
+        match listen_tcp(SocketAddr::new(ip, port)) {
+            Ok(listener) => listeners.push(listener),
+            Err(error) if optional && error.kind() != io::ErrorKind::AddrInUse => {
+                println!("Skipping optional bind address {}: {}", address, error);
+            }
+            Err(error) => {
+                eprintln!("Failed listening on {}:{} (tcp): {}, aborting.", address, port, error);
+                process::exit(1);
+            }
+        }
+    }
+    listeners
+}
+
+fn listen_tcp(address: SocketAddr) -> io::Result<TokioTcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    // so `::*` doesn't also claim the IPv4 port `*` listens on
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    // Redis's default `tcp-backlog`
+    socket.listen(511)?;
+    TokioTcpListener::from_std(socket.into())
+}
+
+fn listen_unix(path: &str, unixsocketperm: u32) -> io::Result<UnixListener> {
+    // the socket file of an earlier run would make binding fail
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    if unixsocketperm != 0 {
+        fs::set_permissions(path, fs::Permissions::from_mode(unixsocketperm))?;
+    }
+    Ok(listener)
+}
+
+// PSYNC/REPLCONF handshake, authenticating first with the AUTH arguments
+// in `auth` unless there are none
+async fn do_replication_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    port: &str,
+    auth: &[String],
+) -> Result<Vec<u8>, String> {
     // PING
     stream.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
     let mut buf = [0u8; 512];
     let _n = stream.read(&mut buf).await.unwrap();
 
+    // AUTH [username] password
+    if !auth.is_empty() {
+        let mut command = vec![RespDataType::BulkString("AUTH".to_string())];
+        command.extend(auth.iter().map(|arg| RespDataType::BulkString(arg.clone())));
+        stream.write_all(RespDataType::Array(command).to_string().as_bytes()).await.unwrap();
+        let n = stream.read(&mut buf).await.unwrap();
+        let reply = String::from_utf8_lossy(&buf[..n]);
+        if reply.starts_with('-') {
+            return Err(format!("Unable to AUTH to MASTER: {}", reply.trim_end()));
+        }
+    }
+
     // REPLCONF listening-port
     stream
         .write_all(
@@ -238,10 +462,7 @@ async fn do_replication_handshake(stream: &mut TokioTcpStream, port: &str) -> Ve
     let mut is_rdb_content_read = false;
 
     while !is_full_resync_read || !is_rdb_content_read {
-        let n = match stream.read(&mut buf).await {
-            Ok(n) => n,
-            Err(_err) => 0,
-        };
+        let n = stream.read(&mut buf).await.unwrap_or_default();
         pending.extend_from_slice(&buf[..n]);
 
         if !is_full_resync_read {
@@ -253,11 +474,11 @@ async fn do_replication_handshake(stream: &mut TokioTcpStream, port: &str) -> Ve
                 }
             }
         }
-        if is_full_resync_read {
-            if pending.len() > 0 && pending[0] == b'$' {
+        if is_full_resync_read
+            && !pending.is_empty() && pending[0] == b'$' {
                 if let Some(end_of_rdb_clrf) = pending.windows(2).position(|w| w == b"\r\n") {
                     let size_of_rdb_content =
-                        String::from_utf8((&pending[1..end_of_rdb_clrf]).to_vec())
+                        String::from_utf8(pending[1..end_of_rdb_clrf].to_vec())
                             .unwrap()
                             .parse::<usize>()
                             .unwrap();
@@ -267,26 +488,30 @@ async fn do_replication_handshake(stream: &mut TokioTcpStream, port: &str) -> Ve
                     is_rdb_content_read = true;
                 }
             }
-        }
     }
 
-    return pending;
+    Ok(pending)
 }
 
-fn find_complete_frame(buf: &Vec<u8>) -> Option<usize> {
+fn find_complete_frame(buf: &[u8]) -> Option<usize> {
     if buf.is_empty() {
         return None;
     };
 
-    fn find_bulk_size(buf: &Vec<u8>) -> Option<usize> {
+    fn find_bulk_size(buf: &[u8]) -> Option<usize> {
         if let Some(length_of_crlf_idx) = buf.windows(2).position(|w| w == b"\r\n") {
             let length_buffer = &buf[1..length_of_crlf_idx];
             let size_as_string = String::from_utf8(length_buffer.to_vec()).unwrap();
             let size = size_as_string.parse::<usize>().unwrap();
             let size_of_size = size_as_string.len();
-            return Some(1 + size_of_size + 2 + size + 2);
+            let frame_len = 1 + size_of_size + 2 + size + 2;
+            // the payload may still be on its way
+            if buf.len() < frame_len {
+                return None;
+            }
+            return Some(frame_len);
         }
-        return None;
+        None
     }
 
     match buf[0] {
@@ -295,11 +520,9 @@ fn find_complete_frame(buf: &Vec<u8>) -> Option<usize> {
                 let frame_len = crlf_idx + 2;
                 return Some(frame_len);
             }
-            return None;
-        }
-        b'$' => {
-            return find_bulk_size(buf);
+            None
         }
+        b'$' => find_bulk_size(buf),
         b'*' => {
             if let Some(array_size_end_crlf) = buf.windows(2).position(|w| w == b"\r\n") {
                 let array_size_buffer = &buf[1..array_size_end_crlf];
@@ -308,7 +531,7 @@ fn find_complete_frame(buf: &Vec<u8>) -> Option<usize> {
                 let mut total_bulk_string_size = 0;
                 let mut start = 1 + array_size_as_string.len() + 2;
                 for _ in 0..array_size {
-                    if let Some(bulk_size) = find_bulk_size(&buf[start..].to_vec()) {
+                    if let Some(bulk_size) = find_bulk_size(&buf[start..]) {
                         start += bulk_size;
                         total_bulk_string_size += bulk_size;
                     } else {
@@ -317,7 +540,7 @@ fn find_complete_frame(buf: &Vec<u8>) -> Option<usize> {
                 }
                 return Some(1 + array_size_as_string.len() + 2 + total_bulk_string_size);
             }
-            return None;
+            None
         }
         _ => None,
     }
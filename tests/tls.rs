@@ -0,0 +1,163 @@
+// TLS connections, client certificate authentication and TLS replication,
+// against the server binary. The certificates in tests/tls come from
+// tests/tls/gen-test-certs.sh.
+
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+// A server process, killed when the test is done with it
+struct Server {
+    child: Child,
+    dir: PathBuf,
+    port: u16,
+    tls_port: u16,
+}
+
+impl Server {
+    async fn start(extra: &[&str]) -> Server {
+        let (port, tls_port) = (free_port(), free_port());
+        // its own directory, so no dump.rdb is loaded or left behind
+        let dir = std::env::temp_dir().join(format!("redis-tls-test-{}-{}", std::process::id(), port));
+        fs::create_dir_all(&dir).unwrap();
+        let (port_text, tls_port_text) = (port.to_string(), tls_port.to_string());
+        let (cert, key, ca) = (fixture("server.crt"), fixture("server.key"), fixture("ca.crt"));
+        let mut args = vec![
+            "--port", &port_text,
+            "--tls-port", &tls_port_text,
+            "--tls-cert-file", &cert,
+            "--tls-key-file", &key,
+            "--tls-ca-cert-file", &ca,
+            "--dir", dir.to_str().unwrap(),
+        ];
+        args.extend_from_slice(extra);
+        let child = Command::new(env!("CARGO_BIN_EXE_codecrafters-redis"))
+            .args(&args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Server { child, dir, port, tls_port };
+        for _ in 0..100 {
+            if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+                return server;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        panic!("server didn't start listening on port {}", port);
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn client_config(with_certificate: bool) -> Arc<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    for certificate in rustls_pemfile::certs(&mut BufReader::new(File::open(fixture("ca.crt")).unwrap())) {
+        roots.add(certificate.unwrap()).unwrap();
+    }
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let config = if with_certificate {
+        let certificates = rustls_pemfile::certs(&mut BufReader::new(File::open(fixture("client.crt")).unwrap()))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(fixture("client.key")).unwrap()))
+            .unwrap()
+            .unwrap();
+        builder.with_client_auth_cert(certificates, key).unwrap()
+    } else {
+        builder.with_no_client_auth()
+    };
+    Arc::new(config)
+}
+
+async fn connect_tls(port: u16, with_certificate: bool) -> std::io::Result<TlsStream<TcpStream>> {
+    let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    let server_name = ServerName::try_from("127.0.0.1").unwrap();
+    TlsConnector::from(client_config(with_certificate)).connect(server_name, stream).await
+}
+
+fn encode(args: &[&str]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend(format!("${}\r\n{}\r\n", arg.len(), arg).into_bytes());
+    }
+    out
+}
+
+// Sends the command and returns the reply, or the error that ended the
+// connection; a connection closed without a reply is an empty reply
+async fn request<S: AsyncReadExt + AsyncWriteExt + Unpin>(stream: &mut S, args: &[&str]) -> std::io::Result<String> {
+    stream.write_all(&encode(args)).await?;
+    let mut buffer = [0u8; 512];
+    let read = timeout(Duration::from_secs(5), stream.read(&mut buffer)).await??;
+    Ok(String::from_utf8_lossy(&buffer[..read]).to_string())
+}
+
+#[tokio::test]
+async fn serves_clients_over_tls() {
+    let server = Server::start(&["--tls-auth-clients", "no"]).await;
+    let mut stream = connect_tls(server.tls_port, false).await.unwrap();
+    assert_eq!(request(&mut stream, &["SET", "k", "v"]).await.unwrap(), "+OK\r\n");
+    assert_eq!(request(&mut stream, &["GET", "k"]).await.unwrap(), "$1\r\nv\r\n");
+    // the plain port still serves the same data
+    let mut plain = TcpStream::connect(("127.0.0.1", server.port)).await.unwrap();
+    assert_eq!(request(&mut plain, &["GET", "k"]).await.unwrap(), "$1\r\nv\r\n");
+}
+
+#[tokio::test]
+async fn rejects_clients_without_a_certificate() {
+    let server = Server::start(&[]).await;
+    // with TLS 1.3 the client only learns about the rejection on its
+    // first read, so either the handshake or the request fails
+    let reply = match connect_tls(server.tls_port, false).await {
+        Ok(mut stream) => request(&mut stream, &["PING"]).await.unwrap_or_default(),
+        Err(_) => String::new(),
+    };
+    assert_eq!(reply, "");
+    let mut stream = connect_tls(server.tls_port, true).await.unwrap();
+    assert_eq!(request(&mut stream, &["PING"]).await.unwrap(), "+PONG\r\n");
+}
+
+#[tokio::test]
+async fn replicates_over_tls() {
+    let master = Server::start(&[]).await;
+    let master_address = format!("127.0.0.1 {}", master.tls_port);
+    let replica = Server::start(&["--tls-replication", "yes", "--replicaof", &master_address]).await;
+
+    let mut stream = connect_tls(master.tls_port, true).await.unwrap();
+    assert_eq!(request(&mut stream, &["SET", "k", "v"]).await.unwrap(), "+OK\r\n");
+
+    let mut plain = TcpStream::connect(("127.0.0.1", replica.port)).await.unwrap();
+    for _ in 0..100 {
+        if request(&mut plain, &["GET", "k"]).await.unwrap() == "$1\r\nv\r\n" {
+            return;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    panic!("the replica never received the write");
+}
@@ -0,0 +1,27 @@
+#!/bin/sh
+# Generates the certificates the TLS tests use: a CA, a server certificate
+# for 127.0.0.1 and localhost, and a client certificate, all signed by the
+# CA. Run it again from anywhere to replace them.
+set -e
+
+OPENSSL=${OPENSSL:-openssl}
+DIR=$(cd "$(dirname "$0")" && pwd)
+DAYS=3650
+
+generate_cert() {
+    name=$1
+    cn=$2
+    "$OPENSSL" genrsa -out "$DIR/$name.key" 2048
+    "$OPENSSL" req -new -sha256 -subj "/O=Redis Test/CN=$cn" -key "$DIR/$name.key" -out "$DIR/$name.csr"
+    printf 'subjectAltName=IP:127.0.0.1,DNS:localhost\nbasicConstraints=CA:FALSE\nkeyUsage=digitalSignature,keyEncipherment\nextendedKeyUsage=serverAuth,clientAuth\n' > "$DIR/$name.cnf"
+    "$OPENSSL" x509 -req -sha256 -days $DAYS -in "$DIR/$name.csr" \
+        -CA "$DIR/ca.crt" -CAkey "$DIR/ca.key" -CAcreateserial -extfile "$DIR/$name.cnf" -out "$DIR/$name.crt"
+    rm -f "$DIR/$name.csr" "$DIR/$name.cnf"
+}
+
+"$OPENSSL" genrsa -out "$DIR/ca.key" 4096
+"$OPENSSL" req -x509 -new -nodes -sha256 -key "$DIR/ca.key" -days $DAYS \
+    -subj "/O=Redis Test/CN=Certificate Authority" -out "$DIR/ca.crt"
+generate_cert server "Server"
+generate_cert client "Client"
+rm -f "$DIR/ca.srl"